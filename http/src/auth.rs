@@ -0,0 +1,393 @@
+// Authenticates requests with the `Authorization` header.
+// "HTTP Authentication" https://tools.ietf.org/html/rfc7235
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use ring::rand::SecureRandom;
+use ring::{digest, hmac, pbkdf2};
+
+use crate::middleware::{around, Flow, Middleware};
+use crate::server::HttpHandler;
+use crate::{Header, HttpError, HttpReaderWriter, HttpStatus};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthFailure {
+    /// The request has no credentials or bad credentials.  The client gets 401 with a challenge.
+    Unauthorized,
+    /// The credentials are good but do not allow the request.  The client gets 403.
+    Forbidden,
+}
+
+/// Authenticator checks a request's credentials and returns the name of the user or client
+/// that sent it, the principal.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Returns the `WWW-Authenticate` header value for 401 responses, like `Basic realm="admin"`.
+    fn challenge(&self) -> String;
+
+    async fn authenticate(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<String, AuthFailure>;
+}
+
+/// Returns the credentials from an `Authorization` header with `scheme`, ignoring the scheme's case.
+fn credentials<'h>(http_reader_writer: &'h HttpReaderWriter<'_>, scheme: &str) -> Option<&'h str> {
+    let value = http_reader_writer.header("authorization")?;
+    let space = value.find(' ')?;
+    if !value[..space].eq_ignore_ascii_case(scheme) {
+        return None;
+    }
+    Some(value[space + 1..].trim())
+}
+
+/// Authenticate is middleware that authenticates requests and sets their principal.
+/// See `HttpReaderWriter::principal`.  The server logs the principal with the request.
+///
+/// Rejects other requests with 401 Unauthorized and a `WWW-Authenticate` header,
+/// or 403 Forbidden.
+pub struct Authenticate<A: Authenticator>(pub A);
+
+#[async_trait]
+impl<A: Authenticator> Middleware for Authenticate<A> {
+    async fn before(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<Flow, HttpError> {
+        match self.0.authenticate(http_reader_writer).await {
+            Ok(principal) => {
+                http_reader_writer.set_principal(principal);
+                Ok(Flow::Continue)
+            }
+            Err(AuthFailure::Unauthorized) => {
+                let challenge = self.0.challenge();
+                http_reader_writer.send_without_body(
+                    HttpStatus::Unauthorized401, &[&Header::new("www-authenticate", &challenge)]).await?;
+                Ok(Flow::Done)
+            }
+            Err(AuthFailure::Forbidden) => Err(HttpError::ProcessingError(HttpStatus::Forbidden403)),
+        }
+    }
+}
+
+/// Authenticated passes authenticated requests to its handler, with the principal set.
+/// See `Authenticate`.
+pub struct Authenticated<A: Authenticator, H: HttpHandler> {
+    authenticate: Authenticate<A>,
+    inner: H,
+}
+
+impl<A: Authenticator, H: HttpHandler> Authenticated<A, H> {
+    pub fn new(authenticator: A, inner: H) -> Authenticated<A, H> {
+        Authenticated { authenticate: Authenticate(authenticator), inner }
+    }
+}
+
+#[async_trait]
+impl<A: Authenticator, H: HttpHandler> HttpHandler for Authenticated<A, H> {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        around(&self.authenticate, &self.inner, http_reader_writer).await
+    }
+}
+
+const PASSWORD_HASH_PREFIX: &str = "pbkdf2-sha256";
+const PASSWORD_HASH_ITERATIONS: u32 = 100_000;
+
+/// A salted PBKDF2-HMAC-SHA256 password hash.
+///
+/// Its string form is `pbkdf2-sha256$ITERATIONS$SALT$HASH`, with base64 salt and hash.
+/// Put hashes in config files, never passwords.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PasswordHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    /// Hashes `password` with a random salt.
+    pub fn new(password: &str) -> PasswordHash {
+        let mut salt = vec![0u8; 16];
+        ring::rand::SystemRandom::new().fill(&mut salt).unwrap();
+        PasswordHash::with_salt(password, salt, NonZeroU32::new(PASSWORD_HASH_ITERATIONS).unwrap())
+    }
+
+    fn with_salt(password: &str, salt: Vec<u8>, iterations: NonZeroU32) -> PasswordHash {
+        let mut hash = vec![0u8; digest::SHA256_OUTPUT_LEN];
+        pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &mut hash);
+        PasswordHash { iterations, salt, hash }
+    }
+
+    pub fn parse(s: &str) -> Option<PasswordHash> {
+        let mut parts = s.split('$');
+        if parts.next()? != PASSWORD_HASH_PREFIX {
+            return None;
+        }
+        let iterations = NonZeroU32::new(parts.next()?.parse().ok()?)?;
+        let salt = base64::decode(parts.next()?).ok()?;
+        let hash = base64::decode(parts.next()?).ok()?;
+        if parts.next().is_some() || hash.len() != digest::SHA256_OUTPUT_LEN {
+            return None;
+        }
+        Some(PasswordHash { iterations, salt, hash })
+    }
+
+    /// Checks the password in constant time.
+    pub fn verify(&self, password: &str) -> bool {
+        pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, self.iterations, &self.salt, password.as_bytes(), &self.hash)
+            .is_ok()
+    }
+}
+
+impl std::fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}${}${}${}", PASSWORD_HASH_PREFIX, self.iterations,
+               base64::encode(&self.salt), base64::encode(&self.hash))
+    }
+}
+
+/// BasicAuth checks usernames and passwords sent with HTTP Basic authentication.
+/// https://tools.ietf.org/html/rfc7617
+///
+/// Basic authentication sends the password with every request.  Use it only with TLS.
+pub struct BasicAuth {
+    realm: String,
+    users: HashMap<String, PasswordHash>,
+    // Checked for unknown users, so they take as long to reject as bad passwords.
+    dummy_hash: PasswordHash,
+}
+
+impl BasicAuth {
+    pub fn new(realm: impl Into<String>) -> BasicAuth {
+        BasicAuth { realm: realm.into(), users: HashMap::new(), dummy_hash: PasswordHash::new("") }
+    }
+
+    pub fn user(mut self, username: impl Into<String>, password_hash: PasswordHash) -> BasicAuth {
+        self.users.insert(username.into(), password_hash);
+        self
+    }
+}
+
+/// Decodes `base64(username:password)`.
+fn decode_basic(encoded: &str) -> Result<(String, String), AuthFailure> {
+    let decoded = base64::decode(encoded).map_err(|_e| AuthFailure::Unauthorized)?;
+    let decoded = String::from_utf8(decoded).map_err(|_e| AuthFailure::Unauthorized)?;
+    let colon = decoded.find(':').ok_or(AuthFailure::Unauthorized)?;
+    Ok((String::from(&decoded[..colon]), String::from(&decoded[colon + 1..])))
+}
+
+#[async_trait]
+impl Authenticator for BasicAuth {
+    fn challenge(&self) -> String {
+        format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm)
+    }
+
+    async fn authenticate(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<String, AuthFailure> {
+        let encoded = credentials(http_reader_writer, "Basic").ok_or(AuthFailure::Unauthorized)?;
+        let (username, password) = decode_basic(encoded)?;
+        let (known_user, hash) = match self.users.get(&username) {
+            Some(hash) => (true, hash.clone()),
+            None => (false, self.dummy_hash.clone()),
+        };
+        // Hashing takes tens of milliseconds, too long to block the executor.
+        let verified = tokio::task::spawn_blocking(move || hash.verify(&password)).await.unwrap_or(false);
+        if known_user && verified {
+            Ok(username)
+        } else {
+            Err(AuthFailure::Unauthorized)
+        }
+    }
+}
+
+/// TokenVerifier checks bearer tokens, like API keys or tokens from an identity provider.
+#[async_trait]
+pub trait TokenVerifier: Send + Sync {
+    /// Returns the principal for the token.
+    async fn verify(&self, token: &str) -> Result<String, AuthFailure>;
+}
+
+/// StaticTokens is a TokenVerifier with a fixed set of tokens.
+///
+/// It keeps only SHA-256 digests of the tokens and compares them in constant time.
+#[derive(Default)]
+pub struct StaticTokens {
+    digests: Vec<(Vec<u8>, String)>,
+}
+
+impl StaticTokens {
+    pub fn new() -> StaticTokens {
+        StaticTokens::default()
+    }
+
+    pub fn token(mut self, token: &str, principal: impl Into<String>) -> StaticTokens {
+        self.digests.push((digest::digest(&digest::SHA256, token.as_bytes()).as_ref().to_vec(), principal.into()));
+        self
+    }
+}
+
+#[async_trait]
+impl TokenVerifier for StaticTokens {
+    async fn verify(&self, token: &str) -> Result<String, AuthFailure> {
+        let token_digest = digest::digest(&digest::SHA256, token.as_bytes());
+        let mut principal = None;
+        // Checks every entry, so the time does not depend on which token matched.
+        for (digest, name) in &self.digests {
+            if ring::constant_time::verify_slices_are_equal(digest, token_digest.as_ref()).is_ok() {
+                principal = Some(name.clone());
+            }
+        }
+        principal.ok_or(AuthFailure::Unauthorized)
+    }
+}
+
+/// BearerAuth checks tokens sent as `Authorization: Bearer TOKEN`.
+/// https://tools.ietf.org/html/rfc6750
+pub struct BearerAuth<V: TokenVerifier> {
+    realm: String,
+    verifier: V,
+}
+
+impl<V: TokenVerifier> BearerAuth<V> {
+    pub fn new(realm: impl Into<String>, verifier: V) -> BearerAuth<V> {
+        BearerAuth { realm: realm.into(), verifier }
+    }
+}
+
+#[async_trait]
+impl<V: TokenVerifier> Authenticator for BearerAuth<V> {
+    fn challenge(&self) -> String {
+        format!("Bearer realm=\"{}\"", self.realm)
+    }
+
+    async fn authenticate(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<String, AuthFailure> {
+        let token = credentials(http_reader_writer, "Bearer").ok_or(AuthFailure::Unauthorized)?;
+        self.verifier.verify(token).await
+    }
+}
+
+const HMAC_SCHEME: &str = "HMAC-SHA256";
+
+/// The signed data: method, path with query, timestamp, and content-length.
+fn hmac_message(method: &str, path: &str, timestamp: u64, content_length: u64) -> String {
+    format!("{}\n{}\n{}\n{}", method, path, timestamp, content_length)
+}
+
+/// Returns an `Authorization` header value for a request signed with `secret`.
+/// `timestamp` is seconds since the Unix epoch.
+pub fn hmac_authorization(
+    key_id: &str, secret: &[u8], method: &str, path: &str, timestamp: u64, content_length: u64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    let signature = hmac::sign(&key, hmac_message(method, path, timestamp, content_length).as_bytes());
+    format!("{} key_id={},timestamp={},signature={}",
+            HMAC_SCHEME, key_id, timestamp, base64::encode(signature.as_ref()))
+}
+
+/// HmacAuth checks requests signed with a shared secret.  See `hmac_authorization`.
+///
+/// The header is `Authorization: HMAC-SHA256 key_id=ID,timestamp=SECONDS,signature=BASE64`.
+/// The signature covers the method, path, query, timestamp, and content-length, but not the body.
+///
+/// Requests with timestamps outside the window are rejected.  Attackers who capture a request
+/// can replay it within the window, so use this only with TLS.
+pub struct HmacAuth {
+    window: Duration,
+    keys: HashMap<String, hmac::Key>,
+}
+
+impl HmacAuth {
+    /// Accepts requests with timestamps up to `window` before or after the server's clock.
+    pub fn new(window: Duration) -> HmacAuth {
+        HmacAuth { window, keys: HashMap::new() }
+    }
+
+    /// Adds a key.  The key id is the principal of requests signed with it.
+    pub fn key(mut self, key_id: impl Into<String>, secret: &[u8]) -> HmacAuth {
+        self.keys.insert(key_id.into(), hmac::Key::new(hmac::HMAC_SHA256, secret));
+        self
+    }
+
+    fn check(&self, params: &str, method: &str, path: &str, content_length: u64, now: u64)
+             -> Result<String, AuthFailure> {
+        let (mut key_id, mut timestamp, mut signature) = (None, None, None);
+        for param in params.split(',') {
+            let eq = param.find('=').ok_or(AuthFailure::Unauthorized)?;
+            let value = param[eq + 1..].trim();
+            match param[..eq].trim() {
+                "key_id" => key_id = Some(value),
+                "timestamp" => timestamp = value.parse::<u64>().ok(),
+                "signature" => signature = base64::decode(value).ok(),
+                _ => return Err(AuthFailure::Unauthorized),
+            }
+        }
+        let key_id = key_id.ok_or(AuthFailure::Unauthorized)?;
+        let timestamp = timestamp.ok_or(AuthFailure::Unauthorized)?;
+        let signature = signature.ok_or(AuthFailure::Unauthorized)?;
+        let key = self.keys.get(key_id).ok_or(AuthFailure::Unauthorized)?;
+        let window = self.window.as_secs();
+        if timestamp > now.saturating_add(window) || now > timestamp.saturating_add(window) {
+            return Err(AuthFailure::Unauthorized);
+        }
+        hmac::verify(key, hmac_message(method, path, timestamp, content_length).as_bytes(), &signature)
+            .map_err(|_e| AuthFailure::Unauthorized)?;
+        Ok(String::from(key_id))
+    }
+}
+
+#[async_trait]
+impl Authenticator for HmacAuth {
+    fn challenge(&self) -> String {
+        String::from(HMAC_SCHEME)
+    }
+
+    async fn authenticate(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<String, AuthFailure> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let params = credentials(http_reader_writer, HMAC_SCHEME).ok_or(AuthFailure::Unauthorized)?;
+        self.check(params, http_reader_writer.method().as_str(), &http_reader_writer.raw_path,
+                   http_reader_writer.content_length(), now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_hash() {
+        let hash = PasswordHash::with_salt("pass1", b"salt".to_vec(), NonZeroU32::new(2).unwrap());
+        assert!(hash.verify("pass1"));
+        assert!(!hash.verify("pass2"));
+        let s = hash.to_string();
+        assert!(s.starts_with("pbkdf2-sha256$2$c2FsdA==$"), "{}", s);
+        assert_eq!(Some(hash), PasswordHash::parse(&s));
+        assert_eq!(None, PasswordHash::parse("pbkdf2-sha256$2$c2FsdA==$c2FsdA=="));
+        assert_eq!(None, PasswordHash::parse("md5$2$c2FsdA==$c2FsdA=="));
+        assert_ne!(PasswordHash::new("pass1").to_string(), PasswordHash::new("pass1").to_string());
+    }
+
+    #[test]
+    fn test_decode_basic() {
+        assert_eq!(Ok((String::from("user1"), String::from("p:w"))), decode_basic(&base64::encode("user1:p:w")));
+        assert_eq!(Ok((String::new(), String::new())), decode_basic(&base64::encode(":")));
+        assert_eq!(Err(AuthFailure::Unauthorized), decode_basic(&base64::encode("user1")));
+        assert_eq!(Err(AuthFailure::Unauthorized), decode_basic(&base64::encode(b"\xff:a")));
+        assert_eq!(Err(AuthFailure::Unauthorized), decode_basic("!"));
+    }
+
+    #[test]
+    fn test_hmac_check() {
+        let hmac_auth = HmacAuth::new(Duration::from_secs(300)).key("client1", b"secret1");
+        let header = hmac_authorization("client1", b"secret1", "POST", "/a?b=c", 1000, 5);
+        let params = header.strip_prefix("HMAC-SHA256 ").unwrap();
+        assert_eq!(Ok(String::from("client1")), hmac_auth.check(params, "POST", "/a?b=c", 5, 1000));
+        assert_eq!(Ok(String::from("client1")), hmac_auth.check(params, "POST", "/a?b=c", 5, 1300));
+        assert_eq!(Ok(String::from("client1")), hmac_auth.check(params, "POST", "/a?b=c", 5, 700));
+        assert_eq!(Err(AuthFailure::Unauthorized), hmac_auth.check(params, "POST", "/a?b=c", 5, 1301));
+        assert_eq!(Err(AuthFailure::Unauthorized), hmac_auth.check(params, "POST", "/a?b=c", 5, 699));
+        assert_eq!(Err(AuthFailure::Unauthorized), hmac_auth.check(params, "PUT", "/a?b=c", 5, 1000));
+        assert_eq!(Err(AuthFailure::Unauthorized), hmac_auth.check(params, "POST", "/a?b=d", 5, 1000));
+        assert_eq!(Err(AuthFailure::Unauthorized), hmac_auth.check(params, "POST", "/a?b=c", 6, 1000));
+        let header = hmac_authorization("client1", b"secret2", "POST", "/a?b=c", 1000, 5);
+        let params = header.strip_prefix("HMAC-SHA256 ").unwrap();
+        assert_eq!(Err(AuthFailure::Unauthorized), hmac_auth.check(params, "POST", "/a?b=c", 5, 1000));
+        let header = hmac_authorization("client2", b"secret1", "POST", "/a?b=c", 1000, 5);
+        let params = header.strip_prefix("HMAC-SHA256 ").unwrap();
+        assert_eq!(Err(AuthFailure::Unauthorized), hmac_auth.check(params, "POST", "/a?b=c", 5, 1000));
+        assert_eq!(Err(AuthFailure::Unauthorized), hmac_auth.check("key_id=client1", "POST", "/", 0, 1000));
+    }
+}
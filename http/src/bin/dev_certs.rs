@@ -0,0 +1,166 @@
+// This program makes a local certificate authority (CA) and uses it to issue server and client
+// certificates for development.  Use them to run servers with mutual TLS (mTLS) on your machine.
+//
+// Usage:
+//   dev_certs OUT_DIR [--san NAME]... [--client NAME]... [--days N]
+//
+// --san adds a Subject Alternative Name to the server certificate.  Use DNS names or IP addresses.
+//   Default: localhost, 127.0.0.1, and ::1.
+// --client issues a client certificate with the given name.  The name is also a file name, so it
+//   may contain only letters, digits, `.`, `_`, and `-`, and must not start with `.`.
+// --days sets how long the certificates are valid.  Must be positive.  Default: 30.
+//
+// It writes:
+//   OUT_DIR/ca.cert                 CA certificate.  Clients trust this to verify the server.
+//   OUT_DIR/ca.key                  CA private key.
+//   OUT_DIR/server.cert             Server certificate chain: server certificate, then CA certificate.
+//   OUT_DIR/server.key              Server private key.
+//   OUT_DIR/clients/NAME.cert       Client certificate chain.
+//   OUT_DIR/clients/NAME.key        Client private key.
+//   OUT_DIR/allowed_clients/NAME.cert  Client certificate, for the server's allowlist.
+//
+// Load the server files with `beatrice_http::cert_reloader::ReloadingCertResolver` and the
+// allowlist with `beatrice_http::cert_allowlist::AllowedClientCertsVerifier::from_dir`.
+//
+// Running it again replaces all of the files with new keys and certificates.
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::println;
+
+struct Args {
+    out_dir: PathBuf,
+    sans: Vec<String>,
+    clients: Vec<String>,
+    days: i64,
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: dev_certs OUT_DIR [--san NAME]... [--client NAME]... [--days N]");
+    std::process::exit(1);
+}
+
+/// Returns true if `name` is safe to use as a file name in the output directories.
+fn valid_client_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.')
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'_' || b == b'-')
+}
+
+fn parse_args() -> Args {
+    let mut out_dir = None;
+    let mut sans = Vec::new();
+    let mut clients = Vec::new();
+    let mut days = 30;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--san" => sans.push(args.next().unwrap_or_else(|| usage())),
+            "--client" => {
+                clients.push(args.next().filter(|s| valid_client_name(s)).unwrap_or_else(|| usage()));
+            }
+            "--days" => {
+                days = args.next().and_then(|s| s.parse().ok()).filter(|d| *d > 0).unwrap_or_else(|| usage());
+            }
+            s if s.starts_with("--") || out_dir.is_some() => usage(),
+            s => out_dir = Some(PathBuf::from(s)),
+        }
+    }
+    if sans.is_empty() {
+        sans = vec![String::from("localhost"), String::from("127.0.0.1"), String::from("::1")];
+    }
+    Args { out_dir: out_dir.unwrap_or_else(|| usage()), sans, clients, days }
+}
+
+fn san(name: &str) -> rcgen::SanType {
+    match name.parse::<std::net::IpAddr>() {
+        Ok(ip) => rcgen::SanType::IpAddress(ip),
+        Err(_) => rcgen::SanType::DnsName(String::from(name)),
+    }
+}
+
+fn serial_number() -> u64 {
+    // Certificates from the same CA must have different serial numbers.
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+fn params(common_name: &str, days: i64) -> rcgen::CertificateParams {
+    let mut params = rcgen::CertificateParams::default();
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params.distinguished_name.push(rcgen::DnType::CommonName, common_name);
+    // Backdate a little to tolerate clock skew between machines.
+    params.not_before = chrono::Utc::now() - chrono::Duration::hours(1);
+    params.not_after = chrono::Utc::now() + chrono::Duration::days(days);
+    params.serial_number = Some(serial_number());
+    params
+}
+
+fn write(path: &Path, contents: &str) {
+    std::fs::write(path, contents)
+        .unwrap_or_else(|e| panic!("Failed writing {:?}: {}", path, e));
+    println!("INFO wrote {}", path.display());
+}
+
+// Writes a private key readable only by the owner.  Rewriting an existing file also fixes its mode.
+fn write_key(path: &Path, contents: &str) {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| {
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+            file.write_all(contents.as_bytes())
+        })
+        .unwrap_or_else(|e| panic!("Failed writing {:?}: {}", path, e));
+    println!("INFO wrote {}", path.display());
+}
+
+fn main() {
+    let args = parse_args();
+    let clients_dir = args.out_dir.join("clients");
+    let allowed_clients_dir = args.out_dir.join("allowed_clients");
+    for dir in &[&args.out_dir, &clients_dir, &allowed_clients_dir] {
+        std::fs::create_dir_all(dir).unwrap_or_else(|e| panic!("Failed creating {:?}: {}", dir, e));
+    }
+
+    let mut ca_params = params("beatrice dev CA", args.days);
+    ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+    let ca_pem = ca.serialize_pem().unwrap();
+    write(&args.out_dir.join("ca.cert"), &ca_pem);
+    write_key(&args.out_dir.join("ca.key"), &ca.serialize_private_key_pem());
+
+    let mut server_params = params(&args.sans[0], args.days);
+    server_params.subject_alt_names = args.sans.iter().map(|s| san(s)).collect();
+    server_params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ServerAuth];
+    let server = rcgen::Certificate::from_params(server_params).unwrap();
+    write(&args.out_dir.join("server.cert"),
+          &(server.serialize_pem_with_signer(&ca).unwrap() + &ca_pem));
+    write_key(&args.out_dir.join("server.key"), &server.serialize_private_key_pem());
+
+    for name in &args.clients {
+        let mut client_params = params(name, args.days);
+        // rustls requires a SAN on every certificate.
+        client_params.subject_alt_names = vec![san(name)];
+        client_params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ClientAuth];
+        let client = rcgen::Certificate::from_params(client_params).unwrap();
+        // Sign once so the client and the allowlist get the same certificate.
+        let client_pem = client.serialize_pem_with_signer(&ca).unwrap();
+        write(&clients_dir.join(format!("{}.cert", name)), &(client_pem.clone() + &ca_pem));
+        write_key(&clients_dir.join(format!("{}.key", name)), &client.serialize_private_key_pem());
+        write(&allowed_clients_dir.join(format!("{}.cert", name)), &client_pem);
+    }
+}
+
+// $ cargo run --bin dev_certs -- dev_certs --client event_store --days 7
+// INFO wrote dev_certs/ca.cert
+// INFO wrote dev_certs/ca.key
+// INFO wrote dev_certs/server.cert
+// INFO wrote dev_certs/server.key
+// INFO wrote dev_certs/clients/event_store.cert
+// INFO wrote dev_certs/clients/event_store.key
+// INFO wrote dev_certs/allowed_clients/event_store.cert
@@ -15,79 +15,14 @@
 //    They can reach a safe stopping place and then stop.
 // 1. Wait a bit to give them time to see the signal and respond.
 // 1. Finally, kill the server process.
+//
+// `beatrice_http::server::HttpServer::stop` does these steps for HTTP servers.
 
 use std::println;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc;
 use tokio::time::{delay_for, Duration};
 
-pub async fn wait_for_stop_signal() {
-    // Handle TERM signal for running in Docker, Kubernetes, supervisord, etc.
-    // Also handle INT signal from CTRL-C in dev terminal.
-    use tokio::signal::unix::{signal, SignalKind};
-    let mut term_signal =
-        signal(SignalKind::terminate()).expect("Failed installing TERM signal handler");
-    let mut int_signal =
-        signal(SignalKind::interrupt()).expect("Failed installing INT signal handler");
-    use tokio::stream::StreamExt;
-    futures::future::select(term_signal.next(), int_signal.next()).await;
-}
-
-pub struct StopperController {
-    signal_rx: mpsc::Receiver<()>,
-    tracker_rx: mpsc::Receiver<()>,
-}
-
-impl StopperController {
-    fn signal_stop(&mut self) {
-        self.signal_rx.close();
-    }
-
-    async fn wait(&mut self) {
-        self.tracker_rx.recv().await;
-    }
-}
-
-#[derive(Clone)]
-pub struct Stopper {
-    signal_tx: mpsc::Sender<()>,
-    tracker_tx: mpsc::Sender<()>,
-}
-
-impl Stopper {
-    async fn wait(&mut self) {
-        // The send() call always waits because new_stopper() fills the channel.
-        match self.signal_tx.send(()).await {
-            Ok(_) => {}
-            Err(_) => {
-                return;
-            }
-        }
-    }
-
-    fn is_signalled(&mut self) -> bool {
-        match self.signal_tx.try_send(()) {
-            Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => true,
-            _ => false,
-        }
-    }
-}
-
-pub fn new_stopper() -> (StopperController, Stopper) {
-    let (mut signal_tx, signal_rx) = mpsc::channel(1);
-    signal_tx.try_send(()).unwrap(); // Fill the channel so senders wait.
-    let (tracker_tx, tracker_rx) = mpsc::channel(1);
-    (
-        StopperController {
-            signal_rx,
-            tracker_rx,
-        },
-        Stopper {
-            signal_tx,
-            tracker_tx,
-        },
-    )
-}
+use beatrice_http::stopper::{new_stopper, wait_for_stop_signal, Stopper};
 
 async fn handle_conn(mut tcp_stream: TcpStream, mut stopper: Stopper) {
     println!("INFO handler writing response slowly");
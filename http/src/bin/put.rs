@@ -1,7 +1,12 @@
 // This program shows how to handle HTTP 1.1 requests.
 use std::pin::Pin;
 use std::println;
+use std::sync::Arc;
 
+use async_trait::async_trait;
+use beatrice_http::cors::CorsPolicy;
+use beatrice_http::middleware::{Middleware, Router, Stack};
+use beatrice_http::server::HttpHandler;
 use beatrice_http::{
     escape_ascii,
     HttpError,
@@ -10,8 +15,31 @@ use beatrice_http::{
     HttpStatus,
 };
 
-async fn handle_get(mut http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
-    if *http_reader_writer.raw_path == *"/big" {
+/// Logs each request, like `HttpServer` does.
+struct LogRequests;
+
+#[async_trait]
+impl Middleware for LogRequests {
+    async fn after(&self, http_reader_writer: &mut HttpReaderWriter<'_>, result: Result<(), HttpError>)
+                   -> Result<(), HttpError> {
+        match &result {
+            Ok(()) => println!("INFO server {:?}", http_reader_writer),
+            Err(e) => println!("INFO server {:?} error={:?}", http_reader_writer, e),
+        }
+        result
+    }
+}
+
+struct BigHandler;
+
+#[async_trait]
+impl HttpHandler for BigHandler {
+    async fn handle(&self, mut http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        match http_reader_writer.method() {
+            HttpMethod::GET => {}
+            HttpMethod::PUT => return handle_put(http_reader_writer).await,
+            _ => return Err(HttpError::ProcessingError(HttpStatus::MethodNotAllowed405)),
+        }
         let size = 1024 * 1024;
         http_reader_writer.send_with_content_length(HttpStatus::Ok200, &[], size)
             .await?;
@@ -21,8 +49,19 @@ async fn handle_get(mut http_reader_writer: &mut HttpReaderWriter<'_>) -> Result
             .await
             .and(Ok(()))
             .map_err(HttpError::from_io_err)
-    } else {
-        http_reader_writer.send_text(HttpStatus::Ok200, &[], "body1").await
+    }
+}
+
+struct SmallHandler;
+
+#[async_trait]
+impl HttpHandler for SmallHandler {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        match http_reader_writer.method() {
+            HttpMethod::GET => http_reader_writer.send_text(HttpStatus::Ok200, &[], "body1").await,
+            HttpMethod::PUT => handle_put(http_reader_writer).await,
+            _ => Err(HttpError::ProcessingError(HttpStatus::MethodNotAllowed405)),
+        }
     }
 }
 
@@ -44,28 +83,24 @@ async fn handle_put(http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(),
     http_reader_writer.send_simple(HttpStatus::Created201).await
 }
 
-async fn read_and_handle_request(http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+fn router() -> Router {
+    Router::new(Stack::new().with(LogRequests))
+        .route("/big", Stack::new(), BigHandler)
+        .route("/", Stack::new().with(CorsPolicy::new().allow_any_origin()), SmallHandler)
+}
+
+async fn read_and_handle_request(router: &Router, http_reader_writer: &mut HttpReaderWriter<'_>)
+                                 -> Result<(), HttpError> {
     http_reader_writer.read_request(&mut []).await?;
-    let method = http_reader_writer.method();
-    match method {
-        HttpMethod::GET => {
-            handle_get(http_reader_writer).await
-        }
-        HttpMethod::PUT => {
-            handle_put(http_reader_writer).await
-        }
-        _ => {
-            Err(HttpError::ProcessingError(HttpStatus::MethodNotAllowed405))
-        }
-    }
+    router.handle(http_reader_writer).await
 }
 
-async fn handle_connection(mut tcp_stream: tokio::net::TcpStream, addr: std::net::SocketAddr) {
+async fn handle_connection(router: Arc<Router>, mut tcp_stream: tokio::net::TcpStream, addr: std::net::SocketAddr) {
     let (mut tcp_reader, mut tcp_writer) = tcp_stream.split();
     let mut http_reader_writer = HttpReaderWriter::new(
         Pin::new(&mut tcp_reader), Pin::new(&mut tcp_writer), addr);
     loop {
-        match read_and_handle_request(&mut http_reader_writer).await {
+        match read_and_handle_request(&router, &mut http_reader_writer).await {
             Err(HttpError::IoError(e)) => {
                 if e.kind() == std::io::ErrorKind::NotFound {
                     println!("INFO server {:?} disconnected", http_reader_writer);
@@ -79,14 +114,11 @@ async fn handle_connection(mut tcp_stream: tokio::net::TcpStream, addr: std::net
                 println!("INFO server {:?} parse_error={:?}", http_reader_writer, e);
                 let _ = http_reader_writer.send_simple(e.status()).await;
             }
+            // LogRequests logged the request.
             Err(HttpError::ProcessingError(status)) => {
-                println!("INFO server {:?} processing_error={:?}",
-                         http_reader_writer.method(), status);
                 let _ = http_reader_writer.send_simple(status).await;
             }
-            Ok(req) => {
-                println!("INFO server {:?} {:?}", req, http_reader_writer);
-            }
+            Ok(()) => {}
         };
     }
 }
@@ -99,10 +131,12 @@ async fn async_main() -> () {
         "INFO server listening on {}",
         listener.local_addr().unwrap()
     );
+    let router = Arc::new(router());
     tokio::spawn(async move {
         loop {
             let (tcp_stream, addr) = listener.accept().await.unwrap();
-            tokio::spawn(async move { handle_connection(tcp_stream, addr).await });
+            let router = router.clone();
+            tokio::spawn(async move { handle_connection(router, tcp_stream, addr).await });
         }
     });
 
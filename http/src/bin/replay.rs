@@ -0,0 +1,66 @@
+// This program re-sends the requests in a recorded transcript to a server and compares the
+// responses with the recorded ones.  Use it to reproduce a production incident against a local
+// server, and then turn the transcript into a regression test.
+//
+// Usage:
+//   replay TRANSCRIPT_FILE HOST:PORT
+//
+// Record transcripts with `HttpServerBuilder::tap` and save them with `Transcript::to_text`.
+// The program sends the requests on one connection, in the recorded reads, then closes its side
+// and reads responses until the server closes the connection.
+//
+// It prints the differences and exits with status 1 when the responses differ.
+// Recorded transcripts have PII masked, so masked bodies and header values often differ too.
+use std::net::SocketAddr;
+use std::println;
+
+use beatrice_http::tap::{diff, replay, Direction, Transcript};
+
+fn usage() -> ! {
+    eprintln!("Usage: replay TRANSCRIPT_FILE HOST:PORT");
+    std::process::exit(1);
+}
+
+async fn async_main() -> i32 {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() != 2 {
+        usage();
+    }
+    let addr: SocketAddr = args[1].parse().unwrap_or_else(|_| usage());
+    let text = std::fs::read_to_string(&args[0]).unwrap();
+    let transcript = Transcript::parse(&text).unwrap();
+    if transcript.truncated {
+        println!("WARN transcript is truncated");
+    }
+    let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let response = replay(&transcript, stream).await.unwrap();
+    match diff(&transcript.bytes(Direction::Sent), &response) {
+        None => {
+            println!("INFO responses match");
+            0
+        }
+        Some(differences) => {
+            println!("INFO responses differ:\n{}", differences);
+            1
+        }
+    }
+}
+
+pub fn main() {
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+    let status = runtime.block_on(async_main());
+    std::process::exit(status);
+}
+
+// $ cargo run --bin replay -- incident.txt 127.0.0.1:1690
+// INFO responses differ:
+//   HTTP/1.1 200 OK\r\n
+// - content-length: 5\r\n
+// + content-length: 6\r\n
+//   \r\n
+// - hello
+// + hello!
@@ -0,0 +1,97 @@
+// This program shows how to stream events to a browser with Server-Sent Events.
+//
+// Open http://127.0.0.1:1690/ in a browser.  The page shows one event per second.
+// Stop the server and start it again.  The browser reconnects and sends the id of the last
+// event it received in the `Last-Event-ID` header.  The server continues from the next event.
+use std::println;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use beatrice_http::server::{HttpHandler, HttpServerBuilder};
+use beatrice_http::sse::{Event, EventStream};
+use beatrice_http::stopper::wait_for_stop_signal;
+use beatrice_http::{Header, HttpError, HttpReaderWriter, HttpStatus};
+use futures::StreamExt;
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html><body><pre id="events"></pre><script>
+const source = new EventSource("/events");
+source.onmessage = (e) => {
+  document.getElementById("events").textContent += e.lastEventId + " " + e.data + "\n";
+};
+</script></body></html>
+"#;
+
+struct Handler;
+
+#[async_trait]
+impl HttpHandler for Handler {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        match &*http_reader_writer.raw_path {
+            "/" => {
+                http_reader_writer.send_with_content_length(
+                    HttpStatus::Ok200,
+                    &[&Header::new("content-type", "text/html; charset=UTF-8")],
+                    PAGE.len() as u64).await?;
+                tokio::io::AsyncWriteExt::write_all(http_reader_writer, PAGE.as_bytes())
+                    .await
+                    .map_err(HttpError::from_io_err)
+            }
+            "/events" => {
+                let mut event_stream = EventStream::start(http_reader_writer).await?;
+                let last_id: u64 = event_stream.last_event_id()
+                    .and_then(|id| id.parse().ok())
+                    .unwrap_or(0);
+                println!("INFO client connected, last event id {}", last_id);
+                let events = tokio::time::interval(Duration::from_secs(1))
+                    .enumerate()
+                    .map(move |(n, _)| {
+                        let id = last_id + 1 + n as u64;
+                        Event::new(format!("event number {}", id)).with_id(id.to_string())
+                    });
+                event_stream.send_all(events).await?;
+                event_stream.finish().await
+            }
+            _ => Err(HttpError::ProcessingError(HttpStatus::NotFound404)),
+        }
+    }
+}
+
+async fn async_main() {
+    let server = HttpServerBuilder::new()
+        .port(1690)
+        .run(Arc::new(Handler)).await.unwrap();
+    println!("INFO listening on {}", server.local_addr);
+    wait_for_stop_signal().await;
+    let cut_off = server.stop(Duration::from_secs(10)).await;
+    println!("INFO stopped, cut off {} connections", cut_off);
+}
+
+pub fn main() {
+    let mut runtime = tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async_main());
+    runtime.shutdown_background();
+}
+
+// $ cargo run --bin server_sent_events
+// INFO listening on 127.0.0.1:1690
+// $ curl -N http://127.0.0.1:1690/events
+// INFO client connected, last event id 0
+// id: 1
+// data: event number 1
+//
+// id: 2
+// data: event number 2
+//
+// ^C
+// $ curl -N -H 'Last-Event-ID: 2' http://127.0.0.1:1690/events
+// INFO client connected, last event id 2
+// id: 3
+// data: event number 3
+//
+// ^C
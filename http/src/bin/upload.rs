@@ -0,0 +1,75 @@
+// This program shows how to save uploaded files with `beatrice_http::body_file::save_body`.
+//
+// `PUT /NAME` saves the request body to `uploads/NAME` in the temp directory and responds with
+// the body's SHA-256 digest.  Each request gets its own file, so concurrent uploads with
+// different names do not replace each other.  An upload replaces an earlier one with the same
+// name all at once: readers never see a partial file.
+use std::path::PathBuf;
+use std::println;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use beatrice_http::body_file::save_body;
+use beatrice_http::server::{HttpHandler, HttpServerBuilder};
+use beatrice_http::stopper::wait_for_stop_signal;
+use beatrice_http::{HttpError, HttpMethod, HttpReaderWriter, HttpStatus};
+
+const MAX_UPLOAD_LEN: u64 = 2 * 1024 * 1024;
+
+struct Handler {
+    dir: PathBuf,
+}
+
+/// Returns true if `name` is safe to use as a file name in the uploads directory.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.')
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'_' || b == b'-')
+}
+
+#[async_trait]
+impl HttpHandler for Handler {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        if http_reader_writer.method() != HttpMethod::PUT {
+            return Err(HttpError::ProcessingError(HttpStatus::MethodNotAllowed405));
+        }
+        let name = http_reader_writer.raw_path.strip_prefix('/').unwrap_or("");
+        if !valid_name(name) {
+            return Err(HttpError::ProcessingError(HttpStatus::NotFound404));
+        }
+        let path = self.dir.join(name);
+        let saved = save_body(http_reader_writer, &path, MAX_UPLOAD_LEN, Some(&ring::digest::SHA256)).await?;
+        let digest: String = saved.digest.unwrap().as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        println!("INFO saved {} bytes to {:?} sha256={}", saved.len, path, digest);
+        http_reader_writer.send_text(HttpStatus::Created201, &[], &digest).await
+    }
+}
+
+async fn async_main() {
+    let dir = std::env::temp_dir().join("uploads");
+    std::fs::create_dir_all(&dir).unwrap();
+    let server = HttpServerBuilder::new()
+        .port(1690)
+        .run(Arc::new(Handler { dir })).await.unwrap();
+    println!("INFO listening on {}", server.local_addr);
+    wait_for_stop_signal().await;
+    let cut_off = server.stop(Duration::from_secs(10)).await;
+    println!("INFO stopped, cut off {} connections", cut_off);
+}
+
+pub fn main() {
+    let mut runtime = tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async_main());
+    runtime.shutdown_background();
+}
+
+// $ cargo run --bin upload
+// INFO listening on 127.0.0.1:1690
+// $ echo 'hello upload' > hello.txt
+// $ curl -T hello.txt http://127.0.0.1:1690/hello.txt
+// INFO saved 13 bytes to "/tmp/uploads/hello.txt" sha256=993a327368cc9a443f6d9a11d146da9e9ba2d561a8ef1e9190d119b2b1a002e0
+// 993a327368cc9a443f6d9a11d146da9e9ba2d561a8ef1e9190d119b2b1a002e0
@@ -0,0 +1,81 @@
+// This program shows how to restart a server without dropping connections.
+//
+// Send the server a USR2 signal.  It starts a new copy of itself and passes it the listening
+// socket.  Then it stops gracefully.  The new process accepts connections on the same socket,
+// so clients never see "connection refused".
+//
+// This lets you deploy a new version by replacing the binary file and sending USR2.
+//
+// The program also accepts a listening socket from systemd socket activation.
+// See `beatrice_http::listen_fd`.
+use std::println;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use beatrice_http::listen_fd::{spawn_replacement, take_inherited_listener, wait_for_restart_signal};
+use beatrice_http::server::{HttpHandler, HttpServerBuilder};
+use beatrice_http::stopper::wait_for_stop_signal;
+use beatrice_http::{HttpError, HttpReaderWriter, HttpStatus};
+
+struct Handler;
+
+#[async_trait]
+impl HttpHandler for Handler {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        let body = format!("response from pid {}\n", std::process::id());
+        http_reader_writer.send_text(HttpStatus::Ok200, &[], &body).await
+    }
+}
+
+async fn async_main(inherited_listener: Option<std::net::TcpListener>) {
+    let pid = std::process::id();
+    let builder = match inherited_listener {
+        Some(listener) => {
+            println!("INFO pid {} inherited listening socket", pid);
+            HttpServerBuilder::new().listener(listener)
+        }
+        None => HttpServerBuilder::new().port(1690),
+    };
+    let server = builder.run(Arc::new(Handler)).await.unwrap();
+    println!("INFO pid {} listening on {}", pid, server.local_addr);
+    tokio::select! {
+        _ = wait_for_stop_signal() => {
+            println!("INFO pid {} got stop signal", pid);
+        },
+        _ = wait_for_restart_signal() => {
+            println!("INFO pid {} got restart signal, starting new process", pid);
+            let child = spawn_replacement(server.listener_fd().unwrap()).unwrap();
+            println!("INFO pid {} started pid {}", pid, child.id());
+        },
+    }
+    let cut_off = server.stop(std::time::Duration::from_secs(10)).await;
+    println!("INFO pid {} stopped, cut off {} connections", pid, cut_off);
+}
+
+pub fn main() {
+    // Removes env vars, so it must run before the runtime starts threads.
+    let inherited_listener = take_inherited_listener().unwrap();
+    let mut runtime = tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async_main(inherited_listener));
+    runtime.shutdown_background();
+}
+
+// $ cargo run --bin zero_downtime_restart &
+// INFO pid 48213 listening on 127.0.0.1:1690
+// $ curl http://127.0.0.1:1690/
+// response from pid 48213
+// $ kill -USR2 48213
+// INFO pid 48213 got restart signal, starting new process
+// INFO pid 48213 started pid 48250
+// INFO pid 48250 inherited listening socket
+// INFO pid 48250 listening on 127.0.0.1:1690
+// INFO pid 48213 stopped, cut off 0 connections
+// $ curl http://127.0.0.1:1690/
+// response from pid 48250
+// $ kill 48250
+// INFO pid 48250 got stop signal
+// INFO pid 48250 stopped, cut off 0 connections
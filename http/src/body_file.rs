@@ -0,0 +1,145 @@
+// Streams request bodies to files.
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use ring::digest;
+
+use crate::{HttpError, HttpReaderWriter, HttpStatus};
+
+const COPY_BUFFER_LEN: usize = 64 * 1024;
+
+static NEXT_TEMP_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Debug)]
+pub struct SavedBody {
+    pub len: u64,
+    /// The digest of the body, when `save_body` got a digest algorithm.
+    pub digest: Option<digest::Digest>,
+}
+
+/// A temp file that is deleted on drop, unless it was renamed.
+struct TempFile {
+    path: PathBuf,
+    renamed: bool,
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.renamed {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                log::warn!("error deleting temp file {:?}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+fn file_error(action: &str, path: &Path, e: std::io::Error) -> HttpError {
+    HttpError::ProcessingError(HttpStatus::InternalServerError500(
+        format!("error {} {:?}: {}", action, path, e)))
+}
+
+/// Returns a hidden temp file path in the same directory as `path`, so renaming is atomic.
+fn temp_path(path: &Path) -> Result<PathBuf, HttpError> {
+    let file_name = path.file_name()
+        .ok_or_else(|| HttpError::ProcessingError(HttpStatus::InternalServerError500(
+            format!("path has no file name: {:?}", path))))?;
+    let temp_name = format!(
+        ".{}.{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id(),
+        NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed));
+    Ok(path.with_file_name(temp_name))
+}
+
+/// Streams the request body into a temp file next to `path` and then renames it to `path`.
+/// Replaces any existing file at `path`.
+/// Computes the body's digest while streaming, when `digest_algorithm` is set.
+///
+/// Calls fsync on the file and its directory before returning, so the file survives a crash.
+/// Readers of `path` see either the old file or the whole new file, never a partial file.
+///
+/// Deletes the temp file on error, on client disconnect, and when the returned future is dropped.
+///
+/// Returns `PayloadTooLarge413` if the body is longer than `max_len` bytes,
+/// `LengthRequired411` if the body is chunked, `IoError` if the client disconnects,
+/// and `InternalServerError500` on file errors.
+pub async fn save_body(
+    http_reader_writer: &mut HttpReaderWriter<'_>,
+    path: &Path,
+    max_len: u64,
+    digest_algorithm: Option<&'static digest::Algorithm>,
+) -> Result<SavedBody, HttpError> {
+    if http_reader_writer.chunked {
+        return Err(HttpError::ProcessingError(HttpStatus::LengthRequired411));
+    }
+    if http_reader_writer.content_length() > max_len {
+        return Err(HttpError::ProcessingError(HttpStatus::PayloadTooLarge413));
+    }
+    let temp_path = temp_path(path)?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp_path)
+        .await
+        .map_err(|e| file_error("creating", &temp_path, e))?;
+    let mut temp_file = TempFile { path: temp_path, renamed: false };
+    let mut context = digest_algorithm.map(digest::Context::new);
+    let mut buf = vec![0u8; COPY_BUFFER_LEN];
+    let mut len = 0u64;
+    loop {
+        let num_bytes_read = tokio::io::AsyncReadExt::read(http_reader_writer, &mut buf)
+            .await
+            .map_err(HttpError::from_io_err)?;
+        if num_bytes_read == 0 {
+            break;
+        }
+        if let Some(context) = &mut context {
+            context.update(&buf[..num_bytes_read]);
+        }
+        tokio::io::AsyncWriteExt::write_all(&mut file, &buf[..num_bytes_read])
+            .await
+            .map_err(|e| file_error("writing", &temp_file.path, e))?;
+        len += num_bytes_read as u64;
+    }
+    if http_reader_writer.unread_content_length > 0 {
+        return Err(HttpError::IoError(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof, "client disconnected before sending whole body")));
+    }
+    tokio::io::AsyncWriteExt::flush(&mut file)
+        .await
+        .map_err(|e| file_error("writing", &temp_file.path, e))?;
+    file.sync_all().await.map_err(|e| file_error("syncing", &temp_file.path, e))?;
+    drop(file);
+    tokio::fs::rename(&temp_file.path, path)
+        .await
+        .map_err(|e| file_error("renaming temp file to", path, e))?;
+    temp_file.renamed = true;
+    // The rename is durable only after the directory is synced.
+    let dir = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    tokio::fs::File::open(dir)
+        .await
+        .map_err(|e| file_error("opening", dir, e))?
+        .sync_all()
+        .await
+        .map_err(|e| file_error("syncing", dir, e))?;
+    Ok(SavedBody { len, digest: context.map(|context| context.finish()) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_temp_path() {
+        let temp = temp_path(Path::new("/a/b/config.tar")).unwrap();
+        assert_eq!(Path::new("/a/b"), temp.parent().unwrap());
+        let name = temp.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with(".config.tar."), "{:?}", name);
+        assert!(name.ends_with(".tmp"), "{:?}", name);
+        assert_ne!(temp, temp_path(Path::new("/a/b/config.tar")).unwrap());
+        assert!(temp_path(Path::new("/")).is_err());
+    }
+}
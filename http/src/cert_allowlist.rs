@@ -0,0 +1,110 @@
+use std::path::Path;
+
+/// Reads every `*.cert` PEM file in `dir` and returns the certificates they contain.
+///
+/// Returns an error if the directory cannot be read or a file is not valid PEM.
+/// Ignores other files.
+pub fn load_certs_from_dir(dir: &Path) -> std::io::Result<Vec<rustls::Certificate>> {
+    let mut certs = Vec::new();
+    let mut paths: Vec<std::path::PathBuf> = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<_>>()?;
+    paths.sort();
+    for path in paths {
+        if path.extension() != Some(std::ffi::OsStr::new("cert")) {
+            continue;
+        }
+        let pems = pem::parse_many(std::fs::read(&path)?);
+        if pems.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("no certificates found in {:?}", path)));
+        }
+        certs.extend(pems.into_iter()
+            .filter(|p| p.tag == "CERTIFICATE")
+            .map(|p| rustls::Certificate(p.contents)));
+    }
+    Ok(certs)
+}
+
+/// AllowedClientCertsVerifier makes a TLS server require a client certificate
+/// and accept only the certificates on its list.
+///
+/// This is certificate pinning for clients.  The server does not check the issuer, so clients
+/// can use self-signed certificates or certificates issued by a dev CA.
+/// rustls still checks that the client holds the private key of the certificate.
+pub struct AllowedClientCertsVerifier {
+    certs: Vec<rustls::Certificate>,
+}
+
+impl AllowedClientCertsVerifier {
+    pub fn new(certs: Vec<rustls::Certificate>) -> AllowedClientCertsVerifier {
+        AllowedClientCertsVerifier { certs }
+    }
+
+    /// Makes a verifier that accepts the certificates in `dir`.  See `load_certs_from_dir`.
+    pub fn from_dir(dir: &Path) -> std::io::Result<AllowedClientCertsVerifier> {
+        Ok(AllowedClientCertsVerifier::new(load_certs_from_dir(dir)?))
+    }
+}
+
+impl rustls::ClientCertVerifier for AllowedClientCertsVerifier {
+    fn client_auth_root_subjects(&self, _sni: Option<&webpki::DNSName>)
+                                 -> Option<rustls::DistinguishedNames> {
+        Some(rustls::DistinguishedNames::new())
+    }
+
+    fn verify_client_cert(&self, presented_certs: &[rustls::Certificate],
+                          _sni: Option<&webpki::DNSName>)
+                          -> Result<rustls::ClientCertVerified, rustls::TLSError> {
+        let presented_cert = presented_certs.first().ok_or(rustls::TLSError::NoCertificatesPresented)?;
+        if self.certs.contains(presented_cert) {
+            return Ok(rustls::ClientCertVerified::assertion());
+        }
+        Err(rustls::TLSError::WebPKIError(webpki::Error::UnknownIssuer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::ClientCertVerifier;
+
+    fn self_signed_der() -> Vec<u8> {
+        rcgen::generate_simple_self_signed([String::from("client1")])
+            .unwrap()
+            .serialize_der()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_load_certs_from_dir() {
+        let dir = std::env::temp_dir()
+            .join(format!("beatrice_http_test_load_certs_from_dir_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let der1 = self_signed_der();
+        let der2 = self_signed_der();
+        let encode = |der: &Vec<u8>| pem::encode(
+            &pem::Pem { tag: String::from("CERTIFICATE"), contents: der.clone() });
+        std::fs::write(dir.join("a.cert"), encode(&der1)).unwrap();
+        std::fs::write(dir.join("b.cert"), encode(&der2)).unwrap();
+        std::fs::write(dir.join("b.key"), "ignored").unwrap();
+        let certs = load_certs_from_dir(&dir).unwrap();
+        assert_eq!(vec![rustls::Certificate(der1), rustls::Certificate(der2)], certs);
+
+        std::fs::write(dir.join("c.cert"), "garbage").unwrap();
+        assert_eq!(std::io::ErrorKind::InvalidData, load_certs_from_dir(&dir).unwrap_err().kind());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_client_cert() {
+        let allowed = rustls::Certificate(self_signed_der());
+        let other = rustls::Certificate(self_signed_der());
+        let verifier = AllowedClientCertsVerifier::new(vec![allowed.clone()]);
+        assert!(verifier.verify_client_cert(&[allowed], None).is_ok());
+        assert!(verifier.verify_client_cert(&[other], None).is_err());
+        assert!(verifier.verify_client_cert(&[], None).is_err());
+    }
+}
@@ -0,0 +1,242 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use arc_swap::ArcSwap;
+use log::{info, warn};
+
+fn invalid_data(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// Returns an error unless `signing_key` is the private key for the public key in `cert`.
+/// It signs a probe message with the key and verifies the signature with the certificate.
+fn check_key_matches_cert(signing_key: &dyn rustls::sign::SigningKey, cert: &rustls::Certificate)
+                          -> Result<(), String> {
+    use rustls::SignatureScheme;
+    let schemes = [
+        (SignatureScheme::ECDSA_NISTP256_SHA256, &webpki::ECDSA_P256_SHA256),
+        (SignatureScheme::ECDSA_NISTP384_SHA384, &webpki::ECDSA_P384_SHA384),
+        (SignatureScheme::ED25519, &webpki::ED25519),
+        (SignatureScheme::RSA_PSS_SHA256, &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY),
+        (SignatureScheme::RSA_PKCS1_SHA256, &webpki::RSA_PKCS1_2048_8192_SHA256),
+    ];
+    let offered: Vec<SignatureScheme> = schemes.iter().map(|(scheme, _)| *scheme).collect();
+    let signer = signing_key.choose_scheme(&offered).ok_or("the key supports no known signature scheme")?;
+    let algorithm = schemes.iter()
+        .find(|(scheme, _)| *scheme == signer.get_scheme())
+        .map(|(_, algorithm)| *algorithm)
+        .unwrap();
+    const PROBE: &[u8] = b"beatrice_http certificate key check";
+    let signature = signer.sign(PROBE).map_err(|e| format!("signing failed: {:?}", e))?;
+    let end_entity = webpki::EndEntityCert::from(&cert.0)
+        .map_err(|e| format!("cannot parse the certificate: {:?}", e))?;
+    end_entity.verify_signature(algorithm, PROBE, &signature)
+        .map_err(|_| String::from("the key does not match the certificate"))
+}
+
+/// Reads a PEM certificate chain file and a PEM private key file
+/// and returns them as a `rustls::sign::CertifiedKey`.
+///
+/// The first certificate in the chain file must be the server's certificate.
+/// The key file may contain a PKCS#8, RSA, or EC private key.
+/// Returns `InvalidData` if the key is not the certificate's key.
+pub fn load_certified_key(cert_path: &Path, key_path: &Path)
+                          -> std::io::Result<rustls::sign::CertifiedKey> {
+    let certs: Vec<rustls::Certificate> = pem::parse_many(std::fs::read(cert_path)?)
+        .into_iter()
+        .filter(|p| p.tag == "CERTIFICATE")
+        .map(|p| rustls::Certificate(p.contents))
+        .collect();
+    if certs.is_empty() {
+        return Err(invalid_data(format!("no certificates found in {:?}", cert_path)));
+    }
+    let key = pem::parse_many(std::fs::read(key_path)?)
+        .into_iter()
+        .filter(|p| p.tag == "PRIVATE KEY" || p.tag == "RSA PRIVATE KEY" || p.tag == "EC PRIVATE KEY")
+        .map(|p| rustls::PrivateKey(p.contents))
+        .next()
+        .ok_or_else(|| invalid_data(format!("no private key found in {:?}", key_path)))?;
+    let signing_key = rustls::sign::any_supported_type(&key)
+        .map_err(|_| invalid_data(format!("unsupported private key in {:?}", key_path)))?;
+    check_key_matches_cert(signing_key.as_ref(), &certs[0])
+        .map_err(|e| invalid_data(format!("{:?} and {:?}: {}", cert_path, key_path, e)))?;
+    Ok(rustls::sign::CertifiedKey::new(certs, Arc::new(signing_key)))
+}
+
+/// ReloadingCertResolver gives rustls the server certificate and key from PEM files
+/// and can re-read the files while the server is running.
+///
+/// Set it as the `cert_resolver` of a `rustls::ServerConfig`.
+/// New TLS handshakes use the most recently loaded certificate.
+/// Established connections keep running with the certificate they negotiated.
+///
+/// Use `reload_on_sighup` or `reload_on_change` to reload automatically.
+/// When reloading fails, the resolver logs a warning and keeps serving the previous certificate.
+pub struct ReloadingCertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    certified_key: ArcSwap<rustls::sign::CertifiedKey>,
+}
+
+impl ReloadingCertResolver {
+    /// Loads the certificate chain and key.  Returns an error if either file is missing or invalid.
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>)
+               -> std::io::Result<ReloadingCertResolver> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let certified_key = load_certified_key(&cert_path, &key_path)?;
+        Ok(ReloadingCertResolver {
+            cert_path,
+            key_path,
+            certified_key: ArcSwap::from_pointee(certified_key),
+        })
+    }
+
+    /// Re-reads the certificate chain and key files.
+    /// On error, keeps the previously loaded certificate.
+    pub fn reload(&self) -> std::io::Result<()> {
+        let certified_key = load_certified_key(&self.cert_path, &self.key_path)?;
+        self.certified_key.store(Arc::new(certified_key));
+        info!("Loaded TLS certificate {:?}", self.cert_path);
+        Ok(())
+    }
+
+    /// Returns the certificate and key that new handshakes will use.
+    pub fn current(&self) -> Arc<rustls::sign::CertifiedKey> {
+        self.certified_key.load_full()
+    }
+
+    fn modified_times(&self) -> Option<(SystemTime, SystemTime)> {
+        let cert_modified = std::fs::metadata(&self.cert_path).and_then(|m| m.modified()).ok()?;
+        let key_modified = std::fs::metadata(&self.key_path).and_then(|m| m.modified()).ok()?;
+        Some((cert_modified, key_modified))
+    }
+
+    fn reload_and_log(&self) {
+        if let Err(e) = self.reload() {
+            warn!("Failed reloading TLS certificate {:?} and key {:?}, keeping previous: {:?}",
+                  self.cert_path, self.key_path, e);
+        }
+    }
+}
+
+impl rustls::ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: rustls::ClientHello) -> Option<rustls::sign::CertifiedKey> {
+        Some(self.certified_key.load().as_ref().clone())
+    }
+}
+
+/// Reloads the certificate every time the process receives a HUP signal.  Never returns.
+///
+/// Panics if it fails to install the signal handler.
+pub async fn reload_on_sighup(resolver: Arc<ReloadingCertResolver>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hup_signal = signal(SignalKind::hangup()).expect("Failed installing HUP signal handler");
+    loop {
+        hup_signal.recv().await;
+        info!("Got HUP signal, reloading TLS certificate");
+        resolver.reload_and_log();
+    }
+}
+
+/// Checks the modification times of the certificate and key files every `interval`
+/// and reloads them when either one changes.  Never returns.
+///
+/// Certificate tools usually write the two files separately.
+/// The resolver may see the new certificate with the old key.
+/// That load fails and the resolver retries on the next check.
+pub async fn reload_on_change(resolver: Arc<ReloadingCertResolver>, interval: Duration) {
+    let mut loaded_times = resolver.modified_times();
+    loop {
+        tokio::time::delay_for(interval).await;
+        let times = resolver.modified_times();
+        if times.is_none() || times == loaded_times {
+            continue;
+        }
+        info!("TLS certificate files changed, reloading");
+        match resolver.reload() {
+            Ok(()) => loaded_times = times,
+            Err(e) => warn!("Failed reloading TLS certificate {:?} and key {:?}, keeping previous: {:?}",
+                            resolver.cert_path, resolver.key_path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("beatrice_http_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_self_signed(cert_path: &Path, key_path: &Path) -> Vec<u8> {
+        let cert = rcgen::generate_simple_self_signed([String::from("localhost")]).unwrap();
+        // Each serialize_*() call makes a new signature, so encode the PEM from the DER.
+        let der = cert.serialize_der().unwrap();
+        let cert_pem = pem::encode(&pem::Pem { tag: String::from("CERTIFICATE"), contents: der.clone() });
+        std::fs::write(cert_path, cert_pem).unwrap();
+        std::fs::write(key_path, cert.serialize_private_key_pem()).unwrap();
+        der
+    }
+
+    #[test]
+    fn test_load_missing_files() {
+        let dir = temp_dir("test_load_missing_files");
+        let result = ReloadingCertResolver::new(dir.join("a.cert"), dir.join("a.key"));
+        assert_eq!(std::io::ErrorKind::NotFound, result.err().unwrap().kind());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_invalid_files() {
+        let dir = temp_dir("test_load_invalid_files");
+        let cert_path = dir.join("a.cert");
+        let key_path = dir.join("a.key");
+        write_self_signed(&cert_path, &key_path);
+        std::fs::write(&key_path, "not a key").unwrap();
+        let result = ReloadingCertResolver::new(&cert_path, &key_path);
+        assert_eq!(std::io::ErrorKind::InvalidData, result.err().unwrap().kind());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_mismatched_key() {
+        let dir = temp_dir("test_load_mismatched_key");
+        let cert_path = dir.join("a.cert");
+        let key_path = dir.join("a.key");
+        write_self_signed(&cert_path, &key_path);
+        // A rotation that has written the new key but not the new certificate.
+        write_self_signed(&dir.join("b.cert"), &key_path);
+        let error = ReloadingCertResolver::new(&cert_path, &key_path).err().unwrap();
+        assert_eq!(std::io::ErrorKind::InvalidData, error.kind());
+        assert!(error.to_string().contains("does not match"), "{}", error);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = temp_dir("test_reload");
+        let cert_path = dir.join("a.cert");
+        let key_path = dir.join("a.key");
+        let der1 = write_self_signed(&cert_path, &key_path);
+        let resolver = ReloadingCertResolver::new(&cert_path, &key_path).unwrap();
+        assert_eq!(der1, resolver.current().cert[0].0);
+
+        let der2 = write_self_signed(&cert_path, &key_path);
+        assert_ne!(der1, der2);
+        assert_eq!(der1, resolver.current().cert[0].0);
+        resolver.reload().unwrap();
+        assert_eq!(der2, resolver.current().cert[0].0);
+
+        std::fs::write(&cert_path, "garbage").unwrap();
+        assert_eq!(std::io::ErrorKind::InvalidData, resolver.reload().unwrap_err().kind());
+        assert_eq!(der2, resolver.current().cert[0].0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
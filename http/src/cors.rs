@@ -0,0 +1,246 @@
+// Cross-Origin Resource Sharing https://fetch.spec.whatwg.org/#http-cors-protocol
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::middleware::{around, Flow, Middleware};
+use crate::server::HttpHandler;
+use crate::{Header, HttpError, HttpReaderWriter, HttpStatus};
+
+/// CorsPolicy says which other origins may call the server from browsers.
+#[derive(Clone, Debug)]
+pub struct CorsPolicy {
+    any_origin: bool,
+    origins: Vec<String>,
+    origin_patterns: Vec<regex::Regex>,
+    methods: Vec<String>,
+    headers: Vec<String>,
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        CorsPolicy::new()
+    }
+}
+
+impl CorsPolicy {
+    /// Makes a policy that allows no origins, and GET, HEAD, and POST methods.
+    pub fn new() -> CorsPolicy {
+        CorsPolicy {
+            any_origin: false,
+            origins: Vec::new(),
+            origin_patterns: Vec::new(),
+            methods: vec![String::from("GET"), String::from("HEAD"), String::from("POST")],
+            headers: Vec::new(),
+            expose_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// Allows requests from an origin like `https://dashboard.example.com`.
+    pub fn allow_origin(mut self, origin: &str) -> CorsPolicy {
+        self.origins.push(String::from(origin));
+        self
+    }
+
+    /// Allows requests from origins that match `pattern`.
+    /// Anchor the pattern with `^` and `$`, or it also matches origins that contain it.
+    ///
+    /// Panics if `pattern` is not a valid regular expression.
+    pub fn allow_origin_pattern(mut self, pattern: &str) -> CorsPolicy {
+        self.origin_patterns.push(regex::Regex::new(pattern).unwrap());
+        self
+    }
+
+    /// Allows requests from every origin.
+    pub fn allow_any_origin(mut self) -> CorsPolicy {
+        self.any_origin = true;
+        self
+    }
+
+    /// Sets the methods that cross-origin requests may use.
+    pub fn allow_methods(mut self, methods: &[&str]) -> CorsPolicy {
+        self.methods = methods.iter().map(|method| method.to_ascii_uppercase()).collect();
+        self
+    }
+
+    /// Sets the request headers that cross-origin requests may send.
+    pub fn allow_headers(mut self, headers: &[&str]) -> CorsPolicy {
+        self.headers = headers.iter().map(|header| header.to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// Sets the response headers that browsers let scripts read, in addition to the safelisted
+    /// headers like `content-type`.
+    pub fn expose_headers(mut self, headers: &[&str]) -> CorsPolicy {
+        self.expose_headers = headers.iter().map(|header| header.to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// Lets cross-origin requests include cookies and HTTP authentication.
+    pub fn allow_credentials(mut self, allow: bool) -> CorsPolicy {
+        self.credentials = allow;
+        self
+    }
+
+    /// Lets browsers cache preflight responses for `max_age`.
+    pub fn max_age(mut self, max_age: Duration) -> CorsPolicy {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn origin_allowed(&self, origin: &str) -> bool {
+        self.any_origin
+            || self.origins.iter().any(|allowed| allowed == origin)
+            || self.origin_patterns.iter().any(|pattern| pattern.is_match(origin))
+    }
+
+    /// Returns the `access-control-allow-origin` value.
+    /// Browsers reject `*` on requests with credentials, so those get the request's origin.
+    fn allow_origin_value<'o>(&self, origin: &'o str) -> &'o str {
+        if self.any_origin && !self.credentials { "*" } else { origin }
+    }
+
+    /// Returns true if responses depend on the request's `Origin` header.
+    fn varies_by_origin(&self) -> bool {
+        !self.any_origin || self.credentials
+    }
+
+    /// Returns the `access-control-allow-headers` value for a preflight, or None if the policy
+    /// does not allow one of the requested headers.
+    fn allow_headers_value(&self, requested: &str) -> Option<String> {
+        let requested: Vec<String> = requested.split(',')
+            .map(|header| header.trim().to_ascii_lowercase())
+            .filter(|header| !header.is_empty())
+            .collect();
+        if requested.iter().all(|header| self.headers.contains(header)) {
+            Some(requested.join(", "))
+        } else {
+            None
+        }
+    }
+
+    async fn preflight(&self, http_reader_writer: &mut HttpReaderWriter<'_>, origin: &str)
+                       -> Result<(), HttpError> {
+        let method = http_reader_writer.header("access-control-request-method").unwrap_or("");
+        let allow_headers = self.allow_headers_value(
+            http_reader_writer.header("access-control-request-headers").unwrap_or(""));
+        let allow_headers = match allow_headers {
+            Some(allow_headers) if self.origin_allowed(origin)
+                && self.methods.iter().any(|allowed| allowed == method) => allow_headers,
+            _ => return Err(HttpError::ProcessingError(HttpStatus::Forbidden403)),
+        };
+        let allow_methods = self.methods.join(", ");
+        let max_age = self.max_age.map(|max_age| max_age.as_secs().to_string());
+        let mut headers = vec![
+            Header::new("access-control-allow-origin", self.allow_origin_value(origin)),
+            Header::new("access-control-allow-methods", &allow_methods),
+        ];
+        if !allow_headers.is_empty() {
+            headers.push(Header::new("access-control-allow-headers", &allow_headers));
+        }
+        if self.credentials {
+            headers.push(Header::new("access-control-allow-credentials", "true"));
+        }
+        if let Some(max_age) = &max_age {
+            headers.push(Header::new("access-control-max-age", max_age));
+        }
+        let header_refs: Vec<&Header> = headers.iter().collect();
+        http_reader_writer.send_without_body(HttpStatus::NoContent204, &header_refs).await
+    }
+}
+
+/// CorsPolicy is middleware that answers preflight requests, which are `OPTIONS` requests with
+/// an `access-control-request-method` header, and adds `access-control-*` headers to responses
+/// for allowed origins.  Responses for other origins have no `access-control-*` headers,
+/// so browsers do not let scripts read them.
+///
+/// It does not reject requests.  Browsers send simple cross-origin requests, like form posts,
+/// without preflight and only hide the response.  Protect state-changing endpoints with
+/// authentication or CSRF tokens.
+#[async_trait]
+impl Middleware for CorsPolicy {
+    async fn before(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<Flow, HttpError> {
+        if self.varies_by_origin() {
+            // Caches must not give one origin's response to another origin.
+            http_reader_writer.add_response_header("vary", "origin")?;
+        }
+        let origin = match http_reader_writer.header("origin") {
+            Some(origin) => String::from(origin),
+            None => return Ok(Flow::Continue),
+        };
+        if http_reader_writer.method().as_str() == "OPTIONS"
+            && http_reader_writer.header("access-control-request-method").is_some() {
+            self.preflight(http_reader_writer, &origin).await?;
+            return Ok(Flow::Done);
+        }
+        if self.origin_allowed(&origin) {
+            http_reader_writer.add_response_header(
+                "access-control-allow-origin", self.allow_origin_value(&origin))?;
+            if self.credentials {
+                http_reader_writer.add_response_header("access-control-allow-credentials", "true")?;
+            }
+            if !self.expose_headers.is_empty() {
+                http_reader_writer.add_response_header(
+                    "access-control-expose-headers", &self.expose_headers.join(", "))?;
+            }
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Cors applies a CorsPolicy to requests and passes them to its inner handler.
+/// See the `Middleware` implementation of `CorsPolicy`.
+pub struct Cors<H: HttpHandler> {
+    policy: CorsPolicy,
+    inner: H,
+}
+
+impl<H: HttpHandler> Cors<H> {
+    pub fn new(policy: CorsPolicy, inner: H) -> Cors<H> {
+        Cors { policy, inner }
+    }
+}
+
+#[async_trait]
+impl<H: HttpHandler> HttpHandler for Cors<H> {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        around(&self.policy, &self.inner, http_reader_writer).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy() {
+        let policy = CorsPolicy::new()
+            .allow_origin("https://a.example.com")
+            .allow_origin_pattern(r"^https://[a-z]+\.b\.example\.com$")
+            .allow_headers(&["Content-Type", "x-request-id"]);
+        assert!(policy.origin_allowed("https://a.example.com"));
+        assert!(policy.origin_allowed("https://x.b.example.com"));
+        assert!(!policy.origin_allowed("https://x.b.example.com.evil.com"));
+        assert!(!policy.origin_allowed("http://a.example.com"));
+        assert!(!policy.origin_allowed("null"));
+        assert_eq!("https://a.example.com", policy.allow_origin_value("https://a.example.com"));
+        assert!(policy.varies_by_origin());
+        assert_eq!(Some(String::from("content-type, x-request-id")),
+                   policy.allow_headers_value("Content-Type,X-Request-Id"));
+        assert_eq!(Some(String::new()), policy.allow_headers_value(""));
+        assert_eq!(None, policy.allow_headers_value("content-type, authorization"));
+
+        let policy = CorsPolicy::new().allow_any_origin();
+        assert!(policy.origin_allowed("https://c.example.com"));
+        assert_eq!("*", policy.allow_origin_value("https://c.example.com"));
+        assert!(!policy.varies_by_origin());
+        let policy = policy.allow_credentials(true);
+        assert_eq!("https://c.example.com", policy.allow_origin_value("https://c.example.com"));
+        assert!(policy.varies_by_origin());
+    }
+}
@@ -0,0 +1,367 @@
+// Wrappers that make AsyncRead and AsyncWrite misbehave the way real networks do,
+// for testing code that must handle partial reads, short writes, and resets.
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Faults says which faults a `FaultyStream` injects and how often.
+/// The same seed and the same calls produce the same faults.
+#[derive(Clone, Debug)]
+pub struct Faults {
+    seed: u64,
+    delay_probability: f64,
+    max_delay: Duration,
+    would_block_probability: f64,
+    max_would_block_storm: u32,
+    one_byte_read_probability: f64,
+    short_write_probability: f64,
+    reset_reads_at: Option<u64>,
+    reset_writes_at: Option<u64>,
+}
+
+impl Faults {
+    /// Makes a config that injects no faults.
+    pub fn new(seed: u64) -> Faults {
+        Faults {
+            seed,
+            delay_probability: 0.0,
+            max_delay: Duration::from_millis(0),
+            would_block_probability: 0.0,
+            max_would_block_storm: 0,
+            one_byte_read_probability: 0.0,
+            short_write_probability: 0.0,
+            reset_reads_at: None,
+            reset_writes_at: None,
+        }
+    }
+
+    /// Makes a config that injects delays, `WouldBlock` storms, one-byte reads, and short writes,
+    /// but no resets.  Correct code gets the same results through it as without it.
+    pub fn adversarial(seed: u64) -> Faults {
+        Faults::new(seed)
+            .delays(0.05, Duration::from_millis(2))
+            .would_block_storms(0.2, 10)
+            .one_byte_reads(0.3)
+            .short_writes(0.3)
+    }
+
+    /// Makes reads and writes wait first, with `probability`, for up to `max_delay`.
+    pub fn delays(mut self, probability: f64, max_delay: Duration) -> Faults {
+        self.delay_probability = probability;
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Makes reads and writes return `Pending`, with `probability`, up to `max_storm` times in a row.
+    /// Each time, the stream wakes the task right away, like a socket that returns `WouldBlock`
+    /// after reporting that it is ready.
+    pub fn would_block_storms(mut self, probability: f64, max_storm: u32) -> Faults {
+        self.would_block_probability = probability;
+        self.max_would_block_storm = max_storm;
+        self
+    }
+
+    /// Makes reads return at most one byte, with `probability`.
+    pub fn one_byte_reads(mut self, probability: f64) -> Faults {
+        self.one_byte_read_probability = probability;
+        self
+    }
+
+    /// Makes writes accept a random number of bytes, with `probability`.
+    pub fn short_writes(mut self, probability: f64) -> Faults {
+        self.short_write_probability = probability;
+        self
+    }
+
+    /// Makes reads fail with `ConnectionReset` after reading `offset` bytes.
+    pub fn reset_reads_at(mut self, offset: u64) -> Faults {
+        self.reset_reads_at = Some(offset);
+        self
+    }
+
+    /// Makes writes fail with `ConnectionReset` after writing `offset` bytes.
+    pub fn reset_writes_at(mut self, offset: u64) -> Faults {
+        self.reset_writes_at = Some(offset);
+        self
+    }
+}
+
+/// The number of adversarial configs in `test_faults`.
+pub const TEST_SEEDS: u64 = 20;
+
+/// Returns the configs that tests run their inputs through: one that injects no faults, and then
+/// `Faults::adversarial` with seeds `0..TEST_SEEDS`.  Correct code gets the same results with each.
+pub fn test_faults() -> impl Iterator<Item = Faults> {
+    std::iter::once(Faults::new(0)).chain((0..TEST_SEEDS).map(Faults::adversarial))
+}
+
+fn reset() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::ConnectionReset, "injected connection reset")
+}
+
+/// FaultyStream wraps an `AsyncRead` or `AsyncWrite` and injects the faults in a `Faults`.
+/// Wrap both halves of a connection, with different seeds, to test the code between them.
+pub struct FaultyStream<T> {
+    inner: T,
+    faults: Faults,
+    rng: StdRng,
+    delay: Option<Pin<Box<tokio::time::Delay>>>,
+    would_block_storm: u32,
+    fault_done: bool,
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+impl<T> FaultyStream<T> {
+    pub fn new(inner: T, faults: Faults) -> FaultyStream<T> {
+        let rng = StdRng::seed_from_u64(faults.seed);
+        FaultyStream {
+            inner,
+            faults,
+            rng,
+            delay: None,
+            would_block_storm: 0,
+            fault_done: false,
+            bytes_read: 0,
+            bytes_written: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Injects a delay or `WouldBlock` storm, or returns `Ready` to let the I/O happen.
+    /// After a fault ends, the next I/O happens without another roll, so the stream makes
+    /// progress even when the probabilities are 1.0.
+    fn poll_faults(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(delay) = self.delay.as_mut() {
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.delay = None;
+            self.fault_done = true;
+        }
+        if self.would_block_storm > 0 {
+            self.would_block_storm -= 1;
+            if self.would_block_storm == 0 {
+                self.fault_done = true;
+            }
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        if self.fault_done {
+            self.fault_done = false;
+            return Poll::Ready(());
+        }
+        if self.faults.delay_probability > 0.0 && self.rng.gen_bool(self.faults.delay_probability) {
+            let max_micros = self.faults.max_delay.as_micros() as u64;
+            let duration = Duration::from_micros(self.rng.gen_range(0, max_micros + 1));
+            self.delay = Some(Box::pin(tokio::time::delay_for(duration)));
+            return self.poll_faults(cx);
+        }
+        if self.faults.max_would_block_storm > 0 && self.faults.would_block_probability > 0.0
+            && self.rng.gen_bool(self.faults.would_block_probability) {
+            self.would_block_storm = self.rng.gen_range(1, self.faults.max_would_block_storm + 1);
+            return self.poll_faults(cx);
+        }
+        Poll::Ready(())
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for FaultyStream<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8])
+                 -> Poll<tokio::io::Result<usize>> {
+        let mut_self = self.get_mut();
+        if buf.is_empty() {
+            return Pin::new(&mut mut_self.inner).poll_read(cx, buf);
+        }
+        if mut_self.poll_faults(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let mut len = buf.len();
+        if let Some(offset) = mut_self.faults.reset_reads_at {
+            if mut_self.bytes_read >= offset {
+                return Poll::Ready(Err(reset()));
+            }
+            len = std::cmp::min(len as u64, offset - mut_self.bytes_read) as usize;
+        }
+        if mut_self.faults.one_byte_read_probability > 0.0
+            && mut_self.rng.gen_bool(mut_self.faults.one_byte_read_probability) {
+            len = 1;
+        }
+        match Pin::new(&mut mut_self.inner).poll_read(cx, &mut buf[..len]) {
+            Poll::Ready(Ok(n)) => {
+                mut_self.bytes_read += n as u64;
+                Poll::Ready(Ok(n))
+            }
+            other => other,
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for FaultyStream<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+                  -> Poll<tokio::io::Result<usize>> {
+        let mut_self = self.get_mut();
+        if buf.is_empty() {
+            return Pin::new(&mut mut_self.inner).poll_write(cx, buf);
+        }
+        if mut_self.poll_faults(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let mut len = buf.len();
+        if let Some(offset) = mut_self.faults.reset_writes_at {
+            if mut_self.bytes_written >= offset {
+                return Poll::Ready(Err(reset()));
+            }
+            len = std::cmp::min(len as u64, offset - mut_self.bytes_written) as usize;
+        }
+        if mut_self.faults.short_write_probability > 0.0
+            && mut_self.rng.gen_bool(mut_self.faults.short_write_probability) {
+            len = mut_self.rng.gen_range(1, len + 1);
+        }
+        match Pin::new(&mut mut_self.inner).poll_write(cx, &buf[..len]) {
+            Poll::Ready(Ok(n)) => {
+                mut_self.bytes_written += n as u64;
+                Poll::Ready(Ok(n))
+            }
+            other => other,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{HttpError, HttpReaderWriter, HttpStatus};
+    use crate::server::HttpHandler;
+    use crate::testing::{round_trip_with, ScriptedReader, ScriptedWriter};
+
+    #[tokio::test]
+    async fn test_no_faults() {
+        let mut reader = FaultyStream::new(&b"abc"[..], Faults::new(1));
+        let mut buf = [0u8; 10];
+        assert_eq!(3, reader.read(&mut buf).await.unwrap());
+        assert_eq!(3, reader.bytes_read());
+        let mut writer = FaultyStream::new(ScriptedWriter::new(), Faults::new(1));
+        assert_eq!(3, writer.write(b"abc").await.unwrap());
+        assert_eq!(3, writer.bytes_written());
+        writer.get_ref().assert_written(b"abc");
+    }
+
+    #[tokio::test]
+    async fn test_one_byte_reads_and_short_writes() {
+        let mut reader = FaultyStream::new(&b"abc"[..], Faults::new(1).one_byte_reads(1.0));
+        let mut buf = [0u8; 10];
+        assert_eq!(1, reader.read(&mut buf).await.unwrap());
+        assert_eq!(1, reader.read(&mut buf).await.unwrap());
+        let mut writer = FaultyStream::new(ScriptedWriter::new(), Faults::new(1).short_writes(1.0));
+        let mut short = false;
+        for _ in 0..20 {
+            short |= writer.write(b"abcdefgh").await.unwrap() < 8;
+        }
+        assert!(short);
+    }
+
+    #[tokio::test]
+    async fn test_delays_and_would_block_storms() {
+        let faults = Faults::new(1)
+            .delays(0.5, Duration::from_millis(1))
+            .would_block_storms(1.0, 5);
+        let mut reader = FaultyStream::new(&b"abcdefgh"[..], faults.clone().one_byte_reads(1.0));
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(b"abcdefgh", &data[..]);
+        let mut writer = FaultyStream::new(ScriptedWriter::new(), faults.short_writes(1.0));
+        writer.write_all(b"abcdefgh").await.unwrap();
+        writer.into_inner().assert_written(b"abcdefgh");
+    }
+
+    #[tokio::test]
+    async fn test_resets() {
+        let mut reader = FaultyStream::new(&b"abcdef"[..], Faults::new(1).reset_reads_at(4));
+        let mut buf = [0u8; 10];
+        assert_eq!(4, reader.read(&mut buf).await.unwrap());
+        assert_eq!(std::io::ErrorKind::ConnectionReset, reader.read(&mut buf).await.unwrap_err().kind());
+        let mut writer = FaultyStream::new(ScriptedWriter::new(), Faults::new(1).reset_writes_at(2));
+        assert_eq!(std::io::ErrorKind::ConnectionReset, writer.write_all(b"abc").await.unwrap_err().kind());
+        writer.get_ref().assert_written(b"ab");
+    }
+
+    #[tokio::test]
+    async fn test_same_seed_same_faults() {
+        async fn read_sizes(seed: u64) -> Vec<usize> {
+            let data = [0u8; 100];
+            let mut reader = FaultyStream::new(&data[..], Faults::adversarial(seed));
+            let mut buf = [0u8; 7];
+            let mut sizes = Vec::new();
+            loop {
+                match reader.read(&mut buf).await.unwrap() {
+                    0 => return sizes,
+                    n => sizes.push(n),
+                }
+            }
+        }
+        assert_eq!(read_sizes(5).await, read_sizes(5).await);
+        assert_ne!(read_sizes(5).await, read_sizes(6).await);
+    }
+
+    struct EchoHandler;
+
+    #[async_trait]
+    impl HttpHandler for EchoHandler {
+        async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+            let body = http_reader_writer.read_body(1000).await?;
+            http_reader_writer.send_bytes(HttpStatus::Ok200, &[], "text/plain", &body).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_reader_writer_resets() {
+        let request = "POST / HTTP/1.1\r\ncontent-length: 5\r\n\r\nabcde";
+        for offset in 0..request.len() as u64 {
+            let reader = FaultyStream::new(
+                ScriptedReader::new().data(request.as_bytes()),
+                Faults::adversarial(offset).reset_reads_at(offset));
+            let writer = FaultyStream::new(ScriptedWriter::new(), Faults::new(offset));
+            let writer = round_trip_with(&EchoHandler, reader, writer).await;
+            assert_eq!(b"", writer.get_ref().written(), "offset {}", offset);
+        }
+        let expected = "HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: 5\r\n\r\nabcde";
+        for offset in 0..expected.len() as u64 {
+            let reader = ScriptedReader::new().data(request.as_bytes());
+            let writer = FaultyStream::new(ScriptedWriter::new(), Faults::adversarial(offset).reset_writes_at(offset));
+            let writer = round_trip_with(&EchoHandler, reader, writer).await;
+            writer.get_ref().assert_written(&expected.as_bytes()[..offset as usize]);
+        }
+    }
+}
@@ -118,6 +118,14 @@ impl FixedBuf {
         }
     }
 
+    /// Consume and return the first `num_bytes` readable bytes.
+    /// Panics if the buffer holds fewer readable bytes.
+    pub fn read_bytes(&mut self, num_bytes: usize) -> &[u8] {
+        let start = self.read_index;
+        self.consume(num_bytes);
+        &self.buf[start..start + num_bytes]
+    }
+
     /// Consume and return all readable bytes.
     /// The buffer becomes empty and subsequent writes can fill the whole buffer.
     pub fn read_all(&mut self) -> &[u8] {
@@ -274,6 +282,7 @@ impl tokio::io::AsyncRead for FixedBuf {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::faulty_stream::{test_faults, Faults, FaultyStream};
 
     #[test]
     fn test_constructors() {
@@ -392,81 +401,165 @@ mod tests {
         assert_eq!("", crate::escape_ascii(buf.read_all()));
     }
 
+    #[test]
+    fn test_read_bytes() {
+        let mut buf = FixedBuf::new();
+        assert_eq!("", crate::escape_ascii(buf.read_bytes(0)));
+        buf.append("abcdef");
+        assert_eq!("ab", crate::escape_ascii(buf.read_bytes(2)));
+        assert_eq!("cdef", crate::escape_ascii(buf.readable()));
+        assert_eq!("cdef", crate::escape_ascii(buf.read_bytes(4)));
+        assert_eq!("", crate::escape_ascii(buf.readable()));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_read_bytes_underflow() {
+        let mut buf = FixedBuf::new();
+        buf.append("abc");
+        buf.read_bytes(4);
+    }
+
+    // Returns `chunks` as an `AsyncRead` that injects `faults`.
+    fn faulty_input(
+        chunks: Vec<&'static [u8]>,
+        faults: Faults,
+    ) -> FaultyStream<impl tokio::io::AsyncRead + Unpin> {
+        let stream = tokio::stream::iter(chunks.into_iter().map(Ok));
+        FaultyStream::new(tokio::io::stream_reader(stream), faults)
+    }
+
     #[tokio::test]
     async fn test_read_delimited_empty() {
-        let mut buf = FixedBuf::new();
-        let mut input = tokio::io::stream_reader(tokio::stream::empty::<std::io::Result<&[u8]>>());
-        assert_eq!(
-            std::io::ErrorKind::NotFound,
-            buf.read_delimited(&mut input, b"b")
-                .await
-                .unwrap_err()
-                .kind()
-        );
+        for faults in test_faults() {
+            let mut buf = FixedBuf::new();
+            let mut input = faulty_input(vec![], faults.clone());
+            assert_eq!(
+                std::io::ErrorKind::NotFound,
+                buf.read_delimited(&mut input, b"b")
+                    .await
+                    .unwrap_err()
+                    .kind(),
+                "{:?}",
+                faults
+            );
+        }
     }
 
     #[tokio::test]
     async fn test_read_delimited_not_found_eof() {
-        let mut buf = FixedBuf::new();
-        let mut input = tokio::io::stream_reader(tokio::stream::iter(vec![Ok("abc".as_bytes())]));
-        assert_eq!(
-            std::io::ErrorKind::NotFound,
-            buf.read_delimited(&mut input, b"d")
-                .await
-                .unwrap_err()
-                .kind()
-        );
-        buf.read_all();
+        for faults in test_faults() {
+            let mut buf = FixedBuf::new();
+            let mut input = faulty_input(vec![b"abc"], faults.clone());
+            assert_eq!(
+                std::io::ErrorKind::NotFound,
+                buf.read_delimited(&mut input, b"d")
+                    .await
+                    .unwrap_err()
+                    .kind(),
+                "{:?}",
+                faults
+            );
+            buf.read_all();
+        }
     }
 
     #[tokio::test]
     async fn test_read_delimited_not_found_buffer_almost_full() {
-        let mut buf = FixedBuf::new();
-        let many_bs = "b".repeat(BUFFER_LEN - 1);
-        let mut input = tokio::io::stream_reader(tokio::stream::iter(vec![Ok(many_bs.as_bytes())]));
-        assert_eq!(
-            std::io::ErrorKind::NotFound,
-            buf.read_delimited(&mut input, b"d")
-                .await
-                .unwrap_err()
-                .kind()
-        );
+        let many_bs: &'static str = Box::leak("b".repeat(BUFFER_LEN - 1).into_boxed_str());
+        for faults in test_faults() {
+            let mut buf = FixedBuf::new();
+            let mut input = faulty_input(vec![many_bs.as_bytes()], faults.clone());
+            assert_eq!(
+                std::io::ErrorKind::NotFound,
+                buf.read_delimited(&mut input, b"d")
+                    .await
+                    .unwrap_err()
+                    .kind(),
+                "{:?}",
+                faults
+            );
+        }
     }
 
     #[tokio::test]
     async fn test_read_delimited_not_found_buffer_full() {
-        let mut buf = FixedBuf::new();
-        let many_bs = "b".repeat(BUFFER_LEN);
-        let mut input = tokio::io::stream_reader(tokio::stream::iter(vec![Ok(many_bs.as_bytes())]));
-        assert_eq!(
-            std::io::ErrorKind::InvalidData,
-            buf.read_delimited(&mut input, b"d")
-                .await
-                .unwrap_err()
-                .kind()
-        );
+        let many_bs: &'static str = Box::leak("b".repeat(BUFFER_LEN).into_boxed_str());
+        for faults in test_faults() {
+            let mut buf = FixedBuf::new();
+            let mut input = faulty_input(vec![many_bs.as_bytes()], faults.clone());
+            assert_eq!(
+                std::io::ErrorKind::InvalidData,
+                buf.read_delimited(&mut input, b"d")
+                    .await
+                    .unwrap_err()
+                    .kind(),
+                "{:?}",
+                faults
+            );
+        }
     }
 
     #[tokio::test]
     async fn test_read_delimited_found() {
-        let mut buf = FixedBuf::new();
-        let mut input = tokio::io::stream_reader(tokio::stream::iter(vec![Ok("abc".as_bytes())]));
-        assert_eq!(
-            "ab",
-            crate::escape_ascii(buf.read_delimited(&mut input, b"c").await.unwrap())
-        );
+        for faults in test_faults() {
+            let mut buf = FixedBuf::new();
+            let mut input = faulty_input(vec![b"abc"], faults.clone());
+            assert_eq!(
+                "ab",
+                crate::escape_ascii(buf.read_delimited(&mut input, b"c").await.unwrap()),
+                "{:?}",
+                faults
+            );
+        }
     }
 
     #[tokio::test]
     async fn test_read_delimited_found_with_leftover() {
-        let mut buf = FixedBuf::new();
-        let mut input =
-            tokio::io::stream_reader(tokio::stream::iter(vec![Ok("abcdef".as_bytes())]));
-        assert_eq!(
-            "ab",
-            crate::escape_ascii(buf.read_delimited(&mut input, b"c").await.unwrap())
-        );
-        assert_eq!("def", crate::escape_ascii(buf.read_all()));
+        for (n, faults) in test_faults().enumerate() {
+            let mut buf = FixedBuf::new();
+            let mut input = faulty_input(vec![b"abcdef"], faults.clone());
+            assert_eq!(
+                "ab",
+                crate::escape_ascii(buf.read_delimited(&mut input, b"c").await.unwrap()),
+                "{:?}",
+                faults
+            );
+            let mut rest = buf.read_all().to_vec();
+            if n == 0 {
+                // Without faults, one read gets all of the input.
+                assert_eq!("def", crate::escape_ascii(&rest));
+            }
+            // With faults, reads may stop at any byte, so the rest may still be in `input`.
+            tokio::io::AsyncReadExt::read_to_end(&mut input, &mut rest).await.unwrap();
+            assert_eq!("def", crate::escape_ascii(&rest), "{:?}", faults);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_delimited_many() {
+        for faults in test_faults() {
+            let mut buf = FixedBuf::new();
+            let mut input = faulty_input(
+                vec![b"a\r\n\r\nbc\r\n", b"\r\n\r\n\r", b"\ndef\r\n\r\nghi"],
+                faults.clone(),
+            );
+            for expected in &["a", "bc", "", "def"] {
+                assert_eq!(
+                    *expected,
+                    crate::escape_ascii(buf.read_delimited(&mut input, b"\r\n\r\n").await.unwrap()),
+                    "{:?}",
+                    faults
+                );
+            }
+            assert_eq!(
+                std::io::ErrorKind::NotFound,
+                buf.read_delimited(&mut input, b"\r\n\r\n")
+                    .await
+                    .unwrap_err()
+                    .kind()
+            );
+        }
     }
 
     struct AsyncReadableThatPanics;
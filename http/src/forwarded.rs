@@ -0,0 +1,232 @@
+// Finds the client's address in the forwarding headers that reverse proxies add.
+// https://tools.ietf.org/html/rfc7239
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::HttpReaderWriter;
+
+/// Returns the IPv4 address inside an IPv4-mapped IPv6 address like `::ffff:192.0.2.1`.
+/// Dual-stack listeners see IPv4 clients with these addresses.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] =>
+                IpAddr::V4(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low))),
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Cidr is a block of IP addresses, like `10.0.0.0/8` or `fd00::/8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Parses a block like `10.0.0.0/8`, or a single address like `192.0.2.1`.
+    /// Returns `None` if `s` is not valid.
+    pub fn parse(s: &str) -> Option<Cidr> {
+        let (addr, prefix_len) = match s.find('/') {
+            Some(slash) => (s[..slash].parse::<IpAddr>().ok()?, Some(s[slash + 1..].parse::<u8>().ok()?)),
+            None => (s.parse::<IpAddr>().ok()?, None),
+        };
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        if prefix_len > max_len {
+            return None;
+        }
+        Some(Cidr { addr, prefix_len })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(block), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(block) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(block), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(block) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// ForwardingHeader is the header that trusted proxies add the client's address to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForwardingHeader {
+    /// `X-Forwarded-For: 192.0.2.1, 198.51.100.1`
+    XForwardedFor,
+    /// `Forwarded: for=192.0.2.1;proto=https, for="[2001:db8::1]:4711"`
+    Forwarded,
+}
+
+impl ForwardingHeader {
+    fn name(&self) -> &'static str {
+        match self {
+            ForwardingHeader::XForwardedFor => "x-forwarded-for",
+            ForwardingHeader::Forwarded => "forwarded",
+        }
+    }
+}
+
+/// Parses a node like `192.0.2.1`, `192.0.2.1:4711`, `"[2001:db8::1]:4711"`, or `2001:db8::1`.
+/// Returns `None` for `unknown`, obfuscated identifiers like `_hidden`, and garbage.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+    let node = node.strip_prefix('"').and_then(|n| n.strip_suffix('"')).unwrap_or(node);
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Some(bracketed) = node.strip_prefix('[') {
+        let end = bracketed.find(']')?;
+        return bracketed[..end].parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    let colon = node.find(':')?;
+    node[..colon].parse::<Ipv4Addr>().ok().map(IpAddr::V4)
+}
+
+/// Returns the `for` value of each element of a `Forwarded` header value.
+/// Elements without one yield `None`.
+fn forwarded_for_values(value: &str) -> impl Iterator<Item = Option<&str>> {
+    value.split(',').map(|element| {
+        element.split(';')
+            .filter_map(|pair| {
+                let equals = pair.find('=')?;
+                Some((pair[..equals].trim(), &pair[equals + 1..]))
+            })
+            .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+            .map(|(_, node)| node)
+    })
+}
+
+/// TrustedProxies finds the client's address in the forwarding headers of requests that come
+/// through reverse proxies on the list.  Requests from other peers keep the peer's address,
+/// so clients cannot pick their own address by sending the headers.
+///
+/// The server reads only the configured header.  A proxy that appends to `X-Forwarded-For`
+/// passes a `Forwarded` header from the client through unchanged, and the reverse.
+///
+/// Set it with `HttpServerBuilder::trusted_proxies`, and then get the address from
+/// `HttpReaderWriter::client_ip`.
+#[derive(Clone, Debug)]
+pub struct TrustedProxies {
+    header: ForwardingHeader,
+    cidrs: Vec<Cidr>,
+    local: bool,
+}
+
+impl TrustedProxies {
+    /// Makes a list with no proxies, for proxies that add the client's address to `header`.
+    pub fn new(header: ForwardingHeader) -> TrustedProxies {
+        TrustedProxies { header, cidrs: Vec::new(), local: false }
+    }
+
+    /// Trusts proxies with addresses in a block like `10.0.0.0/8`, or at an address like `192.0.2.1`.
+    ///
+    /// Panics if `cidr` is not valid.
+    pub fn trust(mut self, cidr: &str) -> TrustedProxies {
+        self.cidrs.push(Cidr::parse(cidr).unwrap_or_else(|| panic!("invalid CIDR block {:?}", cidr)));
+        self
+    }
+
+    /// Trusts proxies connected over Unix domain sockets or in-memory connections.
+    /// See `PeerAddr::is_local`.
+    pub fn trust_local(mut self) -> TrustedProxies {
+        self.local = true;
+        self
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.cidrs.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Returns the address of the client that sent the request.
+    ///
+    /// Each proxy appends the address of its peer to the header.  This walks the header from
+    /// the end, skipping trusted proxies, and returns the first address not on the list.
+    /// Returns the last trusted address it reached when a node is `unknown` or obfuscated,
+    /// and `None` when the peer is not trusted and has no IP address.
+    pub fn client_ip(&self, http_reader_writer: &HttpReaderWriter) -> Option<IpAddr> {
+        let peer_addr = http_reader_writer.peer_addr();
+        let peer_ip = peer_addr.ip();
+        let peer_trusted = match peer_ip {
+            Some(ip) => self.contains(ip),
+            None => self.local && peer_addr.is_local(),
+        };
+        if !peer_trusted {
+            return peer_ip;
+        }
+        let values = http_reader_writer.header_values(self.header.name());
+        let nodes: Vec<Option<&str>> = match self.header {
+            ForwardingHeader::XForwardedFor =>
+                values.flat_map(|value| value.split(',')).map(Some).collect(),
+            ForwardingHeader::Forwarded => values.flat_map(forwarded_for_values).collect(),
+        };
+        let mut client_ip = peer_ip;
+        for node in nodes.into_iter().rev() {
+            match node.and_then(parse_node) {
+                Some(ip) if self.contains(ip) => client_ip = Some(ip),
+                Some(ip) => return Some(canonical(ip)),
+                None => break,
+            }
+        }
+        client_ip
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let block = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(block.contains(ip("10.1.0.0")));
+        assert!(block.contains(ip("10.1.255.255")));
+        assert!(block.contains(ip("::ffff:10.1.2.3")));
+        assert!(!block.contains(ip("10.2.0.0")));
+        assert!(!block.contains(ip("::a01:0")));
+        let single = Cidr::parse("192.0.2.1").unwrap();
+        assert!(single.contains(ip("192.0.2.1")));
+        assert!(!single.contains(ip("192.0.2.2")));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("198.51.100.1")));
+        let v6 = Cidr::parse("fd00::/8").unwrap();
+        assert!(v6.contains(ip("fd12::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+        assert!(Cidr::parse("::/0").unwrap().contains(ip("::1")));
+        assert!(!Cidr::parse("::/0").unwrap().contains(ip("::ffff:10.0.0.1")));
+        for s in &["", "10.0.0.0/", "10.0.0.0/33", "fd00::/129", "10.0.0/8", "example.com", "10.0.0.0/-1"] {
+            assert_eq!(None, Cidr::parse(s), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn test_parse_node() {
+        assert_eq!(Some(ip("192.0.2.1")), parse_node(" 192.0.2.1 "));
+        assert_eq!(Some(ip("192.0.2.1")), parse_node("192.0.2.1:4711"));
+        assert_eq!(Some(ip("192.0.2.1")), parse_node("\"192.0.2.1:4711\""));
+        assert_eq!(Some(ip("2001:db8::1")), parse_node("2001:db8::1"));
+        assert_eq!(Some(ip("2001:db8::1")), parse_node("\"[2001:db8::1]:4711\""));
+        assert_eq!(Some(ip("2001:db8::1")), parse_node("[2001:db8::1]"));
+        for s in &["", "unknown", "_hidden", "\"", "[2001:db8::1", "example.com:80", "[192.0.2.1]"] {
+            assert_eq!(None, parse_node(s), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn test_forwarded_for_values() {
+        assert_eq!(
+            vec![Some("192.0.2.1"), None, Some("\"[2001:db8::1]:4711\"")],
+            forwarded_for_values("for=192.0.2.1;proto=https, proto=http;by=x,FOR=\"[2001:db8::1]:4711\"")
+                .collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,193 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use crate::server::HttpHandler;
+use crate::stopper::StopSignal;
+use crate::{Header, HttpError, HttpMethod, HttpReaderWriter, HttpStatus};
+
+/// HealthCheck checks one thing the server needs, like a database connection.
+///
+/// Any async closure that returns `Result<(), String>` is a HealthCheck.
+#[async_trait]
+pub trait HealthCheck: Send + Sync {
+    /// Returns `Err` with a description of the problem if the check fails.
+    async fn check(&self) -> Result<(), String>;
+}
+
+#[async_trait]
+impl<F, Fut> HealthCheck for F
+    where F: Fn() -> Fut + Send + Sync,
+          Fut: Future<Output=Result<(), String>> + Send {
+    async fn check(&self) -> Result<(), String> {
+        self().await
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Criticality {
+    /// The server cannot do its work when this check fails.
+    /// Failures make `/healthz` and `/readyz` return 503 Service Unavailable.
+    Critical,
+    /// The server can do some of its work when this check fails.
+    /// Failures appear in the response details but do not change the status.
+    NonCritical,
+}
+
+struct RegisteredCheck {
+    name: &'static str,
+    timeout: Duration,
+    criticality: Criticality,
+    check: Box<dyn HealthCheck>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub criticality: Criticality,
+    pub duration: Duration,
+    pub error: Option<String>,
+}
+
+/// HealthChecks is a registry of health checks.
+#[derive(Default)]
+pub struct HealthChecks {
+    checks: Vec<RegisteredCheck>,
+}
+
+impl HealthChecks {
+    pub fn new() -> HealthChecks {
+        HealthChecks { checks: Vec::new() }
+    }
+
+    /// Adds a check.  The check fails if it takes longer than `timeout`.
+    pub fn add(mut self, name: &'static str, timeout: Duration, criticality: Criticality,
+               check: impl HealthCheck + 'static) -> HealthChecks {
+        self.checks.push(RegisteredCheck { name, timeout, criticality, check: Box::new(check) });
+        self
+    }
+
+    /// Runs all of the checks concurrently and returns their results, in the order they were added.
+    pub async fn run(&self) -> Vec<CheckResult> {
+        futures::future::join_all(self.checks.iter().map(|registered| async move {
+            let start = Instant::now();
+            let error = match tokio::time::timeout(registered.timeout, registered.check.check()).await {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => Some(e),
+                Err(_) => Some(format!("timed out after {:?}", registered.timeout)),
+            };
+            CheckResult {
+                name: registered.name,
+                criticality: registered.criticality,
+                duration: start.elapsed(),
+                error,
+            }
+        })).await
+    }
+}
+
+/// HealthHandler serves health endpoints and passes other requests to its inner handler.
+///
+/// `GET /healthz` runs the checks.  It returns 200 OK if every critical check passes and
+/// 503 Service Unavailable otherwise.  Use it for liveness probes.
+///
+/// `GET /readyz` also returns 503 once the server is stopping, so load balancers stop
+/// sending requests before the server stops accepting connections.  Use it for readiness probes.
+///
+/// Both return JSON like:
+/// `{"status":"ok","stopping":false,"checks":[{"name":"db","critical":true,"ok":true,"duration_ms":3,"error":""}]}`
+pub struct HealthHandler<H: HttpHandler> {
+    checks: HealthChecks,
+    stop_signal: Option<StopSignal>,
+    inner: H,
+}
+
+impl<H: HttpHandler> HealthHandler<H> {
+    pub fn new(checks: HealthChecks, inner: H) -> HealthHandler<H> {
+        HealthHandler { checks, stop_signal: None, inner }
+    }
+
+    /// Makes `/readyz` fail as soon as `stop_signal` is signalled.
+    ///
+    /// Without this, `/readyz` fails only after `HttpServer::stop` is called.
+    /// Use the signal from your process's `StopperController` and wait a few seconds between
+    /// signalling stop and calling `HttpServer::stop`, to give load balancers time to notice.
+    pub fn with_stop_signal(mut self, stop_signal: StopSignal) -> HealthHandler<H> {
+        self.stop_signal = Some(stop_signal);
+        self
+    }
+
+    async fn send_health(&self, http_reader_writer: &mut HttpReaderWriter<'_>, readiness: bool)
+                         -> Result<(), HttpError> {
+        let results = self.checks.run().await;
+        let stopping = http_reader_writer.is_stopping()
+            || matches!(&self.stop_signal, Some(stop_signal) if stop_signal.is_signalled());
+        let critical_failure = results.iter()
+            .any(|r| r.criticality == Criticality::Critical && r.error.is_some());
+        let unavailable = critical_failure || (readiness && stopping);
+        let body = serde_json::json!({
+            "status": if unavailable { "unavailable" } else { "ok" },
+            "stopping": stopping,
+            "checks": results.iter().map(|r| serde_json::json!({
+                "name": r.name,
+                "critical": r.criticality == Criticality::Critical,
+                "ok": r.error.is_none(),
+                "duration_ms": r.duration.as_millis() as u64,
+                "error": r.error.as_deref().unwrap_or(""),
+            })).collect::<Vec<_>>(),
+        }).to_string();
+        let status = if unavailable { HttpStatus::ServiceUnavailable503 } else { HttpStatus::Ok200 };
+        http_reader_writer.send_with_content_length(
+            status,
+            &[&Header::new("content-type", "application/json"),
+                &Header::new("cache-control", "no-store")],
+            body.len() as u64).await?;
+        tokio::io::AsyncWriteExt::write_all(http_reader_writer, body.as_bytes())
+            .await
+            .map_err(HttpError::from_io_err)
+    }
+}
+
+#[async_trait]
+impl<H: HttpHandler> HttpHandler for HealthHandler<H> {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        let readiness = match http_reader_writer.raw_path.split('?').next() {
+            Some("/healthz") => false,
+            Some("/readyz") => true,
+            _ => return self.inner.handle(http_reader_writer).await,
+        };
+        if http_reader_writer.method() != HttpMethod::GET {
+            return Err(HttpError::ProcessingError(HttpStatus::MethodNotAllowed405));
+        }
+        self.send_health(http_reader_writer, readiness).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_checks() {
+        let checks = HealthChecks::new()
+            .add("ok", Duration::from_secs(1), Criticality::Critical, || async { Ok(()) })
+            .add("fails", Duration::from_secs(1), Criticality::NonCritical,
+                 || async { Err(String::from("broken")) })
+            .add("slow", Duration::from_millis(10), Criticality::Critical, || async {
+                tokio::time::delay_for(Duration::from_secs(60)).await;
+                Ok(())
+            });
+        let results = checks.run().await;
+        let summary: Vec<(&str, Criticality, Option<String>)> = results.into_iter()
+            .map(|r| (r.name, r.criticality, r.error))
+            .collect();
+        assert_eq!(
+            vec![
+                ("ok", Criticality::Critical, None),
+                ("fails", Criticality::NonCritical, Some(String::from("broken"))),
+                ("slow", Criticality::Critical, Some(String::from("timed out after 10ms"))),
+            ],
+            summary);
+    }
+}
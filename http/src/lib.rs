@@ -1,22 +1,48 @@
 use std::cmp::min;
 use std::convert::TryFrom;
+use std::net::IpAddr;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use lazy_static::lazy_static;
 use log::trace;
 use string_wrapper::StringWrapper;
 use tokio::io::AsyncWrite;
 use tokio::prelude::AsyncRead;
 
 use crate::fixed_buffer::FixedBuf;
+use crate::request_parser::RequestParser;
+use crate::stopper::Stopper;
+use crate::transport::PeerAddr;
 
 pub mod buffer;
-pub mod async_write_logger;
-pub mod async_readable;
+pub mod cors;
+pub mod body_file;
+pub mod auth;
 pub mod split_iterate;
 pub mod async_write_buffer;
 pub mod fixed_buffer;
+pub mod faulty_stream;
+pub mod forwarded;
+pub mod health;
+pub mod metrics;
+pub mod middleware;
+pub mod multipart;
+pub mod proxy_protocol;
+pub mod request_parser;
+pub mod reverse_proxy;
+pub mod cert_reloader;
+pub mod cert_allowlist;
+pub mod listen_fd;
+pub mod memory_stream;
+pub mod stopper;
+pub mod server;
+pub mod sse;
+pub mod static_files;
+pub mod tap;
+pub mod testing;
+pub mod transport;
+pub mod urlencoded;
+pub mod websocket;
 
 pub fn escape_ascii(input: &[u8]) -> String {
     let mut result = String::new();
@@ -101,9 +127,14 @@ pub enum HttpCallerError {
     PathTooLong,
     HeaderLineInvalid,
     HeaderValueTooLong,
+    HeadersTooLong,
     ExpectHeaderInvalid,
     TransferEncodingHeaderInvalid,
     ContentLengthHeaderInvalid,
+    UpgradeInvalid,
+    MultipartInvalid,
+    JsonInvalid,
+    FormInvalid,
 }
 
 impl HttpCallerError {
@@ -117,9 +148,14 @@ impl HttpCallerError {
             Self::PathTooLong => HttpStatus::UriTooLong414,
             Self::HeaderLineInvalid => HttpStatus::BadRequest400,
             Self::HeaderValueTooLong => HttpStatus::RequestHeaderFieldsTooLarge431,
+            Self::HeadersTooLong => HttpStatus::RequestHeaderFieldsTooLarge431,
             Self::ExpectHeaderInvalid => HttpStatus::BadRequest400,
             Self::TransferEncodingHeaderInvalid => HttpStatus::BadRequest400,
             Self::ContentLengthHeaderInvalid => HttpStatus::BadRequest400,
+            Self::UpgradeInvalid => HttpStatus::BadRequest400,
+            Self::MultipartInvalid => HttpStatus::BadRequest400,
+            Self::JsonInvalid => HttpStatus::BadRequest400,
+            Self::FormInvalid => HttpStatus::BadRequest400,
         }
     }
 }
@@ -142,10 +178,12 @@ pub enum HttpMethod {
 impl HttpMethod {
     pub fn from_str(s: &str) -> Result<HttpMethod, HttpError> {
         // HTTP/1.1 Request Methods https://tools.ietf.org/html/rfc7231#section-4
-        lazy_static! {
-            static ref METHOD_RE: regex::Regex = regex::Regex::new("^[A-Z][A-Z0-9]*$").unwrap();
-        }
-        if !METHOD_RE.is_match(s) {
+        let valid = match s.as_bytes().split_first() {
+            Some((first, rest)) => first.is_ascii_uppercase()
+                && rest.iter().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit()),
+            None => false,
+        };
+        if !valid {
             return Err(HttpError::ParseError(HttpCallerError::MethodInvalid));
         }
         match s {
@@ -178,26 +216,20 @@ impl std::fmt::Display for HttpMethod {
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct HttpRequestLine<'a> {
-    method: &'a str,
-    raw_path: &'a str,
+/// The HTTP version of a request.
+/// Responses always say `HTTP/1.1`.  https://tools.ietf.org/html/rfc7230#section-2.6
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HttpVersion {
+    Http10,
+    Http11,
 }
 
-impl<'a> HttpRequestLine<'a> {
-    pub fn parse(line_bytes: &[u8]) -> Result<HttpRequestLine, HttpError> {
-        // HTTP/1.1 Request Line https://tools.ietf.org/html/rfc7230#section-3.1.1
-        let line = std::str::from_utf8(line_bytes)
-            .map_err(|_| HttpError::ParseError(HttpCallerError::RequestLineInvalid))?;
-        lazy_static! {
-            static ref REQUEST_LINE_RE: regex::Regex =
-                regex::Regex::new("^([^ ]+) (/[^ ]*) HTTP/1.1$").unwrap();
-        }
-        let captures: regex::Captures = REQUEST_LINE_RE.captures(line)
-            .ok_or(HttpError::ParseError(HttpCallerError::RequestLineInvalid))?;
-        let method = captures.get(1).unwrap().as_str();
-        let raw_path = captures.get(2).unwrap().as_str();
-        Ok(HttpRequestLine { method, raw_path })
+impl HttpVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpVersion::Http10 => "HTTP/1.0",
+            HttpVersion::Http11 => "HTTP/1.1",
+        }
     }
 }
 
@@ -317,43 +349,178 @@ pub fn header_receivers_to_string(header_receivers: &[&HeaderReceiver]) -> Strin
 #[derive(Debug, Clone)]
 pub enum HttpStatus {
     Continue100,
+    SwitchingProtocols101,
     Ok200,
     Created201,
+    NoContent204,
+    PartialContent206,
+    MovedPermanently301,
+    NotModified304,
     BadRequest400,
+    Unauthorized401,
+    Forbidden403,
     NotFound404,
     MethodNotAllowed405,
     LengthRequired411,
     PayloadTooLarge413,
     UriTooLong414,
+    RangeNotSatisfiable416,
     RequestHeaderFieldsTooLarge431,
     InternalServerError500(String),
+    BadGateway502,
+    ServiceUnavailable503,
+    GatewayTimeout504,
+    /// A status from 100 to 599 without its own variant, like one from an upstream server.
+    /// Other codes get sent as 500.
+    Other(u16),
+}
+
+/// Returns the reason phrase for statuses without their own `HttpStatus` variant.
+/// The phrase is optional, so uncommon codes get none.
+fn reason_phrase(code: u16) -> &'static str {
+    match code {
+        202 => "Accepted",
+        203 => "Non-Authoritative Information",
+        205 => "Reset Content",
+        300 => "Multiple Choices",
+        302 => "Found",
+        303 => "See Other",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        402 => "Payment Required",
+        406 => "Not Acceptable",
+        407 => "Proxy Authentication Required",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        412 => "Precondition Failed",
+        415 => "Unsupported Media Type",
+        417 => "Expectation Failed",
+        421 => "Misdirected Request",
+        422 => "Unprocessable Entity",
+        425 => "Too Early",
+        426 => "Upgrade Required",
+        428 => "Precondition Required",
+        429 => "Too Many Requests",
+        451 => "Unavailable For Legal Reasons",
+        501 => "Not Implemented",
+        505 => "HTTP Version Not Supported",
+        507 => "Insufficient Storage",
+        511 => "Network Authentication Required",
+        _ => "",
+    }
+}
+
+lazy_static::lazy_static! {
+    // Status lines for codes 100 through 599, for `HttpStatus::Other`.
+    static ref STATUS_LINES: Vec<String> = (100..600u16)
+        .map(|code| format!("HTTP/1.1 {} {}\r\n", code, reason_phrase(code)))
+        .collect();
 }
 
 impl HttpStatus {
     pub fn as_line(&self) -> &'static str {
         match self {
             HttpStatus::Continue100 => "HTTP/1.1 100 Continue\r\n",
+            HttpStatus::SwitchingProtocols101 => "HTTP/1.1 101 Switching Protocols\r\n",
             HttpStatus::Ok200 => "HTTP/1.1 200 OK\r\n",
             HttpStatus::Created201 => "HTTP/1.1 201 Created\r\n",
+            HttpStatus::NoContent204 => "HTTP/1.1 204 No Content\r\n",
+            HttpStatus::PartialContent206 => "HTTP/1.1 206 Partial Content\r\n",
+            HttpStatus::MovedPermanently301 => "HTTP/1.1 301 Moved Permanently\r\n",
+            HttpStatus::NotModified304 => "HTTP/1.1 304 Not Modified\r\n",
             HttpStatus::BadRequest400 => "HTTP/1.1 400 Bad Request\r\n",
+            HttpStatus::Unauthorized401 => "HTTP/1.1 401 Unauthorized\r\n",
+            HttpStatus::Forbidden403 => "HTTP/1.1 403 Forbidden\r\n",
             HttpStatus::NotFound404 => "HTTP/1.1 404 Not Found\r\n",
             HttpStatus::MethodNotAllowed405 => "HTTP/1.1 405 Method Not Allowed\r\n",
             HttpStatus::LengthRequired411 => "HTTP/1.1 411 Length Required\r\n",
             HttpStatus::PayloadTooLarge413 => "HTTP/1.1 413 Payload Too Large\r\n",
             HttpStatus::UriTooLong414 => "HTTP/1.1 414 URI Too Long\r\n",
+            HttpStatus::RangeNotSatisfiable416 => "HTTP/1.1 416 Range Not Satisfiable\r\n",
             HttpStatus::RequestHeaderFieldsTooLarge431 =>
                 "HTTP/1.1 431 Request Header Fields Too Large\r\n",
             HttpStatus::InternalServerError500(_) => "HTTP/1.1 500 Internal Server Error\r\n",
+            HttpStatus::BadGateway502 => "HTTP/1.1 502 Bad Gateway\r\n",
+            HttpStatus::ServiceUnavailable503 => "HTTP/1.1 503 Service Unavailable\r\n",
+            HttpStatus::GatewayTimeout504 => "HTTP/1.1 504 Gateway Timeout\r\n",
+            HttpStatus::Other(_) => &STATUS_LINES[usize::from(self.code() - 100)],
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            HttpStatus::Continue100 => 100,
+            HttpStatus::SwitchingProtocols101 => 101,
+            HttpStatus::Ok200 => 200,
+            HttpStatus::Created201 => 201,
+            HttpStatus::NoContent204 => 204,
+            HttpStatus::PartialContent206 => 206,
+            HttpStatus::MovedPermanently301 => 301,
+            HttpStatus::NotModified304 => 304,
+            HttpStatus::BadRequest400 => 400,
+            HttpStatus::Unauthorized401 => 401,
+            HttpStatus::Forbidden403 => 403,
+            HttpStatus::NotFound404 => 404,
+            HttpStatus::MethodNotAllowed405 => 405,
+            HttpStatus::LengthRequired411 => 411,
+            HttpStatus::PayloadTooLarge413 => 413,
+            HttpStatus::UriTooLong414 => 414,
+            HttpStatus::RangeNotSatisfiable416 => 416,
+            HttpStatus::RequestHeaderFieldsTooLarge431 => 431,
+            HttpStatus::InternalServerError500(_) => 500,
+            HttpStatus::BadGateway502 => 502,
+            HttpStatus::ServiceUnavailable503 => 503,
+            HttpStatus::GatewayTimeout504 => 504,
+            HttpStatus::Other(code @ 100..=599) => *code,
+            HttpStatus::Other(_) => 500,
         }
     }
+
+    /// Returns the status with code `code`, or `None` if it is not from 100 to 599.
+    pub fn from_code(code: u16) -> Option<HttpStatus> {
+        Some(match code {
+            100 => HttpStatus::Continue100,
+            101 => HttpStatus::SwitchingProtocols101,
+            200 => HttpStatus::Ok200,
+            201 => HttpStatus::Created201,
+            204 => HttpStatus::NoContent204,
+            206 => HttpStatus::PartialContent206,
+            301 => HttpStatus::MovedPermanently301,
+            304 => HttpStatus::NotModified304,
+            400 => HttpStatus::BadRequest400,
+            401 => HttpStatus::Unauthorized401,
+            403 => HttpStatus::Forbidden403,
+            404 => HttpStatus::NotFound404,
+            405 => HttpStatus::MethodNotAllowed405,
+            411 => HttpStatus::LengthRequired411,
+            413 => HttpStatus::PayloadTooLarge413,
+            414 => HttpStatus::UriTooLong414,
+            416 => HttpStatus::RangeNotSatisfiable416,
+            431 => HttpStatus::RequestHeaderFieldsTooLarge431,
+            500 => HttpStatus::InternalServerError500(String::new()),
+            502 => HttpStatus::BadGateway502,
+            503 => HttpStatus::ServiceUnavailable503,
+            504 => HttpStatus::GatewayTimeout504,
+            _ if (100..=599).contains(&code) => HttpStatus::Other(code),
+            _ => return None,
+        })
+    }
 }
 
 pub struct HttpReaderWriter<'a> {
-    addr: std::net::SocketAddr,
+    addr: PeerAddr,
     input: Pin<&'a mut (dyn tokio::io::AsyncRead + std::marker::Send + std::marker::Unpin)>,
     buffer: FixedBuf,
     method: Option<HttpMethod>,
+    /// The request's path and query, like `/a?b=c`.  It is `*` for `OPTIONS *` requests.
+    /// For absolute-form targets like `http://host/a?b=c`, it is the part after the host.
     pub raw_path: StringWrapper<[u8; 512]>,
+    // The host from an absolute-form target, or empty.
+    target_authority: String,
+    version: HttpVersion,
+    // The request's header lines, each ending with CRLF.  Reused between requests.
+    headers: String,
     unsent_expect_100_bytes: &'static [u8],
     content_length: u64,
     unread_content_length: u64,
@@ -361,21 +528,33 @@ pub struct HttpReaderWriter<'a> {
     output: Pin<&'a mut (dyn tokio::io::AsyncWrite + std::marker::Send + std::marker::Unpin)>,
     status: Option<HttpStatus>,
     unsent_content_length: Option<u64>,
+    chunked_response: bool,
     bytes_written: u64,
+    stopper: Option<Stopper>,
+    // The connection closes after the current response.
+    close_after_response: bool,
+    principal: Option<String>,
+    // The client address from trusted forwarding headers.
+    client_ip: Option<IpAddr>,
+    // Header lines added with `add_response_header`, each ending with CRLF.
+    added_response_headers: String,
 }
 
 impl<'a> HttpReaderWriter<'a> {
     pub fn new(
         input: Pin<&'a mut (dyn tokio::io::AsyncRead + std::marker::Send + std::marker::Unpin)>,
         output: Pin<&'a mut (dyn tokio::io::AsyncWrite + std::marker::Send + std::marker::Unpin)>,
-        addr: std::net::SocketAddr)
+        addr: impl Into<PeerAddr>)
         -> HttpReaderWriter<'a> {
         HttpReaderWriter {
-            addr,
+            addr: addr.into(),
             input,
             buffer: FixedBuf::new(),
             method: None,
             raw_path: StringWrapper::from_str(""),
+            target_authority: String::new(),
+            version: HttpVersion::Http11,
+            headers: String::new(),
             unsent_expect_100_bytes: &[],
             content_length: 0,
             unread_content_length: 0,
@@ -383,10 +562,72 @@ impl<'a> HttpReaderWriter<'a> {
             output,
             status: None,
             unsent_content_length: Some(0),
+            chunked_response: false,
             bytes_written: 0,
+            stopper: None,
+            close_after_response: false,
+            principal: None,
+            client_ip: None,
+            added_response_headers: String::new(),
         }
     }
 
+    /// Makes responses include `connection: close` once `stopper` is signalled.
+    pub fn set_stopper(&mut self, stopper: Stopper) {
+        self.stopper = Some(stopper);
+    }
+
+    /// Returns true when the server is stopping.  Long-running handlers should finish up.
+    pub fn is_stopping(&mut self) -> bool {
+        match self.stopper.as_mut() {
+            Some(stopper) => stopper.is_signalled(),
+            None => false,
+        }
+    }
+
+    /// Returns true when the connection should close after the current response, because the
+    /// client asked, the response needs it, or the server is stopping.
+    /// Responses sent while closing include the `connection: close` header.
+    pub fn is_closing(&mut self) -> bool {
+        if !self.close_after_response {
+            self.close_after_response = self.is_stopping();
+        }
+        self.close_after_response
+    }
+
+    /// Returns true if the handler read the whole request body,
+    /// so the next request on the connection can be read.
+    pub fn body_fully_read(&self) -> bool {
+        !self.chunked && self.unread_content_length == 0
+    }
+
+    /// Returns true if the response to the current request has been sent completely,
+    /// so the connection can carry another response.
+    pub fn response_fully_sent(&self) -> bool {
+        self.unsent_content_length == Some(0)
+    }
+
+    /// Returns the status of the response sent for the current request.
+    pub fn status(&self) -> Option<&HttpStatus> { self.status.as_ref() }
+
+    pub fn peer_addr(&self) -> &PeerAddr { &self.addr }
+
+    /// Returns the user or client that made the request, as set by an authenticator.
+    /// See `auth::Authenticated`.
+    pub fn principal(&self) -> Option<&str> { self.principal.as_deref() }
+
+    pub fn set_principal(&mut self, principal: String) { self.principal = Some(principal); }
+
+    /// Returns the IP address of the client that made the request.
+    /// That is the address from forwarding headers sent by trusted proxies,
+    /// or else the peer's address.  See `forwarded::TrustedProxies`.
+    /// Returns `None` for Unix domain socket and in-memory peers without forwarding headers.
+    ///
+    /// Use this, not `peer_addr`, to rate limit or log clients.
+    pub fn client_ip(&self) -> Option<IpAddr> { self.client_ip.or_else(|| self.addr.ip()) }
+
+    pub fn set_client_ip(&mut self, ip: IpAddr) { self.client_ip = Some(ip); }
+
     pub fn has_body(&self) -> bool {
         // The presence of a message body in a request is signaled by a Content-Length or
         // Transfer-Encoding header field.
@@ -402,6 +643,74 @@ impl<'a> HttpReaderWriter<'a> {
 
     pub fn method(&self) -> HttpMethod { self.method.as_ref().unwrap().clone() }
 
+    pub fn version(&self) -> HttpVersion { self.version }
+
+    /// Returns the host, and maybe port, that the request is for.
+    /// That is the host in an absolute-form target like `http://host:8080/path`,
+    /// or else the `Host` header.  https://tools.ietf.org/html/rfc7230#section-5.4
+    pub fn host(&self) -> Option<&str> {
+        if self.target_authority.is_empty() {
+            self.header("host")
+        } else {
+            Some(&self.target_authority)
+        }
+    }
+
+    /// Returns the value of the first request header named `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.header_values(name).next()
+    }
+
+    /// Returns the values of every request header named `name`, ignoring case, in order.
+    pub fn header_values<'s: 'n, 'n>(&'s self, name: &'n str) -> impl Iterator<Item = &'s str> + 'n {
+        self.headers()
+            .filter(move |(line_name, _)| line_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    /// Returns the name and value of every request header, in order.
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.split("\r\n")
+            .filter_map(|line| {
+                let colon = line.find(':')?;
+                Some((&line[..colon], line[colon + 1..].trim()))
+            })
+    }
+
+    /// Reads the whole request body.
+    ///
+    /// Returns `PayloadTooLarge413` if the body is longer than `limit` bytes,
+    /// and `LengthRequired411` if the body is chunked.
+    pub async fn read_body(&mut self, limit: usize) -> Result<Vec<u8>, HttpError> {
+        if self.chunked {
+            return Err(HttpError::ProcessingError(HttpStatus::LengthRequired411));
+        }
+        let len = self.content_length_usize()?;
+        if len > limit {
+            return Err(HttpError::ProcessingError(HttpStatus::PayloadTooLarge413));
+        }
+        let mut body = vec![0; len];
+        tokio::io::AsyncReadExt::read_exact(self, &mut body)
+            .await
+            .map_err(HttpError::from_io_err)?;
+        Ok(body)
+    }
+
+    /// Reads the request body and deserializes it from JSON.
+    /// Returns `ParseError(JsonInvalid)` if the body is not valid JSON for `T`.
+    /// See `read_body` for other errors.
+    pub async fn read_json<T: serde::de::DeserializeOwned>(&mut self, limit: usize) -> Result<T, HttpError> {
+        let body = self.read_body(limit).await?;
+        serde_json::from_slice(&body).map_err(|_e| HttpError::ParseError(HttpCallerError::JsonInvalid))
+    }
+
+    /// Reads an `application/x-www-form-urlencoded` request body, like an HTML form post.
+    /// See `urlencoded::decode` and `read_body` for errors.
+    pub async fn read_form(&mut self, limit: usize) -> Result<Vec<(String, String)>, HttpError> {
+        let body = self.read_body(limit).await?;
+        urlencoded::decode(&body)
+    }
+
     pub fn decode_path(&self) -> Result<std::borrow::Cow<str>, HttpError> {
         if self.raw_path.is_empty() {
             panic!("HttpReaderWriter::decode_path alled before reading request");
@@ -435,69 +744,107 @@ impl<'a> HttpReaderWriter<'a> {
                 String::from("previous request body not completely read")
             )));
         }
-        if let Some(unsent) = self.unsent_content_length {
-            if unsent > 0 {
-                return Err(HttpError::ProcessingError(HttpStatus::InternalServerError500(
-                    String::from("previous response body not completely sent")
-                )));
-            }
+        if self.status.is_some() && !self.response_fully_sent() {
+            return Err(HttpError::ProcessingError(HttpStatus::InternalServerError500(
+                String::from("previous response body not completely sent")
+            )));
         }
         self.buffer.shift();
         self.method = None;
         self.raw_path.truncate(0);
+        self.target_authority.clear();
+        self.version = HttpVersion::Http11;
+        self.headers.clear();
         self.unsent_expect_100_bytes = &[];
         self.content_length = 0;
         self.unread_content_length = 0;
         self.chunked = false;
         self.status = None;
         self.unsent_content_length = None;
+        self.chunked_response = false;
         self.bytes_written = 0;
+        self.principal = None;
+        self.client_ip = None;
+        self.added_response_headers.clear();
 
-        // "HTTP/1.1 Message Syntax and Routing" https://tools.ietf.org/html/rfc7230
-        let head = self.buffer.read_delimited(&mut self.input, b"\r\n\r\n")
-            .await
-            .map_err(|e| HttpError::IoError(e))
-            ?;
-        trace!("{:?} parsing HTTP request head {:?}", self.addr, escape_ascii(head));
-        let mut lines = split_iterate::split_iterate(head, b"\r\n");
-
-        let line_bytes = lines.next()
-            .ok_or(HttpError::ParseError(HttpCallerError::RequestLineMissing))?;
-        let request_line = HttpRequestLine::parse(line_bytes)?;
+        let mut parser = RequestParser::new();
+        let head_len = loop {
+            // The parser resumes where it stopped, so it scans each byte once.
+            let error = match parser.parse(self.buffer.readable()) {
+                Ok(Some(head_len)) => break head_len,
+                Ok(None) if self.buffer.writable().is_some() => None,
+                Ok(None) => Some(parser.too_long_error()),
+                Err(e) => Some(e),
+            };
+            if let Some(e) = error {
+                // Drop the bad head so callers that keep reading do not parse it again.
+                self.buffer.read_all();
+                return Err(e);
+            }
+            let writable = self.buffer.writable().unwrap();
+            let num_bytes_read = tokio::io::AsyncReadExt::read(&mut self.input, writable)
+                .await
+                .map_err(HttpError::IoError)?;
+            if num_bytes_read == 0 {
+                return Err(HttpError::IoError(if self.buffer.readable().is_empty() {
+                    std::io::Error::new(std::io::ErrorKind::NotFound, "eof with no data read")
+                } else {
+                    std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "eof before request head read")
+                }));
+            }
+            self.buffer.wrote(num_bytes_read);
+        };
+        let head = parser.head(self.buffer.read_bytes(head_len));
+        trace!("{:?} parsed HTTP request head {:?}", self.addr, head);
 
         let mut content_length = HeaderReceiver::new("content-length");
         let mut expect = HeaderReceiver::new("expect");
         let mut transfer_encoding = HeaderReceiver::new("transfer-encoding");
-        for line_bytes in lines {
-            if line_bytes.is_empty() {
-                break;
-            }
-            // HTTP/1.1 Header Fields https://tools.ietf.org/html/rfc7230#section-3.2
-            let line = std::str::from_utf8(line_bytes)
-                .or(Err(HttpError::ParseError(HttpCallerError::HeaderLineInvalid)))?;
-            lazy_static! {
-                static ref LINE_RE: regex::Regex
-                    = regex::Regex::new("^([^:]+):\\s*(.*?)\\s*$").unwrap();
-            }
-            let captures: regex::Captures = LINE_RE.captures(line)
-                .ok_or(HttpError::ParseError(HttpCallerError::HeaderLineInvalid))?;
-            let name = captures.get(1).unwrap().as_str();
-            let value = captures.get(2).unwrap().as_str();
-
+        let mut connection_close = false;
+        let mut connection_keep_alive = false;
+        self.headers.push_str(head.header_lines);
+        for (name, value) in head.headers() {
             Self::save_header_value(
                 name, value,
                 &mut [&mut content_length, &mut expect, &mut transfer_encoding], )?;
             Self::save_header_value(name, value, extra_headers)?;
+            // HTTP/1.1 Connection https://tools.ietf.org/html/rfc7230#section-6.1
+            if name.eq_ignore_ascii_case("connection") {
+                for option in value.split(',').map(str::trim) {
+                    connection_close |= option.eq_ignore_ascii_case("close");
+                    connection_keep_alive |= option.eq_ignore_ascii_case("keep-alive");
+                }
+            }
         }
 
-        self.method = Some(HttpMethod::from_str(request_line.method)?);
+        self.method = Some(HttpMethod::from_str(head.method)?);
+        self.version = head.version;
         self.raw_path.truncate(0);
-        self.raw_path.push_partial_str(request_line.raw_path)
+        if let Some(authority) = head.authority {
+            self.target_authority.push_str(authority);
+            if !head.path.starts_with('/') {
+                self.raw_path.push_str("/");
+            }
+        }
+        self.raw_path.push_partial_str(head.path)
             .or(Err(HttpError::ParseError(HttpCallerError::PathTooLong)))?;
-        if expect.is_100_continue()? {
-            self.unsent_expect_100_bytes = HttpStatus::Continue100.as_line().as_bytes();
+        // HTTP/1.0 connections close after each response, unless the client asks to keep them.
+        // https://tools.ietf.org/html/rfc7230#section-6.3
+        if connection_close || (self.version == HttpVersion::Http10 && !connection_keep_alive) {
+            self.close_after_response = true;
+        }
+        // Servers must ignore `expect: 100-continue` from HTTP/1.0 clients.
+        // https://tools.ietf.org/html/rfc7231#section-5.1.1
+        if self.version == HttpVersion::Http11 && expect.is_100_continue()? {
+            // The interim response has no headers, so the blank line follows the status line.
+            self.unsent_expect_100_bytes = b"HTTP/1.1 100 Continue\r\n\r\n";
         }
         self.content_length = content_length.parse_content_length()?;
+        self.unread_content_length = self.content_length;
+        // HTTP/1.0 has no transfer codings.  https://tools.ietf.org/html/rfc7230#section-3.3.1
+        if self.version == HttpVersion::Http10 && !transfer_encoding.value.is_empty() {
+            return Err(HttpError::ParseError(HttpCallerError::TransferEncodingHeaderInvalid));
+        }
         self.chunked = transfer_encoding.is_chunked()?;
         Ok(())
     }
@@ -509,6 +856,35 @@ impl<'a> HttpReaderWriter<'a> {
         Ok(())
     }
 
+    fn append_common_headers(&mut self, buf: &mut FixedBuf) -> Result<(), HttpError> {
+        if self.is_closing() {
+            buf.append("connection: close\r\n");
+        } else if self.version == HttpVersion::Http10 {
+            buf.append("connection: keep-alive\r\n");
+        }
+        buf.try_append(&self.added_response_headers)
+            .ok_or_else(|| HttpError::ProcessingError(HttpStatus::InternalServerError500(
+                format!("buffer full while pushing added headers {:?}", self.added_response_headers))))
+    }
+
+    /// Adds a header to the response for the current request.
+    /// Wrapping handlers use this to add headers to responses that their inner handlers send.
+    pub fn add_response_header(&mut self, name: &str, value: &str) -> Result<(), HttpError> {
+        if self.status.is_some() {
+            return Err(HttpError::ProcessingError(HttpStatus::InternalServerError500(
+                format!("add_response_header called after response sent: {}", name))));
+        }
+        if name.contains(&[':', '\r', '\n'][..]) || value.contains(&['\r', '\n'][..]) {
+            return Err(HttpError::ProcessingError(HttpStatus::InternalServerError500(
+                format!("invalid response header {:?}: {:?}", name, value))));
+        }
+        self.added_response_headers.push_str(name);
+        self.added_response_headers.push_str(": ");
+        self.added_response_headers.push_str(value);
+        self.added_response_headers.push_str("\r\n");
+        Ok(())
+    }
+
     fn reject_header(name: &str, headers: &[&Header]) -> Result<(), HttpError> {
         for &header in headers {
             if header.name.eq_ignore_ascii_case(name) {
@@ -551,7 +927,9 @@ impl<'a> HttpReaderWriter<'a> {
     pub async fn send_simple(&mut self, status: HttpStatus) -> Result<(), HttpError> {
         let mut buf = fixed_buffer::FixedBuf::new();
         buf.append(status.as_line());
-        buf.append("content-length: 0\r\n\r\n");
+        buf.append("content-length: 0\r\n");
+        self.append_common_headers(&mut buf)?;
+        buf.append("\r\n");
         self.unsent_content_length = Some(0);
         self.send(buf.read_all()).await?;
         self.status = Some(status);
@@ -562,7 +940,12 @@ impl<'a> HttpReaderWriter<'a> {
                                    -> Result<(), HttpError> {
         let mut buf = fixed_buffer::FixedBuf::new();
         buf.append(status.as_line());
-        Self::append_content_length(&mut buf, 0)?;
+        // A 204 response has no content-length.  A 304 response's content-length is the length
+        // of the body the client already has.  https://tools.ietf.org/html/rfc7230#section-3.3.2
+        if status.code() != 204 && status.code() != 304 {
+            Self::append_content_length(&mut buf, 0)?;
+        }
+        self.append_common_headers(&mut buf)?;
         self.unsent_content_length = Some(0);
         Self::reject_header("transfer-encoding", extra_headers)?;
         Self::reject_header("content-length", extra_headers)?;
@@ -580,10 +963,27 @@ impl<'a> HttpReaderWriter<'a> {
         if body.len() == 0 {
             return self.send_without_body(status, extra_headers).await;
         }
+        self.send_bytes(status, extra_headers, "text/plain; charset=UTF-8", body.as_bytes()).await
+    }
+
+    /// Serializes `value` and sends it with content-type `application/json`.
+    pub async fn send_json<T: serde::Serialize + ?Sized>(
+        &mut self, status: HttpStatus, extra_headers: &[&Header<'_>], value: &T)
+        -> Result<(), HttpError> {
+        let body = serde_json::to_vec(value)
+            .map_err(|e| HttpError::ProcessingError(HttpStatus::InternalServerError500(
+                format!("error serializing JSON: {}", e))))?;
+        self.send_bytes(status, extra_headers, "application/json", &body).await
+    }
+
+    pub async fn send_bytes(
+        &mut self, status: HttpStatus, extra_headers: &[&Header<'_>], content_type: &str, body: &[u8])
+        -> Result<(), HttpError> {
         let mut buf = fixed_buffer::FixedBuf::new();
         buf.append(status.as_line());
-        buf.append("content-type: text/plain; charset=UTF-8\r\n");
+        Self::append_extra_headers(&mut buf, &[&Header::new("content-type", content_type)])?;
         Self::append_content_length(&mut buf, body.len() as u64)?;
+        self.append_common_headers(&mut buf)?;
         Self::reject_header("transfer-encoding", extra_headers)?;
         Self::reject_header("content-length", extra_headers)?;
         Self::reject_header("content-type", extra_headers)?;
@@ -591,7 +991,7 @@ impl<'a> HttpReaderWriter<'a> {
         Self::append_extra_headers(&mut buf, extra_headers)?;
         buf.append("\r\n");
         self.send(buf.read_all()).await?;
-        self.send(body.as_bytes()).await?;
+        self.send(body).await?;
         self.unsent_content_length = Some(0);
         self.status = Some(status);
         Ok(())
@@ -604,6 +1004,7 @@ impl<'a> HttpReaderWriter<'a> {
         buf.append(status.as_line());
         //buf.append("transfer-encoding: chunked\r\n");
         Self::append_content_length(&mut buf, content_length)?;
+        self.append_common_headers(&mut buf)?;
         self.unsent_content_length = Some(content_length);
         Self::reject_header("transfer-encoding", extra_headers)?;
         Self::reject_header("content-length", extra_headers)?;
@@ -614,6 +1015,113 @@ impl<'a> HttpReaderWriter<'a> {
         Ok(())
     }
 
+    /// Sends the response head with `transfer-encoding: chunked`.
+    /// Send the body with `send_chunk` and end it with `finish_chunked`.
+    ///
+    /// HTTP/1.0 clients do not support chunked encoding.  They get the body without chunk framing
+    /// and the connection closes after it.
+    ///
+    /// Use this for responses with unknown length, like streams of events.
+    pub async fn send_chunked(&mut self, status: HttpStatus, extra_headers: &[&Header<'_>])
+                              -> Result<(), HttpError> {
+        let mut buf = fixed_buffer::FixedBuf::new();
+        buf.append(status.as_line());
+        if self.version == HttpVersion::Http10 {
+            // Closing the connection ends the body.
+            self.close_after_response = true;
+        } else {
+            buf.append("transfer-encoding: chunked\r\n");
+        }
+        self.append_common_headers(&mut buf)?;
+        Self::reject_header("transfer-encoding", extra_headers)?;
+        Self::reject_header("content-length", extra_headers)?;
+        Self::append_extra_headers(&mut buf, extra_headers)?;
+        buf.append("\r\n");
+        self.unsent_content_length = None;
+        self.chunked_response = true;
+        self.send(buf.read_all()).await?;
+        self.status = Some(status);
+        Ok(())
+    }
+
+    /// Sends `data` as one chunk of a response started with `send_chunked`.
+    pub async fn send_chunk(&mut self, data: &[u8]) -> Result<(), HttpError> {
+        if !self.chunked_response {
+            return Err(HttpError::ProcessingError(HttpStatus::InternalServerError500(
+                String::from("send_chunk called without send_chunked"))));
+        }
+        // A zero-length chunk ends the body.
+        if data.is_empty() {
+            return Ok(());
+        }
+        if self.version == HttpVersion::Http10 {
+            return self.send(data).await;
+        }
+        // HTTP/1.1 Chunked Transfer Coding https://tools.ietf.org/html/rfc7230#section-4.1
+        let size_line = format!("{:x}\r\n", data.len());
+        tokio::io::AsyncWriteExt::write_all(&mut self.output, size_line.as_bytes())
+            .await
+            .map_err(HttpError::from_io_err)?;
+        tokio::io::AsyncWriteExt::write_all(&mut self.output, data)
+            .await
+            .map_err(HttpError::from_io_err)?;
+        self.bytes_written += u64::try_from(data.len()).unwrap();
+        self.send(b"\r\n").await
+    }
+
+    /// Ends a response started with `send_chunked`.
+    pub async fn finish_chunked(&mut self) -> Result<(), HttpError> {
+        if !self.chunked_response {
+            return Err(HttpError::ProcessingError(HttpStatus::InternalServerError500(
+                String::from("finish_chunked called without send_chunked"))));
+        }
+        if self.version == HttpVersion::Http11 {
+            self.send(b"0\r\n\r\n").await?;
+        }
+        self.chunked_response = false;
+        self.unsent_content_length = Some(0);
+        Ok(())
+    }
+
+    /// Sends `101 Switching Protocols` with `extra_headers`.
+    /// Then the connection carries another protocol.  Use `raw_connection` to read and write it.
+    ///
+    /// Returns `UpgradeInvalid` for HTTP/1.0 requests, which cannot switch protocols.
+    pub async fn send_switching_protocols(&mut self, extra_headers: &[&Header<'_>]) -> Result<(), HttpError> {
+        if self.version == HttpVersion::Http10 {
+            return Err(HttpError::ParseError(HttpCallerError::UpgradeInvalid));
+        }
+        let mut buf = fixed_buffer::FixedBuf::new();
+        buf.append(HttpStatus::SwitchingProtocols101.as_line());
+        buf.try_append(&self.added_response_headers)
+            .ok_or_else(|| HttpError::ProcessingError(HttpStatus::InternalServerError500(
+                String::from("buffer full while pushing added headers"))))?;
+        Self::reject_header("transfer-encoding", extra_headers)?;
+        Self::reject_header("content-length", extra_headers)?;
+        Self::append_extra_headers(&mut buf, extra_headers)?;
+        buf.append("\r\n");
+        self.unsent_content_length = Some(0);
+        // The connection cannot carry another HTTP request.
+        self.close_after_response = true;
+        self.send(buf.read_all()).await?;
+        self.status = Some(HttpStatus::SwitchingProtocols101);
+        Ok(())
+    }
+
+    /// Returns the buffer holding bytes the client sent after the request head,
+    /// and the connection's reader and writer.
+    ///
+    /// Call this after `send_switching_protocols`.  Read the buffer's bytes before reading from the reader.
+    pub fn raw_connection(&mut self) -> (
+        &mut FixedBuf,
+        &mut (dyn tokio::io::AsyncRead + std::marker::Send + std::marker::Unpin + 'a),
+        &mut (dyn tokio::io::AsyncWrite + std::marker::Send + std::marker::Unpin + 'a)) {
+        if self.status.as_ref().map(|s| s.code()) != Some(101) {
+            panic!("HttpReaderWriter::raw_connection called before send_switching_protocols");
+        }
+        (&mut self.buffer, self.input.as_mut().get_mut(), self.output.as_mut().get_mut())
+    }
+
     fn send_expect_100_bytes(&mut self, cx: &mut Context<'_>) -> Option<Poll<tokio::io::Result<usize>>> {
         // TODO(mleonhard) Try to merge this back into HttpReaderWriter::poll_read.  Use mut_self.
         while !self.unsent_expect_100_bytes.is_empty() {
@@ -660,14 +1168,16 @@ impl<'a> AsyncRead for HttpReaderWriter<'a> {
         let readable = self.buffer.readable();
         if readable.len() > 0 {
             let num_bytes = min(readable.len(), dest.len());
-            dest.copy_from_slice(&readable[..num_bytes]);
+            dest[..num_bytes].copy_from_slice(&readable[..num_bytes]);
             trace!("{:?} read {} body bytes from buffer", self.addr, num_bytes);
             self.buffer.consume(num_bytes);
+            self.unread_content_length -= num_bytes as u64;
             return Poll::Ready(Ok(num_bytes));
         }
         match self.input.as_mut().poll_read(cx, dest) {
             Poll::Ready(Ok(num_bytes)) => {
                 trace!("{:?} read {} body bytes", self.addr, num_bytes);
+                self.unread_content_length -= num_bytes as u64;
                 Poll::Ready(Ok(num_bytes))
             }
             Poll::Pending => Poll::Pending,
@@ -679,6 +1189,10 @@ impl<'a> AsyncRead for HttpReaderWriter<'a> {
 impl<'a> AsyncWrite for HttpReaderWriter<'a> {
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
                   -> Poll<tokio::io::Result<usize>> {
+        if self.chunked_response {
+            return Poll::Ready(Err(tokio::io::Error::new(
+                tokio::io::ErrorKind::InvalidInput, "use send_chunk to write chunked responses")));
+        }
         if let Some(unsent_len) = self.unsent_content_length {
             if unsent_len < buf.len() as u64 {
                 return Poll::Ready(
@@ -726,6 +1240,9 @@ impl<'a> std::fmt::Debug for HttpReaderWriter<'a> {
         if !self.raw_path.is_empty() {
             dbg.field("raw_path", &self.raw_path);
         }
+        if self.version == HttpVersion::Http10 {
+            dbg.field("version", &self.version);
+        }
         if self.chunked {
             dbg.field("chunked", &self.chunked);
         }
@@ -747,6 +1264,9 @@ impl<'a> std::fmt::Debug for HttpReaderWriter<'a> {
                 dbg.field("unsent_content_length", &unsent_content_length);
             }
         }
+        if self.chunked_response {
+            dbg.field("chunked_response", &self.chunked_response);
+        }
         if self.bytes_written > 0 {
             dbg.field("bytes_written", &self.bytes_written);
         }
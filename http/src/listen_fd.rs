@@ -0,0 +1,206 @@
+// Passing a listening socket to a new server process, for restarts that do not drop connections.
+//
+// A deploy that stops the old process and then starts the new one leaves a window when
+// nothing is listening on the port and clients get "connection refused".
+// Instead, the old process starts the new process and passes it the listening socket.
+// Both processes accept connections from the same socket until the old process stops
+// with `HttpServer::stop`.
+//
+// Steps:
+// 1. The old process receives SIGUSR2.  See `wait_for_restart_signal`.
+// 1. It calls `spawn_replacement` with `HttpServer::listener_fd`.
+// 1. The new process calls `take_inherited_listener` and passes the listener to
+//    `HttpServerBuilder::listener`.
+// 1. The old process calls `HttpServer::stop` and exits.
+//
+// `take_inherited_listener` also accepts a socket from systemd socket activation
+// (`LISTEN_FDS` and `LISTEN_PID`).  With socket activation, systemd holds the socket open
+// and you can restart the service with `systemctl restart`.
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+
+/// The new process reads the listening socket's file descriptor number from this env var.
+pub const LISTEN_FD_ENV_VAR: &str = "BEATRICE_LISTEN_FD";
+
+/// systemd passes sockets starting at this file descriptor number.
+/// See https://www.freedesktop.org/software/systemd/man/sd_listen_fds.html
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+pub(crate) fn to_io_error(e: nix::Error) -> std::io::Error {
+    match e.as_errno() {
+        Some(errno) => std::io::Error::from_raw_os_error(errno as i32),
+        None => std::io::Error::new(std::io::ErrorKind::InvalidInput, e),
+    }
+}
+
+fn invalid_data(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+fn take_env_var(name: &str) -> std::io::Result<Option<String>> {
+    let result = match std::env::var(name) {
+        Ok(s) => Ok(Some(s)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(std::env::VarError::NotUnicode(oss)) =>
+            Err(invalid_data(format!("env var {} is not UTF-8: {:?}", name, oss))),
+    };
+    // Child processes must not see the var and try to use a file descriptor they lack.
+    std::env::remove_var(name);
+    result
+}
+
+fn parse_i32(name: &str, value: &str) -> std::io::Result<i32> {
+    value.parse().map_err(|_| invalid_data(format!("failed parsing env var {}={:?}", name, value)))
+}
+
+/// Returns the file descriptor passed by `spawn_replacement` or systemd socket activation,
+/// if any, from the values of the env vars.  `pid` is this process's ID.
+fn inherited_fd(listen_fd: Option<String>, listen_fds: Option<String>, listen_pid: Option<String>,
+                pid: i32) -> std::io::Result<Option<RawFd>> {
+    if let Some(value) = listen_fd {
+        return Ok(Some(parse_i32(LISTEN_FD_ENV_VAR, &value)?));
+    }
+    match (listen_fds, listen_pid) {
+        (Some(fds), Some(listen_pid)) => {
+            // The vars are for a different process when a parent process leaked them to us.
+            if parse_i32("LISTEN_PID", &listen_pid)? != pid {
+                return Ok(None);
+            }
+            match parse_i32("LISTEN_FDS", &fds)? {
+                0 => Ok(None),
+                1 => Ok(Some(SD_LISTEN_FDS_START)),
+                n => Err(invalid_data(format!("expected LISTEN_FDS=1, got {}", n))),
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Returns the listening socket passed by the process that started this one,
+/// or `None` if there is none.
+///
+/// Checks the `BEATRICE_LISTEN_FD` env var set by `spawn_replacement`, then the
+/// `LISTEN_FDS` and `LISTEN_PID` env vars set by systemd socket activation.
+/// Removes the env vars so child processes do not see them.
+///
+/// Returns an error if the file descriptor is not a listening socket.
+/// Call this at most once per process.
+///
+/// Call this before starting any threads, including the tokio runtime's worker threads.
+/// Removing env vars while another thread reads them is undefined behavior on most platforms.
+pub fn take_inherited_listener() -> std::io::Result<Option<std::net::TcpListener>> {
+    let listen_fd = take_env_var(LISTEN_FD_ENV_VAR)?;
+    let listen_fds = take_env_var("LISTEN_FDS")?;
+    let listen_pid = take_env_var("LISTEN_PID")?;
+    listener_from_fd(inherited_fd(listen_fd, listen_fds, listen_pid, nix::unistd::getpid().as_raw())?)
+}
+
+/// Checks that `fd` is a listening socket and takes ownership of it.
+fn listener_from_fd(fd: Option<RawFd>) -> std::io::Result<Option<std::net::TcpListener>> {
+    let fd = match fd {
+        Some(fd) => fd,
+        None => return Ok(None),
+    };
+    let listening = nix::sys::socket::getsockopt(fd, nix::sys::socket::sockopt::AcceptConn)
+        .map_err(to_io_error)?;
+    if !listening {
+        return Err(invalid_data(format!("inherited file descriptor {} is not listening", fd)));
+    }
+    // Keep the socket out of processes we start, except with `spawn_replacement`.
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(to_io_error)?;
+    // The checks above make sure we own an open socket.
+    Ok(Some(unsafe { std::net::TcpListener::from_raw_fd(fd) }))
+}
+
+/// Makes `command` pass the listening socket `fd` to the process it starts.
+/// The new process gets it with `take_inherited_listener`.
+pub fn pass_listener(command: &mut std::process::Command, fd: RawFd) -> &mut std::process::Command {
+    command.env(LISTEN_FD_ENV_VAR, fd.to_string());
+    // Rust opens files with close-on-exec.  Clear the flag in the child process only, so
+    // processes started by other threads do not get the socket.  Calling fcntl between fork
+    // and exec is safe because it does not allocate or take locks.
+    unsafe {
+        command.pre_exec(move || {
+            fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty())).map_err(to_io_error)?;
+            Ok(())
+        })
+    }
+}
+
+/// Starts a new copy of this program with the same arguments and passes it the
+/// listening socket `fd`.
+///
+/// The new process inherits this process's stdout and stderr.  It keeps running after this
+/// process exits.
+pub fn spawn_replacement(fd: RawFd) -> std::io::Result<std::process::Child> {
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command.args(std::env::args_os().skip(1));
+    pass_listener(&mut command, fd).spawn()
+}
+
+/// Waits for a USR2 signal, the signal for a zero-downtime restart.
+///
+/// Panics if it fails to install the signal handler.
+pub async fn wait_for_restart_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut usr2_signal =
+        signal(SignalKind::user_defined2()).expect("Failed installing USR2 signal handler");
+    usr2_signal.recv().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::{AsRawFd, IntoRawFd};
+
+    #[test]
+    fn test_inherited_fd() {
+        // Tests pass the env var values instead of setting env vars, which races with other
+        // threads reading the environment.
+        let some = |s: &str| Some(String::from(s));
+        assert_eq!(None, inherited_fd(None, None, None, 42).unwrap());
+        assert_eq!(Some(7), inherited_fd(some("7"), some("1"), some("42"), 42).unwrap());
+        assert_eq!(Some(SD_LISTEN_FDS_START), inherited_fd(None, some("1"), some("42"), 42).unwrap());
+        assert_eq!(None, inherited_fd(None, some("0"), some("42"), 42).unwrap());
+        // The vars are for another process.
+        assert_eq!(None, inherited_fd(None, some("1"), some("1"), 42).unwrap());
+        assert_eq!(None, inherited_fd(None, some("1"), None, 42).unwrap());
+        assert_eq!(std::io::ErrorKind::InvalidData,
+                   inherited_fd(None, some("2"), some("42"), 42).unwrap_err().kind());
+        assert_eq!(std::io::ErrorKind::InvalidData,
+                   inherited_fd(some("x"), None, None, 42).unwrap_err().kind());
+        assert_eq!(std::io::ErrorKind::InvalidData,
+                   inherited_fd(None, some("1"), some("x"), 42).unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_listener_from_fd() {
+        assert!(listener_from_fd(None).unwrap().is_none());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let fd = listener.try_clone().unwrap().into_raw_fd();
+        let inherited = listener_from_fd(Some(fd)).unwrap().unwrap();
+        assert_eq!(listener.local_addr().unwrap(), inherited.local_addr().unwrap());
+        let file = std::fs::File::open("/dev/null").unwrap();
+        assert!(listener_from_fd(Some(file.as_raw_fd())).is_err());
+    }
+
+    #[test]
+    fn test_pass_listener() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let script = format!("test -S /proc/self/fd/${}", LISTEN_FD_ENV_VAR);
+        let status = pass_listener(
+            std::process::Command::new("sh").arg("-c").arg(&script), listener.as_raw_fd())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        // Without pass_listener, the child process does not get the socket.
+        let status = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("test -S /proc/self/fd/{}", listener.as_raw_fd()))
+            .status()
+            .unwrap();
+        assert!(!status.success());
+    }
+}
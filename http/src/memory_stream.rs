@@ -0,0 +1,163 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Each direction buffers at most this many bytes.  Writers wait when the buffer is full.
+const MAX_BUFFERED_BYTES: usize = 64 * 1024;
+
+/// One direction of a `MemoryStream` pair.
+struct Pipe {
+    buffer: VecDeque<u8>,
+    closed: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Pipe {
+    fn new() -> Arc<Mutex<Pipe>> {
+        Arc::new(Mutex::new(Pipe {
+            buffer: VecDeque::new(),
+            closed: false,
+            read_waker: None,
+            write_waker: None,
+        }))
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// MemoryStream is one end of an in-memory connection, like a TCP socket without the network.
+/// Bytes written to one end are read from the other end.
+///
+/// Make a connected pair with `memory_stream_pair()`.
+///
+/// Shutting down an end makes the other end read EOF.  Dropping an end also makes writes on
+/// the other end fail with `BrokenPipe`.
+pub struct MemoryStream {
+    read_pipe: Arc<Mutex<Pipe>>,
+    write_pipe: Arc<Mutex<Pipe>>,
+}
+
+pub fn memory_stream_pair() -> (MemoryStream, MemoryStream) {
+    let a_to_b = Pipe::new();
+    let b_to_a = Pipe::new();
+    (
+        MemoryStream { read_pipe: b_to_a.clone(), write_pipe: a_to_b.clone() },
+        MemoryStream { read_pipe: a_to_b, write_pipe: b_to_a },
+    )
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        self.read_pipe.lock().unwrap().close();
+        self.write_pipe.lock().unwrap().close();
+    }
+}
+
+impl std::fmt::Debug for MemoryStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MemoryStream")
+    }
+}
+
+impl AsyncRead for MemoryStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8])
+                 -> Poll<tokio::io::Result<usize>> {
+        let mut pipe = self.read_pipe.lock().unwrap();
+        if pipe.buffer.is_empty() {
+            if pipe.closed || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            pipe.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let num_bytes = std::cmp::min(buf.len(), pipe.buffer.len());
+        for (dest, src) in buf.iter_mut().zip(pipe.buffer.drain(..num_bytes)) {
+            *dest = src;
+        }
+        if let Some(waker) = pipe.write_waker.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(num_bytes))
+    }
+}
+
+impl AsyncWrite for MemoryStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+                  -> Poll<tokio::io::Result<usize>> {
+        let mut pipe = self.write_pipe.lock().unwrap();
+        if pipe.closed {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe, "memory stream closed")));
+        }
+        let num_bytes = std::cmp::min(buf.len(), MAX_BUFFERED_BYTES - pipe.buffer.len());
+        if num_bytes == 0 && !buf.is_empty() {
+            pipe.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        pipe.buffer.extend(&buf[..num_bytes]);
+        if let Some(waker) = pipe.read_waker.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(num_bytes))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        self.write_pipe.lock().unwrap().close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_read_write_shutdown() {
+        let (mut a, mut b) = memory_stream_pair();
+        a.write_all(b"abc").await.unwrap();
+        b.write_all(b"123").await.unwrap();
+        let mut buf = [0u8; 3];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"abc", &buf);
+        a.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"123", &buf);
+        a.write_all(b"def").await.unwrap();
+        a.shutdown().await.unwrap();
+        let mut s = String::new();
+        b.read_to_string(&mut s).await.unwrap();
+        assert_eq!("def", s);
+        drop(a);
+        assert_eq!(std::io::ErrorKind::BrokenPipe, b.write_all(b"x").await.unwrap_err().kind());
+    }
+
+    #[tokio::test]
+    async fn test_writer_waits_for_reader() {
+        let (mut a, mut b) = memory_stream_pair();
+        let data = vec![7u8; 3 * MAX_BUFFERED_BYTES];
+        let data_clone = data.clone();
+        let writer = tokio::spawn(async move {
+            a.write_all(&data_clone).await.unwrap();
+        });
+        let mut received = vec![0u8; data.len()];
+        b.read_exact(&mut received).await.unwrap();
+        writer.await.unwrap();
+        assert_eq!(data, received);
+    }
+}
@@ -0,0 +1,44 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use logging::metrics::Registry;
+
+use crate::server::HttpHandler;
+use crate::{Header, HttpError, HttpMethod, HttpReaderWriter, HttpStatus};
+
+/// MetricsHandler serves `GET /metrics` in the Prometheus text format and passes other
+/// requests to its inner handler.
+///
+/// Anyone who can reach the server can read the metrics.  To serve them only to local clients,
+/// run a second server on a Unix socket with `LocalOnly(MetricsHandler::new(registry, ...))`.
+pub struct MetricsHandler<H: HttpHandler> {
+    registry: Arc<Registry>,
+    inner: H,
+}
+
+impl<H: HttpHandler> MetricsHandler<H> {
+    pub fn new(registry: Arc<Registry>, inner: H) -> MetricsHandler<H> {
+        MetricsHandler { registry, inner }
+    }
+}
+
+#[async_trait]
+impl<H: HttpHandler> HttpHandler for MetricsHandler<H> {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        if http_reader_writer.raw_path.split('?').next() != Some("/metrics") {
+            return self.inner.handle(http_reader_writer).await;
+        }
+        if http_reader_writer.method() != HttpMethod::GET {
+            return Err(HttpError::ProcessingError(HttpStatus::MethodNotAllowed405));
+        }
+        let body = self.registry.prometheus_text();
+        http_reader_writer.send_with_content_length(
+            HttpStatus::Ok200,
+            &[&Header::new("content-type", "text/plain; version=0.0.4"),
+                &Header::new("cache-control", "no-store")],
+            body.len() as u64).await?;
+        tokio::io::AsyncWriteExt::write_all(http_reader_writer, body.as_bytes())
+            .await
+            .map_err(HttpError::from_io_err)
+    }
+}
@@ -0,0 +1,245 @@
+// Middleware runs code before and after request handlers, so handlers can share
+// cross-cutting behavior like authentication, CORS, and request logging.
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::server::HttpHandler;
+use crate::static_files::relative_path;
+use crate::{HttpError, HttpReaderWriter, HttpStatus};
+
+/// Flow tells a `Chain` whether to keep going after a middleware's `before` hook.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flow {
+    /// Run the next middleware, or the handler.
+    Continue,
+    /// The middleware sent a response.  Skip the rest of the chain and the handler.
+    Done,
+}
+
+/// Middleware wraps request handling with hooks.  Add it to a `Stack`.
+///
+/// `before` runs before the handler.  It can decorate the response with
+/// `HttpReaderWriter::add_response_header`, set the principal, or short-circuit the request:
+/// return `Flow::Done` after sending a response, or return an error to make the server
+/// send the error's status.
+///
+/// `after` runs once the handler or a later middleware finishes, and gets its result.
+/// It can log the request, record the response status, or replace the result.
+/// It runs only if this middleware's `before` returned `Flow::Continue`.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    async fn before(&self, _http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<Flow, HttpError> {
+        Ok(Flow::Continue)
+    }
+
+    async fn after(&self, _http_reader_writer: &mut HttpReaderWriter<'_>, result: Result<(), HttpError>)
+                   -> Result<(), HttpError> {
+        result
+    }
+}
+
+/// Runs `middleware` around `inner`.  Handler wrappers like `Cors` use this.
+pub(crate) async fn around<M: Middleware, H: HttpHandler>(
+    middleware: &M,
+    inner: &H,
+    http_reader_writer: &mut HttpReaderWriter<'_>,
+) -> Result<(), HttpError> {
+    let result = match middleware.before(http_reader_writer).await? {
+        Flow::Continue => inner.handle(http_reader_writer).await,
+        Flow::Done => return Ok(()),
+    };
+    middleware.after(http_reader_writer, result).await
+}
+
+/// Stack is an ordered list of middleware.  The first middleware added is the outermost:
+/// its `before` runs first and its `after` runs last.
+///
+/// Cloning a stack shares its middleware, so state like rate limiter buckets is shared too.
+#[derive(Clone, Default)]
+pub struct Stack {
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+impl Stack {
+    pub fn new() -> Stack {
+        Stack { middleware: Vec::new() }
+    }
+
+    /// Adds `middleware` inside the middleware already in the stack.
+    pub fn with<M: Middleware + 'static>(mut self, middleware: M) -> Stack {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Returns a stack that runs this stack's middleware and then `inner`'s middleware.
+    pub fn then(&self, inner: &Stack) -> Stack {
+        let mut middleware = self.middleware.clone();
+        middleware.extend(inner.middleware.iter().cloned());
+        Stack { middleware }
+    }
+
+    /// Returns a handler that runs the stack around `handler`.
+    pub fn wrap<H: HttpHandler>(self, handler: H) -> Chain<H> {
+        Chain { stack: self, handler }
+    }
+}
+
+/// Chain runs a `Stack` around a handler.  Make one with `Stack::wrap`.
+pub struct Chain<H: HttpHandler> {
+    stack: Stack,
+    handler: H,
+}
+
+#[async_trait]
+impl<H: HttpHandler> HttpHandler for Chain<H> {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        let mut num_continued = 0;
+        let mut short_circuit = None;
+        for middleware in &self.stack.middleware {
+            match middleware.before(http_reader_writer).await {
+                Ok(Flow::Continue) => num_continued += 1,
+                Ok(Flow::Done) => {
+                    short_circuit = Some(Ok(()));
+                    break;
+                }
+                Err(e) => {
+                    short_circuit = Some(Err(e));
+                    break;
+                }
+            }
+        }
+        let mut result = match short_circuit {
+            Some(result) => result,
+            None => self.handler.handle(http_reader_writer).await,
+        };
+        for middleware in self.stack.middleware[..num_continued].iter().rev() {
+            result = middleware.after(http_reader_writer, result).await;
+        }
+        result
+    }
+}
+
+struct NotFound;
+
+#[async_trait]
+impl HttpHandler for NotFound {
+    async fn handle(&self, _http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        Err(HttpError::ProcessingError(HttpStatus::NotFound404))
+    }
+}
+
+/// Returns the path that routes match: the path part of `raw_path`, percent-decoded, without
+/// empty and `.` segments, like `StaticFiles` sees it.  Keeps a trailing slash.
+/// So `//admin/x` and `/%61dmin/x` both become `/admin/x`.
+///
+/// Returns 400 Bad Request if the path has a `..` segment or an encoded `/`, or does not decode.
+fn route_path(raw_path: &str) -> Result<String, HttpError> {
+    let bad_request = || HttpError::ProcessingError(HttpStatus::BadRequest400);
+    let path = raw_path.split('?').next().unwrap_or("");
+    if path == "*" {
+        return Ok(String::from(path));
+    }
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        let decoded = percent_encoding::percent_decode_str(segment).decode_utf8().map_err(|_e| bad_request())?;
+        if decoded.contains('/') {
+            return Err(bad_request());
+        }
+        segments.push(decoded);
+    }
+    let relative = relative_path(&segments.join("/")).ok_or_else(bad_request)?;
+    let mut normalized = String::from("/");
+    normalized.push_str(relative.to_str().ok_or_else(bad_request)?);
+    if normalized.len() > 1 && matches!(segments.last().map(|s| s.as_ref()), Some("") | Some(".")) {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Returns true if `path` matches `pattern`.
+/// A pattern ending in `/` matches paths that start with it.  Other patterns match only themselves.
+fn route_matches(pattern: &str, path: &str) -> bool {
+    if pattern.ends_with('/') {
+        path.starts_with(pattern)
+    } else {
+        path == pattern
+    }
+}
+
+/// Router sends requests to handlers by path.  Each route has its own middleware stack,
+/// which runs inside the router's global stack.
+///
+/// The route with the longest matching pattern gets the request.
+/// Routes match the decoded path without empty and `.` segments, see `route_path`,
+/// so encoding tricks cannot skip a route's stack.  Paths with `..` segments get 400 Bad Request.
+/// Requests that match no route get 404 Not Found, after the global stack runs.
+pub struct Router {
+    global: Stack,
+    routes: Vec<(String, Box<dyn HttpHandler>)>,
+    not_found: Chain<NotFound>,
+}
+
+impl Router {
+    pub fn new(global: Stack) -> Router {
+        Router { global: global.clone(), routes: Vec::new(), not_found: global.wrap(NotFound) }
+    }
+
+    /// Sends requests with paths matching `pattern` to `handler`, through the global stack
+    /// and then `stack`.
+    pub fn route<H: HttpHandler + 'static>(mut self, pattern: &str, stack: Stack, handler: H) -> Router {
+        self.routes.push((String::from(pattern), Box::new(self.global.then(&stack).wrap(handler))));
+        self
+    }
+
+    fn find(&self, path: &str) -> Option<&dyn HttpHandler> {
+        self.routes.iter()
+            .filter(|(pattern, _)| route_matches(pattern, path))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, handler)| handler.as_ref())
+    }
+}
+
+#[async_trait]
+impl HttpHandler for Router {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        let path = route_path(&http_reader_writer.raw_path)?;
+        match self.find(&path) {
+            Some(handler) => handler.handle(http_reader_writer).await,
+            None => self.not_found.handle(http_reader_writer).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_path() {
+        let path = |raw_path: &str| route_path(raw_path).ok();
+        assert_eq!(Some(String::from("/")), path("/"));
+        assert_eq!(Some(String::from("/")), path("//./"));
+        assert_eq!(Some(String::from("/admin/x")), path("//admin/x"));
+        assert_eq!(Some(String::from("/admin/x")), path("/%61dmin/./x?a=/b"));
+        assert_eq!(Some(String::from("/admin/")), path("/admin//"));
+        assert_eq!(Some(String::from("/admin/")), path("/admin/."));
+        assert_eq!(Some(String::from("/admin")), path("/admin"));
+        assert_eq!(Some(String::from("/a b")), path("/a%20b"));
+        assert_eq!(Some(String::from("*")), path("*"));
+        for raw_path in &["/a/../admin/x", "/%2e%2e/admin", "/admin%2fx", "/admin%2Fx", "/%ff", "/a%00b"] {
+            assert_eq!(None, path(raw_path), "{:?}", raw_path);
+        }
+    }
+
+    #[test]
+    fn test_route_matches() {
+        assert!(route_matches("/", "/"));
+        assert!(route_matches("/", "/a/b"));
+        assert!(route_matches("/admin/", "/admin/users"));
+        assert!(!route_matches("/admin/", "/admin"));
+        assert!(route_matches("/metrics", "/metrics"));
+        assert!(!route_matches("/metrics", "/metrics/x"));
+        assert!(!route_matches("/metrics", "/metricsx"));
+    }
+}
@@ -0,0 +1,358 @@
+// Streaming multipart/form-data request bodies.
+// "Returning Values from Forms: multipart/form-data" https://tools.ietf.org/html/rfc7578
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::AsyncRead;
+
+use crate::fixed_buffer::FixedBuf;
+use crate::{HttpCallerError, HttpError, HttpReaderWriter, HttpStatus};
+
+#[derive(Clone, Copy, Debug)]
+pub struct MultipartLimits {
+    /// The largest request body to accept.
+    pub max_total_len: u64,
+    /// The largest part body to accept.
+    pub max_part_len: u64,
+    pub max_parts: usize,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        MultipartLimits { max_total_len: 10 * 1024 * 1024, max_part_len: 10 * 1024 * 1024, max_parts: 100 }
+    }
+}
+
+/// The error inside the `io::Error` that a part returns when it is longer than `max_part_len`.
+#[derive(Debug)]
+pub struct PartTooLarge;
+
+impl std::fmt::Display for PartTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "multipart part too large")
+    }
+}
+
+impl std::error::Error for PartTooLarge {}
+
+/// Converts an error from reading a part into an HttpError.
+/// Returns `PayloadTooLarge413` for parts that are too large, and `ParseError` for malformed bodies.
+pub fn to_http_error(e: io::Error) -> HttpError {
+    if e.get_ref().map(|inner| inner.is::<PartTooLarge>()).unwrap_or(false) {
+        HttpError::ProcessingError(HttpStatus::PayloadTooLarge413)
+    } else if e.kind() == io::ErrorKind::InvalidData {
+        HttpError::ParseError(HttpCallerError::MultipartInvalid)
+    } else {
+        HttpError::IoError(e)
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Returns the `boundary` parameter of a `multipart/form-data` content type.
+fn parse_boundary(content_type: &str) -> Option<&str> {
+    let mut params = content_type.split(';');
+    if !params.next()?.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    let boundary = params
+        .filter_map(|param| {
+            let eq = param.find('=')?;
+            Some((param[..eq].trim(), param[eq + 1..].trim()))
+        })
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))?
+        .1;
+    let boundary = boundary.trim_matches('"');
+    // https://tools.ietf.org/html/rfc2046#section-5.1.1
+    if boundary.is_empty() || boundary.len() > 70 {
+        return None;
+    }
+    Some(boundary)
+}
+
+/// Returns the value of a parameter like `name="file1"` in a `Content-Disposition` header.
+/// Quoted values may contain `;` and `=`, and `\"` for a quote.
+fn disposition_param(disposition: &str, param_name: &str) -> Option<String> {
+    let mut rest = &disposition[disposition.find(';')? + 1..];
+    loop {
+        let name_end = rest.find(&[';', '='][..]).unwrap_or(rest.len());
+        let name = rest[..name_end].trim();
+        rest = &rest[name_end..];
+        if let Some(after_eq) = rest.strip_prefix('=') {
+            let after_eq = after_eq.trim_start();
+            let mut value = String::new();
+            if let Some(quoted) = after_eq.strip_prefix('"') {
+                let mut chars = quoted.char_indices().peekable();
+                let mut value_end = quoted.len();
+                while let Some((index, c)) = chars.next() {
+                    match c {
+                        '"' => {
+                            value_end = index + 1;
+                            break;
+                        }
+                        '\\' if chars.peek().map(|(_, c)| *c) == Some('"') => {
+                            value.push('"');
+                            chars.next();
+                        }
+                        c => value.push(c),
+                    }
+                }
+                rest = &quoted[value_end..];
+                rest = &rest[rest.find(';').unwrap_or(rest.len())..];
+            } else {
+                let value_end = after_eq.find(';').unwrap_or(after_eq.len());
+                value.push_str(after_eq[..value_end].trim());
+                rest = &after_eq[value_end..];
+            }
+            if name.eq_ignore_ascii_case(param_name) {
+                return Some(value);
+            }
+        }
+        rest = rest.strip_prefix(';')?;
+    }
+}
+
+/// MultipartReader reads the parts of a `multipart/form-data` request body one at a time.
+///
+/// It holds a few KB of the body in a buffer.  Part headers must fit in the buffer.
+/// Part bodies stream through it, so parts can be much larger than the buffer.
+pub struct MultipartReader<'r, 'a> {
+    body: &'r mut HttpReaderWriter<'a>,
+    buffer: FixedBuf,
+    // "\r\n--" followed by the boundary.
+    delimiter: Vec<u8>,
+    limits: MultipartLimits,
+    num_parts: usize,
+    part_len: u64,
+    at_delimiter: bool,
+    done: bool,
+}
+
+impl<'r, 'a> MultipartReader<'r, 'a> {
+    /// Checks the request's content type and length.
+    ///
+    /// Returns `ParseError(MultipartInvalid)` if the request is not `multipart/form-data`,
+    /// `LengthRequired411` if the request has no content-length, and `PayloadTooLarge413`
+    /// if the content-length is larger than `limits.max_total_len`.
+    pub fn new(http_reader_writer: &'r mut HttpReaderWriter<'a>, limits: MultipartLimits)
+               -> Result<MultipartReader<'r, 'a>, HttpError> {
+        let boundary = http_reader_writer.header("content-type")
+            .and_then(parse_boundary)
+            .ok_or(HttpError::ParseError(HttpCallerError::MultipartInvalid))?;
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+        if !http_reader_writer.has_body() || http_reader_writer.chunked {
+            return Err(HttpError::ProcessingError(HttpStatus::LengthRequired411));
+        }
+        if http_reader_writer.content_length() > limits.max_total_len {
+            return Err(HttpError::ProcessingError(HttpStatus::PayloadTooLarge413));
+        }
+        let mut buffer = FixedBuf::new();
+        // The first boundary has no CRLF before it.  Text before it is ignored, like part data.
+        buffer.append("\r\n");
+        Ok(MultipartReader {
+            body: http_reader_writer,
+            buffer,
+            delimiter,
+            limits,
+            num_parts: 0,
+            part_len: 0,
+            at_delimiter: false,
+            done: false,
+        })
+    }
+
+    /// Returns the next part, or None after the last part.
+    /// Skips any unread data of the previous part.
+    pub async fn next_part(&mut self) -> Result<Option<Part<'_, 'r, 'a>>, HttpError> {
+        if self.done {
+            return Ok(None);
+        }
+        self.skip_part().await.map_err(to_http_error)?;
+        self.fill(self.delimiter.len() + 2).await.map_err(to_http_error)?;
+        self.buffer.consume(self.delimiter.len());
+        let after_delimiter = &self.buffer.readable()[..2];
+        if after_delimiter == b"--" {
+            self.done = true;
+            // Discard the epilogue, so the connection can carry another request.
+            loop {
+                self.buffer.read_all();
+                let writable = self.buffer.writable().unwrap();
+                if tokio::io::AsyncReadExt::read(self.body, writable).await.map_err(HttpError::from_io_err)? == 0 {
+                    return Ok(None);
+                }
+            }
+        }
+        if after_delimiter != b"\r\n" {
+            return Err(HttpError::ParseError(HttpCallerError::MultipartInvalid));
+        }
+        self.num_parts += 1;
+        if self.num_parts > self.limits.max_parts {
+            return Err(HttpError::ProcessingError(HttpStatus::PayloadTooLarge413));
+        }
+        // The head starts with the CRLF after the boundary, and is empty for a part with no headers.
+        let head = self.buffer.read_delimited(&mut *self.body, b"\r\n\r\n")
+            .await
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::InvalidData
+                | io::ErrorKind::NotFound
+                | io::ErrorKind::UnexpectedEof => HttpError::ParseError(HttpCallerError::MultipartInvalid),
+                _ => HttpError::IoError(e),
+            })?;
+        let head = std::str::from_utf8(head)
+            .map_err(|_| HttpError::ParseError(HttpCallerError::MultipartInvalid))?;
+        let mut part_headers = Vec::new();
+        for line in head.split("\r\n").filter(|line| !line.is_empty()) {
+            let colon = line.find(':').ok_or(HttpError::ParseError(HttpCallerError::MultipartInvalid))?;
+            part_headers.push((String::from(line[..colon].trim()), String::from(line[colon + 1..].trim())));
+        }
+        self.part_len = 0;
+        self.at_delimiter = false;
+        Ok(Some(Part { reader: self, headers: part_headers }))
+    }
+
+    /// Reads until the buffer holds `n` bytes.
+    async fn fill(&mut self, n: usize) -> io::Result<()> {
+        while self.buffer.readable().len() < n {
+            self.buffer.shift();
+            let writable = self.buffer.writable().ok_or_else(|| invalid("end of buffer full"))?;
+            let num_bytes_read = tokio::io::AsyncReadExt::read(self.body, writable).await?;
+            if num_bytes_read == 0 {
+                return Err(invalid("multipart body ended before closing boundary"));
+            }
+            self.buffer.wrote(num_bytes_read);
+        }
+        Ok(())
+    }
+
+    async fn skip_part(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 1024];
+        // Skipped data does not count against the part limit.
+        while futures::future::poll_fn(|cx| self.poll_part(cx, &mut buf, u64::MAX)).await? > 0 {}
+        Ok(())
+    }
+
+    fn take(&mut self, n: usize, buf: &mut [u8], max_len: u64) -> io::Result<usize> {
+        let n = std::cmp::min(n, buf.len());
+        if self.part_len + n as u64 > max_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, PartTooLarge));
+        }
+        buf[..n].copy_from_slice(&self.buffer.readable()[..n]);
+        self.buffer.consume(n);
+        self.part_len += n as u64;
+        Ok(n)
+    }
+
+    /// Reads part data into `buf`.  Returns 0 at the delimiter after the part.
+    fn poll_part(&mut self, cx: &mut Context<'_>, buf: &mut [u8], max_len: u64) -> Poll<io::Result<usize>> {
+        if self.at_delimiter || buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            let readable = self.buffer.readable();
+            let delimiter_index = readable.windows(self.delimiter.len())
+                .position(|window| window == &self.delimiter[..]);
+            if let Some(index) = delimiter_index {
+                if index == 0 {
+                    self.at_delimiter = true;
+                    return Poll::Ready(Ok(0));
+                }
+                return Poll::Ready(self.take(index, buf, max_len));
+            }
+            // The end of the buffer may hold the start of the delimiter.
+            let safe_len = readable.len().saturating_sub(self.delimiter.len() - 1);
+            if safe_len > 0 {
+                return Poll::Ready(self.take(safe_len, buf, max_len));
+            }
+            self.buffer.shift();
+            let writable = match self.buffer.writable() {
+                Some(writable) => writable,
+                None => return Poll::Ready(Err(invalid("end of buffer full"))),
+            };
+            match Pin::new(&mut *self.body).poll_read(cx, writable) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(invalid("multipart body ended before closing boundary")));
+                }
+                Poll::Ready(Ok(num_bytes_read)) => self.buffer.wrote(num_bytes_read),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Part is one part of a multipart body.  Read its data with `tokio::io::AsyncReadExt`.
+///
+/// Reading returns an error when the data is longer than `max_part_len`.
+/// Convert errors with `multipart::to_http_error`.
+pub struct Part<'m, 'r, 'a> {
+    reader: &'m mut MultipartReader<'r, 'a>,
+    headers: Vec<(String, String)>,
+}
+
+impl<'m, 'r, 'a> Part<'m, 'r, 'a> {
+    /// Returns the value of the first part header named `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the form field name from the `Content-Disposition` header.
+    pub fn name(&self) -> Option<String> {
+        disposition_param(self.header("content-disposition")?, "name")
+    }
+
+    /// Returns the uploaded file's name from the `Content-Disposition` header.
+    /// Browsers send only the name, without the directory.  Do not trust it as a path.
+    pub fn filename(&self) -> Option<String> {
+        disposition_param(self.header("content-disposition")?, "filename")
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.header("content-type")
+    }
+
+    /// Reads the whole part into a String.
+    pub async fn read_to_string(&mut self) -> Result<String, HttpError> {
+        let mut value = String::new();
+        tokio::io::AsyncReadExt::read_to_string(self, &mut value).await.map_err(to_http_error)?;
+        Ok(value)
+    }
+}
+
+impl<'m, 'r, 'a> AsyncRead for Part<'m, 'r, 'a> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let reader = &mut self.get_mut().reader;
+        let max_len = reader.limits.max_part_len;
+        reader.poll_part(cx, buf, max_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_headers() {
+        assert_eq!(Some("abc"), parse_boundary("multipart/form-data; boundary=abc"));
+        assert_eq!(Some("a b"), parse_boundary("Multipart/Form-Data;charset=utf-8; BOUNDARY=\"a b\""));
+        assert_eq!(None, parse_boundary("multipart/mixed; boundary=abc"));
+        assert_eq!(None, parse_boundary("multipart/form-data"));
+        assert_eq!(None, parse_boundary("multipart/form-data; boundary="));
+        let disposition = "form-data; name=\"file1\"; filename=\"a \\\"b\\\".txt\"";
+        assert_eq!(Some(String::from("file1")), disposition_param(disposition, "name"));
+        assert_eq!(Some(String::from("a \"b\".txt")), disposition_param(disposition, "filename"));
+        assert_eq!(Some(String::from("x")), disposition_param("form-data; name=x", "name"));
+        assert_eq!(None, disposition_param("form-data; name=x", "filename"));
+        let disposition = "form-data; name=\"a=b; name=c\"; filename=\"a;b.txt\" ; x";
+        assert_eq!(Some(String::from("a=b; name=c")), disposition_param(disposition, "name"));
+        assert_eq!(Some(String::from("a;b.txt")), disposition_param(disposition, "filename"));
+        assert_eq!(None, disposition_param(disposition, "x"));
+        assert_eq!(Some(String::from("a\\b")), disposition_param("form-data; filename=\"a\\b\"", "filename"));
+        assert_eq!(None, disposition_param("form-data", "name"));
+    }
+}
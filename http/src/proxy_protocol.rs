@@ -0,0 +1,200 @@
+// Reads the PROXY protocol header that TCP load balancers send before the client's bytes.
+// https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Version 1 headers are at most 107 bytes, including CRLF.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("PROXY protocol: {}", message))
+}
+
+/// Parses a version 1 header line without its CRLF, like `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443`.
+fn parse_v1(line: &[u8]) -> std::io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.get(1) {
+        // The balancer does not know the client's address, as with health checks.
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => {}
+        _ => return Err(invalid("bad header")),
+    }
+    // Check the destination fields too, to reject garbled headers.
+    let src_ip: IpAddr = if fields[1] == "TCP4" {
+        fields[3].parse::<Ipv4Addr>().map_err(|_| invalid("bad destination address"))?;
+        fields[2].parse::<Ipv4Addr>().map_err(|_| invalid("bad source address"))?.into()
+    } else {
+        fields[3].parse::<Ipv6Addr>().map_err(|_| invalid("bad destination address"))?;
+        fields[2].parse::<Ipv6Addr>().map_err(|_| invalid("bad source address"))?.into()
+    };
+    fields[5].parse::<u16>().map_err(|_| invalid("bad destination port"))?;
+    let src_port: u16 = fields[4].parse().map_err(|_| invalid("bad source port"))?;
+    Ok(Some(SocketAddr::new(src_ip, src_port)))
+}
+
+/// Parses the address block of a version 2 header.
+fn parse_v2_addresses(command: u8, family: u8, addresses: &[u8]) -> std::io::Result<Option<SocketAddr>> {
+    match command {
+        // The balancer made the connection itself, as with health checks.
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("bad command")),
+    }
+    match family {
+        // TCP over IPv4
+        0x11 if addresses.len() >= 12 => {
+            let mut ip = [0u8; 4];
+            ip.copy_from_slice(&addresses[..4]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::from(ip), port)))
+        }
+        // TCP over IPv6
+        0x21 if addresses.len() >= 36 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::from(ip), port)))
+        }
+        0x11 | 0x21 => Err(invalid("address block too short")),
+        // Unspecified, UDP, or Unix socket addresses.
+        _ => Ok(None),
+    }
+}
+
+/// Reads a PROXY protocol version 1 or 2 header from `reader`.
+/// Reads no bytes after the header.
+///
+/// Returns the client's address, or `None` when the header has no client address,
+/// like on the balancer's health check connections.
+/// Returns `InvalidData` if the connection does not start with a valid header.
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Option<SocketAddr>> {
+    // Both versions are longer than 8 bytes.
+    let mut header = [0u8; V1_MAX_LEN];
+    reader.read_exact(&mut header[..8]).await?;
+    if header.starts_with(V1_PREFIX) {
+        let mut len = 8;
+        while !header[..len].ends_with(b"\r\n") {
+            if len == V1_MAX_LEN {
+                return Err(invalid("header too long"));
+            }
+            header[len] = reader.read_u8().await?;
+            len += 1;
+        }
+        return parse_v1(&header[..len - 2]);
+    }
+    if header[..8] != V2_SIGNATURE[..8] {
+        return Err(invalid("missing header"));
+    }
+    reader.read_exact(&mut header[8..V2_HEADER_LEN]).await?;
+    if header[..12] != *V2_SIGNATURE {
+        return Err(invalid("missing header"));
+    }
+    let version_command = header[12];
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    let family = header[13];
+    let len = u16::from_be_bytes([header[14], header[15]]) as u64;
+    // Read the addresses and skip the TLVs after them.
+    let mut addresses = [0u8; 36];
+    let addresses_len = std::cmp::min(len, addresses.len() as u64) as usize;
+    reader.read_exact(&mut addresses[..addresses_len]).await?;
+    let skipped = tokio::io::copy(&mut reader.take(len - addresses_len as u64), &mut tokio::io::sink()).await?;
+    if skipped != len - addresses_len as u64 {
+        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "PROXY protocol: header cut off"));
+    }
+    parse_v2_addresses(version_command & 0x0F, family, &addresses[..addresses_len])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(input: &[u8]) -> (std::io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut reader = input;
+        let result = read_header(&mut reader).await;
+        (result, reader.to_vec())
+    }
+
+    fn v2(version_command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(version_command);
+        header.push(family);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(body);
+        header.extend_from_slice(b"GET");
+        header
+    }
+
+    #[tokio::test]
+    async fn test_v1() {
+        let (result, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET").await;
+        assert_eq!(Some(SocketAddr::from(([192, 0, 2, 1], 56324))), result.unwrap());
+        assert_eq!(b"GET", &rest[..]);
+        let (result, rest) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\nGET").await;
+        assert_eq!(Some("[2001:db8::1]:56324".parse().unwrap()), result.unwrap());
+        assert_eq!(b"GET", &rest[..]);
+        let (result, rest) = read(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\nGET").await;
+        assert_eq!(None, result.unwrap());
+        assert_eq!(b"GET", &rest[..]);
+        for input in &[
+            &b"GET / HTTP/1.1\r\n\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+            b"PROXY TCP4 2001:db8::1 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP6 192.0.2.1 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+        ] {
+            let (result, _rest) = read(input).await;
+            assert_eq!(std::io::ErrorKind::InvalidData, result.unwrap_err().kind(), "{:?}", input);
+        }
+        let too_long = format!("PROXY TCP6 {}\r\n", "1".repeat(200));
+        assert_eq!(std::io::ErrorKind::InvalidData, read(too_long.as_bytes()).await.0.unwrap_err().kind());
+        assert_eq!(std::io::ErrorKind::UnexpectedEof,
+                   read(b"PROXY TCP4 192.0.2.1").await.0.unwrap_err().kind());
+    }
+
+    #[tokio::test]
+    async fn test_v2() {
+        let ipv4 = [192, 0, 2, 1, 198, 51, 100, 1, 0xDC, 0x04, 0x01, 0xBB];
+        let (result, rest) = read(&v2(0x21, 0x11, &ipv4)).await;
+        assert_eq!(Some(SocketAddr::from(([192, 0, 2, 1], 56324))), result.unwrap());
+        assert_eq!(b"GET", &rest[..]);
+        // TLVs after the addresses get skipped.
+        let mut with_tlvs = ipv4.to_vec();
+        with_tlvs.extend_from_slice(&[0x04, 0x00, 0x03, b'a', b'b', b'c']);
+        let (result, rest) = read(&v2(0x21, 0x11, &with_tlvs)).await;
+        assert_eq!(Some(SocketAddr::from(([192, 0, 2, 1], 56324))), result.unwrap());
+        assert_eq!(b"GET", &rest[..]);
+
+        let mut ipv6 = vec![0u8; 36];
+        ipv6[0] = 0x20;
+        ipv6[1] = 0x01;
+        ipv6[15] = 1;
+        ipv6[32] = 0x01;
+        ipv6[33] = 0x02;
+        let (result, rest) = read(&v2(0x21, 0x21, &ipv6)).await;
+        assert_eq!(Some("[2001::1]:258".parse().unwrap()), result.unwrap());
+        assert_eq!(b"GET", &rest[..]);
+
+        // LOCAL connections and unspecified addresses have no client address.
+        let (result, rest) = read(&v2(0x20, 0x00, &[])).await;
+        assert_eq!(None, result.unwrap());
+        assert_eq!(b"GET", &rest[..]);
+        let (result, _rest) = read(&v2(0x21, 0x00, &[1, 2, 3])).await;
+        assert_eq!(None, result.unwrap());
+
+        for input in &[v2(0x11, 0x11, &ipv4), v2(0x22, 0x11, &ipv4), v2(0x21, 0x11, &ipv4[..8])] {
+            let (result, _rest) = read(input).await;
+            assert_eq!(std::io::ErrorKind::InvalidData, result.unwrap_err().kind(), "{:?}", input);
+        }
+        let mut cut_off = v2(0x21, 0x11, &with_tlvs);
+        cut_off.truncate(cut_off.len() - 5);
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, read(&cut_off).await.0.unwrap_err().kind());
+    }
+}
@@ -0,0 +1,398 @@
+// Incremental parser for HTTP/1.0 and HTTP/1.1 request heads.
+// "HTTP/1.1 Message Syntax and Routing" https://tools.ietf.org/html/rfc7230
+use crate::{HttpCallerError, HttpError, HttpVersion};
+
+/// The version and line ending.  The parser accepts `0` or `1` for the `?`.
+const VERSION: &[u8] = b"HTTP/1.?\r\n";
+const MINOR_VERSION_INDEX: usize = 7;
+
+fn request_line_invalid() -> HttpError {
+    HttpError::ParseError(HttpCallerError::RequestLineInvalid)
+}
+
+fn header_line_invalid() -> HttpError {
+    HttpError::ParseError(HttpCallerError::HeaderLineInvalid)
+}
+
+/// Returns true for bytes allowed in tokens, like methods and header names.
+/// https://tools.ietf.org/html/rfc7230#section-3.2.6
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Returns true for bytes allowed in header values, other than whitespace.
+/// Values may contain `obs-text` bytes, but `RequestParser` accepts them only as UTF-8.
+fn is_field_vchar(b: u8) -> bool {
+    (0x21..=0x7E).contains(&b) || b >= 0x80
+}
+
+/// Returns the authority and the rest of an absolute-form target, like `http://host:8080/path?q`.
+/// Returns None if `target` is not an `http` or `https` URI with an authority.
+/// https://tools.ietf.org/html/rfc7230#section-5.3.2
+fn split_absolute_form(target: &[u8]) -> Option<(&[u8], &[u8])> {
+    let scheme_len = target.iter().position(|b| *b == b':')?;
+    let scheme = &target[..scheme_len];
+    if !scheme.eq_ignore_ascii_case(b"http") && !scheme.eq_ignore_ascii_case(b"https") {
+        return None;
+    }
+    let rest = target[scheme_len..].strip_prefix(b"://")?;
+    let authority_len = rest.iter().position(|b| *b == b'/' || *b == b'?').unwrap_or(rest.len());
+    let authority = &rest[..authority_len];
+    // Recipients should reject userinfo in http URIs.  https://tools.ietf.org/html/rfc7230#section-2.7.1
+    if authority.is_empty() || authority.contains(&b'@') {
+        return None;
+    }
+    Some((authority, &rest[authority_len..]))
+}
+
+/// Checks that `target` is in origin-form `/path?query`, absolute-form `http://host/path?query`,
+/// or asterisk-form `*`, which only `OPTIONS` requests may use.
+/// https://tools.ietf.org/html/rfc7230#section-5.3
+fn check_target(method: &[u8], target: &[u8]) -> Result<(), HttpError> {
+    let valid = match target.first() {
+        Some(b'/') => true,
+        Some(b'*') => target == b"*" && method == b"OPTIONS",
+        _ => split_absolute_form(target).is_some(),
+    };
+    if valid { Ok(()) } else { Err(request_line_invalid()) }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    MethodStart,
+    Method,
+    TargetStart,
+    Target,
+    /// The number of bytes of `VERSION` matched.
+    Version(usize),
+    LineStart,
+    Name,
+    ValueStart,
+    Value,
+    ValueLf,
+    EndLf,
+    Done,
+}
+
+/// RequestParser parses a request head with a byte-level state machine.
+///
+/// It resumes where it stopped, so callers can pass the bytes received so far,
+/// read more, and pass them all again without the parser rescanning them.
+/// It does not allocate.
+///
+/// Compared to RFC 7230, it does not accept:
+/// - empty lines before the request line
+/// - bare LF line endings
+/// - whitespace between a header name and its colon
+/// - obsolete line folding, continuation lines that start with whitespace
+/// - header values that are not UTF-8
+#[derive(Clone, Debug)]
+pub struct RequestParser {
+    state: State,
+    pos: usize,
+    method_end: usize,
+    target_start: usize,
+    target_end: usize,
+    version: HttpVersion,
+    headers_start: usize,
+    line_start: usize,
+    line_non_ascii: bool,
+}
+
+impl Default for RequestParser {
+    fn default() -> Self {
+        RequestParser::new()
+    }
+}
+
+impl RequestParser {
+    pub fn new() -> RequestParser {
+        RequestParser {
+            state: State::MethodStart,
+            pos: 0,
+            method_end: 0,
+            target_start: 0,
+            target_end: 0,
+            version: HttpVersion::Http11,
+            headers_start: 0,
+            line_start: 0,
+            line_non_ascii: false,
+        }
+    }
+
+    /// Parses more of `input`, which must start with the bytes passed to earlier calls.
+    ///
+    /// Returns the length of the request head, including the final empty line,
+    /// once `input` contains the whole head.  Returns `None` when it needs more bytes.
+    pub fn parse(&mut self, input: &[u8]) -> Result<Option<usize>, HttpError> {
+        while self.pos < input.len() {
+            let b = input[self.pos];
+            self.state = match self.state {
+                State::MethodStart if b.is_ascii_uppercase() => State::Method,
+                State::MethodStart | State::Method if b == b' ' || b == b'\r' || b == b'\n' => {
+                    if self.state == State::MethodStart || b != b' ' {
+                        return Err(request_line_invalid());
+                    }
+                    self.method_end = self.pos;
+                    State::TargetStart
+                }
+                State::Method if b.is_ascii_uppercase() || b.is_ascii_digit() => State::Method,
+                State::MethodStart | State::Method =>
+                    return Err(HttpError::ParseError(HttpCallerError::MethodInvalid)),
+                State::TargetStart if b == b'/' || b == b'*' || b.is_ascii_alphabetic() => {
+                    self.target_start = self.pos;
+                    State::Target
+                }
+                State::TargetStart => return Err(request_line_invalid()),
+                State::Target if b == b' ' => {
+                    self.target_end = self.pos;
+                    check_target(&input[..self.method_end], &input[self.target_start..self.target_end])?;
+                    State::Version(0)
+                }
+                State::Target if (0x21..=0x7E).contains(&b) => State::Target,
+                State::Target => return Err(request_line_invalid()),
+                State::Version(MINOR_VERSION_INDEX) if b == b'0' || b == b'1' => {
+                    self.version = if b == b'0' { HttpVersion::Http10 } else { HttpVersion::Http11 };
+                    State::Version(MINOR_VERSION_INDEX + 1)
+                }
+                State::Version(n) if n != MINOR_VERSION_INDEX && b == VERSION[n] => {
+                    if n + 1 < VERSION.len() {
+                        State::Version(n + 1)
+                    } else {
+                        self.headers_start = self.pos + 1;
+                        self.line_start = self.pos + 1;
+                        State::LineStart
+                    }
+                }
+                State::Version(_) => return Err(request_line_invalid()),
+                State::LineStart if b == b'\r' => State::EndLf,
+                State::LineStart if is_tchar(b) => {
+                    self.line_start = self.pos;
+                    self.line_non_ascii = false;
+                    State::Name
+                }
+                State::LineStart => return Err(header_line_invalid()),
+                State::Name if b == b':' => State::ValueStart,
+                State::Name if is_tchar(b) => State::Name,
+                State::Name => return Err(header_line_invalid()),
+                State::ValueStart | State::Value if b == b' ' || b == b'\t' => self.state,
+                State::ValueStart | State::Value if b == b'\r' => State::ValueLf,
+                State::ValueStart | State::Value if is_field_vchar(b) => {
+                    self.line_non_ascii |= b >= 0x80;
+                    State::Value
+                }
+                State::ValueStart | State::Value => return Err(header_line_invalid()),
+                State::ValueLf if b == b'\n' => {
+                    if self.line_non_ascii && std::str::from_utf8(&input[self.line_start..self.pos]).is_err() {
+                        return Err(header_line_invalid());
+                    }
+                    State::LineStart
+                }
+                State::ValueLf => return Err(header_line_invalid()),
+                State::EndLf if b == b'\n' => {
+                    self.pos += 1;
+                    self.state = State::Done;
+                    return Ok(Some(self.pos));
+                }
+                State::EndLf => return Err(header_line_invalid()),
+                State::Done => return Ok(Some(self.pos)),
+            };
+            self.pos += 1;
+        }
+        if self.state == State::Done { Ok(Some(self.pos)) } else { Ok(None) }
+    }
+
+    /// Returns the error to send when the head does not fit in the buffer.
+    pub fn too_long_error(&self) -> HttpError {
+        match self.state {
+            State::MethodStart | State::Method | State::TargetStart | State::Target | State::Version(_) =>
+                HttpError::ParseError(HttpCallerError::PathTooLong),
+            _ => HttpError::ParseError(HttpCallerError::HeadersTooLong),
+        }
+    }
+
+    /// Returns the parsed head, borrowing from `input`.
+    ///
+    /// Panics if `parse` has not returned the head's length.
+    pub fn head<'b>(&self, input: &'b [u8]) -> RequestHead<'b> {
+        assert_eq!(State::Done, self.state, "RequestParser::head called before parsing completed");
+        // The parser checked that these are ASCII and UTF-8, so unwrap never panics.
+        let target = &input[self.target_start..self.target_end];
+        let (authority, path) = match target.first() {
+            Some(b'/') | Some(b'*') => (None, target),
+            _ => {
+                let (authority, path) = split_absolute_form(target).unwrap();
+                (Some(std::str::from_utf8(authority).unwrap()), path)
+            }
+        };
+        RequestHead {
+            method: std::str::from_utf8(&input[..self.method_end]).unwrap(),
+            target: std::str::from_utf8(target).unwrap(),
+            authority,
+            path: std::str::from_utf8(path).unwrap(),
+            version: self.version,
+            header_lines: std::str::from_utf8(&input[self.headers_start..self.pos - 2]).unwrap(),
+        }
+    }
+}
+
+/// RequestHead is a parsed request head.  Make one with `RequestParser`.
+#[derive(Debug, PartialEq)]
+pub struct RequestHead<'b> {
+    pub method: &'b str,
+    /// The request target, as the client sent it.
+    pub target: &'b str,
+    /// The host and optional port from an absolute-form target like `http://host:8080/path`.
+    pub authority: Option<&'b str>,
+    /// The path and query.  It is `*` for asterisk-form targets.
+    /// Absolute-form targets may have an empty path, like `http://host` or `http://host?q`.
+    pub path: &'b str,
+    pub version: HttpVersion,
+    /// The header lines, each ending with CRLF.
+    pub header_lines: &'b str,
+}
+
+impl<'b> RequestHead<'b> {
+    /// Returns the header names and values, in order.  Values have no leading or trailing whitespace.
+    pub fn headers(&self) -> impl Iterator<Item = (&'b str, &'b str)> {
+        self.header_lines.split_terminator("\r\n").map(|line| {
+            let colon = line.find(':').unwrap();
+            (&line[..colon], line[colon + 1..].trim_matches(&[' ', '\t'][..]))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &[u8]) -> Result<Option<usize>, HttpError> {
+        RequestParser::new().parse(input)
+    }
+
+    fn error(input: &[u8]) -> String {
+        format!("{:?}", parse(input).unwrap_err())
+    }
+
+    #[test]
+    fn test_parse() {
+        let input = b"GET /a?b=c HTTP/1.1\r\nHost: example.com\r\nx-empty:\r\nX-Pad: \t v  1 \t\r\n\r\nbody";
+        let mut parser = RequestParser::new();
+        assert_eq!(Some(input.len() - 4), parser.parse(input).unwrap());
+        let head = parser.head(input);
+        assert_eq!("GET", head.method);
+        assert_eq!("/a?b=c", head.target);
+        assert_eq!("Host: example.com\r\nx-empty:\r\nX-Pad: \t v  1 \t\r\n", head.header_lines);
+        assert_eq!(vec![("Host", "example.com"), ("x-empty", ""), ("X-Pad", "v  1")],
+                   head.headers().collect::<Vec<_>>());
+
+        let input = b"CUSTOM123 / HTTP/1.1\r\n\r\n";
+        let mut parser = RequestParser::new();
+        assert_eq!(Some(input.len()), parser.parse(input).unwrap());
+        let head = parser.head(input);
+        assert_eq!(("CUSTOM123", "/", ""), (head.method, head.target, head.header_lines));
+        assert_eq!(0, head.headers().count());
+
+        let input = "GET / HTTP/1.1\r\na: caf\u{e9}\r\n\r\n".as_bytes();
+        let mut parser = RequestParser::new();
+        parser.parse(input).unwrap().unwrap();
+        assert_eq!(vec![("a", "caf\u{e9}")], parser.head(input).headers().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_parse_target_forms() {
+        for (input, expected) in &[
+            (&b"GET /a?b HTTP/1.1\r\n\r\n"[..], ("/a?b", None, "/a?b", HttpVersion::Http11)),
+            (b"GET /a HTTP/1.0\r\n\r\n", ("/a", None, "/a", HttpVersion::Http10)),
+            (b"OPTIONS * HTTP/1.1\r\n\r\n", ("*", None, "*", HttpVersion::Http11)),
+            (b"GET http://example.com:8080/a?b HTTP/1.1\r\n\r\n",
+             ("http://example.com:8080/a?b", Some("example.com:8080"), "/a?b", HttpVersion::Http11)),
+            (b"GET HTTPS://example.com HTTP/1.0\r\n\r\n",
+             ("HTTPS://example.com", Some("example.com"), "", HttpVersion::Http10)),
+            (b"GET http://example.com?b HTTP/1.1\r\n\r\n",
+             ("http://example.com?b", Some("example.com"), "?b", HttpVersion::Http11)),
+        ] {
+            let mut parser = RequestParser::new();
+            parser.parse(input).unwrap().unwrap();
+            let head = parser.head(input);
+            assert_eq!(*expected, (head.target, head.authority, head.path, head.version), "{:?}", escape(input));
+        }
+    }
+
+    #[test]
+    fn test_parse_resumes() {
+        let input = b"POST /upload HTTP/1.1\r\ncontent-length: 5\r\nexpect: 100-continue\r\n\r\n";
+        for split in 0..input.len() {
+            let mut parser = RequestParser::new();
+            assert_eq!(None, parser.parse(&input[..split]).unwrap(), "split={}", split);
+            assert_eq!(Some(input.len()), parser.parse(input).unwrap(), "split={}", split);
+            assert_eq!(Some(input.len()), parser.parse(input).unwrap(), "split={}", split);
+            let head = parser.head(input);
+            assert_eq!(("POST", "/upload"), (head.method, head.target));
+        }
+        let mut parser = RequestParser::new();
+        for end in 1..input.len() {
+            assert_eq!(None, parser.parse(&input[..end]).unwrap());
+        }
+        assert_eq!(Some(input.len()), parser.parse(input).unwrap());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for input in &[
+            &b"\r\nGET / HTTP/1.1\r\n\r\n"[..],
+            b" GET / HTTP/1.1\r\n\r\n",
+            b"GET\r\n\r\n",
+            b"GET  / HTTP/1.1\r\n\r\n",
+            b"GET a HTTP/1.1\r\n\r\n",
+            b"GET ftp://example.com/ HTTP/1.1\r\n\r\n",
+            b"GET http:/example.com/ HTTP/1.1\r\n\r\n",
+            b"GET http:///a HTTP/1.1\r\n\r\n",
+            b"GET http://user@example.com/ HTTP/1.1\r\n\r\n",
+            b"GET example.com:80 HTTP/1.1\r\n\r\n",
+            b"GET * HTTP/1.1\r\n\r\n",
+            b"OPTIONS */ HTTP/1.1\r\n\r\n",
+            b"GET /a b HTTP/1.1\r\n\r\n",
+            b"GET /\x7F HTTP/1.1\r\n\r\n",
+            b"GET /\xC3\xA9 HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.2\r\n\r\n",
+            b"GET / HTTP/2.0\r\n\r\n",
+            b"GET / http/1.1\r\n\r\n",
+            b"GET / HTTP/1.1 \r\n\r\n",
+            b"GET / HTTP/1.1\n\r\n",
+        ] {
+            assert_eq!("ParseError(RequestLineInvalid)", error(input), "{:?}", escape(input));
+        }
+        for input in &[&b"get / HTTP/1.1\r\n\r\n"[..], b"G-T / HTTP/1.1\r\n\r\n", b"1GET / HTTP/1.1\r\n\r\n"] {
+            assert_eq!("ParseError(MethodInvalid)", error(input), "{:?}", escape(input));
+        }
+        for input in &[
+            &b"GET / HTTP/1.1\r\n: v\r\n\r\n"[..],
+            b"GET / HTTP/1.1\r\na : v\r\n\r\n",
+            b"GET / HTTP/1.1\r\na v\r\n\r\n",
+            b"GET / HTTP/1.1\r\na(b): v\r\n\r\n",
+            b"GET / HTTP/1.1\r\na: v\r\n b\r\n\r\n",
+            b"GET / HTTP/1.1\r\na: v\nb: v\r\n\r\n",
+            b"GET / HTTP/1.1\r\na: v\rb: v\r\n\r\n",
+            b"GET / HTTP/1.1\r\na: v\x00\r\n\r\n",
+            b"GET / HTTP/1.1\r\na: \x7F\r\n\r\n",
+            b"GET / HTTP/1.1\r\na: \xFF\r\n\r\n",
+            b"GET / HTTP/1.1\r\na: v\r\n\rx",
+        ] {
+            assert_eq!("ParseError(HeaderLineInvalid)", error(input), "{:?}", escape(input));
+        }
+    }
+
+    #[test]
+    fn test_too_long_error() {
+        let mut parser = RequestParser::new();
+        parser.parse(b"GET /aaaa").unwrap();
+        assert_eq!("ParseError(PathTooLong)", format!("{:?}", parser.too_long_error()));
+        parser.parse(b"GET /aaaa HTTP/1.1\r\na: bbbb").unwrap();
+        assert_eq!("ParseError(HeadersTooLong)", format!("{:?}", parser.too_long_error()));
+    }
+
+    fn escape(input: &[u8]) -> String {
+        crate::escape_ascii(input)
+    }
+}
@@ -0,0 +1,551 @@
+// A handler that forwards requests to an upstream HTTP server and streams back its responses.
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::warn;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, UnixStream};
+
+use crate::server::HttpHandler;
+use crate::transport::Connection;
+use crate::{Header, HttpError, HttpMethod, HttpReaderWriter, HttpStatus};
+
+const BODY_BUFFER_LEN: usize = 64 * 1024;
+const MAX_LINE_LEN: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+
+/// Headers that describe one connection, not the message.  Proxies must not forward them.
+/// https://tools.ietf.org/html/rfc7230#section-6.1
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Upstream is the address of the server that a `ReverseProxy` forwards requests to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Upstream {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Upstream::Tcp(socket_addr) => write!(f, "{}", socket_addr),
+            Upstream::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Why forwarding a request failed, before the response head went to the client.
+enum UpstreamError {
+    /// Responds with 504 Gateway Timeout.
+    Timeout(&'static str),
+    /// Responds with 502 Bad Gateway.
+    Failed(String),
+}
+
+impl UpstreamError {
+    fn io(context: &str, e: std::io::Error) -> UpstreamError {
+        UpstreamError::Failed(format!("{}: {}", context, e))
+    }
+
+    fn invalid(context: &str) -> UpstreamError {
+        UpstreamError::Failed(String::from(context))
+    }
+}
+
+/// How the upstream server's response body ends.
+/// https://tools.ietf.org/html/rfc7230#section-3.3.3
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BodyFraming {
+    None,
+    ContentLength(u64),
+    Chunked,
+    UntilClose,
+}
+
+struct ResponseHead {
+    status: HttpStatus,
+    headers: Vec<(String, String)>,
+    framing: BodyFraming,
+}
+
+/// Returns true for hop-by-hop headers, including headers listed in the `Connection` headers
+/// `connection_values`.
+fn is_hop_by_hop<'v>(name: &str, mut connection_values: impl Iterator<Item = &'v str>) -> bool {
+    HOP_BY_HOP_HEADERS.iter().any(|hop| name.eq_ignore_ascii_case(hop))
+        || connection_values.any(|value|
+        value.split(',').any(|token| token.trim().eq_ignore_ascii_case(name)))
+}
+
+/// Parses a status line like `HTTP/1.1 200 OK`.  Returns the code, which is in 100..=599.
+fn parse_status_line(line: &str) -> Option<u16> {
+    let rest = line.strip_prefix("HTTP/1.")?;
+    let mut parts = rest.splitn(3, ' ');
+    match parts.next()? {
+        "0" | "1" => {}
+        _ => return None,
+    }
+    let code = parts.next()?;
+    if code.len() != 3 || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    code.parse().ok().filter(|code| (100..=599).contains(code))
+}
+
+/// Returns the body framing of a response with `code` and `headers` to a request with `method`.
+fn response_framing(method: &HttpMethod, code: u16, headers: &[(String, String)])
+                    -> Result<BodyFraming, UpstreamError> {
+    if *method == HttpMethod::HEAD || code == 204 || code == 304 {
+        return Ok(BodyFraming::None);
+    }
+    let mut transfer_codings = headers.iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("transfer-encoding"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(str::trim)
+        .peekable();
+    if transfer_codings.peek().is_some() {
+        return if transfer_codings.last().unwrap_or("").eq_ignore_ascii_case("chunked") {
+            Ok(BodyFraming::Chunked)
+        } else {
+            Ok(BodyFraming::UntilClose)
+        };
+    }
+    let mut content_length = None;
+    for (_, value) in headers.iter().filter(|(name, _)| name.eq_ignore_ascii_case("content-length")) {
+        let len: u64 = value.parse().map_err(|_| UpstreamError::invalid("bad content-length"))?;
+        if matches!(content_length.replace(len), Some(prev) if prev != len) {
+            return Err(UpstreamError::invalid("conflicting content-length headers"));
+        }
+    }
+    Ok(content_length.map_or(BodyFraming::UntilClose, BodyFraming::ContentLength))
+}
+
+/// Reads a line ending in LF and returns it without the CRLF or LF.
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> std::io::Result<String> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line).await?;
+    if !line.ends_with(b"\n") {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "line too long or cut off"));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "line is not UTF-8"))
+}
+
+/// Reads the size line of a chunk, its data, and its CRLF.  Returns `None` after the last chunk
+/// and its trailers.  https://tools.ietf.org/html/rfc7230#section-4.1
+async fn read_chunk<R: AsyncBufRead + Unpin>(reader: &mut R, buf: &mut Vec<u8>) -> std::io::Result<Option<()>> {
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "bad chunk");
+    let size_line = read_line(reader).await?;
+    let size_hex = size_line.split(';').next().unwrap_or("").trim();
+    let size = usize::from_str_radix(size_hex, 16).map_err(|_| invalid())?;
+    if size == 0 {
+        // Skip the trailers.
+        while !read_line(reader).await?.is_empty() {}
+        return Ok(None);
+    }
+    if size > BODY_BUFFER_LEN * 16 {
+        return Err(invalid());
+    }
+    buf.resize(size, 0);
+    reader.read_exact(buf).await?;
+    if !read_line(reader).await?.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Some(()))
+}
+
+/// ReverseProxy forwards requests to an upstream server and streams its responses to clients.
+///
+/// It forwards request bodies with `content-length` and responds to chunked request bodies
+/// with 411 Length Required.  It removes hop-by-hop headers and adds `x-forwarded-for`
+/// and `x-forwarded-host`.  It uses a new upstream connection for each request.
+///
+/// It responds with 502 Bad Gateway when it cannot connect to the upstream server or gets a bad
+/// response, and with 504 Gateway Timeout when the upstream server is too slow.
+/// When the upstream server fails after the response head went to the client,
+/// the client's connection closes without the rest of the body.
+///
+/// To expose several upstream servers behind one endpoint, route path prefixes to proxies with
+/// `middleware::Router` and add authentication to the router's global stack:
+/// `Router::new(Stack::new().with(Authenticate(authenticator))).route("/a/", Stack::new(),
+/// ReverseProxy::new(upstream_a).strip_prefix("/a"))`
+pub struct ReverseProxy {
+    upstream: Upstream,
+    strip_prefix: String,
+    connect_timeout: Duration,
+    idle_timeout: Duration,
+    removed_request_headers: Vec<String>,
+}
+
+impl ReverseProxy {
+    /// Makes a proxy with a 10 second connect timeout and a 60 second idle timeout.
+    pub fn new(upstream: Upstream) -> ReverseProxy {
+        ReverseProxy {
+            upstream,
+            strip_prefix: String::new(),
+            connect_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(60),
+            removed_request_headers: Vec::new(),
+        }
+    }
+
+    /// Removes `prefix` from request paths, so a request for `/prefix/a` goes upstream as `/a`.
+    /// Requests with paths that do not start with `prefix` get 404 Not Found.
+    pub fn strip_prefix(mut self, prefix: &str) -> ReverseProxy {
+        self.strip_prefix = String::from(prefix);
+        self
+    }
+
+    /// Sets how long to wait for the upstream server to accept the connection.
+    pub fn connect_timeout(mut self, timeout: Duration) -> ReverseProxy {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets how long to wait for the upstream server to read request bytes, send the response
+    /// head, or send more response body bytes.
+    pub fn idle_timeout(mut self, timeout: Duration) -> ReverseProxy {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Does not forward the request header `name`.
+    /// Use this to keep credentials for the proxy, like `authorization`, from upstream servers.
+    pub fn remove_request_header(mut self, name: &str) -> ReverseProxy {
+        self.removed_request_headers.push(name.to_ascii_lowercase());
+        self
+    }
+
+    async fn connect(&self) -> Result<Connection, UpstreamError> {
+        let connect = async {
+            match &self.upstream {
+                Upstream::Tcp(socket_addr) => TcpStream::connect(socket_addr).await.map(Connection::Tcp),
+                Upstream::Unix(path) => UnixStream::connect(path).await.map(Connection::Unix),
+            }
+        };
+        match tokio::time::timeout(self.connect_timeout, connect).await {
+            Ok(Ok(connection)) => Ok(connection),
+            Ok(Err(e)) => Err(UpstreamError::io("error connecting", e)),
+            Err(_) => Err(UpstreamError::Timeout("connecting")),
+        }
+    }
+
+    /// Returns the request head to send upstream.
+    fn request_head(&self, http_reader_writer: &HttpReaderWriter) -> Result<String, HttpError> {
+        let path = match http_reader_writer.raw_path.strip_prefix(self.strip_prefix.as_str()) {
+            Some(path) if path.starts_with('/') || &*http_reader_writer.raw_path == "*" => path.to_string(),
+            Some(path) if path.is_empty() || path.starts_with('?') => format!("/{}", path),
+            _ => return Err(HttpError::ProcessingError(HttpStatus::NotFound404)),
+        };
+        let mut head = format!("{} {} HTTP/1.1\r\n", http_reader_writer.method().as_str(), path);
+        let mut forwarded_for = Vec::new();
+        for (name, value) in http_reader_writer.headers() {
+            let lower_name = name.to_ascii_lowercase();
+            if lower_name == "x-forwarded-for" {
+                forwarded_for.push(value);
+                continue;
+            }
+            // The server answered `expect: 100-continue` when the proxy started reading the body.
+            if is_hop_by_hop(name, http_reader_writer.header_values("connection"))
+                || ["host", "content-length", "expect", "x-forwarded-host"].contains(&lower_name.as_str())
+                || self.removed_request_headers.contains(&lower_name) {
+                continue;
+            }
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        let peer_ip = http_reader_writer.peer_addr().ip()
+            .map_or_else(|| String::from("unknown"), |ip| ip.to_string());
+        forwarded_for.push(&peer_ip);
+        head.push_str(&format!("x-forwarded-for: {}\r\n", forwarded_for.join(", ")));
+        let upstream_host = match &self.upstream {
+            Upstream::Tcp(socket_addr) => socket_addr.to_string(),
+            Upstream::Unix(_) => String::from("localhost"),
+        };
+        let host = http_reader_writer.host().unwrap_or(&upstream_host);
+        head.push_str(&format!("host: {}\r\n", host));
+        if let Some(host) = http_reader_writer.host() {
+            head.push_str(&format!("x-forwarded-host: {}\r\n", host));
+        }
+        if http_reader_writer.content_length() > 0 {
+            head.push_str(&format!("content-length: {}\r\n", http_reader_writer.content_length()));
+        }
+        head.push_str("connection: close\r\n\r\n");
+        Ok(head)
+    }
+
+    async fn write_upstream(&self, upstream: &mut Connection, data: &[u8]) -> Result<(), UpstreamError> {
+        match tokio::time::timeout(self.idle_timeout, upstream.write_all(data)).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(UpstreamError::io("error sending request", e)),
+            Err(_) => Err(UpstreamError::Timeout("sending request")),
+        }
+    }
+
+    /// Sends the request head and body upstream.
+    async fn send_request(&self, http_reader_writer: &mut HttpReaderWriter<'_>, upstream: &mut Connection,
+                          head: &str) -> Result<Result<(), UpstreamError>, HttpError> {
+        if let Err(e) = self.write_upstream(upstream, head.as_bytes()).await {
+            return Ok(Err(e));
+        }
+        let mut buf = vec![0u8; BODY_BUFFER_LEN];
+        loop {
+            let num_read = http_reader_writer.read(&mut buf).await.map_err(HttpError::from_io_err)?;
+            if num_read == 0 {
+                break;
+            }
+            if let Err(e) = self.write_upstream(upstream, &buf[..num_read]).await {
+                return Ok(Err(e));
+            }
+        }
+        Ok(Ok(()))
+    }
+
+    /// Reads the response head, skipping informational responses like `103 Early Hints`.
+    async fn read_response_head(&self, method: &HttpMethod, upstream: &mut BufReader<Connection>)
+                                -> Result<ResponseHead, UpstreamError> {
+        let read = async {
+            loop {
+                let status_line = read_line(upstream).await
+                    .map_err(|e| UpstreamError::io("error reading response", e))?;
+                let code = parse_status_line(&status_line)
+                    .ok_or_else(|| UpstreamError::Failed(format!("bad status line {:?}", status_line)))?;
+                let mut headers = Vec::new();
+                loop {
+                    let line = read_line(upstream).await
+                        .map_err(|e| UpstreamError::io("error reading response", e))?;
+                    if line.is_empty() {
+                        break;
+                    }
+                    let colon = line.find(':').ok_or_else(|| UpstreamError::invalid("bad header line"))?;
+                    let name = &line[..colon];
+                    if name.is_empty() || name.contains(|c: char| c.is_ascii_whitespace()) {
+                        return Err(UpstreamError::invalid("bad header name"));
+                    }
+                    headers.push((String::from(name), String::from(line[colon + 1..].trim())));
+                    if headers.len() > MAX_HEADERS {
+                        return Err(UpstreamError::invalid("too many headers"));
+                    }
+                }
+                match code {
+                    // The proxy removes `upgrade` headers, so upstream servers cannot switch protocols.
+                    101 => return Err(UpstreamError::invalid("unexpected 101 Switching Protocols")),
+                    100..=199 => continue,
+                    _ => {}
+                }
+                let framing = response_framing(method, code, &headers)?;
+                // parse_status_line() only accepts codes in 100..=599.
+                let status = HttpStatus::from_code(code).unwrap();
+                return Ok(ResponseHead { status, headers, framing });
+            }
+        };
+        match tokio::time::timeout(self.idle_timeout, read).await {
+            Ok(result) => result,
+            Err(_) => Err(UpstreamError::Timeout("waiting for response")),
+        }
+    }
+
+    /// Sends the response head to the client.
+    async fn send_response_head(http_reader_writer: &mut HttpReaderWriter<'_>, head: &ResponseHead)
+                                -> Result<(), HttpError> {
+        let connection_values = || head.headers.iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("connection"))
+            .map(|(_, value)| value.as_str());
+        let head_request = http_reader_writer.method() == HttpMethod::HEAD;
+        // `send_without_body` rejects headers that describe a body.
+        let without_body = head.framing == BodyFraming::None && !head_request;
+        let headers: Vec<Header> = head.headers.iter()
+            .filter(|(name, _)| !is_hop_by_hop(name, connection_values())
+                && !name.eq_ignore_ascii_case("content-length"))
+            .filter(|(name, _)| !without_body || !(name.eq_ignore_ascii_case("content-type")
+                || name.eq_ignore_ascii_case("content-encoding")))
+            .map(|(name, value)| Header::new(name, value))
+            .collect();
+        let header_refs: Vec<&Header> = headers.iter().collect();
+        match head.framing {
+            BodyFraming::None if head_request => {
+                // Responses to HEAD have the headers of a GET response and no body.
+                match response_framing(&HttpMethod::GET, head.status.code(), &head.headers) {
+                    Ok(BodyFraming::ContentLength(len)) =>
+                        http_reader_writer.send_with_content_length(head.status.clone(), &header_refs, len).await?,
+                    _ => {
+                        http_reader_writer.send_chunked(head.status.clone(), &header_refs).await?;
+                        http_reader_writer.chunked_response = false;
+                    }
+                }
+                http_reader_writer.unsent_content_length = Some(0);
+                Ok(())
+            }
+            BodyFraming::None => http_reader_writer.send_without_body(head.status.clone(), &header_refs).await,
+            BodyFraming::ContentLength(len) =>
+                http_reader_writer.send_with_content_length(head.status.clone(), &header_refs, len).await,
+            BodyFraming::Chunked | BodyFraming::UntilClose =>
+                http_reader_writer.send_chunked(head.status.clone(), &header_refs).await,
+        }
+    }
+
+    /// Streams the response body from `upstream` to the client.
+    async fn copy_response_body(&self, http_reader_writer: &mut HttpReaderWriter<'_>,
+                                upstream: &mut BufReader<Connection>, framing: BodyFraming)
+                                -> Result<(), HttpError> {
+        let timed_out = || HttpError::IoError(std::io::Error::new(
+            std::io::ErrorKind::TimedOut, "upstream response body timed out"));
+        let mut buf = vec![0u8; BODY_BUFFER_LEN];
+        match framing {
+            BodyFraming::None => {}
+            BodyFraming::ContentLength(mut remaining) => {
+                while remaining > 0 {
+                    let len = std::cmp::min(remaining, buf.len() as u64) as usize;
+                    let num_read = tokio::time::timeout(self.idle_timeout, upstream.read(&mut buf[..len]))
+                        .await.map_err(|_| timed_out())?.map_err(HttpError::from_io_err)?;
+                    if num_read == 0 {
+                        return Err(HttpError::IoError(std::io::Error::new(
+                            std::io::ErrorKind::UnexpectedEof, "upstream response body cut off")));
+                    }
+                    http_reader_writer.write_all(&buf[..num_read]).await.map_err(HttpError::from_io_err)?;
+                    remaining -= num_read as u64;
+                }
+            }
+            BodyFraming::Chunked => {
+                while tokio::time::timeout(self.idle_timeout, read_chunk(upstream, &mut buf))
+                    .await.map_err(|_| timed_out())?.map_err(HttpError::from_io_err)?.is_some() {
+                    http_reader_writer.send_chunk(&buf).await?;
+                }
+                http_reader_writer.finish_chunked().await?;
+            }
+            BodyFraming::UntilClose => {
+                loop {
+                    let num_read = tokio::time::timeout(self.idle_timeout, upstream.read(&mut buf))
+                        .await.map_err(|_| timed_out())?.map_err(HttpError::from_io_err)?;
+                    if num_read == 0 {
+                        break;
+                    }
+                    http_reader_writer.send_chunk(&buf[..num_read]).await?;
+                }
+                http_reader_writer.finish_chunked().await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn forward(&self, http_reader_writer: &mut HttpReaderWriter<'_>)
+                     -> Result<Result<(), UpstreamError>, HttpError> {
+        if http_reader_writer.chunked {
+            return Err(HttpError::ProcessingError(HttpStatus::LengthRequired411));
+        }
+        let head = self.request_head(http_reader_writer)?;
+        let mut upstream = match self.connect().await {
+            Ok(upstream) => upstream,
+            Err(e) => return Ok(Err(e)),
+        };
+        if let Err(e) = self.send_request(http_reader_writer, &mut upstream, &head).await? {
+            return Ok(Err(e));
+        }
+        let mut upstream = BufReader::new(upstream);
+        let response_head = match self.read_response_head(&http_reader_writer.method(), &mut upstream).await {
+            Ok(response_head) => response_head,
+            Err(e) => return Ok(Err(e)),
+        };
+        Self::send_response_head(http_reader_writer, &response_head).await?;
+        self.copy_response_body(http_reader_writer, &mut upstream, response_head.framing).await?;
+        Ok(Ok(()))
+    }
+}
+
+#[async_trait]
+impl HttpHandler for ReverseProxy {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        match self.forward(http_reader_writer).await? {
+            Ok(()) => Ok(()),
+            Err(UpstreamError::Timeout(context)) => {
+                warn!("upstream {} timed out {}", self.upstream, context);
+                Err(HttpError::ProcessingError(HttpStatus::GatewayTimeout504))
+            }
+            Err(UpstreamError::Failed(message)) => {
+                warn!("upstream {} failed: {}", self.upstream, message);
+                Err(HttpError::ProcessingError(HttpStatus::BadGateway502))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (String::from(*name), String::from(*value))).collect()
+    }
+
+    fn framing(method: HttpMethod, code: u16, pairs: &[(&str, &str)]) -> Option<BodyFraming> {
+        response_framing(&method, code, &headers(pairs)).ok()
+    }
+
+    #[test]
+    fn test_is_hop_by_hop() {
+        assert!(is_hop_by_hop("Connection", std::iter::empty()));
+        assert!(is_hop_by_hop("transfer-encoding", std::iter::empty()));
+        assert!(!is_hop_by_hop("x-a", std::iter::empty()));
+        assert!(is_hop_by_hop("X-A", vec!["close", "keep-alive, x-a"].into_iter()));
+    }
+
+    #[test]
+    fn test_parse_status_line() {
+        assert_eq!(Some(200), parse_status_line("HTTP/1.1 200 OK"));
+        assert_eq!(Some(418), parse_status_line("HTTP/1.0 418 I'm a teapot"));
+        assert_eq!(Some(299), parse_status_line("HTTP/1.1 299"));
+        assert_eq!(Some(299), parse_status_line("HTTP/1.1 299 "));
+        for line in &["", "HTTP/1.1", "HTTP/1.1 20 OK", "HTTP/1.1 2000 OK", "HTTP/2 200 OK", "HTTP/1.1 2x0 OK",
+            "ICY 200 OK", "HTTP/1.1 600 X", "HTTP/1.1 099 X", "HTTP/1.1 000 X"] {
+            assert_eq!(None, parse_status_line(line), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn test_response_framing() {
+        assert_eq!(Some(BodyFraming::None), framing(HttpMethod::HEAD, 200, &[("content-length", "5")]));
+        assert_eq!(Some(BodyFraming::None), framing(HttpMethod::GET, 204, &[]));
+        assert_eq!(Some(BodyFraming::None), framing(HttpMethod::GET, 304, &[("content-length", "5")]));
+        assert_eq!(Some(BodyFraming::ContentLength(5)), framing(HttpMethod::GET, 200, &[("Content-Length", "5")]));
+        assert_eq!(Some(BodyFraming::ContentLength(5)),
+                   framing(HttpMethod::GET, 200, &[("content-length", "5"), ("content-length", "5")]));
+        assert_eq!(None, framing(HttpMethod::GET, 200, &[("content-length", "5"), ("content-length", "6")]));
+        assert_eq!(None, framing(HttpMethod::GET, 200, &[("content-length", "-5")]));
+        assert_eq!(Some(BodyFraming::Chunked),
+                   framing(HttpMethod::GET, 200, &[("transfer-encoding", "gzip, Chunked"), ("content-length", "5")]));
+        assert_eq!(Some(BodyFraming::UntilClose), framing(HttpMethod::GET, 200, &[("transfer-encoding", "gzip")]));
+        assert_eq!(Some(BodyFraming::UntilClose), framing(HttpMethod::GET, 200, &[]));
+    }
+
+    #[tokio::test]
+    async fn test_read_chunk() {
+        let mut reader = BufReader::new(&b"3\r\nabc\r\n2;ext=1\r\nde\r\n0\r\ntrailer: x\r\n\r\nrest"[..]);
+        let mut buf = Vec::new();
+        assert_eq!(Some(()), read_chunk(&mut reader, &mut buf).await.unwrap());
+        assert_eq!(b"abc", &buf[..]);
+        assert_eq!(Some(()), read_chunk(&mut reader, &mut buf).await.unwrap());
+        assert_eq!(b"de", &buf[..]);
+        assert_eq!(None, read_chunk(&mut reader, &mut buf).await.unwrap());
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await.unwrap();
+        assert_eq!("rest", rest);
+        for input in &[&b"x\r\n"[..], b"3\r\nabcd\r\n", b"3\r\nab", b"3"] {
+            let mut reader = BufReader::new(*input);
+            assert!(read_chunk(&mut reader, &mut buf).await.is_err(), "{:?}", input);
+        }
+    }
+}
@@ -0,0 +1,528 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::future::AbortHandle;
+use log::{debug, info, warn};
+use logging::metrics::{Counter, Gauge, Histogram, Registry, DURATION_MS_BUCKETS};
+
+use crate::forwarded::TrustedProxies;
+use crate::middleware::{around, Flow, Middleware};
+use crate::stopper::{new_stopper, Stopper, StopperController};
+use crate::tap::{Tap, TapReader, TapWriter, Transcript};
+use crate::transport::{Accepted, Listener, LocalAddr, PeerAddr};
+use crate::{HttpError, HttpReaderWriter, HttpStatus};
+
+// How long `HttpServer::stop` waits for tasks after cancelling them.
+const ABORT_WAIT: Duration = Duration::from_secs(1);
+
+/// HttpHandler handles requests received by an `HttpServer`.
+///
+/// The server reads the request head before calling `handle`.
+/// The handler reads the request body, if any, and sends a response.
+///
+/// When `handle` returns `HttpError::ParseError` or `HttpError::ProcessingError` before sending
+/// a response, the server sends a response with the error's status.
+#[async_trait]
+pub trait HttpHandler: Send + Sync {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError>;
+}
+
+/// Keeps track of connection tasks so `HttpServer::stop` can cancel them.
+struct ConnectionTracker {
+    connections: Mutex<(u64, HashMap<u64, AbortHandle>)>,
+    active_requests: AtomicUsize,
+}
+
+impl ConnectionTracker {
+    fn new() -> ConnectionTracker {
+        ConnectionTracker {
+            connections: Mutex::new((0, HashMap::new())),
+            active_requests: AtomicUsize::new(0),
+        }
+    }
+
+    fn add(&self, abort_handle: AbortHandle) -> u64 {
+        let mut guard = self.connections.lock().unwrap();
+        let (next_id, connections) = &mut *guard;
+        let id = *next_id;
+        *next_id += 1;
+        connections.insert(id, abort_handle);
+        id
+    }
+
+    fn remove(&self, id: u64) {
+        self.connections.lock().unwrap().1.remove(&id);
+    }
+
+    /// Returns the number of connection tasks, including ones doing a TLS handshake
+    /// or reading a request head.
+    fn len(&self) -> usize {
+        self.connections.lock().unwrap().1.len()
+    }
+
+    fn abort_all(&self) {
+        for (_id, abort_handle) in self.connections.lock().unwrap().1.drain() {
+            abort_handle.abort();
+        }
+    }
+}
+
+/// Request metrics that an `HttpServer` records in a `logging::metrics::Registry`.
+struct ServerMetrics {
+    connections: Arc<Counter>,
+    requests: Arc<Counter>,
+    client_errors: Arc<Counter>,
+    server_errors: Arc<Counter>,
+    active_requests: Arc<Gauge>,
+    request_duration_ms: Arc<Histogram>,
+}
+
+impl ServerMetrics {
+    fn new(registry: &Registry) -> ServerMetrics {
+        registry.set_help("http_connections", "Connections accepted.");
+        registry.set_help("http_requests", "Requests handled.");
+        registry.set_help("http_client_errors", "Responses with 4xx status.");
+        registry.set_help("http_server_errors", "Responses with 5xx status and requests that ended without a response.");
+        registry.set_help("http_active_requests", "Requests being handled now.");
+        registry.set_help("http_request_duration_ms", "Time from reading the request to finishing the response, in milliseconds.");
+        ServerMetrics {
+            connections: registry.counter("http_connections"),
+            requests: registry.counter("http_requests"),
+            client_errors: registry.counter("http_client_errors"),
+            server_errors: registry.counter("http_server_errors"),
+            active_requests: registry.gauge("http_active_requests"),
+            request_duration_ms: registry.histogram("http_request_duration_ms", DURATION_MS_BUCKETS),
+        }
+    }
+
+    fn record_response(&self, status_code: u16, duration: Duration) {
+        self.requests.inc();
+        match status_code {
+            400..=499 => self.client_errors.inc(),
+            // Requests that end without a response count as server errors.
+            0 | 500..=599 => self.server_errors.inc(),
+            _ => {}
+        }
+        self.request_duration_ms.record_duration(duration);
+    }
+}
+
+/// Counts a request as active until dropped.
+struct ActiveRequest<'a> {
+    counter: &'a AtomicUsize,
+    gauge: &'a Gauge,
+}
+
+impl<'a> ActiveRequest<'a> {
+    fn new(counter: &'a AtomicUsize, gauge: &'a Gauge) -> ActiveRequest<'a> {
+        counter.fetch_add(1, Ordering::SeqCst);
+        gauge.inc();
+        ActiveRequest { counter, gauge }
+    }
+}
+
+impl<'a> Drop for ActiveRequest<'a> {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+        self.gauge.dec();
+    }
+}
+
+/// TapSink receives the masked transcript of each connection when it closes.
+pub type TapSink = Arc<dyn Fn(PeerAddr, Transcript) + Send + Sync>;
+
+/// Settings that `handle_connection` applies to each connection.
+struct ConnectionOptions {
+    proxy_protocol: bool,
+    trusted_proxies: Option<TrustedProxies>,
+    tap_sink: Option<TapSink>,
+}
+
+fn log_request(http_reader_writer: &HttpReaderWriter, duration: Duration) {
+    let status = http_reader_writer.status().map(|s| s.code()).unwrap_or(0);
+    let peer_addr = http_reader_writer.peer_addr();
+    logging::info!("http_request";
+        "http_method" => http_reader_writer.method().as_str(),
+        "path" => &*http_reader_writer.raw_path,
+        "http_status" => status,
+        "duration_ms" => duration.as_millis() as u64,
+        "pii_ip" => http_reader_writer.client_ip().map(|ip| ip.to_string())
+            .unwrap_or_else(|| peer_addr.to_string()),
+        "pii_principal" => http_reader_writer.principal(),
+    );
+}
+
+async fn handle_connection(
+    mut accepted: Accepted,
+    handler: Arc<dyn HttpHandler>,
+    mut stopper: Stopper,
+    tracker: Arc<ConnectionTracker>,
+    metrics: Arc<ServerMetrics>,
+    options: Arc<ConnectionOptions>,
+) {
+    metrics.connections.inc();
+    let addr = accepted.peer_addr.clone();
+    if options.proxy_protocol {
+        accepted = accepted.expect_proxy_protocol();
+    }
+    let (connection, peer_addr) = tokio::select! {
+        result = accepted.handshake() => match result {
+            Ok(connection_and_peer_addr) => connection_and_peer_addr,
+            Err(e) => {
+                debug!("{} handshake failed: {:?}", addr, e);
+                return;
+            }
+        },
+        _ = stopper.wait() => return,
+    };
+    // The PROXY protocol header may have replaced the balancer's address with the client's.
+    let addr = peer_addr.clone();
+    let (reader, writer) = tokio::io::split(connection);
+    let tap = options.tap_sink.as_ref().map(|_| Tap::new());
+    let mut reader = TapReader::new(reader, tap.clone());
+    let mut writer = TapWriter::new(writer, tap.clone());
+    let mut http_reader_writer = HttpReaderWriter::new(
+        Pin::new(&mut reader), Pin::new(&mut writer), peer_addr);
+    http_reader_writer.set_stopper(stopper.clone());
+    loop {
+        // Idle keep-alive connections close as soon as the server stops.
+        let result = tokio::select! {
+            result = http_reader_writer.read_request(&mut []) => result,
+            _ = stopper.wait() => break,
+        };
+        match result {
+            Ok(()) => {}
+            Err(HttpError::IoError(e)) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    debug!("{} error reading request: {:?}", addr, e);
+                }
+                break;
+            }
+            // The request framing may be broken, so close the connection after responding.
+            Err(HttpError::ParseError(e)) => {
+                let _ = http_reader_writer.send_simple(e.status()).await;
+                break;
+            }
+            Err(HttpError::ProcessingError(status)) => {
+                let _ = http_reader_writer.send_simple(status).await;
+                break;
+            }
+        }
+        if let Some(trusted_proxies) = &options.trusted_proxies {
+            if let Some(client_ip) = trusted_proxies.client_ip(&http_reader_writer) {
+                http_reader_writer.set_client_ip(client_ip);
+            }
+        }
+        let start = Instant::now();
+        let _active_request = ActiveRequest::new(&tracker.active_requests, &metrics.active_requests);
+        let result = handler.handle(&mut http_reader_writer).await;
+        let response_sent = http_reader_writer.status().is_some();
+        match result {
+            Ok(()) => {}
+            Err(HttpError::IoError(e)) => {
+                debug!("{} error handling request: {:?}", addr, e);
+                break;
+            }
+            Err(HttpError::ParseError(e)) if !response_sent => {
+                let _ = http_reader_writer.send_simple(e.status()).await;
+            }
+            Err(HttpError::ProcessingError(status)) if !response_sent => {
+                let _ = http_reader_writer.send_simple(status).await;
+            }
+            Err(e) => {
+                warn!("{} handler returned error after sending response: {:?}", addr, e);
+                break;
+            }
+        }
+        let duration = start.elapsed();
+        log_request(&http_reader_writer, duration);
+        metrics.record_response(http_reader_writer.status().map(|s| s.code()).unwrap_or(0), duration);
+        if http_reader_writer.is_closing() || !http_reader_writer.body_fully_read()
+            || !http_reader_writer.response_fully_sent() {
+            break;
+        }
+    }
+    drop(http_reader_writer);
+    let _ = tokio::io::AsyncWriteExt::shutdown(&mut writer).await;
+    if let (Some(tap), Some(tap_sink)) = (tap, &options.tap_sink) {
+        tap_sink(addr, tap.transcript().masked());
+    }
+}
+
+async fn accept_loop(
+    mut listener: Listener,
+    handler: Arc<dyn HttpHandler>,
+    mut stopper: Stopper,
+    tracker: Arc<ConnectionTracker>,
+    metrics: Arc<ServerMetrics>,
+    options: Arc<ConnectionOptions>,
+) {
+    loop {
+        tokio::select! {
+            accept_result = listener.accept() => {
+                match accept_result {
+                    Ok(accepted) => {
+                        let (connection, abort_handle) = futures::future::abortable(
+                            handle_connection(accepted, handler.clone(), stopper.clone(),
+                                              tracker.clone(), metrics.clone(), options.clone()));
+                        let id = tracker.add(abort_handle);
+                        let tracker_clone = tracker.clone();
+                        tokio::spawn(async move {
+                            let _ = connection.await;
+                            tracker_clone.remove(id);
+                        });
+                    }
+                    Err(e) => {
+                        warn!("Failed accepting connection from socket: {:?}", e);
+                        match e.kind() {
+                            // Do not sleep on connection error.
+                            std::io::ErrorKind::ConnectionAborted
+                            | std::io::ErrorKind::ConnectionRefused
+                            | std::io::ErrorKind::ConnectionReset => {}
+                            // Sleep on accept error, like "Too many open files".
+                            _ => tokio::time::delay_for(Duration::from_secs(1)).await,
+                        }
+                    }
+                }
+            },
+            _ = stopper.wait() => break,
+        }
+    }
+    info!("Stopped accepting connections on {:?}", listener.local_addr());
+    // Dropping the listener closes the socket, and deletes the file of a Unix domain socket.
+    // New connections get refused.  Drop it before `stopper`, so `HttpServer::stop` returns after.
+    drop(listener);
+}
+
+pub struct HttpServerBuilder {
+    all_interfaces: bool,
+    port: u16,
+    std_listener: Option<std::net::TcpListener>,
+    listener: Option<Listener>,
+    tls_config: Option<Arc<rustls::ServerConfig>>,
+    metrics_registry: Arc<Registry>,
+    proxy_protocol: bool,
+    trusted_proxies: Option<TrustedProxies>,
+    tap_sink: Option<TapSink>,
+}
+
+impl HttpServerBuilder {
+    /// Makes a builder for a server that listens on localhost on any available port.
+    pub fn new() -> HttpServerBuilder {
+        HttpServerBuilder {
+            all_interfaces: false,
+            port: 0,
+            std_listener: None,
+            listener: None,
+            tls_config: None,
+            metrics_registry: logging::metrics::global(),
+            proxy_protocol: false,
+            trusted_proxies: None,
+            tap_sink: None,
+        }
+    }
+
+    /// Listen only on the IPv4 loopback interface.
+    pub fn localhost(mut self) -> HttpServerBuilder {
+        self.all_interfaces = false;
+        self
+    }
+
+    /// Listen on all IPv4 and IPv6 interfaces.
+    pub fn all_interfaces(mut self) -> HttpServerBuilder {
+        self.all_interfaces = true;
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> HttpServerBuilder {
+        self.port = port;
+        self
+    }
+
+    /// Listen on any available port.  Get the port from `HttpServer::local_addr`.
+    pub fn any_port(self) -> HttpServerBuilder {
+        self.port(0)
+    }
+
+    /// Accept connections on `listener` instead of binding a port.
+    /// The server ignores the interface and port settings.
+    ///
+    /// Use this with `listen_fd::take_inherited_listener`.
+    pub fn listener(mut self, listener: std::net::TcpListener) -> HttpServerBuilder {
+        self.std_listener = Some(listener);
+        self
+    }
+
+    /// Accept connections on `listener`, for any transport.
+    /// The server ignores the interface and port settings.
+    ///
+    /// Use this to listen on a Unix domain socket or to make in-memory connections in tests.
+    pub fn transport(mut self, listener: Listener) -> HttpServerBuilder {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Do TLS on TCP connections.  `run` returns an error for other transports.
+    pub fn tls(mut self, server_config: Arc<rustls::ServerConfig>) -> HttpServerBuilder {
+        self.tls_config = Some(server_config);
+        self
+    }
+
+    /// Record request metrics in `registry` instead of `logging::metrics::global()`.
+    pub fn metrics(mut self, registry: Arc<Registry>) -> HttpServerBuilder {
+        self.metrics_registry = registry;
+        self
+    }
+
+    /// Require a PROXY protocol header at the start of each connection, and use the client
+    /// address from it as the peer address.  Connections without a valid header get closed.
+    ///
+    /// Use this behind a TCP load balancer that sends the header.
+    /// See `transport::Accepted::expect_proxy_protocol`.
+    pub fn proxy_protocol(mut self) -> HttpServerBuilder {
+        self.proxy_protocol = true;
+        self
+    }
+
+    /// Take the client address from the forwarding headers of requests from `trusted_proxies`.
+    /// Get it from `HttpReaderWriter::client_ip`.  The request log uses it too.
+    pub fn trusted_proxies(mut self, trusted_proxies: TrustedProxies) -> HttpServerBuilder {
+        self.trusted_proxies = Some(trusted_proxies);
+        self
+    }
+
+    /// Record the bytes of each connection, after TLS, and pass the transcript to `tap_sink` when
+    /// the connection closes.  The transcript has PII masked, see `tap::Transcript::masked`.
+    /// Transcripts stop at `tap::DEFAULT_MAX_LEN` bytes.
+    ///
+    /// Save transcripts with `Transcript::to_text` and replay them with the `replay` program.
+    pub fn tap(mut self, tap_sink: impl Fn(PeerAddr, Transcript) + Send + Sync + 'static) -> HttpServerBuilder {
+        self.tap_sink = Some(Arc::new(tap_sink));
+        self
+    }
+
+    /// Binds the port and starts accepting connections in a background task.
+    pub async fn run(self, handler: Arc<dyn HttpHandler>) -> std::io::Result<HttpServer> {
+        let mut listener = if let Some(listener) = self.listener {
+            listener
+        } else if let Some(std_listener) = self.std_listener {
+            Listener::from_std(std_listener)?
+        } else {
+            let interface = if self.all_interfaces {
+                std::net::IpAddr::from(std::net::Ipv6Addr::UNSPECIFIED /* includes ipv4 */)
+            } else {
+                std::net::IpAddr::from(std::net::Ipv4Addr::LOCALHOST)
+            };
+            Listener::bind_tcp(SocketAddr::from((interface, self.port))).await?
+        };
+        if let Some(server_config) = self.tls_config {
+            listener = listener.with_tls(server_config)?;
+        }
+        let local_addr = listener.local_addr()?;
+        let listener_fd = listener.raw_fd();
+        info!("Listening for HTTP connections on {}", local_addr);
+        let (stopper_controller, stopper) = new_stopper();
+        let tracker = Arc::new(ConnectionTracker::new());
+        let metrics = Arc::new(ServerMetrics::new(&self.metrics_registry));
+        let options = Arc::new(ConnectionOptions {
+            proxy_protocol: self.proxy_protocol,
+            trusted_proxies: self.trusted_proxies,
+            tap_sink: self.tap_sink,
+        });
+        tokio::spawn(accept_loop(listener, handler, stopper, tracker.clone(), metrics, options));
+        Ok(HttpServer { local_addr, listener_fd, stopper_controller, tracker })
+    }
+}
+
+impl Default for HttpServerBuilder {
+    fn default() -> Self {
+        HttpServerBuilder::new()
+    }
+}
+
+/// HttpServer is a running server.  Make one with `HttpServerBuilder`.
+///
+/// Dropping the server does not stop it.  Call `stop` to stop it.
+pub struct HttpServer {
+    pub local_addr: LocalAddr,
+    listener_fd: Option<RawFd>,
+    stopper_controller: StopperController,
+    tracker: Arc<ConnectionTracker>,
+}
+
+impl HttpServer {
+    /// Returns the file descriptor of the listening socket, or `None` for in-memory listeners.
+    /// It stays open until you call `stop`.
+    ///
+    /// Pass it to `listen_fd::spawn_replacement` for a zero-downtime restart.
+    pub fn listener_fd(&self) -> Option<RawFd> {
+        self.listener_fd
+    }
+
+    /// Stops the server gracefully:
+    /// 1. Closes the listening socket.
+    /// 1. Closes idle keep-alive connections.
+    ///    Connections with a request in progress get `connection: close` on their response
+    ///    and close after sending it.
+    /// 1. Waits up to `deadline` for the handlers to finish.
+    /// 1. Cancels the remaining handlers and closes their connections.
+    ///
+    /// Returns the number of connections that were cut off.
+    /// That includes connections with a request in progress and connections still doing
+    /// a TLS handshake or reading a request head.
+    pub async fn stop(mut self, deadline: Duration) -> usize {
+        info!("Stopping HTTP server on {}", self.local_addr);
+        self.stopper_controller.signal_stop();
+        if tokio::time::timeout(deadline, self.stopper_controller.wait()).await.is_ok() {
+            info!("HTTP server on {} stopped", self.local_addr);
+            return 0;
+        }
+        let cut_off = self.tracker.len();
+        warn!("HTTP server on {} handlers did not finish within {:?}, cancelling {} connections ({} requests)",
+              self.local_addr, deadline, cut_off, self.tracker.active_requests.load(Ordering::SeqCst));
+        self.tracker.abort_all();
+        // Cancelled tasks drop their stoppers when they next get polled.  A task spawned by a
+        // handler may still hold one.
+        if tokio::time::timeout(ABORT_WAIT, self.stopper_controller.wait()).await.is_err() {
+            warn!("HTTP server on {} tasks still running {:?} after cancelling", self.local_addr, ABORT_WAIT);
+        }
+        cut_off
+    }
+}
+
+/// RequireLocal is middleware that rejects requests from non-local peers with 403 Forbidden.
+/// See `PeerAddr::is_local`.
+///
+/// When a trusted proxy reports the client's address, the request is local only if that address
+/// is loopback.  A local reverse proxy does not make its remote clients local.
+pub struct RequireLocal;
+
+#[async_trait]
+impl Middleware for RequireLocal {
+    async fn before(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<Flow, HttpError> {
+        let forwarded_from_remote = http_reader_writer.client_ip.is_some_and(|ip| !ip.is_loopback());
+        if !http_reader_writer.peer_addr().is_local() || forwarded_from_remote {
+            return Err(HttpError::ProcessingError(HttpStatus::Forbidden403));
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// LocalOnly passes requests from local peers to its handler and rejects other requests
+/// with 403 Forbidden.  See `RequireLocal`.
+///
+/// Use it for admin endpoints, and serve them on a Unix domain socket.
+pub struct LocalOnly<H: HttpHandler>(pub H);
+
+#[async_trait]
+impl<H: HttpHandler> HttpHandler for LocalOnly<H> {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        around(&RequireLocal, &self.0, http_reader_writer).await
+    }
+}
@@ -0,0 +1,210 @@
+// Server-Sent Events https://html.spec.whatwg.org/multipage/server-sent-events.html
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+use tokio::time::Instant;
+
+use crate::{Header, HttpError, HttpReaderWriter, HttpStatus};
+
+/// Browsers and proxies close connections that are idle for too long.
+/// Comments sent at this interval keep the connection open.
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Event is one message in an event stream.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Event {
+    /// The browser sends the id of the last event it received in the `Last-Event-ID` header
+    /// when it reconnects.
+    pub id: Option<String>,
+    /// The event type.  Browsers dispatch events without a type as `message` events.
+    pub event: Option<String>,
+    pub data: String,
+}
+
+impl Event {
+    pub fn new(data: impl Into<String>) -> Event {
+        Event { id: None, event: None, data: data.into() }
+    }
+
+    pub fn with_id(mut self, id: impl Into<String>) -> Event {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn with_event(mut self, event: impl Into<String>) -> Event {
+        self.event = Some(event.into());
+        self
+    }
+}
+
+fn invalid_field(name: &str, value: &str) -> HttpError {
+    HttpError::ProcessingError(HttpStatus::InternalServerError500(
+        format!("event {} contains a line break: {:?}", name, value)))
+}
+
+/// Appends the event in the `text/event-stream` format.  Each line of `data` gets its own `data:` field.
+/// Lines end at CRLF, a lone CR, or a lone LF, like clients split them.
+fn format_event(event: &Event, out: &mut String) -> Result<(), HttpError> {
+    for (name, value) in &[("id", &event.id), ("event", &event.event)] {
+        if let Some(value) = value {
+            if value.contains(&['\r', '\n'][..]) {
+                return Err(invalid_field(name, value));
+            }
+            out.push_str(name);
+            out.push_str(": ");
+            out.push_str(value);
+            out.push('\n');
+        }
+    }
+    for line in event.data.replace("\r\n", "\n").split(&['\r', '\n'][..]) {
+        out.push_str("data: ");
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push('\n');
+    Ok(())
+}
+
+/// EventStream sends a `text/event-stream` response.
+///
+/// Browsers read it with `new EventSource(url)` and reconnect automatically when the connection
+/// drops.  On reconnect they send the id of the last event they received.  Resume the stream
+/// after that event, so the client does not miss or repeat events.
+pub struct EventStream<'a, 'b> {
+    http_reader_writer: &'b mut HttpReaderWriter<'a>,
+    last_event_id: Option<String>,
+    heartbeat_interval: Duration,
+    last_send: Instant,
+}
+
+impl<'a, 'b> EventStream<'a, 'b> {
+    /// Sends the response head.
+    pub async fn start(http_reader_writer: &'b mut HttpReaderWriter<'a>) -> Result<EventStream<'a, 'b>, HttpError> {
+        let last_event_id = http_reader_writer.header("last-event-id").map(String::from);
+        http_reader_writer.send_chunked(
+            HttpStatus::Ok200,
+            &[&Header::new("content-type", "text/event-stream"),
+                &Header::new("cache-control", "no-store")]).await?;
+        Ok(EventStream {
+            http_reader_writer,
+            last_event_id,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            last_send: Instant::now(),
+        })
+    }
+
+    /// Returns the request's `Last-Event-ID` header, which browsers send when they reconnect.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+
+    /// Sets how long `send_all` waits for an event before sending a heartbeat comment.
+    pub fn heartbeat_interval(&mut self, interval: Duration) {
+        self.heartbeat_interval = interval;
+    }
+
+    /// Tells the browser to wait `delay` before reconnecting.
+    pub async fn send_retry(&mut self, delay: Duration) -> Result<(), HttpError> {
+        self.send_raw(format!("retry: {}\n\n", delay.as_millis())).await
+    }
+
+    pub async fn send(&mut self, event: &Event) -> Result<(), HttpError> {
+        let mut text = String::new();
+        format_event(event, &mut text)?;
+        self.send_raw(text).await
+    }
+
+    /// Sends a comment line.  Clients ignore comments.
+    pub async fn send_comment(&mut self, comment: &str) -> Result<(), HttpError> {
+        if comment.contains(&['\r', '\n'][..]) {
+            return Err(invalid_field("comment", comment));
+        }
+        self.send_raw(format!(": {}\n\n", comment)).await
+    }
+
+    async fn send_raw(&mut self, text: String) -> Result<(), HttpError> {
+        self.http_reader_writer.send_chunk(text.as_bytes()).await?;
+        self.last_send = Instant::now();
+        Ok(())
+    }
+
+    /// Sends events from `events` until it ends or the server starts stopping.
+    /// Sends a heartbeat comment whenever no event arrives for the heartbeat interval.
+    ///
+    /// Returns an IoError when the client disconnects, which the server notices
+    /// while sending an event or heartbeat.
+    pub async fn send_all(&mut self, mut events: impl Stream<Item=Event> + Unpin) -> Result<(), HttpError> {
+        let mut stopper = self.http_reader_writer.stopper.clone();
+        loop {
+            if self.http_reader_writer.is_stopping() {
+                return Ok(());
+            }
+            tokio::select! {
+                _ = async {
+                    match stopper.as_mut() {
+                        Some(stopper) => stopper.wait().await,
+                        None => futures::future::pending().await,
+                    }
+                } => return Ok(()),
+                event = events.next() => match event {
+                    Some(event) => self.send(&event).await?,
+                    None => return Ok(()),
+                },
+                _ = tokio::time::delay_until(self.last_send + self.heartbeat_interval) => {
+                    self.send_comment("heartbeat").await?;
+                },
+            }
+        }
+    }
+
+    /// Ends the response.  The client reconnects if it is an `EventSource`.
+    pub async fn finish(self) -> Result<(), HttpError> {
+        self.http_reader_writer.finish_chunked().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+
+    use crate::stopper::new_stopper;
+    use crate::testing::{ScriptedReader, ScriptedWriter};
+    use crate::transport::PeerAddr;
+
+    #[test]
+    fn test_format_event() {
+        let mut text = String::new();
+        format_event(&Event::new("a"), &mut text).unwrap();
+        format_event(&Event::new("b\nc\r\n").with_id("7").with_event("update"), &mut text).unwrap();
+        assert_eq!("data: a\n\nid: 7\nevent: update\ndata: b\ndata: c\ndata: \n\n", text);
+        // A lone CR ends a line too, so it cannot start a field.
+        let mut text = String::new();
+        format_event(&Event::new("x\rid: 99\r\revent: y\n\rz"), &mut text).unwrap();
+        assert_eq!("data: x\ndata: id: 99\ndata: \ndata: event: y\ndata: \ndata: z\n\n", text);
+        match format_event(&Event::new("a").with_id("1\n2"), &mut text) {
+            Err(HttpError::ProcessingError(HttpStatus::InternalServerError500(_))) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_all_stops_with_server() {
+        let mut reader = ScriptedReader::new().data(b"GET /events HTTP/1.1\r\n\r\n");
+        let mut writer = ScriptedWriter::new();
+        let mut http_reader_writer =
+            HttpReaderWriter::new(Pin::new(&mut reader), Pin::new(&mut writer), PeerAddr::Test(0));
+        http_reader_writer.read_request(&mut []).await.unwrap();
+        let (mut controller, stopper) = new_stopper();
+        http_reader_writer.set_stopper(stopper);
+        let mut stream = EventStream::start(&mut http_reader_writer).await.unwrap();
+        stream.heartbeat_interval(Duration::from_secs(60));
+        let stop = async {
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            controller.signal_stop();
+        };
+        let send_all = tokio::time::timeout(Duration::from_secs(5), stream.send_all(futures::stream::pending()));
+        let (result, ()) = tokio::join!(send_all, stop);
+        result.expect("send_all did not notice the stop").unwrap();
+    }
+}
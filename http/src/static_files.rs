@@ -0,0 +1,345 @@
+// Serves files from a directory tree.
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use percent_encoding::{percent_decode_str, AsciiSet, NON_ALPHANUMERIC};
+
+use crate::server::HttpHandler;
+use crate::{Header, HttpCallerError, HttpError, HttpMethod, HttpReaderWriter, HttpStatus};
+
+/// Characters to escape in directory listing links.
+const HREF_ESCAPE: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+fn not_found() -> HttpError {
+    HttpError::ProcessingError(HttpStatus::NotFound404)
+}
+
+fn file_error(action: &str, path: &Path, e: std::io::Error) -> HttpError {
+    HttpError::ProcessingError(HttpStatus::InternalServerError500(
+        format!("error {} {:?}: {}", action, path, e)))
+}
+
+/// Returns the request path as a relative path, without empty and `.` segments.
+/// Returns None if the path has a `..` segment or a NUL byte.
+pub(crate) fn relative_path(decoded_path: &str) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for segment in decoded_path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            segment if segment.contains('\0') => return None,
+            segment => relative.push(segment),
+        }
+    }
+    Some(relative)
+}
+
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path.extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=UTF-8",
+        "css" => "text/css; charset=UTF-8",
+        "js" | "mjs" => "text/javascript; charset=UTF-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=UTF-8",
+        "md" => "text/markdown; charset=UTF-8",
+        "csv" => "text/csv; charset=UTF-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Makes a strong ETag from the file's length and modification time.
+fn etag(metadata: &std::fs::Metadata) -> String {
+    let modified_ns = metadata.modified().ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", metadata.len(), modified_ns)
+}
+
+/// Returns true if an `If-None-Match` header value matches `etag`, using weak comparison.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',')
+        .map(|value| value.trim())
+        .any(|value| value == "*" || value.trim_start_matches("W/") == etag)
+}
+
+/// Parses a `Range` header value like `bytes=0-99`, `bytes=100-`, or `bytes=-100`.
+/// Returns the first and last byte positions.
+///
+/// Returns None for headers to ignore: malformed values, other units, and multiple ranges.
+/// Returns `Some(Err(()))` if the range is outside the file.
+fn parse_range(value: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    // https://tools.ietf.org/html/rfc7233#section-2.1
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let dash = spec.find('-')?;
+    let (first, last) = (spec[..dash].trim(), spec[dash + 1..].trim());
+    if first.is_empty() {
+        let suffix_len: u64 = last.parse().ok()?;
+        if suffix_len == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        return Some(Ok((len.saturating_sub(suffix_len), len - 1)));
+    }
+    let first: u64 = first.parse().ok()?;
+    let last: u64 = if last.is_empty() { u64::MAX } else { last.parse().ok()? };
+    if last < first {
+        return None;
+    }
+    if first >= len {
+        return Some(Err(()));
+    }
+    Some(Ok((first, std::cmp::min(last, len - 1))))
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Makes an HTML page with links to `entries`, which are names and is-directory flags.
+fn listing_html(decoded_path: &str, entries: &[(String, bool)]) -> String {
+    let title = escape_html(decoded_path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"UTF-8\"><title>{}</title></head>\n\
+         <body><h1>{}</h1><ul>\n", title, title);
+    if decoded_path != "/" {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (name, is_dir) in entries {
+        let slash = if *is_dir { "/" } else { "" };
+        html.push_str(&format!(
+            "<li><a href=\"{}{}\">{}{}</a></li>\n",
+            percent_encoding::utf8_percent_encode(name, HREF_ESCAPE), slash, escape_html(name), slash));
+    }
+    html.push_str("</ul></body></html>\n");
+    html
+}
+
+/// StaticFiles serves GET and HEAD requests with files from a directory tree.
+///
+/// It rejects paths with `..` segments and paths that lead through symlinks to files outside
+/// the directory.  Requests for a directory get its `index.html` file.  When a directory has no
+/// `index.html`, the handler returns 404, or an HTML list of files if directory listings are on.
+///
+/// Responses have an `ETag` header.  Requests with a matching `If-None-Match` header get
+/// `304 Not Modified`.  Requests with a single-range `Range` header get `206 Partial Content`.
+pub struct StaticFiles {
+    root: PathBuf,
+    directory_listings: bool,
+}
+
+impl StaticFiles {
+    /// Returns an error if `root` does not exist.
+    pub fn new(root: impl AsRef<Path>) -> std::io::Result<StaticFiles> {
+        Ok(StaticFiles { root: std::fs::canonicalize(root)?, directory_listings: false })
+    }
+
+    /// Serves an HTML list of files for directories with no `index.html` file.
+    pub fn directory_listings(mut self, enabled: bool) -> StaticFiles {
+        self.directory_listings = enabled;
+        self
+    }
+
+    /// Returns the path of the file or directory, with symlinks resolved.
+    /// Returns 404 if it does not exist or is outside the root directory.
+    async fn resolve(&self, relative: &Path) -> Result<PathBuf, HttpError> {
+        let path = tokio::fs::canonicalize(self.root.join(relative))
+            .await
+            .map_err(|_e| not_found())?;
+        if !path.starts_with(&self.root) {
+            return Err(not_found());
+        }
+        Ok(path)
+    }
+
+    pub async fn serve(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        let method = http_reader_writer.method();
+        if method != HttpMethod::GET && method != HttpMethod::HEAD {
+            return Err(HttpError::ProcessingError(HttpStatus::MethodNotAllowed405));
+        }
+        // Split off the query before decoding so an encoded '?' stays part of the path.
+        let raw_path = http_reader_writer.raw_path.split('?').next().unwrap();
+        let decoded_path = String::from(percent_decode_str(raw_path)
+            .decode_utf8()
+            .map_err(|_e| HttpError::ParseError(HttpCallerError::PathInvalid))?);
+        let relative = relative_path(&decoded_path).ok_or_else(not_found)?;
+        let mut path = self.resolve(&relative).await?;
+        let metadata = tokio::fs::metadata(&path).await.map_err(|_e| not_found())?;
+        if metadata.is_dir() {
+            if !decoded_path.ends_with('/') {
+                // Relative links in the page need the trailing slash.
+                let mut location = String::from(http_reader_writer.raw_path.split('?').next().unwrap());
+                location.push('/');
+                if let Some(query_index) = http_reader_writer.raw_path.find('?') {
+                    location.push_str(&http_reader_writer.raw_path[query_index..]);
+                }
+                return http_reader_writer.send_without_body(
+                    HttpStatus::MovedPermanently301, &[&Header::new("location", &location)]).await;
+            }
+            match self.resolve(&relative.join("index.html")).await {
+                Ok(index) => path = index,
+                Err(_) if self.directory_listings => {
+                    return self.send_listing(http_reader_writer, &path, &decoded_path, method == HttpMethod::HEAD)
+                        .await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.send_file(http_reader_writer, &path, method == HttpMethod::HEAD).await
+    }
+
+    async fn send_listing(&self, http_reader_writer: &mut HttpReaderWriter<'_>, dir: &Path, decoded_path: &str,
+                          head: bool) -> Result<(), HttpError> {
+        let mut entries = Vec::new();
+        let mut read_dir = tokio::fs::read_dir(dir).await.map_err(|e| file_error("listing", dir, e))?;
+        while let Some(entry) = read_dir.next_entry().await.map_err(|e| file_error("listing", dir, e))? {
+            // Follows symlinks, so a link to a directory gets a trailing slash.
+            let is_dir = tokio::fs::metadata(entry.path()).await.map(|m| m.is_dir()).unwrap_or(false);
+            entries.push((entry.file_name().to_string_lossy().to_string(), is_dir));
+        }
+        entries.sort();
+        let html = listing_html(decoded_path, &entries);
+        if head {
+            let content_type = Header::new("content-type", "text/html; charset=UTF-8");
+            http_reader_writer.send_with_content_length(HttpStatus::Ok200, &[&content_type], html.len() as u64)
+                .await?;
+            http_reader_writer.unsent_content_length = Some(0);
+            return Ok(());
+        }
+        http_reader_writer.send_bytes(HttpStatus::Ok200, &[], "text/html; charset=UTF-8", html.as_bytes()).await
+    }
+
+    async fn send_file(&self, http_reader_writer: &mut HttpReaderWriter<'_>, path: &Path, head: bool)
+                       -> Result<(), HttpError> {
+        let mut file = tokio::fs::File::open(path).await.map_err(|e| file_error("opening", path, e))?;
+        // Get the metadata from the open file, so it matches the data even if the file is replaced.
+        let metadata = file.metadata().await.map_err(|e| file_error("reading", path, e))?;
+        let len = metadata.len();
+        let etag = etag(&metadata);
+        let etag_header = Header::new("etag", &etag);
+        if let Some(if_none_match) = http_reader_writer.header("if-none-match") {
+            if etag_matches(if_none_match, &etag) {
+                return http_reader_writer.send_without_body(HttpStatus::NotModified304, &[&etag_header]).await;
+            }
+        }
+        // A client with an old copy sends `If-Range` to get the whole new file instead of part of it.
+        let range = match http_reader_writer.header("range") {
+            Some(value) if http_reader_writer.header("if-range").map(|v| v == etag).unwrap_or(true) =>
+                parse_range(value, len),
+            _ => None,
+        };
+        let (status, first, body_len, content_range) = match range {
+            None => (HttpStatus::Ok200, 0, len, None),
+            Some(Ok((first, last))) => (
+                HttpStatus::PartialContent206, first, last - first + 1,
+                Some(format!("bytes {}-{}/{}", first, last, len))),
+            Some(Err(())) => {
+                return http_reader_writer.send_without_body(
+                    HttpStatus::RangeNotSatisfiable416,
+                    &[&Header::new("content-range", &format!("bytes */{}", len))]).await;
+            }
+        };
+        let content_type = Header::new("content-type", mime_type(path));
+        let accept_ranges = Header::new("accept-ranges", "bytes");
+        let content_range_header;
+        let mut headers = vec![&content_type, &etag_header, &accept_ranges];
+        if let Some(content_range) = &content_range {
+            content_range_header = Header::new("content-range", content_range);
+            headers.push(&content_range_header);
+        }
+        http_reader_writer.send_with_content_length(status, &headers, body_len).await?;
+        if head {
+            // Responses to HEAD have the headers of a GET response and no body.
+            http_reader_writer.unsent_content_length = Some(0);
+            return Ok(());
+        }
+        tokio::io::AsyncSeekExt::seek(&mut file, std::io::SeekFrom::Start(first))
+            .await
+            .map_err(|e| file_error("reading", path, e))?;
+        tokio::io::copy(&mut tokio::io::AsyncReadExt::take(file, body_len), http_reader_writer)
+            .await
+            .map_err(HttpError::from_io_err)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl HttpHandler for StaticFiles {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        self.serve(http_reader_writer).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(Some(PathBuf::from("")), relative_path("/"));
+        assert_eq!(Some(PathBuf::from("a/b.txt")), relative_path("/a//./b.txt"));
+        assert_eq!(Some(PathBuf::from("a/b")), relative_path("/a/b/"));
+        assert_eq!(Some(PathBuf::from("a\\..")), relative_path("/a\\.."));
+        assert_eq!(None, relative_path("/a/../b"));
+        assert_eq!(None, relative_path("/.."));
+        assert_eq!(None, relative_path("/a\0b"));
+    }
+
+    #[test]
+    fn test_mime_type() {
+        assert_eq!("text/html; charset=UTF-8", mime_type(Path::new("a/index.HTML")));
+        assert_eq!("text/javascript; charset=UTF-8", mime_type(Path::new("app.js")));
+        assert_eq!("image/png", mime_type(Path::new("logo.png")));
+        assert_eq!("application/octet-stream", mime_type(Path::new("data.bin")));
+        assert_eq!("application/octet-stream", mime_type(Path::new("README")));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(Some(Ok((0, 9))), parse_range("bytes=0-9", 100));
+        assert_eq!(Some(Ok((90, 99))), parse_range("bytes=90-", 100));
+        assert_eq!(Some(Ok((90, 99))), parse_range("bytes=90-200", 100));
+        assert_eq!(Some(Ok((80, 99))), parse_range("bytes=-20", 100));
+        assert_eq!(Some(Ok((0, 99))), parse_range("bytes=-200", 100));
+        assert_eq!(Some(Err(())), parse_range("bytes=100-", 100));
+        assert_eq!(Some(Err(())), parse_range("bytes=-0", 100));
+        assert_eq!(None, parse_range("bytes=0-1,5-6", 100));
+        assert_eq!(None, parse_range("bytes=5-1", 100));
+        assert_eq!(None, parse_range("items=0-1", 100));
+        assert_eq!(None, parse_range("bytes=a-", 100));
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        assert!(etag_matches("\"1-2\"", "\"1-2\""));
+        assert!(etag_matches("\"0-0\", W/\"1-2\"", "\"1-2\""));
+        assert!(etag_matches("*", "\"1-2\""));
+        assert!(!etag_matches("\"1-3\"", "\"1-2\""));
+    }
+
+    #[test]
+    fn test_listing_html() {
+        let html = listing_html("/a/", &[(String::from("b c"), true), (String::from("<x>.txt"), false)]);
+        assert!(html.contains("<title>/a/</title>"), "{}", html);
+        assert!(html.contains("<li><a href=\"../\">../</a></li>\n"), "{}", html);
+        assert!(html.contains("<li><a href=\"b%20c/\">b c/</a></li>\n"), "{}", html);
+        assert!(html.contains("<li><a href=\"%3Cx%3E.txt\">&lt;x&gt;.txt</a></li>\n"), "{}", html);
+        assert!(!listing_html("/", &[]).contains("../"));
+    }
+}
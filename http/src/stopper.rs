@@ -0,0 +1,147 @@
+use tokio::sync::mpsc;
+
+/// Waits for a TERM or INT signal.
+///
+/// Handles TERM signal for running in Docker, Kubernetes, supervisord, etc.
+/// Also handles INT signal from CTRL-C in dev terminal.
+///
+/// Panics if it fails to install the signal handlers.
+pub async fn wait_for_stop_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut term_signal =
+        signal(SignalKind::terminate()).expect("Failed installing TERM signal handler");
+    let mut int_signal =
+        signal(SignalKind::interrupt()).expect("Failed installing INT signal handler");
+    use tokio::stream::StreamExt;
+    futures::future::select(term_signal.next(), int_signal.next()).await;
+}
+
+/// StopperController signals tasks to stop and waits for them to finish.
+///
+/// Make one with `new_stopper()`.
+pub struct StopperController {
+    signal_tx: mpsc::Sender<()>,
+    signal_rx: mpsc::Receiver<()>,
+    tracker_rx: mpsc::Receiver<()>,
+}
+
+impl StopperController {
+    /// Returns a `StopSignal`.  The controller does not wait for it to drop.
+    pub fn stop_signal(&self) -> StopSignal {
+        StopSignal { signal_tx: self.signal_tx.clone() }
+    }
+
+    /// Signals every `Stopper` to stop.  Tasks blocked in `Stopper::wait()` return.
+    pub fn signal_stop(&mut self) {
+        self.signal_rx.close();
+    }
+
+    /// Waits until every `Stopper` is dropped.
+    pub async fn wait(&mut self) {
+        self.tracker_rx.recv().await;
+    }
+}
+
+/// Stopper tells a task when to stop.
+///
+/// Clone it and give a copy to each task.
+/// The `StopperController` tracks the clones and can wait until they are all dropped.
+#[derive(Clone)]
+pub struct Stopper {
+    signal_tx: mpsc::Sender<()>,
+    // The controller waits until every clone of this sender is dropped.
+    _tracker_tx: mpsc::Sender<()>,
+}
+
+impl Stopper {
+    /// Waits until the controller signals stop.
+    pub async fn wait(&mut self) {
+        // The send() call always waits because new_stopper() fills the channel.
+        let _ = self.signal_tx.send(()).await;
+    }
+
+    /// Returns true if the controller signaled stop.
+    pub fn is_signalled(&mut self) -> bool {
+        matches!(self.signal_tx.try_send(()), Err(mpsc::error::TrySendError::Closed(_)))
+    }
+
+    /// Returns a `StopSignal`.  The controller does not wait for it to drop.
+    pub fn stop_signal(&self) -> StopSignal {
+        StopSignal { signal_tx: self.signal_tx.clone() }
+    }
+}
+
+/// StopSignal tells whether a `StopperController` signaled stop.
+///
+/// Unlike `Stopper`, the controller does not wait for it to drop.  Use it in long-lived
+/// objects that do not need to finish work before the process stops, like readiness checks.
+#[derive(Clone)]
+pub struct StopSignal {
+    signal_tx: mpsc::Sender<()>,
+}
+
+impl StopSignal {
+    /// Returns true if the controller signaled stop.
+    pub fn is_signalled(&self) -> bool {
+        matches!(self.signal_tx.clone().try_send(()), Err(mpsc::error::TrySendError::Closed(_)))
+    }
+}
+
+pub fn new_stopper() -> (StopperController, Stopper) {
+    let (mut signal_tx, signal_rx) = mpsc::channel(1);
+    signal_tx.try_send(()).unwrap(); // Fill the channel so senders wait.
+    let (tracker_tx, tracker_rx) = mpsc::channel(1);
+    (
+        StopperController {
+            signal_tx: signal_tx.clone(),
+            signal_rx,
+            tracker_rx,
+        },
+        Stopper {
+            signal_tx,
+            _tracker_tx: tracker_tx,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_signal_stop() {
+        let (mut controller, mut stopper) = new_stopper();
+        let mut stopper2 = stopper.clone();
+        assert!(!stopper.is_signalled());
+        assert!(tokio::time::timeout(Duration::from_millis(10), stopper.wait()).await.is_err());
+        controller.signal_stop();
+        assert!(stopper.is_signalled());
+        assert!(stopper2.is_signalled());
+        stopper.wait().await;
+    }
+
+    #[tokio::test]
+    async fn test_stop_signal() {
+        let (mut controller, stopper) = new_stopper();
+        let stop_signal = controller.stop_signal();
+        let stop_signal2 = stopper.stop_signal();
+        drop(stopper);
+        // The controller does not wait for stop signals.
+        controller.wait().await;
+        assert!(!stop_signal.is_signalled());
+        controller.signal_stop();
+        assert!(stop_signal.is_signalled());
+        assert!(stop_signal2.is_signalled());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_stoppers_to_drop() {
+        let (mut controller, stopper) = new_stopper();
+        let stopper2 = stopper.clone();
+        drop(stopper);
+        assert!(tokio::time::timeout(Duration::from_millis(10), controller.wait()).await.is_err());
+        drop(stopper2);
+        controller.wait().await;
+    }
+}
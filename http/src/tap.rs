@@ -0,0 +1,567 @@
+// Records the bytes that pass through a connection, with timestamps, so exchanges can be
+// saved, masked, and replayed against a server.
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{escape_ascii, is_non_pii_header};
+
+/// Taps stop recording after this many bytes, unless made with `Tap::with_max_len`.
+pub const DEFAULT_MAX_LEN: usize = 1024 * 1024;
+const MASK: u8 = b'*';
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Bytes read from the peer.  On a server, these are requests.
+    Received,
+    /// Bytes written to the peer.  On a server, these are responses.
+    Sent,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Received => "recv",
+            Direction::Sent => "sent",
+        }
+    }
+}
+
+/// Event is the bytes from one read or write.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// Time since the tap started.
+    pub elapsed: Duration,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+/// Transcript is the events recorded by a `Tap`, in order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Transcript {
+    pub events: Vec<Event>,
+    /// True when the tap reached its maximum length and dropped later bytes.
+    pub truncated: bool,
+}
+
+/// Returns the bytes of `s`, a string made with `escape_ascii`.
+fn unescape_ascii(s: &str) -> Result<Vec<u8>, String> {
+    let mut result = Vec::new();
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            result.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b't') => result.push(b'\t'),
+            Some(b'r') => result.push(b'\r'),
+            Some(b'n') => result.push(b'\n'),
+            Some(b @ b'\\') | Some(b @ b'\'') | Some(b @ b'"') => result.push(b),
+            Some(b'x') => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                let value = std::str::from_utf8(&hex).ok()
+                    .filter(|h| h.len() == 2)
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    .ok_or_else(|| format!("bad escape in {:?}", s))?;
+                result.push(value);
+            }
+            _ => return Err(format!("bad escape in {:?}", s)),
+        }
+    }
+    Ok(result)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Masks the values of header lines in `head` that may carry PII.  Leaves the first line.
+fn mask_head(head: &mut [u8]) -> (Option<u64>, bool) {
+    let mut content_length = None;
+    let mut chunked = false;
+    let mut start = match find(head, b"\r\n") {
+        Some(index) => index + 2,
+        None => return (None, false),
+    };
+    while let Some(len) = find(&head[start..], b"\r\n") {
+        let line = &mut head[start..start + len];
+        if let Some(colon) = line.iter().position(|b| *b == b':') {
+            let name = String::from_utf8_lossy(&line[..colon]).trim().to_string();
+            let value = String::from_utf8_lossy(&line[colon + 1..]).trim().to_string();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.parse().ok();
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                chunked = value.eq_ignore_ascii_case("chunked");
+            }
+            if !is_non_pii_header(&name) {
+                line[colon + 1..].iter_mut().filter(|b| **b != b' ').for_each(|b| *b = MASK);
+            }
+        }
+        start += len + 2;
+    }
+    (content_length, chunked)
+}
+
+/// Masks the body of a chunked message at the start of `data`, keeping the chunk sizes.
+/// Returns the length of the body, or `None` if it does not end in `data`.
+fn mask_chunked_body(data: &mut [u8]) -> Option<usize> {
+    let mut index = 0;
+    loop {
+        let line_len = find(&data[index..], b"\r\n")?;
+        let size_line = std::str::from_utf8(&data[index..index + line_len]).ok()?;
+        let size = usize::from_str_radix(size_line.split(';').next()?.trim(), 16).ok()?;
+        index += line_len + 2;
+        if size == 0 {
+            let trailers_len = find(&data[index - 2..], b"\r\n\r\n")? + 2;
+            mask_head(&mut data[index - 2..index + trailers_len]);
+            return Some(index + trailers_len);
+        }
+        if data.len() < index + size + 2 {
+            return None;
+        }
+        data[index..index + size].iter_mut().for_each(|b| *b = MASK);
+        index += size + 2;
+    }
+}
+
+/// Masks PII in the HTTP messages in `data`: header values, except for framing headers,
+/// and bodies.  Keeps request and status lines and the framing, so masked requests are still
+/// valid requests.  Masks everything after a message it cannot frame.
+fn mask_messages(data: &mut [u8], direction: Direction) {
+    let mut index = 0;
+    while index < data.len() {
+        let head_len = match find(&data[index..], b"\r\n\r\n") {
+            Some(len) => len + 4,
+            None => break,
+        };
+        let interim = direction == Direction::Sent && data[index..].starts_with(b"HTTP/1.1 1");
+        let no_body = interim || data[index..].starts_with(b"HTTP/1.1 204")
+            || data[index..].starts_with(b"HTTP/1.1 304");
+        let (content_length, chunked) = mask_head(&mut data[index..index + head_len]);
+        index += head_len;
+        let body_len = if no_body {
+            Some(0)
+        } else if chunked {
+            mask_chunked_body(&mut data[index..])
+        } else if let Some(len) = content_length {
+            let len = std::cmp::min(len, (data.len() - index) as u64) as usize;
+            data[index..index + len].iter_mut().for_each(|b| *b = MASK);
+            Some(len)
+        } else if direction == Direction::Received {
+            Some(0)
+        } else {
+            // The response body lasts until the connection closes.
+            None
+        };
+        match body_len {
+            Some(len) => index += len,
+            None => break,
+        }
+    }
+    data[index..].iter_mut().filter(|b| **b != b'\r' && **b != b'\n').for_each(|b| *b = MASK);
+}
+
+impl Transcript {
+    /// Returns all of the bytes that went in `direction`.
+    pub fn bytes(&self, direction: Direction) -> Vec<u8> {
+        self.events.iter()
+            .filter(|event| event.direction == direction)
+            .flat_map(|event| event.data.iter().copied())
+            .collect()
+    }
+
+    /// Returns a copy with PII replaced by `*`: header values, except for framing headers
+    /// like `content-length`, and bodies.  Request lines and status lines stay.
+    /// Masked bytes keep their length, so the masked requests can still be replayed.
+    pub fn masked(&self) -> Transcript {
+        let mut masked = self.clone();
+        for direction in &[Direction::Received, Direction::Sent] {
+            let mut data = self.bytes(*direction);
+            mask_messages(&mut data, *direction);
+            let mut remaining = &data[..];
+            for event in masked.events.iter_mut().filter(|event| event.direction == *direction) {
+                let (event_data, rest) = remaining.split_at(event.data.len());
+                event.data = event_data.to_vec();
+                remaining = rest;
+            }
+        }
+        masked
+    }
+
+    /// Writes the transcript as text, one event per line, like
+    /// `1532 recv GET / HTTP/1.1\r\n\r\n`, with the microseconds since the tap started.
+    /// The data is encoded with `escape_ascii`.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for event in &self.events {
+            text.push_str(&format!(
+                "{} {} {}\n", event.elapsed.as_micros(), event.direction.as_str(), escape_ascii(&event.data)));
+        }
+        if self.truncated {
+            text.push_str("truncated\n");
+        }
+        text
+    }
+
+    /// Parses text made by `to_text`.  Skips empty lines and lines starting with `#`.
+    pub fn parse(text: &str) -> Result<Transcript, String> {
+        let mut transcript = Transcript::default();
+        for line in text.lines() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line == "truncated" {
+                transcript.truncated = true;
+                continue;
+            }
+            let mut parts = line.splitn(3, ' ');
+            let elapsed = parts.next().and_then(|micros| micros.parse().ok()).map(Duration::from_micros);
+            let direction = match parts.next() {
+                Some("recv") => Some(Direction::Received),
+                Some("sent") => Some(Direction::Sent),
+                _ => None,
+            };
+            match (elapsed, direction, parts.next()) {
+                (Some(elapsed), Some(direction), Some(data)) =>
+                    transcript.events.push(Event { elapsed, direction, data: unescape_ascii(data)? }),
+                _ => return Err(format!("bad transcript line {:?}", line)),
+            }
+        }
+        Ok(transcript)
+    }
+}
+
+struct TapState {
+    start: Instant,
+    len: usize,
+    max_len: usize,
+    transcript: Transcript,
+}
+
+/// Tap records the bytes read by `TapReader`s and written by `TapWriter`s into one `Transcript`.
+/// Clones share the transcript.
+///
+/// `HttpServerBuilder::tap` taps every connection.
+#[derive(Clone)]
+pub struct Tap {
+    state: Arc<Mutex<TapState>>,
+}
+
+impl Tap {
+    /// Makes a tap that records up to `DEFAULT_MAX_LEN` bytes.
+    pub fn new() -> Tap {
+        Tap::with_max_len(DEFAULT_MAX_LEN)
+    }
+
+    /// Makes a tap that records up to `max_len` bytes, and then sets `Transcript::truncated`.
+    pub fn with_max_len(max_len: usize) -> Tap {
+        Tap {
+            state: Arc::new(Mutex::new(TapState {
+                start: Instant::now(),
+                len: 0,
+                max_len,
+                transcript: Transcript::default(),
+            }))
+        }
+    }
+
+    /// Returns a copy of the events recorded so far.
+    pub fn transcript(&self) -> Transcript {
+        self.state.lock().unwrap().transcript.clone()
+    }
+
+    fn record(&self, direction: Direction, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let len = std::cmp::min(data.len(), state.max_len - state.len);
+        if len < data.len() {
+            state.transcript.truncated = true;
+        }
+        if len == 0 {
+            return;
+        }
+        state.len += len;
+        let elapsed = state.start.elapsed();
+        state.transcript.events.push(Event { elapsed, direction, data: data[..len].to_vec() });
+    }
+}
+
+impl Default for Tap {
+    fn default() -> Self {
+        Tap::new()
+    }
+}
+
+/// TapReader is an `AsyncRead` that records the bytes it reads from `inner` in a `Tap`.
+/// With no tap, it just reads.
+pub struct TapReader<R> {
+    inner: R,
+    tap: Option<Tap>,
+}
+
+impl<R> TapReader<R> {
+    pub fn new(inner: R, tap: Option<Tap>) -> TapReader<R> {
+        TapReader { inner, tap }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TapReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8])
+                 -> Poll<tokio::io::Result<usize>> {
+        let mut_self = self.get_mut();
+        let result = Pin::new(&mut mut_self.inner).poll_read(cx, buf);
+        if let (Some(tap), Poll::Ready(Ok(len))) = (&mut_self.tap, &result) {
+            tap.record(Direction::Received, &buf[..*len]);
+        }
+        result
+    }
+}
+
+/// TapWriter is an `AsyncWrite` that records the bytes it writes to `inner` in a `Tap`.
+/// With no tap, it just writes.
+pub struct TapWriter<W> {
+    inner: W,
+    tap: Option<Tap>,
+}
+
+impl<W> TapWriter<W> {
+    pub fn new(inner: W, tap: Option<Tap>) -> TapWriter<W> {
+        TapWriter { inner, tap }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for TapWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+                  -> Poll<tokio::io::Result<usize>> {
+        let mut_self = self.get_mut();
+        let result = Pin::new(&mut mut_self.inner).poll_write(cx, buf);
+        if let (Some(tap), Poll::Ready(Ok(len))) = (&mut_self.tap, &result) {
+            tap.record(Direction::Sent, &buf[..*len]);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Sends the received bytes of `transcript` to a server on `stream`, in the recorded reads,
+/// then shuts down writing and returns everything the server sends until it closes the connection.
+///
+/// Compare the result with `transcript.bytes(Direction::Sent)` using `diff`.
+pub async fn replay<S>(transcript: &Transcript, stream: S) -> std::io::Result<Vec<u8>>
+    where S: AsyncRead + AsyncWrite + Unpin {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let send = async {
+        for event in transcript.events.iter().filter(|event| event.direction == Direction::Received) {
+            writer.write_all(&event.data).await?;
+        }
+        writer.shutdown().await
+    };
+    let mut response = Vec::new();
+    let (send_result, receive_result) = futures::future::join(send, reader.read_to_end(&mut response)).await;
+    send_result?;
+    receive_result?;
+    Ok(response)
+}
+
+// The largest table `diff` builds to find the fewest changed lines.  With more lines, after
+// removing the common start and end, it lists the remaining lines as removed and then added.
+const MAX_DIFF_CELLS: usize = 1 << 20;
+
+/// Compares `expected` and `actual` line by line.
+/// Returns `None` when they are the same.  Otherwise returns the lines, encoded with `escape_ascii`,
+/// with `-` before lines only in `expected` and `+` before lines only in `actual`.
+pub fn diff(expected: &[u8], actual: &[u8]) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+    fn lines(data: &[u8]) -> Vec<&[u8]> {
+        data.split_inclusive(|b| *b == b'\n').collect()
+    }
+    let (all_a, all_b) = (lines(expected), lines(actual));
+    let prefix_len = all_a.iter().zip(&all_b).take_while(|(x, y)| x == y).count();
+    let suffix_len = all_a[prefix_len..].iter().rev().zip(all_b[prefix_len..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (a, b) = (&all_a[prefix_len..all_a.len() - suffix_len], &all_b[prefix_len..all_b.len() - suffix_len]);
+    let mut result = String::new();
+    for line in &all_a[..prefix_len] {
+        result.push_str(&format!("  {}\n", escape_ascii(line)));
+    }
+    if (a.len() + 1).saturating_mul(b.len() + 1) > MAX_DIFF_CELLS {
+        for line in a {
+            result.push_str(&format!("- {}\n", escape_ascii(line)));
+        }
+        for line in b {
+            result.push_str(&format!("+ {}\n", escape_ascii(line)));
+        }
+    } else {
+        // common[i][j] is the length of the longest common subsequence of a[i..] and b[j..].
+        let mut common = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                common[i][j] = if a[i] == b[j] {
+                    common[i + 1][j + 1] + 1
+                } else {
+                    std::cmp::max(common[i + 1][j], common[i][j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            if i < a.len() && j < b.len() && a[i] == b[j] {
+                result.push_str(&format!("  {}\n", escape_ascii(a[i])));
+                i += 1;
+                j += 1;
+            } else if i < a.len() && (j == b.len() || common[i + 1][j] >= common[i][j + 1]) {
+                result.push_str(&format!("- {}\n", escape_ascii(a[i])));
+                i += 1;
+            } else {
+                result.push_str(&format!("+ {}\n", escape_ascii(b[j])));
+                j += 1;
+            }
+        }
+    }
+    for line in &all_a[all_a.len() - suffix_len..] {
+        result.push_str(&format!("  {}\n", escape_ascii(line)));
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(direction: Direction, data: &[u8]) -> Event {
+        Event { elapsed: Duration::from_micros(0), direction, data: data.to_vec() }
+    }
+
+    #[tokio::test]
+    async fn test_tap() {
+        let tap = Tap::with_max_len(10);
+        let mut reader = TapReader::new(&b"abc"[..], Some(tap.clone()));
+        let mut writer = TapWriter::new(Vec::new(), Some(tap.clone()));
+        let mut buf = [0u8; 2];
+        reader.read_exact(&mut buf).await.unwrap();
+        writer.write_all(b"def").await.unwrap();
+        reader.read_exact(&mut buf[..1]).await.unwrap();
+        let transcript = tap.transcript();
+        assert!(!transcript.truncated);
+        let events: Vec<(Direction, &[u8])> =
+            transcript.events.iter().map(|e| (e.direction, &e.data[..])).collect();
+        assert_eq!(vec![
+            (Direction::Received, &b"ab"[..]),
+            (Direction::Sent, &b"def"[..]),
+            (Direction::Received, &b"c"[..]),
+        ], events);
+        assert!(transcript.events[0].elapsed <= transcript.events[2].elapsed);
+
+        writer.write_all(b"ghijk").await.unwrap();
+        assert_eq!(b"defghijk", &writer.inner[..]);
+        let transcript = tap.transcript();
+        assert!(transcript.truncated);
+        assert_eq!(b"defghij", &transcript.bytes(Direction::Sent)[..]);
+
+        let mut untapped = TapReader::new(&b"abc"[..], None);
+        untapped.read_exact(&mut buf).await.unwrap();
+    }
+
+    #[test]
+    fn test_text() {
+        let transcript = Transcript {
+            events: vec![
+                Event { elapsed: Duration::from_micros(12), direction: Direction::Received,
+                    data: b"GET / HTTP/1.1\r\n\r\n".to_vec() },
+                Event { elapsed: Duration::from_micros(3456), direction: Direction::Sent,
+                    data: b"a b\t\"\\\'\x00\xff".to_vec() },
+            ],
+            truncated: true,
+        };
+        let text = transcript.to_text();
+        assert_eq!(
+            "12 recv GET / HTTP/1.1\\r\\n\\r\\n\n3456 sent a b\\t\\\"\\\\\\\'\\x00\\xff\ntruncated\n", text);
+        assert_eq!(transcript, Transcript::parse(&("# comment\n\n".to_string() + &text)).unwrap());
+        for text in &["12 recv", "x recv a", "12 got a", "12 recv \\x", "12 recv \\xzz", "12 recv \\q"] {
+            assert!(Transcript::parse(text).is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_masked() {
+        let request = "POST /a HTTP/1.1\r\nCookie: id=1\r\nContent-Length: 3\r\n\r\nabcGET / HTTP/1.1\r\n\r\n";
+        let response = "HTTP/1.1 100 Continue\r\n\r\n\
+            HTTP/1.1 200 OK\r\nx-user: bob\r\ntransfer-encoding: chunked\r\n\r\n2\r\nab\r\n0\r\nx: y\r\n\r\n\
+            HTTP/1.1 304 Not Modified\r\netag: 1\r\n\r\n\
+            HTTP/1.1 200 OK\r\n\r\nsecret\r\n";
+        let transcript = Transcript {
+            events: vec![
+                event(Direction::Received, &request.as_bytes()[..20]),
+                event(Direction::Received, &request.as_bytes()[20..]),
+                event(Direction::Sent, response.as_bytes()),
+            ],
+            truncated: false,
+        };
+        let masked = transcript.masked();
+        assert_eq!(
+            "POST /a HTTP/1.1\r\nCookie: ****\r\nContent-Length: 3\r\n\r\n***GET / HTTP/1.1\r\n\r\n",
+            String::from_utf8(masked.bytes(Direction::Received)).unwrap());
+        assert_eq!(20, masked.events[0].data.len());
+        assert_eq!(
+            "HTTP/1.1 100 Continue\r\n\r\n\
+            HTTP/1.1 200 OK\r\nx-user: ***\r\ntransfer-encoding: chunked\r\n\r\n2\r\n**\r\n0\r\nx: *\r\n\r\n\
+            HTTP/1.1 304 Not Modified\r\netag: *\r\n\r\n\
+            HTTP/1.1 200 OK\r\n\r\n******\r\n",
+            String::from_utf8(masked.bytes(Direction::Sent)).unwrap());
+        // Garbage gets masked.
+        let garbage = Transcript { events: vec![event(Direction::Received, b"hello\r\nx")], truncated: false };
+        assert_eq!(b"*****\r\n*", &garbage.masked().bytes(Direction::Received)[..]);
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let transcript = Transcript {
+            events: vec![event(Direction::Received, b"abc"), event(Direction::Received, b"def")],
+            truncated: false,
+        };
+        let (client, mut server) = crate::memory_stream::memory_stream_pair();
+        let server_task = tokio::spawn(async move {
+            let mut request = Vec::new();
+            server.read_to_end(&mut request).await.unwrap();
+            server.write_all(b"response").await.unwrap();
+            server.shutdown().await.unwrap();
+            request
+        });
+        assert_eq!(b"response", &replay(&transcript, client).await.unwrap()[..]);
+        assert_eq!(b"abcdef", &server_task.await.unwrap()[..]);
+    }
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(None, diff(b"a\nb\n", b"a\nb\n"));
+        assert_eq!(
+            "  a\\n\n- b\\r\\n\n+ B\\r\\n\n  c\\n\n+ d\n",
+            diff(b"a\nb\r\nc\n", b"a\nB\r\nc\nd").unwrap());
+        assert_eq!("  a\\n\n+ b\\n\n  c\\n\n", diff(b"a\nc\n", b"a\nb\nc\n").unwrap());
+        assert_eq!("- x\\n\n  y\\n\n", diff(b"x\ny\n", b"y\n").unwrap());
+
+        // Too many lines for the table.
+        let expected: Vec<u8> = (0..2000).flat_map(|n| format!("{}\n", n).into_bytes()).collect();
+        let actual: Vec<u8> = (0..2000).flat_map(|n| format!("{}\n", 1999 - n).into_bytes()).collect();
+        let result = diff(&expected, &actual).unwrap();
+        assert_eq!(4000, result.lines().count());
+        assert!(result.starts_with("- 0\\n\n"), "{:?}", &result[..20]);
+        assert!(result.ends_with("+ 0\\n\n"), "{:?}", &result[result.len() - 20..]);
+    }
+}
@@ -0,0 +1,334 @@
+// Scripted readers and writers for testing code that uses AsyncRead and AsyncWrite,
+// and helpers that run requests through an `HttpReaderWriter` without a network.
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::faulty_stream::{test_faults, FaultyStream};
+use crate::server::HttpHandler;
+use crate::transport::PeerAddr;
+use crate::{escape_ascii, HttpError, HttpReaderWriter};
+
+enum ReadStep {
+    Data(Vec<u8>),
+    Pending,
+    Error(std::io::ErrorKind),
+}
+
+/// ScriptedReader is an `AsyncRead` that returns data, `Pending`, and errors in the order you
+/// add them.  Reads after the end of the script return EOF.
+#[derive(Default)]
+pub struct ScriptedReader {
+    steps: VecDeque<ReadStep>,
+}
+
+impl ScriptedReader {
+    pub fn new() -> ScriptedReader {
+        ScriptedReader { steps: VecDeque::new() }
+    }
+
+    /// Makes reads return `data`, in as many reads as it takes.
+    /// Empty data makes one read return 0, which callers treat as EOF.
+    pub fn data(mut self, data: &[u8]) -> ScriptedReader {
+        self.steps.push_back(ReadStep::Data(data.to_vec()));
+        self
+    }
+
+    /// Makes the next read return `Pending`.  The reader wakes the task right away.
+    pub fn pending(mut self) -> ScriptedReader {
+        self.steps.push_back(ReadStep::Pending);
+        self
+    }
+
+    /// Makes the next read return an error with `kind`.
+    pub fn error(mut self, kind: std::io::ErrorKind) -> ScriptedReader {
+        self.steps.push_back(ReadStep::Error(kind));
+        self
+    }
+
+    /// Returns true when every step of the script has happened.
+    pub fn is_done(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+impl AsyncRead for ScriptedReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8])
+                 -> Poll<tokio::io::Result<usize>> {
+        let steps = &mut self.get_mut().steps;
+        match steps.pop_front() {
+            None => Poll::Ready(Ok(0)),
+            Some(ReadStep::Data(mut data)) => {
+                let len = std::cmp::min(buf.len(), data.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                if len < data.len() {
+                    steps.push_front(ReadStep::Data(data.split_off(len)));
+                }
+                Poll::Ready(Ok(len))
+            }
+            Some(ReadStep::Pending) => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Some(ReadStep::Error(kind)) => Poll::Ready(Err(std::io::Error::new(kind, "scripted error"))),
+        }
+    }
+}
+
+enum WriteStep {
+    Accept(usize),
+    Pending,
+    Error(std::io::ErrorKind),
+}
+
+/// ScriptedWriter is an `AsyncWrite` that records the bytes written to it.
+/// Its writes do what you add to the script, in order: accept some bytes, return `Pending`,
+/// or return an error.  Writes after the end of the script accept every byte.
+#[derive(Default)]
+pub struct ScriptedWriter {
+    steps: VecDeque<WriteStep>,
+    written: Vec<u8>,
+    shut_down: bool,
+}
+
+impl ScriptedWriter {
+    pub fn new() -> ScriptedWriter {
+        ScriptedWriter { steps: VecDeque::new(), written: Vec::new(), shut_down: false }
+    }
+
+    /// Makes the next write accept at most `max_len` bytes.
+    pub fn short_write(mut self, max_len: usize) -> ScriptedWriter {
+        self.steps.push_back(WriteStep::Accept(max_len));
+        self
+    }
+
+    /// Makes the next write return `Pending`.  The writer wakes the task right away.
+    pub fn pending(mut self) -> ScriptedWriter {
+        self.steps.push_back(WriteStep::Pending);
+        self
+    }
+
+    /// Makes the next write return an error with `kind`.
+    pub fn error(mut self, kind: std::io::ErrorKind) -> ScriptedWriter {
+        self.steps.push_back(WriteStep::Error(kind));
+        self
+    }
+
+    /// Returns the bytes written so far.
+    pub fn written(&self) -> &[u8] {
+        &self.written
+    }
+
+    /// Returns true if the writer was shut down.
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down
+    }
+
+    /// Panics if the bytes written so far are not `expected`.
+    /// The message shows both, with non-printable bytes escaped.
+    pub fn assert_written(&self, expected: &[u8]) {
+        if self.written != expected {
+            panic!("ScriptedWriter got {:?}, expected {:?}", escape_ascii(&self.written), escape_ascii(expected));
+        }
+    }
+}
+
+impl AsyncWrite for ScriptedWriter {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+                  -> Poll<tokio::io::Result<usize>> {
+        let mut_self = self.get_mut();
+        if mut_self.shut_down {
+            return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "writer shut down")));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let len = match mut_self.steps.pop_front() {
+            None => buf.len(),
+            Some(WriteStep::Accept(max_len)) => std::cmp::min(buf.len(), max_len),
+            Some(WriteStep::Pending) => {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Some(WriteStep::Error(kind)) =>
+                return Poll::Ready(Err(std::io::Error::new(kind, "scripted error"))),
+        };
+        mut_self.written.extend_from_slice(&buf[..len]);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        self.get_mut().shut_down = true;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Reads one request from `reader` and passes it to `handler`, like `HttpServer` does,
+/// with peer address `PeerAddr::Test(0)`.  Returns `writer`, holding the response.
+/// Usually `reader` and `writer` are a `ScriptedReader` and `ScriptedWriter`, sometimes wrapped
+/// in `FaultyStream`.
+///
+/// When reading the request fails, or the handler returns an error before responding,
+/// the response has the error's status.
+pub async fn round_trip_with<H, R, W>(handler: &H, mut reader: R, mut writer: W) -> W
+    where H: HttpHandler + ?Sized, R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send {
+    {
+        let mut http_reader_writer =
+            HttpReaderWriter::new(Pin::new(&mut reader), Pin::new(&mut writer), PeerAddr::Test(0));
+        let result = match http_reader_writer.read_request(&mut []).await {
+            Ok(()) => handler.handle(&mut http_reader_writer).await,
+            Err(e) => Err(e),
+        };
+        if http_reader_writer.status().is_none() {
+            match result {
+                Err(HttpError::ParseError(e)) => { let _ = http_reader_writer.send_simple(e.status()).await; }
+                Err(HttpError::ProcessingError(status)) => { let _ = http_reader_writer.send_simple(status).await; }
+                Ok(()) | Err(HttpError::IoError(_)) => {}
+            }
+        }
+    }
+    writer
+}
+
+/// Sends `request` to `handler` with `round_trip_with` and returns the response.
+/// Replaces bytes that are not UTF-8 with U+FFFD.
+pub async fn round_trip<H: HttpHandler + ?Sized>(handler: &H, request: &str) -> String {
+    let writer = round_trip_with(handler, ScriptedReader::new().data(request.as_bytes()), ScriptedWriter::new()).await;
+    String::from_utf8_lossy(writer.written()).into_owned()
+}
+
+/// Sends `request` to `handler` with `round_trip_with` once for each config in `test_faults`,
+/// with the reader and writer wrapped in `FaultyStream`, and returns the response.
+/// Panics if the responses differ.
+pub async fn round_trip_with_faults<H: HttpHandler + ?Sized>(handler: &H, request: &str) -> String {
+    let mut first_response: Option<String> = None;
+    for faults in test_faults() {
+        let reader = FaultyStream::new(ScriptedReader::new().data(request.as_bytes()), faults.clone());
+        let writer = FaultyStream::new(ScriptedWriter::new(), faults.clone());
+        let writer = round_trip_with(handler, reader, writer).await;
+        let response = String::from_utf8_lossy(writer.get_ref().written()).into_owned();
+        match &first_response {
+            None => first_response = Some(response),
+            Some(first) => assert_eq!(first, &response, "{:?}", faults),
+        }
+    }
+    first_response.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::HttpStatus;
+
+    struct EchoHandler;
+
+    #[async_trait]
+    impl HttpHandler for EchoHandler {
+        async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+            let body = http_reader_writer.read_body(1000).await?;
+            if body.is_empty() {
+                return Err(HttpError::ProcessingError(HttpStatus::BadRequest400));
+            }
+            http_reader_writer.send_bytes(HttpStatus::Ok200, &[], "text/plain", &body).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scripted_reader() {
+        let mut reader = ScriptedReader::new()
+            .data(b"abc").pending().data(b"d").error(std::io::ErrorKind::ConnectionReset).data(b"e");
+        let mut buf = [0u8; 2];
+        assert_eq!(2, reader.read(&mut buf).await.unwrap());
+        assert_eq!(b"ab", &buf);
+        assert_eq!(1, reader.read(&mut buf).await.unwrap());
+        assert_eq!(b'c', buf[0]);
+        // `read` polls again after the reader wakes the task.
+        assert_eq!(1, reader.read(&mut buf).await.unwrap());
+        assert_eq!(b'd', buf[0]);
+        assert_eq!(std::io::ErrorKind::ConnectionReset, reader.read(&mut buf).await.unwrap_err().kind());
+        assert!(!reader.is_done());
+        assert_eq!(1, reader.read(&mut buf).await.unwrap());
+        assert!(reader.is_done());
+        assert_eq!(0, reader.read(&mut buf).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_scripted_writer() {
+        let mut writer = ScriptedWriter::new()
+            .short_write(2).pending().short_write(5).error(std::io::ErrorKind::BrokenPipe);
+        assert_eq!(2, writer.write(b"abc").await.unwrap());
+        // `write` polls again after the writer wakes the task.
+        assert_eq!(1, writer.write(b"c").await.unwrap());
+        assert_eq!(std::io::ErrorKind::BrokenPipe, writer.write(b"d").await.unwrap_err().kind());
+        writer.write_all(b"efg").await.unwrap();
+        writer.assert_written(b"abcefg");
+        assert!(!writer.is_shut_down());
+        writer.shutdown().await.unwrap();
+        assert!(writer.is_shut_down());
+        assert!(writer.write(b"h").await.is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "ScriptedWriter got \"a\\\\r\\\\n\", expected \"b\"")]
+    fn test_assert_written() {
+        let mut writer = ScriptedWriter::new();
+        tokio::runtime::Runtime::new().unwrap().block_on(writer.write_all(b"a\r\n")).unwrap();
+        writer.assert_written(b"b");
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        // The request arrives in pieces and the response leaves in pieces.
+        let reader = ScriptedReader::new()
+            .data(b"POST / HTTP/1.1\r\ncontent-").pending().data(b"length: 5\r\n\r\nab").pending().data(b"cde");
+        let writer = ScriptedWriter::new().short_write(3).pending().short_write(10);
+        let writer = round_trip_with(&EchoHandler, reader, writer).await;
+        writer.assert_written(
+            b"HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: 5\r\n\r\nabcde");
+
+        assert_eq!(
+            "HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n",
+            round_trip(&EchoHandler, "POST / HTTP/1.1\r\n\r\n").await);
+        assert_eq!(
+            "HTTP/1.1 405 Method Not Allowed\r\ncontent-length: 0\r\n\r\n",
+            round_trip(&EchoHandler, "post / HTTP/1.1\r\n\r\n").await);
+        // The body ends early.
+        assert_eq!("", round_trip(&EchoHandler, "POST / HTTP/1.1\r\ncontent-length: 5\r\n\r\nab").await);
+        let reader = ScriptedReader::new().data(b"POST / HTTP/1.1\r\ncontent-length: 1\r\n\r\na");
+        let writer = ScriptedWriter::new().error(std::io::ErrorKind::ConnectionReset);
+        round_trip_with(&EchoHandler, reader, writer).await.assert_written(b"");
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_with_faults() {
+        let body = "0123456789".repeat(50);
+        assert_eq!(
+            format!("HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: {}\r\n\r\n{}",
+                    body.len(), body),
+            round_trip_with_faults(
+                &EchoHandler,
+                &format!("POST /a?b=c HTTP/1.1\r\nx-a: 1\r\ncontent-length: {}\r\n\r\n{}", body.len(), body),
+            ).await);
+        assert_eq!(
+            "HTTP/1.1 100 Continue\r\n\r\n\
+             HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: 5\r\n\r\nabcde",
+            round_trip_with_faults(
+                &EchoHandler, "POST / HTTP/1.1\r\nexpect: 100-continue\r\ncontent-length: 5\r\n\r\nabcde").await);
+        assert_eq!(
+            "HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n",
+            round_trip_with_faults(&EchoHandler, "POST / HTTP/1.1\r\n\r\n").await);
+        assert_eq!(
+            "HTTP/1.1 405 Method Not Allowed\r\ncontent-length: 0\r\n\r\n",
+            round_trip_with_faults(&EchoHandler, "post / HTTP/1.1\r\n\r\n").await);
+        assert_eq!("", round_trip_with_faults(&EchoHandler, "POST / HTTP/1.1\r\ncontent-length: 5\r\n\r\nab").await);
+    }
+}
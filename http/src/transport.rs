@@ -0,0 +1,504 @@
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::mpsc;
+
+use crate::listen_fd::to_io_error;
+use crate::memory_stream::{memory_stream_pair, MemoryStream};
+use crate::proxy_protocol::read_header;
+
+/// The credentials of the process on the other end of a Unix domain socket,
+/// from the `SO_PEERCRED` socket option.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnixPeer {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// PeerAddr identifies the client on the other end of a connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerAddr {
+    /// A TCP connection, with or without TLS.
+    Ip(SocketAddr),
+    /// A Unix domain socket connection.
+    Unix(UnixPeer),
+    /// An in-memory connection made with `MemoryConnector`.  Each connection gets a new id.
+    Test(u64),
+}
+
+impl PeerAddr {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PeerAddr::Ip(socket_addr) => Some(socket_addr.ip()),
+            PeerAddr::Unix(_) | PeerAddr::Test(_) => None,
+        }
+    }
+
+    /// Returns true if the peer connected over a Unix domain socket or an in-memory connection.
+    ///
+    /// TCP connections from loopback addresses are not local.  Containers, SSH tunnels, and
+    /// port forwarding let remote clients connect from loopback addresses.
+    pub fn is_local(&self) -> bool {
+        match self {
+            PeerAddr::Ip(_) => false,
+            PeerAddr::Unix(_) | PeerAddr::Test(_) => true,
+        }
+    }
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(socket_addr: SocketAddr) -> Self {
+        PeerAddr::Ip(socket_addr)
+    }
+}
+
+impl std::fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerAddr::Ip(socket_addr) => write!(f, "{}", socket_addr),
+            PeerAddr::Unix(peer) =>
+                write!(f, "unix(pid={},uid={},gid={})", peer.pid, peer.uid, peer.gid),
+            PeerAddr::Test(id) => write!(f, "test({})", id),
+        }
+    }
+}
+
+/// LocalAddr is the address where a `Listener` accepts connections.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LocalAddr {
+    Ip(SocketAddr),
+    Unix(PathBuf),
+    Memory,
+}
+
+impl LocalAddr {
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            LocalAddr::Ip(socket_addr) => Some(*socket_addr),
+            LocalAddr::Unix(_) | LocalAddr::Memory => None,
+        }
+    }
+}
+
+impl std::fmt::Display for LocalAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LocalAddr::Ip(socket_addr) => write!(f, "{}", socket_addr),
+            LocalAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            LocalAddr::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+/// Connection is a byte stream from any transport.
+pub enum Connection {
+    Tcp(TcpStream),
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+    Unix(UnixStream),
+    Memory(MemoryStream),
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8])
+                 -> Poll<tokio::io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Connection::Tls(s) => Pin::new(s).poll_read(cx, buf),
+            Connection::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            Connection::Memory(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+                  -> Poll<tokio::io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Connection::Tls(s) => Pin::new(s).poll_write(cx, buf),
+            Connection::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            Connection::Memory(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Connection::Tls(s) => Pin::new(s).poll_flush(cx),
+            Connection::Unix(s) => Pin::new(s).poll_flush(cx),
+            Connection::Memory(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Connection::Tls(s) => Pin::new(s).poll_shutdown(cx),
+            Connection::Unix(s) => Pin::new(s).poll_shutdown(cx),
+            Connection::Memory(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+enum AcceptedStream {
+    Ready(Connection),
+    Tls(TcpStream, tokio_rustls::TlsAcceptor),
+}
+
+/// Accepted is a connection returned by `Listener::accept`.
+/// Call `handshake` to get the `Connection`.
+pub struct Accepted {
+    stream: AcceptedStream,
+    pub peer_addr: PeerAddr,
+    proxy_protocol: bool,
+}
+
+impl Accepted {
+    fn new(stream: AcceptedStream, peer_addr: PeerAddr) -> Accepted {
+        Accepted { stream, peer_addr, proxy_protocol: false }
+    }
+
+    /// Makes `handshake` read a PROXY protocol header before anything else, and use the
+    /// client address from it as the peer address.
+    /// Connections without a valid header fail the handshake.
+    ///
+    /// Use this only for listeners that get connections from a load balancer that sends the
+    /// header.  Otherwise clients can send any address.
+    pub fn expect_proxy_protocol(mut self) -> Accepted {
+        self.proxy_protocol = true;
+        self
+    }
+
+    /// Finishes setting up the connection.  For TLS connections, this does the TLS handshake.
+    ///
+    /// Call this in the connection's task, not the accept loop, so slow clients
+    /// do not delay other connections.
+    pub async fn handshake(mut self) -> std::io::Result<(Connection, PeerAddr)> {
+        if self.proxy_protocol {
+            // The balancer sends the header before the TLS handshake.
+            let client_addr = match &mut self.stream {
+                AcceptedStream::Ready(connection) => read_header(connection).await?,
+                AcceptedStream::Tls(tcp_stream, _) => read_header(tcp_stream).await?,
+            };
+            // Health check connections from the balancer keep the balancer's address.
+            if let Some(client_addr) = client_addr {
+                self.peer_addr = PeerAddr::Ip(client_addr);
+            }
+        }
+        let connection = match self.stream {
+            AcceptedStream::Ready(connection) => connection,
+            AcceptedStream::Tls(tcp_stream, tls_acceptor) =>
+                Connection::Tls(Box::new(tls_acceptor.accept(tcp_stream).await?)),
+        };
+        Ok((connection, self.peer_addr))
+    }
+}
+
+/// MemoryConnector makes in-memory connections to a `Listener::memory()`.
+/// Use it in tests.
+#[derive(Clone)]
+pub struct MemoryConnector {
+    sender: mpsc::UnboundedSender<(MemoryStream, u64)>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl MemoryConnector {
+    /// Connects to the listener.
+    ///
+    /// Returns `ConnectionRefused` if the listener is gone.
+    pub fn connect(&self) -> std::io::Result<MemoryStream> {
+        let (client, server) = memory_stream_pair();
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.sender.send((server, id)).map_err(|_| std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused, "memory listener is gone"))?;
+        Ok(client)
+    }
+}
+
+/// SocketFile is the file of a bound Unix domain socket.
+/// Dropping it deletes the file, unless another listener has replaced it.
+pub struct SocketFile {
+    path: PathBuf,
+    // Identifies the file this listener made.
+    dev: u64,
+    ino: u64,
+}
+
+impl SocketFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        match std::fs::symlink_metadata(&self.path) {
+            Ok(metadata) if metadata.dev() == self.dev && metadata.ino() == self.ino => {
+                let _ = std::fs::remove_file(&self.path);
+            }
+            Ok(_) | Err(_) => {}
+        }
+    }
+}
+
+/// Listener accepts connections from any transport: TCP, TLS, Unix domain sockets,
+/// or in-memory connections for tests.
+pub enum Listener {
+    Tcp(TcpListener),
+    Tls(TcpListener, tokio_rustls::TlsAcceptor),
+    Unix(UnixListener, SocketFile),
+    Memory(mpsc::UnboundedReceiver<(MemoryStream, u64)>),
+}
+
+impl Listener {
+    pub async fn bind_tcp(socket_addr: SocketAddr) -> std::io::Result<Listener> {
+        Ok(Listener::Tcp(TcpListener::bind(socket_addr).await?))
+    }
+
+    /// Makes a listener from a bound socket, like one from `listen_fd::take_inherited_listener`.
+    pub fn from_std(listener: std::net::TcpListener) -> std::io::Result<Listener> {
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+    }
+
+    /// Binds a Unix domain socket at `path`.
+    ///
+    /// Deletes any socket file left at `path` by a previous process.
+    /// Returns an error if `path` is some other kind of file.
+    /// Set the permissions of the parent directory to control which users can connect.
+    ///
+    /// Dropping the listener deletes the socket file.
+    pub fn bind_unix(path: &Path) -> std::io::Result<Listener> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) | Err(_) => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        let metadata = std::fs::symlink_metadata(path)?;
+        let socket_file = SocketFile { path: path.to_path_buf(), dev: metadata.dev(), ino: metadata.ino() };
+        Ok(Listener::Unix(listener, socket_file))
+    }
+
+    /// Makes a listener for in-memory connections.  Connect to it with the returned connector.
+    pub fn memory() -> (Listener, MemoryConnector) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Listener::Memory(receiver), MemoryConnector { sender, next_id: Arc::new(AtomicU64::new(0)) })
+    }
+
+    /// Makes the listener do TLS on its connections.
+    ///
+    /// Returns `InvalidInput` for listeners that are not TCP.
+    pub fn with_tls(self, server_config: Arc<rustls::ServerConfig>) -> std::io::Result<Listener> {
+        match self {
+            Listener::Tcp(listener) =>
+                Ok(Listener::Tls(listener, tokio_rustls::TlsAcceptor::from(server_config))),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput, "TLS works only with TCP listeners")),
+        }
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<LocalAddr> {
+        match self {
+            Listener::Tcp(listener) | Listener::Tls(listener, _) =>
+                Ok(LocalAddr::Ip(listener.local_addr()?)),
+            Listener::Unix(_, socket_file) => Ok(LocalAddr::Unix(socket_file.path.clone())),
+            Listener::Memory(_) => Ok(LocalAddr::Memory),
+        }
+    }
+
+    /// Returns the file descriptor of the listening socket, or `None` for in-memory listeners.
+    pub fn raw_fd(&self) -> Option<RawFd> {
+        match self {
+            Listener::Tcp(listener) | Listener::Tls(listener, _) => Some(listener.as_raw_fd()),
+            Listener::Unix(listener, _) => Some(listener.as_raw_fd()),
+            Listener::Memory(_) => None,
+        }
+    }
+
+    pub async fn accept(&mut self) -> std::io::Result<Accepted> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (tcp_stream, addr) = listener.accept().await?;
+                Ok(Accepted::new(AcceptedStream::Ready(Connection::Tcp(tcp_stream)), PeerAddr::Ip(addr)))
+            }
+            Listener::Tls(listener, tls_acceptor) => {
+                let (tcp_stream, addr) = listener.accept().await?;
+                Ok(Accepted::new(AcceptedStream::Tls(tcp_stream, tls_acceptor.clone()), PeerAddr::Ip(addr)))
+            }
+            Listener::Unix(listener, _) => {
+                let (unix_stream, _addr) = listener.accept().await?;
+                let credentials = nix::sys::socket::getsockopt(
+                    unix_stream.as_raw_fd(), nix::sys::socket::sockopt::PeerCredentials)
+                    .map_err(to_io_error)?;
+                Ok(Accepted::new(
+                    AcceptedStream::Ready(Connection::Unix(unix_stream)),
+                    PeerAddr::Unix(UnixPeer {
+                        pid: credentials.pid(),
+                        uid: credentials.uid(),
+                        gid: credentials.gid(),
+                    })))
+            }
+            Listener::Memory(receiver) => match receiver.recv().await {
+                Some((memory_stream, id)) => Ok(Accepted::new(
+                    AcceptedStream::Ready(Connection::Memory(memory_stream)), PeerAddr::Test(id))),
+                // Every connector is gone, so no connections will arrive.
+                None => futures::future::pending().await,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_peer_addr() {
+        let ip = PeerAddr::from(SocketAddr::from(([127, 0, 0, 1], 1690)));
+        assert!(!ip.is_local());
+        assert_eq!(Some(IpAddr::from([127, 0, 0, 1])), ip.ip());
+        assert_eq!("127.0.0.1:1690", ip.to_string());
+        let unix = PeerAddr::Unix(UnixPeer { pid: 1, uid: 2, gid: 3 });
+        assert!(unix.is_local());
+        assert_eq!(None, unix.ip());
+        assert_eq!("unix(pid=1,uid=2,gid=3)", unix.to_string());
+        assert!(PeerAddr::Test(4).is_local());
+        assert_eq!("test(4)", PeerAddr::Test(4).to_string());
+    }
+
+    #[tokio::test]
+    async fn test_unix_listener() {
+        let path = std::env::temp_dir()
+            .join(format!("beatrice_http_test_unix_listener_{}.sock", std::process::id()));
+        // Replaces the stale socket left by a listener that did not delete it.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let mut listener = Listener::bind_unix(&path).unwrap();
+        assert_eq!(LocalAddr::Unix(path.clone()), listener.local_addr().unwrap());
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let (mut connection, peer_addr) = listener.accept().await.unwrap().handshake().await.unwrap();
+        assert_eq!(
+            PeerAddr::Unix(UnixPeer {
+                pid: std::process::id() as i32,
+                uid: nix::unistd::getuid().as_raw(),
+                gid: nix::unistd::getgid().as_raw(),
+            }),
+            peer_addr);
+        client.write_all(b"abc").await.unwrap();
+        let mut buf = [0u8; 3];
+        connection.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"abc", &buf);
+        drop(listener);
+        assert!(!path.exists());
+
+        // Dropping a listener leaves the socket of a listener that replaced it.
+        let old_listener = Listener::bind_unix(&path).unwrap();
+        let new_listener = Listener::bind_unix(&path).unwrap();
+        drop(old_listener);
+        UnixStream::connect(&path).await.unwrap();
+        drop(new_listener);
+        assert!(!path.exists());
+
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(Listener::bind_unix(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tls_listener() {
+        let mut ca_params = rcgen::CertificateParams::new(Vec::new());
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+        let server_cert = rcgen::generate_simple_self_signed([String::from("localhost")]).unwrap();
+        let mut server_config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+        server_config.set_single_cert(
+            vec![rustls::Certificate(server_cert.serialize_der_with_signer(&ca).unwrap())],
+            rustls::PrivateKey(server_cert.serialize_private_key_der())).unwrap();
+        let mut listener = Listener::bind_tcp(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap()
+            .with_tls(Arc::new(server_config)).unwrap();
+        let addr = listener.local_addr().unwrap().socket_addr().unwrap();
+        assert!(listener.raw_fd().is_some());
+        let mut client_config = rustls::ClientConfig::new();
+        client_config.root_store.add(&rustls::Certificate(ca.serialize_der().unwrap())).unwrap();
+        let tls_connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+        let client = async {
+            let tcp_stream = TcpStream::connect(addr).await.unwrap();
+            let server_name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+            let mut tls_stream = tls_connector.connect(server_name, tcp_stream).await.unwrap();
+            tls_stream.write_all(b"abc").await.unwrap();
+            let mut buf = [0u8; 3];
+            tls_stream.read_exact(&mut buf).await.unwrap();
+            buf
+        };
+        let server = async {
+            let (mut connection, peer_addr) = listener.accept().await.unwrap().handshake().await.unwrap();
+            assert!(matches!(connection, Connection::Tls(_)));
+            let mut buf = [0u8; 3];
+            connection.read_exact(&mut buf).await.unwrap();
+            connection.write_all(&buf).await.unwrap();
+            peer_addr
+        };
+        let (buf, peer_addr) = tokio::join!(client, server);
+        assert_eq!(b"abc", &buf);
+        assert_eq!(Some(IpAddr::from([127, 0, 0, 1])), peer_addr.ip());
+
+        // Clients that do not speak TLS fail the handshake.
+        let mut tcp_stream = TcpStream::connect(addr).await.unwrap();
+        tcp_stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        assert!(listener.accept().await.unwrap().handshake().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_memory_listener() {
+        let (mut listener, connector) = Listener::memory();
+        assert_eq!(LocalAddr::Memory, listener.local_addr().unwrap());
+        assert_eq!(None, listener.raw_fd());
+        let mut client0 = connector.connect().unwrap();
+        let _client1 = connector.connect().unwrap();
+        let (mut connection0, peer_addr0) = listener.accept().await.unwrap().handshake().await.unwrap();
+        let (_connection1, peer_addr1) = listener.accept().await.unwrap().handshake().await.unwrap();
+        assert_eq!(PeerAddr::Test(0), peer_addr0);
+        assert_eq!(PeerAddr::Test(1), peer_addr1);
+        connection0.write_all(b"abc").await.unwrap();
+        let mut buf = [0u8; 3];
+        client0.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"abc", &buf);
+        assert!(listener.with_tls(Arc::new(rustls::ServerConfig::new(rustls::NoClientAuth::new())))
+            .is_err());
+        assert_eq!(std::io::ErrorKind::ConnectionRefused, connector.connect().unwrap_err().kind());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol() {
+        let (mut listener, connector) = Listener::memory();
+        let mut client = connector.connect().unwrap();
+        client.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nabc").await.unwrap();
+        let (mut connection, peer_addr) =
+            listener.accept().await.unwrap().expect_proxy_protocol().handshake().await.unwrap();
+        assert_eq!(PeerAddr::Ip(SocketAddr::from(([192, 0, 2, 1], 56324))), peer_addr);
+        let mut buf = [0u8; 3];
+        connection.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"abc", &buf);
+
+        // Health checks keep the balancer's address.
+        let mut client = connector.connect().unwrap();
+        client.write_all(b"PROXY UNKNOWN\r\n").await.unwrap();
+        let (_connection, peer_addr) =
+            listener.accept().await.unwrap().expect_proxy_protocol().handshake().await.unwrap();
+        assert_eq!(PeerAddr::Test(1), peer_addr);
+
+        let mut client = connector.connect().unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let result = listener.accept().await.unwrap().expect_proxy_protocol().handshake().await;
+        assert_eq!(std::io::ErrorKind::InvalidData, result.err().unwrap().kind());
+    }
+}
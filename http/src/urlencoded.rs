@@ -0,0 +1,52 @@
+// Decodes `application/x-www-form-urlencoded` data, the format of HTML form posts and URL query strings.
+// https://url.spec.whatwg.org/#application/x-www-form-urlencoded
+use crate::{HttpCallerError, HttpError};
+
+fn decode_component(bytes: &[u8]) -> Result<String, HttpError> {
+    let bytes: Vec<u8> = bytes.iter().map(|b| if *b == b'+' { b' ' } else { *b }).collect();
+    let decoded: Vec<u8> = percent_encoding::percent_decode(&bytes).collect();
+    String::from_utf8(decoded).map_err(|_e| HttpError::ParseError(HttpCallerError::FormInvalid))
+}
+
+/// Decodes `name1=value1&name2=value2` into name-value pairs, in order.
+/// A name without `=` gets an empty value.  Names may repeat.
+///
+/// Returns `ParseError(FormInvalid)` if a name or value is not UTF-8 after decoding.
+pub fn decode(input: &[u8]) -> Result<Vec<(String, String)>, HttpError> {
+    let mut pairs = Vec::new();
+    for sequence in input.split(|b| *b == b'&').filter(|sequence| !sequence.is_empty()) {
+        let (name, value) = match sequence.iter().position(|b| *b == b'=') {
+            Some(eq) => (&sequence[..eq], &sequence[eq + 1..]),
+            None => (sequence, &b""[..]),
+        };
+        pairs.push((decode_component(name)?, decode_component(value)?));
+    }
+    Ok(pairs)
+}
+
+/// Returns the value of the first pair named `name`.
+pub fn get<'a>(pairs: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    pairs.iter().find(|(pair_name, _)| pair_name == name).map(|(_, value)| value.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let pairs = decode(b"a=1&b=x+y%26z&&c&a=%E2%9C%93&=v&d=").unwrap();
+        assert_eq!(
+            vec![("a", "1"), ("b", "x y&z"), ("c", ""), ("a", "\u{2713}"), ("", "v"), ("d", "")],
+            pairs.iter().map(|(n, v)| (n.as_str(), v.as_str())).collect::<Vec<_>>());
+        assert_eq!(Some("1"), get(&pairs, "a"));
+        assert_eq!(None, get(&pairs, "e"));
+        assert!(decode(b"").unwrap().is_empty());
+        // A malformed escape is kept as-is, like browsers do.
+        assert_eq!(vec![(String::from("a"), String::from("%zz"))], decode(b"a=%zz").unwrap());
+        match decode(b"a=%FF") {
+            Err(HttpError::ParseError(HttpCallerError::FormInvalid)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,447 @@
+// WebSocket server connections.
+// "The WebSocket Protocol" https://tools.ietf.org/html/rfc6455
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::fixed_buffer::FixedBuf;
+use crate::{Header, HttpCallerError, HttpError, HttpMethod, HttpReaderWriter};
+
+// https://tools.ietf.org/html/rfc6455#section-1.3
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+// Close status codes https://tools.ietf.org/html/rfc6455#section-7.4.1
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Clone, Copy, Debug)]
+pub struct WebSocketConfig {
+    /// The largest frame to accept from the client.  Larger outgoing messages are split into
+    /// frames of this size.
+    pub max_frame_len: usize,
+    /// The largest message to accept from the client, after joining its fragments.
+    pub max_message_len: usize,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig { max_frame_len: 64 * 1024, max_message_len: 1024 * 1024 }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// A reply to a ping sent with `WebSocket::ping`.
+    Pong(Vec<u8>),
+    /// The client closed the connection, with an optional status code and reason.
+    Close(Option<(u16, String)>),
+}
+
+/// Returns the `Sec-WebSocket-Accept` value for the client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let digest = ring::digest::digest(
+        &ring::digest::SHA1_FOR_LEGACY_USE_ONLY, format!("{}{}", key, ACCEPT_GUID).as_bytes());
+    base64::encode(digest.as_ref())
+}
+
+fn has_token(header_value: Option<&str>, token: &str) -> bool {
+    header_value.map(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+        .unwrap_or(false)
+}
+
+/// Checks the request's WebSocket handshake headers and sends `101 Switching Protocols`.
+///
+/// Returns `ParseError(HttpCallerError::UpgradeInvalid)` if the request is not a valid
+/// WebSocket handshake.  The server responds with 400 Bad Request.
+///
+/// The server closes the connection after the handler returns.
+pub async fn accept<'a, 'b>(http_reader_writer: &'b mut HttpReaderWriter<'a>, config: WebSocketConfig)
+                            -> Result<WebSocket<'b>, HttpError> {
+    // Opening Handshake https://tools.ietf.org/html/rfc6455#section-4.2.1
+    let invalid = || HttpError::ParseError(HttpCallerError::UpgradeInvalid);
+    if http_reader_writer.method() != HttpMethod::GET
+        || http_reader_writer.has_body()
+        || !has_token(http_reader_writer.header("upgrade"), "websocket")
+        || !has_token(http_reader_writer.header("connection"), "upgrade")
+        || http_reader_writer.header("sec-websocket-version") != Some("13") {
+        return Err(invalid());
+    }
+    let key = http_reader_writer.header("sec-websocket-key").ok_or_else(invalid)?;
+    if base64::decode(key).map(|nonce| nonce.len()) != Ok(16) {
+        return Err(invalid());
+    }
+    let accept = accept_key(key);
+    http_reader_writer.send_switching_protocols(
+        &[&Header::new("upgrade", "websocket"),
+            &Header::new("connection", "Upgrade"),
+            &Header::new("sec-websocket-accept", &accept)]).await?;
+    let (buffer, input, output) = http_reader_writer.raw_connection();
+    Ok(WebSocket::new(buffer, input, output, config))
+}
+
+fn protocol_error(msg: &str) -> (u16, io::Error) {
+    (CLOSE_PROTOCOL_ERROR, io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// WebSocket is the server side of a WebSocket connection.  Make one with `accept`.
+///
+/// It answers pings and close frames from the client.
+/// When the client breaks the protocol or sends something too big, it sends a close frame
+/// with the matching status code and returns an `InvalidData` error.
+pub struct WebSocket<'s> {
+    buffer: &'s mut FixedBuf,
+    input: &'s mut (dyn AsyncRead + Send + Unpin),
+    output: &'s mut (dyn AsyncWrite + Send + Unpin),
+    config: WebSocketConfig,
+    close_sent: bool,
+    close_received: bool,
+    // The opcode and data of a fragmented message.  It survives `recv` returning a pong.
+    partial_message: Option<(u8, Vec<u8>)>,
+}
+
+impl<'s> WebSocket<'s> {
+    pub(crate) fn new(buffer: &'s mut FixedBuf,
+                      input: &'s mut (dyn AsyncRead + Send + Unpin),
+                      output: &'s mut (dyn AsyncWrite + Send + Unpin),
+                      config: WebSocketConfig) -> WebSocket<'s> {
+        WebSocket { buffer, input, output, config, close_sent: false, close_received: false,
+                    partial_message: None }
+    }
+
+    /// Reads until the buffer holds `n` bytes.
+    async fn fill(&mut self, n: usize) -> io::Result<()> {
+        while self.buffer.readable().len() < n {
+            self.buffer.shift();
+            let writable = self.buffer.writable()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "end of buffer full"))?;
+            let num_bytes_read = self.input.read(writable).await?;
+            if num_bytes_read == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "eof in websocket frame"));
+            }
+            self.buffer.wrote(num_bytes_read);
+        }
+        Ok(())
+    }
+
+    async fn read_frame(&mut self) -> Result<Frame, (u16, io::Error)> {
+        // Base Framing Protocol https://tools.ietf.org/html/rfc6455#section-5.2
+        self.fill(2).await.map_err(|e| (0, e))?;
+        let (b0, b1) = (self.buffer.readable()[0], self.buffer.readable()[1]);
+        let fin = b0 & 0x80 != 0;
+        let opcode = b0 & 0x0F;
+        if b0 & 0x70 != 0 {
+            return Err(protocol_error("reserved bits set"));
+        }
+        if b1 & 0x80 == 0 {
+            return Err(protocol_error("client frame is not masked"));
+        }
+        let (len_bytes, len7) = match b1 & 0x7F {
+            126 => (2, None),
+            127 => (8, None),
+            n => (0, Some(n as u64)),
+        };
+        self.fill(2 + len_bytes + 4).await.map_err(|e| (0, e))?;
+        let header = &self.buffer.readable()[..2 + len_bytes + 4];
+        let len = match len7 {
+            Some(n) => n,
+            None => header[2..2 + len_bytes].iter().fold(0u64, |len, b| (len << 8) | u64::from(*b)),
+        };
+        // Lengths use the fewest bytes, and 64-bit lengths have the most significant bit clear.
+        if (len_bytes == 2 && len < 126) || (len_bytes == 8 && (len <= 0xFFFF || len >> 63 != 0)) {
+            return Err(protocol_error("invalid payload length"));
+        }
+        let mut mask = [0u8; 4];
+        mask.copy_from_slice(&header[2 + len_bytes..]);
+        self.buffer.consume(2 + len_bytes + 4);
+        let is_control = opcode & 0x8 != 0;
+        if is_control && (!fin || len > 125) {
+            return Err(protocol_error("invalid control frame"));
+        }
+        if len > self.config.max_frame_len as u64 {
+            return Err((CLOSE_TOO_BIG, io::Error::new(io::ErrorKind::InvalidData, "frame too big")));
+        }
+        let mut payload = vec![0u8; len as usize];
+        let from_buffer = std::cmp::min(payload.len(), self.buffer.readable().len());
+        payload[..from_buffer].copy_from_slice(&self.buffer.readable()[..from_buffer]);
+        self.buffer.consume(from_buffer);
+        self.input.read_exact(&mut payload[from_buffer..]).await.map_err(|e| (0, e))?;
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+        Ok(Frame { fin, opcode, payload })
+    }
+
+    async fn recv_internal(&mut self) -> Result<Message, (u16, io::Error)> {
+        loop {
+            let frame = self.read_frame().await?;
+            match frame.opcode {
+                OPCODE_PING => {
+                    self.send_frame(OPCODE_PONG, &frame.payload).await.map_err(|e| (0, e))?;
+                    continue;
+                }
+                OPCODE_PONG => return Ok(Message::Pong(frame.payload)),
+                OPCODE_CLOSE => return self.handle_close(frame.payload).await,
+                OPCODE_TEXT | OPCODE_BINARY if self.partial_message.is_none() => {
+                    self.partial_message = Some((frame.opcode, frame.payload));
+                }
+                OPCODE_CONTINUATION if self.partial_message.is_some() => {
+                    let (_, data) = self.partial_message.as_mut().unwrap();
+                    if data.len() + frame.payload.len() > self.config.max_message_len {
+                        return Err((CLOSE_TOO_BIG, io::Error::new(io::ErrorKind::InvalidData, "message too big")));
+                    }
+                    data.extend_from_slice(&frame.payload);
+                }
+                _ => return Err(protocol_error("unexpected opcode")),
+            }
+            if frame.fin {
+                let (opcode, data) = self.partial_message.take().unwrap();
+                if data.len() > self.config.max_message_len {
+                    return Err((CLOSE_TOO_BIG, io::Error::new(io::ErrorKind::InvalidData, "message too big")));
+                }
+                return if opcode == OPCODE_TEXT {
+                    String::from_utf8(data).map(Message::Text).map_err(|_| (
+                        CLOSE_INVALID_DATA, io::Error::new(io::ErrorKind::InvalidData, "text is not UTF-8")))
+                } else {
+                    Ok(Message::Binary(data))
+                };
+            }
+        }
+    }
+
+    async fn handle_close(&mut self, payload: Vec<u8>) -> Result<Message, (u16, io::Error)> {
+        // https://tools.ietf.org/html/rfc6455#section-5.5.1
+        self.close_received = true;
+        let status = match payload.len() {
+            0 => None,
+            1 => return Err(protocol_error("invalid close frame")),
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                if !matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999) {
+                    return Err(protocol_error("invalid close code"));
+                }
+                let reason = String::from_utf8(payload[2..].to_vec()).map_err(|_| (
+                    CLOSE_INVALID_DATA, io::Error::new(io::ErrorKind::InvalidData, "close reason is not UTF-8")))?;
+                Some((code, reason))
+            }
+        };
+        if !self.close_sent {
+            self.close_sent = true;
+            let echo = status.as_ref().map(|(code, _)| code.to_be_bytes().to_vec()).unwrap_or_default();
+            self.send_frame(OPCODE_CLOSE, &echo).await.map_err(|e| (0, e))?;
+        }
+        Ok(Message::Close(status))
+    }
+
+    /// Reads the next message from the client.
+    ///
+    /// After it returns `Message::Close`, the connection is closed and `recv` returns `NotConnected`.
+    pub async fn recv(&mut self) -> io::Result<Message> {
+        if self.close_received {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "websocket closed"));
+        }
+        match self.recv_internal().await {
+            Ok(message) => Ok(message),
+            Err((code, e)) => {
+                self.close_received = true;
+                if code != 0 && !self.close_sent {
+                    self.close_sent = true;
+                    let _ = self.send_frame(OPCODE_CLOSE, &code.to_be_bytes()).await;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        self.send_frame_with_fin(true, opcode, payload).await?;
+        self.output.flush().await
+    }
+
+    async fn send_frame_with_fin(&mut self, fin: bool, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut header = Vec::with_capacity(10);
+        header.push(if fin { 0x80 } else { 0 } | opcode);
+        // Server frames are not masked.
+        match payload.len() {
+            len if len < 126 => header.push(len as u8),
+            len if len <= 0xFFFF => {
+                header.push(126);
+                header.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                header.push(127);
+                header.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        self.output.write_all(&header).await?;
+        self.output.write_all(payload).await
+    }
+
+    async fn send_data(&mut self, opcode: u8, data: &[u8]) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "websocket closed"));
+        }
+        // Fragmentation https://tools.ietf.org/html/rfc6455#section-5.4
+        let mut chunks = data.chunks(self.config.max_frame_len.max(1)).peekable();
+        let mut frame_opcode = opcode;
+        if chunks.peek().is_none() {
+            return self.send_frame(opcode, &[]).await;
+        }
+        while let Some(chunk) = chunks.next() {
+            self.send_frame_with_fin(chunks.peek().is_none(), frame_opcode, chunk).await?;
+            frame_opcode = OPCODE_CONTINUATION;
+        }
+        self.output.flush().await
+    }
+
+    pub async fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.send_data(OPCODE_TEXT, text.as_bytes()).await
+    }
+
+    pub async fn send_binary(&mut self, data: &[u8]) -> io::Result<()> {
+        self.send_data(OPCODE_BINARY, data).await
+    }
+
+    /// Sends a ping.  The client replies with a pong, which `recv` returns as `Message::Pong`.
+    /// `data` must be at most 125 bytes.
+    pub async fn ping(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.len() > 125 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "ping data longer than 125 bytes"));
+        }
+        self.send_frame(OPCODE_PING, data).await
+    }
+
+    /// Sends a close frame.  Keep calling `recv` until it returns `Message::Close`,
+    /// which is the client's reply.
+    pub async fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if self.close_sent {
+            return Ok(());
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        payload.truncate(125);
+        self.close_sent = true;
+        self.send_frame(OPCODE_CLOSE, &payload).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_stream::memory_stream_pair;
+
+    fn client_frame(b0: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1u8, 2, 3, 4];
+        let mut frame = vec![b0];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    async fn read_available(stream: &mut crate::memory_stream::MemoryStream) -> Vec<u8> {
+        let mut buf = [0u8; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        buf[..n].to_vec()
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // Example from https://tools.ietf.org/html/rfc6455#section-1.3
+        assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", accept_key("dGhlIHNhbXBsZSBub25jZQ=="));
+    }
+
+    #[tokio::test]
+    async fn test_messages() {
+        let (server_stream, mut client) = memory_stream_pair();
+        let (mut input, mut output) = tokio::io::split(server_stream);
+        let mut buffer = FixedBuf::new();
+        // Bytes read along with the request head.
+        std::io::Write::write(&mut buffer, &client_frame(0x81, b"hello")).unwrap();
+        let config = WebSocketConfig { max_frame_len: 100, max_message_len: 150 };
+        let mut websocket = WebSocket::new(&mut buffer, &mut input, &mut output, config);
+        assert_eq!(Message::Text(String::from("hello")), websocket.recv().await.unwrap());
+
+        // Fragmented binary message with a ping in the middle.
+        let mut bytes = client_frame(0x02, b"ab");
+        bytes.extend(client_frame(0x89, b"p"));
+        bytes.extend(client_frame(0x80, b"cd"));
+        client.write_all(&bytes).await.unwrap();
+        assert_eq!(Message::Binary(b"abcd".to_vec()), websocket.recv().await.unwrap());
+        assert_eq!(vec![0x8A, 1, b'p'], read_available(&mut client).await);
+
+        // Fragmented text message with a pong in the middle.
+        let mut bytes = client_frame(0x01, b"ab");
+        bytes.extend(client_frame(0x8A, b"q"));
+        bytes.extend(client_frame(0x80, b"cd"));
+        client.write_all(&bytes).await.unwrap();
+        assert_eq!(Message::Pong(b"q".to_vec()), websocket.recv().await.unwrap());
+        assert_eq!(Message::Text(String::from("abcd")), websocket.recv().await.unwrap());
+
+        websocket.send_text("hi").await.unwrap();
+        assert_eq!(vec![0x81, 2, b'h', b'i'], read_available(&mut client).await);
+        websocket.send_binary(&[7u8; 130]).await.unwrap();
+        let sent = read_available(&mut client).await;
+        assert_eq!(&[0x02, 100], &sent[..2]);
+        assert_eq!(&[0x80, 30], &sent[102..104]);
+        assert_eq!(134, sent.len());
+
+        client.write_all(&client_frame(0x88, &[0x03, 0xE8, b'b', b'y', b'e'])).await.unwrap();
+        assert_eq!(Message::Close(Some((CLOSE_NORMAL, String::from("bye")))), websocket.recv().await.unwrap());
+        assert_eq!(vec![0x88, 2, 0x03, 0xE8], read_available(&mut client).await);
+        assert_eq!(io::ErrorKind::NotConnected, websocket.recv().await.unwrap_err().kind());
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        for (frames, close_code) in &[
+            (client_frame(0x81, &[0xFF]), CLOSE_INVALID_DATA),
+            (client_frame(0x82, &[0u8; 101]), CLOSE_TOO_BIG),
+            ([client_frame(0x02, &[0u8; 100]), client_frame(0x80, &[0u8; 51])].concat(), CLOSE_TOO_BIG),
+            (client_frame(0x80, b"a"), CLOSE_PROTOCOL_ERROR),
+            (client_frame(0xC1, b"a"), CLOSE_PROTOCOL_ERROR),
+            (client_frame(0x09, b"a"), CLOSE_PROTOCOL_ERROR),
+            (vec![0x81, 0x01, b'a'], CLOSE_PROTOCOL_ERROR),
+            // Payload lengths that do not use the fewest bytes.
+            ([&[0x82, 0x80 | 126, 0, 5, 1, 2, 3, 4][..], &[0u8; 5]].concat(), CLOSE_PROTOCOL_ERROR),
+            ([&[0x82, 0x80 | 127, 0, 0, 0, 0, 0, 0, 0, 5, 1, 2, 3, 4][..], &[0u8; 5]].concat(), CLOSE_PROTOCOL_ERROR),
+            (vec![0x82, 0x80 | 127, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, 1, 2, 3, 4], CLOSE_PROTOCOL_ERROR),
+            (vec![0x82, 0x80 | 127, 0x80, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, 4], CLOSE_PROTOCOL_ERROR),
+            (vec![0x82, 0x80 | 127, 0, 0, 0, 0, 0, 1, 0, 0, 1, 2, 3, 4], CLOSE_TOO_BIG),
+        ] {
+            let (server_stream, mut client) = memory_stream_pair();
+            let (mut input, mut output) = tokio::io::split(server_stream);
+            let mut buffer = FixedBuf::new();
+            let config = WebSocketConfig { max_frame_len: 100, max_message_len: 150 };
+            let mut websocket = WebSocket::new(&mut buffer, &mut input, &mut output, config);
+            client.write_all(frames).await.unwrap();
+            assert_eq!(io::ErrorKind::InvalidData, websocket.recv().await.unwrap_err().kind());
+            let mut expected = vec![0x88, 2];
+            expected.extend_from_slice(&close_code.to_be_bytes());
+            assert_eq!(expected, read_available(&mut client).await);
+        }
+    }
+}
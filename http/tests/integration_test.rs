@@ -1,73 +1,1229 @@
-use std::borrow::BorrowMut;
+use std::os::unix::io::FromRawFd;
 use std::sync::Arc;
 use std::time::Duration;
 
-use ::function_name::named;
-
 use async_trait::async_trait;
-use http::{HttpServerBuilder, HttpSession, HttpSessionHandler};
-use logging::info;
+use beatrice_http::auth::{
+    hmac_authorization, AuthFailure, Authenticated, BasicAuth, BearerAuth, HmacAuth, PasswordHash, StaticTokens,
+    TokenVerifier,
+};
+use beatrice_http::body_file::save_body;
+use beatrice_http::cors::{Cors, CorsPolicy};
+use beatrice_http::faulty_stream::{test_faults, FaultyStream};
+use beatrice_http::forwarded::{ForwardingHeader, TrustedProxies};
+use beatrice_http::health::{Criticality, HealthChecks, HealthHandler};
+use beatrice_http::metrics::MetricsHandler;
+use beatrice_http::middleware::{Flow, Middleware, Router, Stack};
+use beatrice_http::multipart::{MultipartLimits, MultipartReader};
+use beatrice_http::reverse_proxy::{ReverseProxy, Upstream};
+use beatrice_http::server::{HttpHandler, HttpServerBuilder, LocalOnly, RequireLocal};
+use beatrice_http::sse::{Event, EventStream};
+use beatrice_http::static_files::StaticFiles;
+use beatrice_http::tap::{diff, replay, Direction, Transcript};
+use beatrice_http::testing::round_trip;
+use beatrice_http::transport::Listener;
+use beatrice_http::websocket::{Message, WebSocketConfig};
+use beatrice_http::{Header, HttpError, HttpReaderWriter, HttpStatus};
+
+struct Handler {
+    delay: Duration,
+}
+
+#[async_trait]
+impl HttpHandler for Handler {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        tokio::time::delay_for(self.delay).await;
+        http_reader_writer.send_text(HttpStatus::Ok200, &[], "hello").await
+    }
+}
+
+fn handler(delay: Duration) -> Arc<Handler> {
+    Arc::new(Handler { delay })
+}
+
+/// Sends `request` and reads until the server closes the connection.
+async fn send_raw(addr: std::net::SocketAddr, request: &str) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut tcp_stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    tcp_stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    tcp_stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_port() {
+    let http_server = HttpServerBuilder::new()
+        .localhost()
+        .any_port()
+        .run(handler(Duration::from_millis(0))).await.unwrap();
+    let response = reqwest::get(&format!("http://{}/", http_server.local_addr)).await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!("hello", response.text().await.unwrap());
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+}
+
+#[tokio::test]
+async fn test_keep_alive() {
+    let (listener, connector) = Listener::memory();
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .run(handler(Duration::from_millis(0))).await.unwrap();
+    for faults in test_faults() {
+        let stream = FaultyStream::new(connector.connect().unwrap(), faults.clone());
+        let response = send_raw_on(stream, &"GET / HTTP/1.1\r\n\r\n".repeat(3)).await;
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\ncontent-type: text/plain; charset=UTF-8\r\ncontent-length: 5\r\n\r\nhello".repeat(3),
+            response,
+            "{:?}", faults);
+    }
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+}
+
+#[tokio::test]
+async fn test_stop_closes_listener_and_idle_connections() {
+    let http_server = HttpServerBuilder::new()
+        .run(handler(Duration::from_millis(0))).await.unwrap();
+    let addr = http_server.local_addr.socket_addr().unwrap();
+    let mut idle_conn = tokio::net::TcpStream::connect(addr).await.unwrap();
+    // Let the server accept the connection.  Connections still in the backlog get reset.
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+    let mut buf = [0u8; 10];
+    assert_eq!(0, tokio::io::AsyncReadExt::read(&mut idle_conn, &mut buf).await.unwrap());
+    assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+}
+
+#[tokio::test]
+async fn test_stop_drains_in_flight_request() {
+    let http_server = HttpServerBuilder::new()
+        .run(handler(Duration::from_millis(200))).await.unwrap();
+    let addr = http_server.local_addr.socket_addr().unwrap();
+    let client = tokio::spawn(send_raw(addr, "GET / HTTP/1.1\r\n\r\n"));
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    assert_eq!(0, http_server.stop(Duration::from_secs(5)).await);
+    let response = client.await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+    assert!(response.contains("\r\nconnection: close\r\n"), "{:?}", response);
+    assert!(response.ends_with("\r\n\r\nhello"), "{:?}", response);
+}
+
+#[tokio::test]
+async fn test_stop_cuts_off_slow_requests() {
+    let http_server = HttpServerBuilder::new()
+        .run(handler(Duration::from_secs(60))).await.unwrap();
+    let addr = http_server.local_addr.socket_addr().unwrap();
+    let client1 = tokio::spawn(send_raw(addr, "GET / HTTP/1.1\r\n\r\n"));
+    let client2 = tokio::spawn(send_raw(addr, "GET / HTTP/1.1\r\n\r\n"));
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    assert_eq!(2, http_server.stop(Duration::from_millis(100)).await);
+    assert_eq!("", client1.await.unwrap());
+    assert_eq!("", client2.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_hand_off_listener() {
+    let old_server = HttpServerBuilder::new()
+        .run(handler(Duration::from_millis(0))).await.unwrap();
+    let addr = old_server.local_addr.clone();
+    // A new process gets its copy of the socket from `listen_fd::take_inherited_listener`.
+    let fd = nix::unistd::dup(old_server.listener_fd().unwrap()).unwrap();
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    let new_server = HttpServerBuilder::new()
+        .listener(listener)
+        .run(handler(Duration::from_millis(0))).await.unwrap();
+    assert_eq!(addr, new_server.local_addr);
+    assert_eq!(0, old_server.stop(Duration::from_secs(1)).await);
+    for _ in 0..3 {
+        let response = reqwest::get(&format!("http://{}/", addr)).await.unwrap();
+        assert_eq!("hello", response.text().await.unwrap());
+    }
+    assert_eq!(0, new_server.stop(Duration::from_secs(1)).await);
+}
+
+/// Sends `request` on `stream` and reads until the server closes the connection.
+async fn send_raw_on<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(
+    mut stream: S, request: &str) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    // The server may respond and close the connection before reading the whole request.
+    match stream.write_all(request.as_bytes()).await {
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+        result => {
+            result.unwrap();
+            stream.shutdown().await.unwrap();
+        }
+    }
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_unix_socket() {
+    let path = std::env::temp_dir()
+        .join(format!("beatrice_http_test_unix_socket_{}.sock", std::process::id()));
+    let http_server = HttpServerBuilder::new()
+        .transport(Listener::bind_unix(&path).unwrap())
+        .run(Arc::new(LocalOnly(Handler { delay: Duration::from_millis(0) }))).await.unwrap();
+    assert_eq!(None, http_server.local_addr.socket_addr());
+    let unix_stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    let response = send_raw_on(unix_stream, "GET / HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+    assert!(response.ends_with("\r\n\r\nhello"), "{:?}", response);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+    // Stopping the server deletes the socket file.
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn test_memory() {
+    let (listener, connector) = Listener::memory();
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .run(handler(Duration::from_millis(0))).await.unwrap();
+    assert_eq!(None, http_server.listener_fd());
+    for _ in 0..2 {
+        let response = send_raw_on(connector.connect().unwrap(), "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+        assert!(response.ends_with("\r\n\r\nhello"), "{:?}", response);
+    }
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+}
+
+#[tokio::test]
+async fn test_tap_and_replay() {
+    let (listener, connector) = Listener::memory();
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .tap(move |peer_addr, transcript| sender.send((peer_addr, transcript)).unwrap())
+        .run(handler(Duration::from_millis(0))).await.unwrap();
+    let request = "GET /a HTTP/1.1\r\ncookie: id=1\r\n\r\nPOST /b HTTP/1.1\r\ncontent-length: 3\r\n\r\nabc";
+    let response = send_raw_on(connector.connect().unwrap(), request).await;
+    let (peer_addr, transcript) = receiver.recv().await.unwrap();
+    assert!(peer_addr.is_local());
+    assert!(!transcript.truncated);
+    assert_eq!(
+        "GET /a HTTP/1.1\r\ncookie: ****\r\n\r\nPOST /b HTTP/1.1\r\ncontent-length: 3\r\n\r\n***",
+        String::from_utf8(transcript.bytes(Direction::Received)).unwrap());
+    assert_eq!(
+        response.replace("hello", "*****"),
+        String::from_utf8(transcript.bytes(Direction::Sent)).unwrap());
+
+    // A saved transcript replays with the same responses.
+    let transcript = Transcript::parse(&transcript.to_text()).unwrap();
+    let replayed = replay(&transcript, connector.connect().unwrap()).await.unwrap();
+    assert_eq!(response.as_bytes(), &replayed[..]);
+    assert_eq!(
+        "  HTTP/1.1 200 OK\\r\\n\n  content-type: text/plain; charset=UTF-8\\r\\n\n  content-length: 5\\r\\n\n  \\r\\n\n\
+        - *****HTTP/1.1 200 OK\\r\\n\n+ helloHTTP/1.1 200 OK\\r\\n\n  content-type: text/plain; charset=UTF-8\\r\\n\n  \
+        content-length: 5\\r\\n\n  \\r\\n\n- *****\n+ hello\n",
+        diff(&transcript.bytes(Direction::Sent), &replayed).unwrap());
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+}
+
+#[tokio::test]
+async fn test_bad_request_heads() {
+    let (listener, connector) = Listener::memory();
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .run(handler(Duration::from_millis(0))).await.unwrap();
+    let long_path = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(5000));
+    let long_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "a: b\r\n".repeat(1000));
+    for (request, expected_response) in &[
+        (long_path.as_str(), "HTTP/1.1 414 URI Too Long\r\ncontent-length: 0\r\n\r\n"),
+        (long_headers.as_str(), "HTTP/1.1 431 Request Header Fields Too Large\r\ncontent-length: 0\r\n\r\n"),
+        ("GET / HTTP/1.1\r\na: b\r\n c\r\n\r\n", "HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n"),
+        ("GET / HTTP/1.1\r\na : b\r\n\r\n", "HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n"),
+        ("get / HTTP/1.1\r\n\r\n", "HTTP/1.1 405 Method Not Allowed\r\ncontent-length: 0\r\n\r\n"),
+    ] {
+        for faults in test_faults() {
+            let response = send_raw_on(FaultyStream::new(connector.connect().unwrap(), faults.clone()), request).await;
+            assert_eq!(*expected_response, response, "{:?}", faults);
+        }
+    }
+    let response = send_raw_on(
+        connector.connect().unwrap(), "GET / HTTP/1.1\r\nUser-Agent: \t curl \t\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+}
+
+struct TargetHandler;
+
+#[async_trait]
+impl HttpHandler for TargetHandler {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        if &*http_reader_writer.raw_path == "/chunked" {
+            http_reader_writer.send_chunked(HttpStatus::Ok200, &[]).await?;
+            http_reader_writer.send_chunk(b"ab").await?;
+            http_reader_writer.send_chunk(b"cd").await?;
+            return http_reader_writer.finish_chunked().await;
+        }
+        let body = format!("{} {} {} {}", http_reader_writer.method(), http_reader_writer.version().as_str(),
+                           &*http_reader_writer.raw_path, http_reader_writer.host().unwrap_or("-"));
+        http_reader_writer.send_text(HttpStatus::Ok200, &[], &body).await
+    }
+}
 
+/// Sends `request` without closing the sending side, and reads until the server closes the connection.
+async fn send_raw_and_wait_for_close<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(
+    mut stream: S, request: &str) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    // The server may respond and close the connection before reading the whole request.
+    match stream.write_all(request.as_bytes()).await {
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+        result => result.unwrap(),
+    }
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut response)).await.unwrap().unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_request_versions_and_targets() {
+    let (listener, connector) = Listener::memory();
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .run(Arc::new(TargetHandler)).await.unwrap();
+    for faults in test_faults() {
+        let send = |request| {
+            send_raw_and_wait_for_close(FaultyStream::new(connector.connect().unwrap(), faults.clone()), request)
+        };
+        // HTTP/1.0 connections close by default.
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\ncontent-type: text/plain; charset=UTF-8\r\ncontent-length: 17\r\n\
+             connection: close\r\n\r\nGET HTTP/1.0 /a -",
+            send("GET /a HTTP/1.0\r\n\r\n").await,
+            "{:?}", faults);
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\ncontent-type: text/plain; charset=UTF-8\r\ncontent-length: 18\r\n\
+             connection: keep-alive\r\n\r\nGET HTTP/1.0 /a h1\
+             HTTP/1.1 200 OK\r\ncontent-type: text/plain; charset=UTF-8\r\ncontent-length: 18\r\n\
+             connection: close\r\n\r\nGET HTTP/1.0 /b h2",
+            send(
+                "GET /a HTTP/1.0\r\nHost: h1\r\nConnection: Keep-Alive\r\n\r\nGET /b HTTP/1.0\r\nHost: h2\r\n\r\n").await,
+            "{:?}", faults);
+        // HTTP/1.0 clients get unframed bodies in place of chunked encoding.
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nconnection: close\r\n\r\nabcd",
+            send("GET /chunked HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").await,
+            "{:?}", faults);
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\nconnection: close\r\n\r\n2\r\nab\r\n2\r\ncd\r\n0\r\n\r\n",
+            send("GET /chunked HTTP/1.1\r\nConnection: close\r\n\r\n").await,
+            "{:?}", faults);
+        assert_eq!(
+            "HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            send(
+                "POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n").await,
+            "{:?}", faults);
+        // Servers use the host in an absolute-form target, not the Host header.
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\ncontent-type: text/plain; charset=UTF-8\r\ncontent-length: 33\r\n\
+             connection: close\r\n\r\nGET HTTP/1.1 /?x example.com:8080",
+            send(
+                "GET http://example.com:8080?x HTTP/1.1\r\nHost: other\r\nConnection: close\r\n\r\n").await,
+            "{:?}", faults);
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\ncontent-type: text/plain; charset=UTF-8\r\ncontent-length: 21\r\n\
+             connection: close\r\n\r\nOPTIONS HTTP/1.1 * h1",
+            send("OPTIONS * HTTP/1.1\r\nHost: h1\r\nConnection: close\r\n\r\n").await,
+            "{:?}", faults);
+    }
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+}
+
+struct ClientIpHandler;
+
+#[async_trait]
+impl HttpHandler for ClientIpHandler {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        let body = format!("{} {}", http_reader_writer.peer_addr(),
+                           http_reader_writer.client_ip().map(|ip| ip.to_string()).unwrap_or_default());
+        http_reader_writer.send_text(HttpStatus::Ok200, &[], &body).await
+    }
+}
+
+#[tokio::test]
+async fn test_proxy_protocol_and_forwarded_headers() {
+    let (listener, connector) = Listener::memory();
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .proxy_protocol()
+        .trusted_proxies(TrustedProxies::new(ForwardingHeader::XForwardedFor).trust("10.0.0.0/8"))
+        .run(Arc::new(ClientIpHandler)).await.unwrap();
+    let send = |request: &'static str| send_raw_on(connector.connect().unwrap(), request);
+    let response = send("PROXY TCP4 192.0.2.1 10.0.0.1 56324 443\r\nGET / HTTP/1.1\r\n\r\n").await;
+    assert!(response.ends_with("\r\n\r\n192.0.2.1:56324 192.0.2.1"), "{:?}", response);
+    // Untrusted peers cannot pick their address.
+    let response = send(
+        "PROXY TCP4 192.0.2.1 10.0.0.1 56324 443\r\nGET / HTTP/1.1\r\nX-Forwarded-For: 198.51.100.1\r\n\r\n").await;
+    assert!(response.ends_with("\r\n\r\n192.0.2.1:56324 192.0.2.1"), "{:?}", response);
+    // Trusted proxies and the client's forged entries get skipped.
+    let response = send(
+        "PROXY TCP4 10.0.0.2 10.0.0.1 56324 443\r\nGET / HTTP/1.1\r\n\
+         X-Forwarded-For: 203.0.113.1, 198.51.100.1\r\nX-Forwarded-For: 10.0.0.3\r\n\r\n").await;
+    assert!(response.ends_with("\r\n\r\n10.0.0.2:56324 198.51.100.1"), "{:?}", response);
+    // Connections without a PROXY header get closed.
+    assert_eq!("", send("GET / HTTP/1.1\r\n\r\n").await);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+
+    let (listener, connector) = Listener::memory();
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .trusted_proxies(TrustedProxies::new(ForwardingHeader::Forwarded).trust_local())
+        .run(Arc::new(ClientIpHandler)).await.unwrap();
+    let response = send_raw_on(
+        connector.connect().unwrap(),
+        "GET / HTTP/1.1\r\nForwarded: for=\"[2001:db8::1]:4711\";proto=https\r\n\r\n").await;
+    assert!(response.ends_with("\r\n\r\ntest(0) 2001:db8::1"), "{:?}", response);
+    let response = send_raw_on(connector.connect().unwrap(), "GET / HTTP/1.1\r\n\r\n").await;
+    assert!(response.ends_with("\r\n\r\ntest(1) "), "{:?}", response);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+}
+
+#[tokio::test]
+async fn test_local_only_behind_proxy() {
+    let (listener, connector) = Listener::memory();
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .trusted_proxies(TrustedProxies::new(ForwardingHeader::XForwardedFor).trust_local())
+        .run(Arc::new(LocalOnly(Handler { delay: Duration::from_millis(0) }))).await.unwrap();
+    let send = |request: &'static str| send_raw_on(connector.connect().unwrap(), request);
+    let response = send("GET / HTTP/1.1\r\nX-Forwarded-For: 198.51.100.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{:?}", response);
+    let response = send("GET / HTTP/1.1\r\nX-Forwarded-For: 127.0.0.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+    let response = send("GET / HTTP/1.1\r\nX-Forwarded-For: ::1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+    let response = send("GET / HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+}
+
+#[tokio::test]
+async fn test_local_only() {
+    let http_server = HttpServerBuilder::new()
+        .run(Arc::new(LocalOnly(Handler { delay: Duration::from_millis(0) }))).await.unwrap();
+    let response = reqwest::get(&format!("http://{}/", http_server.local_addr)).await.unwrap();
+    assert_eq!(403, response.status().as_u16());
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let registry = Arc::new(logging::metrics::Registry::new());
+    let http_server = HttpServerBuilder::new()
+        .metrics(registry.clone())
+        .run(Arc::new(LocalOnly(Handler { delay: Duration::from_millis(0) }))).await.unwrap();
+    let (listener, connector) = Listener::memory();
+    let local_server = HttpServerBuilder::new()
+        .transport(listener)
+        .metrics(registry.clone())
+        .run(Arc::new(LocalOnly(Handler { delay: Duration::from_millis(0) }))).await.unwrap();
+    let response = reqwest::get(&format!("http://{}/", http_server.local_addr)).await.unwrap();
+    assert_eq!(403, response.status().as_u16());
+    send_raw_on(connector.connect().unwrap(), "GET / HTTP/1.1\r\n\r\n").await;
+    // Servers record metrics after sending responses.
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+    assert_eq!(0, local_server.stop(Duration::from_secs(1)).await);
+    let snapshot: std::collections::HashMap<&str, u64> = registry.snapshot().into_iter().collect();
+    assert_eq!(2, snapshot["http_connections"]);
+    assert_eq!(2, snapshot["http_requests"]);
+    assert_eq!(1, snapshot["http_client_errors"]);
+    assert_eq!(0, snapshot["http_server_errors"]);
+    assert_eq!(0, snapshot["http_active_requests"]);
+    assert_eq!(2, snapshot["http_request_duration_ms_count"]);
+}
+
+#[tokio::test]
+async fn test_prometheus_metrics() {
+    let registry = Arc::new(logging::metrics::Registry::new());
+    registry.counter_with_labels("jobs", &[("queue", "email")]).add(3);
+    let http_server = HttpServerBuilder::new()
+        .metrics(registry.clone())
+        .run(Arc::new(MetricsHandler::new(registry.clone(), Handler { delay: Duration::from_millis(0) })))
+        .await.unwrap();
+    let response = reqwest::get(&format!("http://{}/", http_server.local_addr)).await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+    let response = reqwest::get(&format!("http://{}/metrics", http_server.local_addr)).await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!("text/plain; version=0.0.4", response.headers()["content-type"]);
+    let body = response.text().await.unwrap();
+    assert!(body.contains("# TYPE jobs counter\njobs{queue=\"email\"} 3\n"), "{}", body);
+    assert!(body.contains("# HELP http_requests Requests handled.\n# TYPE http_requests counter\nhttp_requests 1\n"),
+            "{}", body);
+    assert!(body.contains("# TYPE http_request_duration_ms histogram\n"), "{}", body);
+    assert!(body.contains("http_request_duration_ms_bucket{le=\"+Inf\"} 1\n"), "{}", body);
+    assert!(body.contains("http_request_duration_ms_count 1\n"), "{}", body);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+}
+
+#[tokio::test]
+async fn test_tls() {
+    let mut ca_params = rcgen::CertificateParams::new(Vec::new());
+    ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    ca_params.distinguished_name.push(rcgen::DnType::CommonName, "test CA");
+    let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+    let server_cert = rcgen::generate_simple_self_signed([String::from("localhost")]).unwrap();
+    let mut server_config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+    server_config.set_single_cert(
+        vec![rustls::Certificate(server_cert.serialize_der_with_signer(&ca).unwrap())],
+        rustls::PrivateKey(server_cert.serialize_private_key_der())).unwrap();
+    let http_server = HttpServerBuilder::new()
+        .tls(Arc::new(server_config))
+        .run(handler(Duration::from_millis(0))).await.unwrap();
+    let port = http_server.local_addr.socket_addr().unwrap().port();
+    let client = reqwest::Client::builder()
+        .use_rustls_tls()
+        .add_root_certificate(reqwest::Certificate::from_der(&ca.serialize_der().unwrap()).unwrap())
+        .build()
+        .unwrap();
+    let response = client.get(&format!("https://localhost:{}/", port)).send().await.unwrap();
+    assert_eq!("hello", response.text().await.unwrap());
+    assert!(reqwest::get(&format!("http://localhost:{}/", port)).await.is_err());
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+}
+
+#[tokio::test]
+async fn test_health() {
+    let (mut stopper_controller, _stopper) = beatrice_http::stopper::new_stopper();
+    let checks = HealthChecks::new()
+        .add("db", Duration::from_secs(1), Criticality::Critical, || async { Ok(()) })
+        .add("cache", Duration::from_secs(1), Criticality::NonCritical,
+             || async { Err(String::from("cache down")) });
+    let health_handler = HealthHandler::new(checks, Handler { delay: Duration::from_millis(0) })
+        .with_stop_signal(stopper_controller.stop_signal());
+    let get = |path: &'static str| {
+        let request = format!("GET {} HTTP/1.1\r\n\r\n", path);
+        let health_handler = &health_handler;
+        async move {
+            let response = round_trip(health_handler, &request).await;
+            let (head, body) = response.split_at(response.find("\r\n\r\n").unwrap() + 4);
+            assert!(head.contains("\r\ncontent-type: application/json\r\n"), "{:?}", head);
+            let status: u16 = head[9..12].parse().unwrap();
+            let body: serde_json::Value = serde_json::from_str(body).unwrap();
+            (status, body["status"].as_str().unwrap().to_string(), body["checks"][1]["error"].clone())
+        }
+    };
+    assert_eq!((200, String::from("ok"), serde_json::json!("cache down")), get("/healthz").await);
+    assert_eq!((200, String::from("ok"), serde_json::json!("cache down")), get("/readyz").await);
+    assert!(round_trip(&health_handler, "GET /other HTTP/1.1\r\n\r\n").await.ends_with("\r\n\r\nhello"));
+    // HTTP/1.0 connections close after the response, but the server is not stopping.
+    let response = round_trip(&health_handler, "GET /readyz HTTP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+    assert!(response.contains("\"stopping\":false"), "{:?}", response);
+
+    stopper_controller.signal_stop();
+    assert_eq!(200, get("/healthz").await.0);
+    assert_eq!((503, String::from("unavailable"), serde_json::json!("cache down")), get("/readyz").await);
+}
+
+#[tokio::test]
+async fn test_health_critical_failure() {
+    let checks = HealthChecks::new()
+        .add("db", Duration::from_millis(10), Criticality::Critical, || async {
+            tokio::time::delay_for(Duration::from_secs(60)).await;
+            Ok(())
+        });
+    let health_handler = HealthHandler::new(checks, Handler { delay: Duration::from_millis(0) });
+    let response = round_trip(&health_handler, "GET /healthz HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{:?}", response);
+    let body: serde_json::Value = serde_json::from_str(&response[response.find("\r\n\r\n").unwrap() + 4..]).unwrap();
+    assert_eq!("timed out after 10ms", body["checks"][0]["error"]);
+}
+
+struct EventsHandler;
+
+#[async_trait]
+impl HttpHandler for EventsHandler {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        let mut event_stream = EventStream::start(http_reader_writer).await?;
+        event_stream.heartbeat_interval(Duration::from_millis(50));
+        let first: u64 = event_stream.last_event_id().unwrap_or("0").parse().unwrap();
+        let (mut tx, rx) = tokio::sync::mpsc::channel(2);
+        tokio::spawn(async move {
+            tx.send(Event::new("first").with_id((first + 1).to_string())).await.unwrap();
+            tokio::time::delay_for(Duration::from_millis(120)).await;
+            tx.send(Event::new("second\nline").with_id((first + 2).to_string()).with_event("update"))
+                .await.unwrap();
+        });
+        event_stream.send_all(rx).await?;
+        event_stream.finish().await
+    }
+}
 
-// https://stackoverflow.com/a/63190858
-// #[test]
-// fn test_cli() {
-//     let mut path = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
-//     path.push("target/debug/passman");
-//     let output = Command::new(path)
-//         .arg("--an-arg")
-//         .output()
-//         .expect("Failed to execute command");
-//
-//     assert_eq!(&output.stdout[..], b"Hello, world!\n");
-// }
+#[tokio::test]
+async fn test_server_sent_events() {
+    let (listener, connector) = Listener::memory();
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .run(Arc::new(EventsHandler)).await.unwrap();
+    let response = send_raw_on(
+        connector.connect().unwrap(), "GET /events HTTP/1.1\r\nLast-Event-ID: 5\r\n\r\n").await;
+    assert!(response.starts_with(
+        "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\
+         content-type: text/event-stream\r\ncache-control: no-store\r\n\r\n\
+         13\r\nid: 6\ndata: first\n\n\r\n\
+         d\r\n: heartbeat\n\n\r\n"),
+            "{:?}", response);
+    assert!(response.ends_with(
+        "2d\r\nid: 7\nevent: update\ndata: second\ndata: line\n\n\r\n0\r\n\r\n"),
+            "{:?}", response);
+    // HTTP/1.0 clients still get events, even though the connection closes afterwards.
+    let response = send_raw_on(connector.connect().unwrap(), "GET /events HTTP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+    assert!(response.contains("id: 1\ndata: first\n\n"), "{:?}", response);
+    assert!(response.contains("id: 2\nevent: update\ndata: second\ndata: line\n\n"), "{:?}", response);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+}
+
+struct EchoHandler;
 
-// use std::net::SocketAddr;
-// use tokio::net::TcpStream;
-// use logging::warn;
+#[async_trait]
+impl HttpHandler for EchoHandler {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        let mut websocket = beatrice_http::websocket::accept(http_reader_writer, WebSocketConfig::default()).await?;
+        loop {
+            match websocket.recv().await.map_err(HttpError::from_io_err)? {
+                Message::Text(text) => websocket.send_text(&text).await.map_err(HttpError::from_io_err)?,
+                Message::Close(_) => return Ok(()),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+}
 
-struct Handler {}
+#[tokio::test]
+async fn test_websocket() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let (listener, connector) = Listener::memory();
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .run(Arc::new(EchoHandler)).await.unwrap();
+    let mut stream = connector.connect().unwrap();
+    let request = String::from(
+        "GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n");
+    // A masked text frame "hi" and a close frame, sent with the handshake.
+    let mut frames = vec![0x81u8, 0x82, 1, 2, 3, 4, b'h' ^ 1, b'i' ^ 2];
+    frames.extend_from_slice(&[0x88, 0x80, 1, 2, 3, 4]);
+    let mut bytes = request.into_bytes();
+    bytes.extend_from_slice(&frames);
+    stream.write_all(&bytes).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let expected_head = "HTTP/1.1 101 Switching Protocols\r\nupgrade: websocket\r\nconnection: Upgrade\r\n\
+        sec-websocket-accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+    let mut expected = expected_head.as_bytes().to_vec();
+    expected.extend_from_slice(&[0x81, 2, b'h', b'i', 0x88, 0]);
+    assert_eq!(expected, response, "{}", String::from_utf8_lossy(&response));
+
+    let response = send_raw_on(
+        connector.connect().unwrap(),
+        "GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}", response);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+}
+
+struct UploadHandler;
 
 #[async_trait]
-impl HttpSessionHandler for Handler {
-    async fn handle(&self, session: &mut HttpSession) {
-        info!("Handler::handle() {:?}", session);
-        use tokio::io::AsyncWriteExt;
-        tokio::io::AsyncWriteExt::write_all(&mut session.tcp_stream, b"hello").await.unwrap();
-    }
-}
-
-#[test]
-#[named]
-fn test_port() {
-    logging::configure_for_test("info").unwrap();
-    // logging::configure_for_test("info").unwrap();
-    tokio_test::block_on(logging::task_scope(function_name!(), async {
-        let _http_server = HttpServerBuilder::new()
-            .localhost()
-            .port(24854)
-            .run(Arc::new(Handler {})).await.unwrap();
-        use tokio::io::AsyncReadExt;
-        let mut response = String::new();
-        tokio::io::AsyncReadExt::read_to_string(
-            tokio::net::TcpStream::connect("127.0.0.1:24854").await.unwrap().borrow_mut(),
-            response.borrow_mut()).await.unwrap();
-        assert_eq!("hello", response);
-    }));
-}
-
-// #[test]
-// #[named]
-// fn test_ipv4_and_ipv6() {
-//     logging::configure_for_test("info").unwrap();
-//     tokio_test::block_on(logging::task_scope(function_name!(), async {
-//         let builder = HttpServerBuilder::new();
-//         info!("{:?}", builder);
-//         let builder = builder.any_port();
-//         info!("{:?}", builder);
-//         let http_server = builder.run(Arc::new(Handler {})).await.unwrap();
-//         tokio::net::TcpStream::connect(("127.0.0.1", http_server.socket_addr.port())).await.unwrap();
-//         tokio::net::TcpStream::connect(("::1", http_server.socket_addr.port())).await.unwrap();
-//         tokio::time::delay_for(Duration::from_millis(100)).await;
-//     }));
-// }
+impl HttpHandler for UploadHandler {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        let limits = MultipartLimits { max_total_len: 50_000, max_part_len: 10_000, max_parts: 3 };
+        let mut multipart = MultipartReader::new(http_reader_writer, limits)?;
+        let mut summary = String::new();
+        while let Some(mut part) = multipart.next_part().await? {
+            let name = part.name().unwrap_or_default();
+            let filename = part.filename().unwrap_or_default();
+            let content_type = part.content_type().unwrap_or("").to_string();
+            let mut data = Vec::new();
+            tokio::io::AsyncReadExt::read_to_end(&mut part, &mut data).await
+                .map_err(beatrice_http::multipart::to_http_error)?;
+            let x_count = data.iter().filter(|b| **b == b'x').count();
+            summary.push_str(&format!(
+                "{} {:?} {:?} len={} x={}\n", name, filename, content_type, data.len(), x_count));
+        }
+        drop(multipart);
+        http_reader_writer.send_with_content_length(HttpStatus::Ok200, &[], summary.len() as u64).await?;
+        tokio::io::AsyncWriteExt::write_all(http_reader_writer, summary.as_bytes())
+            .await
+            .map_err(HttpError::from_io_err)
+    }
+}
+
+fn multipart_request(body: &str) -> String {
+    format!("POST /upload HTTP/1.1\r\ncontent-type: multipart/form-data; boundary=\"b0und\"\r\n\
+             content-length: {}\r\n\r\n{}", body.len(), body)
+}
+
+#[tokio::test]
+async fn test_multipart() {
+    let (listener, connector) = Listener::memory();
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .run(Arc::new(UploadHandler)).await.unwrap();
+    // The file holds text that looks like the start of the delimiter.
+    let file = "x\r\n--b0un".repeat(1000);
+    let body = format!(
+        "preamble\r\n--b0und\r\ncontent-disposition: form-data; name=\"comment\"\r\n\r\nhello\r\n\
+         --b0und\r\nContent-Disposition: form-data; name=\"bundle\"; filename=\"config.tar\"\r\n\
+         Content-Type: application/x-tar\r\n\r\n{}\r\n--b0und\r\n\
+         content-disposition: form-data; name=\"empty\"\r\n\r\n\r\n--b0und--\r\nepilogue",
+        file);
+    let response = send_raw_on(connector.connect().unwrap(), &multipart_request(&body)).await;
+    assert_eq!(
+        "HTTP/1.1 200 OK\r\ncontent-length: 102\r\n\r\ncomment \"\" \"\" len=5 x=0\n\
+         bundle \"config.tar\" \"application/x-tar\" len=9000 x=1000\nempty \"\" \"\" len=0 x=0\n",
+        response);
+
+    let file = "x".repeat(10_001);
+    let body = format!(
+        "--b0und\r\ncontent-disposition: form-data; name=\"bundle\"; filename=\"a\"\r\n\r\n{}\r\n--b0und--\r\n",
+        file);
+    let response = send_raw_on(connector.connect().unwrap(), &multipart_request(&body)).await;
+    assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "{:?}", response);
+
+    let body = "--b0und\r\ncontent-disposition: form-data; name=\"a\"\r\n\r\na\r\n--b0und\r\n";
+    let response = send_raw_on(connector.connect().unwrap(), &multipart_request(body)).await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}", response);
+
+    let response = send_raw_on(
+        connector.connect().unwrap(),
+        "POST /upload HTTP/1.1\r\ncontent-type: text/plain\r\ncontent-length: 1\r\n\r\na").await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}", response);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+}
+
+struct BodyHandler;
+
+#[async_trait]
+impl HttpHandler for BodyHandler {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        match &*http_reader_writer.raw_path {
+            "/json" => {
+                let counts: std::collections::BTreeMap<String, u64> = http_reader_writer.read_json(32).await?;
+                let total: u64 = counts.values().sum();
+                http_reader_writer.send_json(HttpStatus::Ok200, &[], &serde_json::json!({"total": total})).await
+            }
+            "/form" => {
+                let form = http_reader_writer.read_form(32).await?;
+                let name = beatrice_http::urlencoded::get(&form, "name").unwrap_or("");
+                http_reader_writer.send_bytes(
+                    HttpStatus::Ok200, &[], "text/csv", format!("{},{}", form.len(), name).as_bytes()).await
+            }
+            _ => Err(HttpError::ProcessingError(HttpStatus::NotFound404)),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_typed_bodies() {
+    let (listener, connector) = Listener::memory();
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .run(Arc::new(BodyHandler)).await.unwrap();
+    let response = send_raw_on(
+        connector.connect().unwrap(),
+        "POST /json HTTP/1.1\r\ncontent-length: 13\r\n\r\n{\"a\":1,\"b\":2}").await;
+    assert_eq!(
+        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: 11\r\n\r\n{\"total\":3}",
+        response);
+    let response = send_raw_on(
+        connector.connect().unwrap(),
+        "POST /json HTTP/1.1\r\ncontent-length: 5\r\n\r\n{\"a\":").await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}", response);
+    let body = format!("{{\"a\":{}}}", "1".repeat(30));
+    let response = send_raw_on(
+        connector.connect().unwrap(),
+        &format!("POST /json HTTP/1.1\r\ncontent-length: {}\r\n\r\n{}", body.len(), body)).await;
+    assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "{:?}", response);
+
+    let response = send_raw_on(
+        connector.connect().unwrap(),
+        "POST /form HTTP/1.1\r\ncontent-type: application/x-www-form-urlencoded\r\n\
+         content-length: 20\r\n\r\nname=J%C3%BCrgen+S&x").await;
+    assert_eq!(
+        "HTTP/1.1 200 OK\r\ncontent-type: text/csv\r\ncontent-length: 11\r\n\r\n2,J\u{fc}rgen S",
+        response);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+}
+
+struct SaveHandler {
+    dir: std::path::PathBuf,
+}
+
+#[async_trait]
+impl HttpHandler for SaveHandler {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        let path = self.dir.join("body");
+        let saved = save_body(http_reader_writer, &path, 10, Some(&ring::digest::SHA256)).await?;
+        let digest: String = saved.digest.unwrap().as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        http_reader_writer.send_text(HttpStatus::Created201, &[], &format!("{} {}", saved.len, digest)).await
+    }
+}
+
+#[tokio::test]
+async fn test_save_body() {
+    let dir = std::env::temp_dir().join(format!("beatrice_http_test_save_body_{}", std::process::id()));
+    std::fs::create_dir(&dir).unwrap();
+    let (listener, connector) = Listener::memory();
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .run(Arc::new(SaveHandler { dir: dir.clone() })).await.unwrap();
+    let list_dir = || {
+        let mut names: Vec<String> = std::fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    };
+    for body in &["abc", "0123456789"] {
+        let response = send_raw_on(
+            connector.connect().unwrap(),
+            &format!("PUT /body HTTP/1.1\r\ncontent-length: {}\r\n\r\n{}", body.len(), body)).await;
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"), "{:?}", response);
+        assert_eq!(vec![String::from("body")], list_dir());
+        assert_eq!(*body, std::fs::read_to_string(dir.join("body")).unwrap());
+    }
+    let response = send_raw_on(
+        connector.connect().unwrap(),
+        "PUT /body HTTP/1.1\r\ncontent-length: 3\r\n\r\nabc").await;
+    assert!(
+        response.ends_with("\r\n\r\n3 ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+        "{:?}", response);
+
+    let response = send_raw_on(
+        connector.connect().unwrap(),
+        "PUT /body HTTP/1.1\r\ncontent-length: 11\r\n\r\n01234567890").await;
+    assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "{:?}", response);
+    // The client disconnects before sending the whole body.
+    let response = send_raw_on(
+        connector.connect().unwrap(),
+        "PUT /body HTTP/1.1\r\ncontent-length: 10\r\n\r\nxyz").await;
+    assert_eq!("", response);
+    assert_eq!(vec![String::from("body")], list_dir());
+    assert_eq!("abc", std::fs::read_to_string(dir.join("body")).unwrap());
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_static_files() {
+    let dir = std::env::temp_dir().join(format!("beatrice_http_test_static_files_{}", std::process::id()));
+    let outside = std::env::temp_dir().join(format!("beatrice_http_test_static_files_outside_{}", std::process::id()));
+    let root = dir.join("root");
+    std::fs::create_dir_all(root.join("ui")).unwrap();
+    std::fs::create_dir_all(root.join("empty dir")).unwrap();
+    std::fs::write(root.join("ui/index.html"), "<p>ui</p>").unwrap();
+    std::fs::write(root.join("a b.txt"), "0123456789").unwrap();
+    std::fs::write(root.join("a?b.txt"), "question").unwrap();
+    std::fs::write(root.join("secret"), "secret").unwrap();
+    std::fs::write(&outside, "secret").unwrap();
+    std::os::unix::fs::symlink(&outside, root.join("escape.txt")).unwrap();
+    let (listener, connector) = Listener::memory();
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .run(Arc::new(StaticFiles::new(&root).unwrap())).await.unwrap();
+    let get = |path: &str, headers: &str| {
+        let request = format!("GET {} HTTP/1.1\r\n{}\r\n", path, headers);
+        let stream = connector.connect().unwrap();
+        async move { send_raw_on(stream, &request).await }
+    };
+
+    let response = get("/a%20b.txt?v=1", "").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+    assert!(response.contains("content-type: text/plain; charset=UTF-8\r\n"), "{:?}", response);
+    assert!(response.contains("accept-ranges: bytes\r\n"), "{:?}", response);
+    assert!(response.contains("content-length: 10\r\n"), "{:?}", response);
+    assert!(response.ends_with("\r\n\r\n0123456789"), "{:?}", response);
+    let etag = response.lines().find(|line| line.starts_with("etag: ")).unwrap()[6..].to_string();
+
+    let response = get("/a%20b.txt", &format!("If-None-Match: {}\r\n", etag)).await;
+    assert_eq!(format!("HTTP/1.1 304 Not Modified\r\netag: {}\r\n\r\n", etag), response);
+    let response = get("/a%20b.txt", "Range: bytes=2-4\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"), "{:?}", response);
+    assert!(response.contains("content-range: bytes 2-4/10\r\n"), "{:?}", response);
+    assert!(response.contains("content-length: 3\r\n"), "{:?}", response);
+    assert!(response.ends_with("\r\n\r\n234"), "{:?}", response);
+    let response = get("/a%20b.txt", "Range: bytes=2-4\r\nIf-Range: \"old\"\r\n").await;
+    assert!(response.ends_with("\r\n\r\n0123456789"), "{:?}", response);
+    let response = get("/a%20b.txt", "Range: bytes=10-\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"), "{:?}", response);
+    assert!(response.contains("content-range: bytes */10\r\n"), "{:?}", response);
+    let response = send_raw_on(connector.connect().unwrap(), "HEAD /a%20b.txt HTTP/1.1\r\n\r\n").await;
+    assert!(response.contains("content-length: 10\r\n"), "{:?}", response);
+    assert!(response.ends_with("accept-ranges: bytes\r\n\r\n"), "{:?}", response);
+
+    let response = get("/a%3Fb.txt?v=1", "").await;
+    assert!(response.ends_with("\r\n\r\nquestion"), "{:?}", response);
+    let response = get("/ui/", "").await;
+    assert!(response.ends_with("\r\n\r\n<p>ui</p>"), "{:?}", response);
+    let response = get("/ui?x=1", "").await;
+    assert_eq!("HTTP/1.1 301 Moved Permanently\r\ncontent-length: 0\r\nlocation: /ui/?x=1\r\n\r\n", response);
+    for path in &["/empty%20dir/", "/escape.txt", "/secret%3Fx", "/%ff", "/../beatrice_http_test_static_files_outside", "/missing"] {
+        let response = get(path, "").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{} {:?}", path, response);
+    }
+    let response = send_raw_on(connector.connect().unwrap(), "DELETE /a%20b.txt HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{:?}", response);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+
+    let (listener, connector) = Listener::memory();
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .run(Arc::new(StaticFiles::new(&root).unwrap().directory_listings(true))).await.unwrap();
+    let response = send_raw_on(connector.connect().unwrap(), "GET / HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+    assert!(response.contains("<li><a href=\"a%20b.txt\">a b.txt</a></li>\n"), "{:?}", response);
+    assert!(response.contains("<li><a href=\"empty%20dir/\">empty dir/</a></li>\n"), "{:?}", response);
+    assert!(response.contains("<li><a href=\"ui/\">ui/</a></li>\n"), "{:?}", response);
+    let listing_len = response.len() - response.find("\r\n\r\n").unwrap() - 4;
+    let response = send_raw_on(connector.connect().unwrap(), "HEAD / HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+    assert!(response.contains(&format!("\r\ncontent-length: {}\r\n", listing_len)), "{:?}", response);
+    assert!(response.ends_with("\r\n\r\n"), "{:?}", response);
+    let response = send_raw_on(connector.connect().unwrap(), "GET /ui/ HTTP/1.1\r\n\r\n").await;
+    assert!(response.ends_with("\r\n\r\n<p>ui</p>"), "{:?}", response);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+    std::fs::remove_dir_all(&dir).unwrap();
+    std::fs::remove_file(&outside).unwrap();
+}
+
+struct WhoAmIHandler;
+
+#[async_trait]
+impl HttpHandler for WhoAmIHandler {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        let principal = String::from(http_reader_writer.principal().unwrap());
+        http_reader_writer.send_text(HttpStatus::Ok200, &[], &principal).await
+    }
+}
+
+/// Accepts tokens like `user:NAME` and forbids `user:guest`.
+struct UserTokens;
+
+#[async_trait]
+impl TokenVerifier for UserTokens {
+    async fn verify(&self, token: &str) -> Result<String, AuthFailure> {
+        match token.strip_prefix("user:") {
+            Some("guest") => Err(AuthFailure::Forbidden),
+            Some(name) => Ok(String::from(name)),
+            None => Err(AuthFailure::Unauthorized),
+        }
+    }
+}
+
+async fn send_with_authorization(connector: &beatrice_http::transport::MemoryConnector, authorization: &str) -> String {
+    send_raw_on(
+        connector.connect().unwrap(),
+        &format!("GET /whoami HTTP/1.1\r\nAuthorization: {}\r\n\r\n", authorization)).await
+}
+
+#[tokio::test]
+async fn test_auth() {
+    let (listener, connector) = Listener::memory();
+    let basic = BasicAuth::new("admin").user("user1", PasswordHash::new("pass1"));
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .run(Arc::new(Authenticated::new(basic, WhoAmIHandler))).await.unwrap();
+    let response = send_with_authorization(&connector, &format!("Basic {}", base64::encode("user1:pass1"))).await;
+    assert!(response.ends_with("\r\n\r\nuser1"), "{:?}", response);
+    let response = send_with_authorization(&connector, &format!("basic {}", base64::encode("user1:pass2"))).await;
+    assert_eq!(
+        "HTTP/1.1 401 Unauthorized\r\ncontent-length: 0\r\n\
+         www-authenticate: Basic realm=\"admin\", charset=\"UTF-8\"\r\n\r\n",
+        response);
+    let response = send_raw_on(connector.connect().unwrap(), "GET /whoami HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{:?}", response);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+
+    let (listener, connector) = Listener::memory();
+    let bearer = BearerAuth::new("api", UserTokens);
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .run(Arc::new(Authenticated::new(bearer, WhoAmIHandler))).await.unwrap();
+    let response = send_with_authorization(&connector, "Bearer user:alice").await;
+    assert!(response.ends_with("\r\n\r\nalice"), "{:?}", response);
+    let response = send_with_authorization(&connector, "Bearer user:guest").await;
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{:?}", response);
+    let response = send_with_authorization(&connector, "Bearer x").await;
+    assert!(response.contains("www-authenticate: Bearer realm=\"api\"\r\n"), "{:?}", response);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+
+    let (listener, connector) = Listener::memory();
+    let bearer = BearerAuth::new("api", StaticTokens::new().token("t1", "service1"));
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .run(Arc::new(Authenticated::new(bearer, WhoAmIHandler))).await.unwrap();
+    let response = send_with_authorization(&connector, "Bearer t1").await;
+    assert!(response.ends_with("\r\n\r\nservice1"), "{:?}", response);
+    let response = send_with_authorization(&connector, "Bearer t2").await;
+    assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{:?}", response);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+
+    let (listener, connector) = Listener::memory();
+    let hmac_auth = HmacAuth::new(Duration::from_secs(60)).key("client1", b"secret1");
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .run(Arc::new(Authenticated::new(hmac_auth, WhoAmIHandler))).await.unwrap();
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    let authorization = hmac_authorization("client1", b"secret1", "GET", "/whoami", now, 0);
+    let response = send_with_authorization(&connector, &authorization).await;
+    assert!(response.ends_with("\r\n\r\nclient1"), "{:?}", response);
+    let authorization = hmac_authorization("client1", b"secret1", "GET", "/whoami", now - 120, 0);
+    let response = send_with_authorization(&connector, &authorization).await;
+    assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{:?}", response);
+    assert!(response.contains("www-authenticate: HMAC-SHA256\r\n"), "{:?}", response);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+}
+
+#[tokio::test]
+async fn test_cors() {
+    let (listener, connector) = Listener::memory();
+    let policy = CorsPolicy::new()
+        .allow_origin("https://dashboard.example.com")
+        .allow_methods(&["GET", "DELETE"])
+        .allow_headers(&["content-type"])
+        .expose_headers(&["x-request-id"])
+        .allow_credentials(true)
+        .max_age(Duration::from_secs(600));
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .run(Arc::new(Cors::new(policy, Handler { delay: Duration::from_secs(0) }))).await.unwrap();
+    let response = send_raw_on(
+        connector.connect().unwrap(),
+        "OPTIONS /events HTTP/1.1\r\nOrigin: https://dashboard.example.com\r\n\
+         Access-Control-Request-Method: DELETE\r\nAccess-Control-Request-Headers: Content-Type\r\n\r\n").await;
+    assert_eq!(
+        "HTTP/1.1 204 No Content\r\nvary: origin\r\n\
+         access-control-allow-origin: https://dashboard.example.com\r\n\
+         access-control-allow-methods: GET, DELETE\r\naccess-control-allow-headers: content-type\r\n\
+         access-control-allow-credentials: true\r\naccess-control-max-age: 600\r\n\r\n",
+        response);
+    for request in &[
+        "OPTIONS /events HTTP/1.1\r\nOrigin: https://evil.example.com\r\nAccess-Control-Request-Method: GET\r\n\r\n",
+        "OPTIONS /events HTTP/1.1\r\nOrigin: https://dashboard.example.com\r\nAccess-Control-Request-Method: PUT\r\n\r\n",
+        "OPTIONS /events HTTP/1.1\r\nOrigin: https://dashboard.example.com\r\nAccess-Control-Request-Method: GET\r\n\
+         Access-Control-Request-Headers: authorization\r\n\r\n",
+    ] {
+        let response = send_raw_on(connector.connect().unwrap(), request).await;
+        assert_eq!("HTTP/1.1 403 Forbidden\r\ncontent-length: 0\r\nvary: origin\r\n\r\n", response);
+    }
+
+    let response = send_raw_on(
+        connector.connect().unwrap(),
+        "GET /events HTTP/1.1\r\nOrigin: https://dashboard.example.com\r\n\r\n").await;
+    assert_eq!(
+        "HTTP/1.1 200 OK\r\ncontent-type: text/plain; charset=UTF-8\r\ncontent-length: 5\r\nvary: origin\r\n\
+         access-control-allow-origin: https://dashboard.example.com\r\naccess-control-allow-credentials: true\r\n\
+         access-control-expose-headers: x-request-id\r\n\r\nhello",
+        response);
+    let response = send_raw_on(
+        connector.connect().unwrap(),
+        "GET /events HTTP/1.1\r\nOrigin: https://evil.example.com\r\n\r\n").await;
+    assert_eq!(
+        "HTTP/1.1 200 OK\r\ncontent-type: text/plain; charset=UTF-8\r\ncontent-length: 5\r\nvary: origin\r\n\r\nhello",
+        response);
+    let response = send_raw_on(connector.connect().unwrap(), "GET /events HTTP/1.1\r\n\r\n").await;
+    assert_eq!(
+        "HTTP/1.1 200 OK\r\ncontent-type: text/plain; charset=UTF-8\r\ncontent-length: 5\r\nvary: origin\r\n\r\nhello",
+        response);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+}
+
+/// Records hook calls and adds a response header.
+struct Record {
+    name: &'static str,
+    calls: Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+#[async_trait]
+impl Middleware for Record {
+    async fn before(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<Flow, HttpError> {
+        self.calls.lock().unwrap().push(format!("before {}", self.name));
+        http_reader_writer.add_response_header("x-middleware", self.name)?;
+        Ok(Flow::Continue)
+    }
+
+    async fn after(&self, _http_reader_writer: &mut HttpReaderWriter<'_>, result: Result<(), HttpError>)
+                   -> Result<(), HttpError> {
+        self.calls.lock().unwrap().push(format!("after {}", self.name));
+        result
+    }
+}
+
+struct DenyAll;
+
+#[async_trait]
+impl Middleware for DenyAll {
+    async fn before(&self, _http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<Flow, HttpError> {
+        Err(HttpError::ProcessingError(HttpStatus::Forbidden403))
+    }
+}
+
+/// Sends a text body for 404 errors.
+struct NotFoundPage;
+
+#[async_trait]
+impl Middleware for NotFoundPage {
+    async fn after(&self, http_reader_writer: &mut HttpReaderWriter<'_>, result: Result<(), HttpError>)
+                   -> Result<(), HttpError> {
+        match result {
+            Err(HttpError::ProcessingError(HttpStatus::NotFound404)) if http_reader_writer.status().is_none() => {
+                let body = format!("not found: {}", http_reader_writer.raw_path);
+                http_reader_writer.send_text(HttpStatus::NotFound404, &[], &body).await
+            }
+            result => result,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_middleware() {
+    let (listener, connector) = Listener::memory();
+    let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let record = |name| Record { name, calls: calls.clone() };
+    let router = Router::new(Stack::new().with(record("global")).with(NotFoundPage))
+        .route("/hello", Stack::new().with(record("route")), Handler { delay: Duration::from_secs(0) })
+        .route("/admin/", Stack::new().with(record("admin")).with(RequireLocal).with(DenyAll),
+               Handler { delay: Duration::from_secs(0) })
+        .route("/cors/", Stack::new().with(CorsPolicy::new().allow_any_origin()),
+               Handler { delay: Duration::from_secs(0) });
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .run(Arc::new(router)).await.unwrap();
+
+    let response = send_raw_on(connector.connect().unwrap(), "GET /hello HTTP/1.1\r\n\r\n").await;
+    assert_eq!(
+        "HTTP/1.1 200 OK\r\ncontent-type: text/plain; charset=UTF-8\r\ncontent-length: 5\r\n\
+         x-middleware: global\r\nx-middleware: route\r\n\r\nhello",
+        response);
+    assert_eq!(vec!["before global", "before route", "after route", "after global"],
+               calls.lock().unwrap().drain(..).collect::<Vec<String>>());
+
+    // DenyAll short-circuits the chain.  Only middleware whose `before` continued gets `after`.
+    let response = send_raw_on(connector.connect().unwrap(), "GET /admin/users HTTP/1.1\r\n\r\n").await;
+    assert_eq!(
+        "HTTP/1.1 403 Forbidden\r\ncontent-length: 0\r\nx-middleware: global\r\nx-middleware: admin\r\n\r\n",
+        response);
+    assert_eq!(vec!["before global", "before admin", "after admin", "after global"],
+               calls.lock().unwrap().drain(..).collect::<Vec<String>>());
+
+    // Routes match the decoded path, so these still go through the admin stack.
+    for path in &["//admin/users", "/%61dmin/users", "/./admin//users"] {
+        let request = format!("GET {} HTTP/1.1\r\n\r\n", path);
+        let response = send_raw_on(connector.connect().unwrap(), &request).await;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{:?} {:?}", path, response);
+        assert_eq!(vec!["before global", "before admin", "after admin", "after global"],
+                   calls.lock().unwrap().drain(..).collect::<Vec<String>>());
+    }
+    for path in &["/hello/../admin/users", "/admin%2fusers", "/%ff"] {
+        let request = format!("GET {} HTTP/1.1\r\n\r\n", path);
+        let response = send_raw_on(connector.connect().unwrap(), &request).await;
+        assert_eq!("HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n", response, "{:?}", path);
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    let response = send_raw_on(connector.connect().unwrap(), "GET /nope HTTP/1.1\r\n\r\n").await;
+    assert_eq!(
+        "HTTP/1.1 404 Not Found\r\ncontent-type: text/plain; charset=UTF-8\r\ncontent-length: 16\r\n\
+         x-middleware: global\r\n\r\nnot found: /nope",
+        response);
+    assert_eq!(vec!["before global", "after global"],
+               calls.lock().unwrap().drain(..).collect::<Vec<String>>());
+
+    let response = send_raw_on(
+        connector.connect().unwrap(), "GET /cors/a HTTP/1.1\r\nOrigin: https://a.example.com\r\n\r\n").await;
+    assert_eq!(
+        "HTTP/1.1 200 OK\r\ncontent-type: text/plain; charset=UTF-8\r\ncontent-length: 5\r\n\
+         x-middleware: global\r\naccess-control-allow-origin: *\r\n\r\nhello",
+        response);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+}
+
+struct UpstreamHandler;
+
+#[async_trait]
+impl HttpHandler for UpstreamHandler {
+    async fn handle(&self, http_reader_writer: &mut HttpReaderWriter<'_>) -> Result<(), HttpError> {
+        match &*http_reader_writer.raw_path {
+            "/chunked" => {
+                http_reader_writer.send_chunked(HttpStatus::Ok200, &[]).await?;
+                http_reader_writer.send_chunk(b"ab").await?;
+                http_reader_writer.send_chunk(b"cd").await?;
+                http_reader_writer.finish_chunked().await
+            }
+            "/limited" => http_reader_writer.send_text(HttpStatus::Other(429), &[], "slow down").await,
+            "/slow" => {
+                tokio::time::delay_for(Duration::from_millis(500)).await;
+                http_reader_writer.send_text(HttpStatus::Ok200, &[], "slow").await
+            }
+            _ => {
+                let body = http_reader_writer.read_body(100).await?;
+                let text = {
+                    let header = |name| http_reader_writer.header(name).unwrap_or("-");
+                    format!(
+                    "{} {} host={} xff={} xfh={} custom={} secret={} auth={} keep-alive={} body={}",
+                    http_reader_writer.method(), &*http_reader_writer.raw_path, header("host"),
+                    header("x-forwarded-for"), header("x-forwarded-host"), header("x-custom"),
+                    header("x-secret"), header("authorization"), header("keep-alive"),
+                    String::from_utf8(body).unwrap())
+                };
+                http_reader_writer.send_text(HttpStatus::Ok200, &[&Header::new("x-upstream", "1")], &text).await
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_reverse_proxy() {
+    let upstream_server = HttpServerBuilder::new().run(Arc::new(UpstreamHandler)).await.unwrap();
+    let upstream_addr = upstream_server.local_addr.socket_addr().unwrap();
+    let closed_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let router = Router::new(Stack::new())
+        .route("/up/", Stack::new(),
+               ReverseProxy::new(Upstream::Tcp(upstream_addr))
+                   .strip_prefix("/up")
+                   .idle_timeout(Duration::from_millis(100))
+                   .remove_request_header("authorization"))
+        .route("/down/", Stack::new(), ReverseProxy::new(Upstream::Tcp(closed_addr)));
+    let (listener, connector) = Listener::memory();
+    let http_server = HttpServerBuilder::new()
+        .transport(listener)
+        .run(Arc::new(router)).await.unwrap();
+
+    // The proxy removes hop-by-hop headers, including ones named in `connection`.
+    let response = send_raw_on(
+        connector.connect().unwrap(),
+        "POST /up/echo?a=1 HTTP/1.1\r\nHost: front\r\nX-Custom: c\r\nX-Secret: s\r\n\
+         Connection: keep-alive, X-Secret\r\nKeep-Alive: timeout=5\r\nAuthorization: Bearer t\r\n\
+         X-Forwarded-For: 192.0.2.1\r\nContent-Length: 5\r\n\r\nhello").await;
+    assert_eq!(
+        "HTTP/1.1 200 OK\r\ncontent-length: 107\r\ncontent-type: text/plain; charset=UTF-8\r\n\
+         x-upstream: 1\r\n\r\nPOST /echo?a=1 host=front xff=192.0.2.1, unknown xfh=front custom=c \
+         secret=- auth=- keep-alive=- body=hello",
+        response);
+    let response = send_raw_on(connector.connect().unwrap(), "GET /up HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{:?}", response);
+    let response = send_raw_on(connector.connect().unwrap(), "GET /up/chunked HTTP/1.1\r\n\r\n").await;
+    assert_eq!(
+        "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n2\r\nab\r\n2\r\ncd\r\n0\r\n\r\n",
+        response);
+    let response = send_raw_on(connector.connect().unwrap(), "GET /up/limited HTTP/1.1\r\n\r\n").await;
+    assert_eq!(
+        "HTTP/1.1 429 Too Many Requests\r\ncontent-length: 9\r\ncontent-type: text/plain; charset=UTF-8\r\n\
+         \r\nslow down",
+        response);
+    let response = send_raw_on(
+        connector.connect().unwrap(),
+        "POST /up/echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 411 Length Required\r\n"), "{:?}", response);
+    let response = send_raw_on(connector.connect().unwrap(), "GET /up/slow HTTP/1.1\r\n\r\n").await;
+    assert_eq!("HTTP/1.1 504 Gateway Timeout\r\ncontent-length: 0\r\n\r\n", response);
+    let response = send_raw_on(connector.connect().unwrap(), "GET /down/ HTTP/1.1\r\n\r\n").await;
+    assert_eq!("HTTP/1.1 502 Bad Gateway\r\ncontent-length: 0\r\n\r\n", response);
+    assert_eq!(0, http_server.stop(Duration::from_secs(1)).await);
+    assert_eq!(0, upstream_server.stop(Duration::from_secs(5)).await);
+}
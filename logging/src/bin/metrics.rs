@@ -0,0 +1,25 @@
+// Emit metrics as events in the same stream as log messages.
+use std::time::Duration;
+
+fn main() {
+    let _global_logger_guard = logging::configure("info").unwrap();
+    let registry = logging::metrics::global();
+    let queue_length = registry.gauge("queue_length");
+    let failed_item_count = registry.counter("failed_item_count");
+    let work_ms = registry.histogram("work_ms", &[10, 100, 1000]);
+    let _emitter = logging::metrics::emit_periodically(registry, Duration::from_millis(100));
+    for n in 0..5 {
+        queue_length.set(5 - n);
+        if n == 3 {
+            failed_item_count.inc();
+        }
+        work_ms.record(n * 30);
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    // Dropping the emitter emits one last time.
+
+    // $ cargo run --bin metrics
+    // {"time_ns":1603061023112416000,"time":"2020-10-18T15:43:43.112-07:00","module":"logging::metrics","level":"INFO","message":"metrics","queue_length":4,"failed_item_count":0,"work_ms_count":2,"work_ms_sum":30,"work_ms_le_10":1,"work_ms_le_100":2,"work_ms_le_1000":2}
+    // {"time_ns":1603061023213031000,"time":"2020-10-18T15:43:43.213-07:00","module":"logging::metrics","level":"INFO","message":"metrics","queue_length":2,"failed_item_count":1,"work_ms_count":4,"work_ms_sum":180,"work_ms_le_10":1,"work_ms_le_100":4,"work_ms_le_1000":4}
+    // {"time_ns":1603061023263744000,"time":"2020-10-18T15:43:43.263-07:00","module":"logging::metrics","level":"INFO","message":"metrics","queue_length":1,"failed_item_count":1,"work_ms_count":5,"work_ms_sum":300,"work_ms_le_10":1,"work_ms_le_100":4,"work_ms_le_1000":5}
+}
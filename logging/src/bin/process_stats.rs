@@ -0,0 +1,19 @@
+// Emit the process's memory, thread, and file descriptor counts as events.
+use std::time::Duration;
+
+fn main() {
+    let _global_logger_guard = logging::configure("info").unwrap();
+    let mut leaked_files = Vec::new();
+    let _emitter = logging::process_stats::ProcessSampler::new()
+        .emit_periodically(Duration::from_millis(100));
+    for _ in 0..3 {
+        leaked_files.push(std::fs::File::open("/proc/self/status").unwrap());
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    // Dropping the emitter emits one last time.
+
+    // $ cargo run --bin process_stats
+    // {"time_ns":1603063217305102000,"time":"2020-10-18T16:20:17.305-07:00","module":"logging::process_stats","level":"INFO","message":"process_stats","heap_size_mb":4,"rss_mb":4,"threads":3,"tokio_named_threads":0,"fds":6,"sockets":0,"files":5}
+    // {"time_ns":1603063217406284000,"time":"2020-10-18T16:20:17.406-07:00","module":"logging::process_stats","level":"INFO","message":"process_stats","heap_size_mb":4,"rss_mb":5,"threads":3,"tokio_named_threads":0,"fds":7,"sockets":0,"files":6}
+    // {"time_ns":1603063217506931000,"time":"2020-10-18T16:20:17.506-07:00","module":"logging::process_stats","level":"INFO","message":"process_stats","heap_size_mb":4,"rss_mb":5,"threads":3,"tokio_named_threads":0,"fds":8,"sockets":0,"files":7}
+}
@@ -5,6 +5,8 @@ use std::result::Result;
 
 pub mod apple;
 pub mod banana;
+pub mod metrics;
+pub mod process_stats;
 pub mod using_log;
 pub mod using_slog;
 pub mod using_slog_scope;
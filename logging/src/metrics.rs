@@ -0,0 +1,681 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Histogram bucket upper bounds for durations in milliseconds.
+pub const DURATION_MS_BUCKETS: &[u64] = &[1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000, 10000];
+
+/// The most labeled metrics a `Registry` holds.  Each one leaks its event keys, so a label value
+/// taken from requests, like a path, would otherwise leak memory without bound.
+pub const MAX_LABEL_SETS: usize = 1000;
+
+/// Label names and values that distinguish metrics with the same name,
+/// like `[("method", "GET")]`.
+pub type Labels = Vec<(&'static str, String)>;
+
+/// Returns the event key for a metric: the name and suffix followed by the labels in Prometheus
+/// syntax, like `http_requests{method="GET"}` or `latency_ms_count{method="GET"}`.
+/// Names cannot contain `{`, so keys of labeled metrics never equal keys of unlabeled ones.
+fn event_key(name: &'static str, suffix: &str, labels: &[(&'static str, String)]) -> &'static str {
+    if suffix.is_empty() && labels.is_empty() {
+        return name;
+    }
+    let mut key = format!("{}{}", name, suffix);
+    if !labels.is_empty() {
+        let pairs: Vec<String> = labels.iter()
+            .map(|(n, v)| format!("{}=\"{}\"", n, escape_label_value(v)))
+            .collect();
+        key.push('{');
+        key.push_str(&pairs.join(","));
+        key.push('}');
+    }
+    intern(key)
+}
+
+lazy_static::lazy_static! {
+    static ref EVENT_KEYS: Mutex<HashSet<&'static str>> = Mutex::new(HashSet::new());
+}
+
+/// Returns a `&'static str` equal to `key`, leaking it only the first time the process sees it.
+fn intern(key: String) -> &'static str {
+    let mut keys = EVENT_KEYS.lock().unwrap();
+    if let Some(interned) = keys.get(key.as_str()) {
+        return interned;
+    }
+    let interned = Box::leak(key.into_boxed_str());
+    keys.insert(interned);
+    interned
+}
+
+/// Panics unless `name` matches the Prometheus name syntax `[a-zA-Z_:][a-zA-Z0-9_:]*`.
+fn check_name(name: &str) {
+    let valid = name.chars().enumerate().all(|(i, c)| {
+        c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit())
+    });
+    assert!(valid && !name.is_empty(), "invalid metric or label name {:?}", name);
+}
+
+/// Counter is a number that only goes up, like the number of requests handled.
+#[derive(Debug)]
+pub struct Counter {
+    name: &'static str,
+    labels: Labels,
+    key: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn labels(&self) -> &[(&'static str, String)] {
+        &self.labels
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// Gauge is a number that goes up and down, like the length of a queue.
+///
+/// Gauges cannot go below zero.
+#[derive(Debug)]
+pub struct Gauge {
+    name: &'static str,
+    labels: Labels,
+    key: &'static str,
+    value: AtomicU64,
+}
+
+impl Gauge {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn labels(&self) -> &[(&'static str, String)] {
+        &self.labels
+    }
+
+    pub fn set(&self, n: u64) {
+        self.value.store(n, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Subtracts one.  Does nothing if the gauge is zero.
+    pub fn dec(&self) {
+        let _ = self.value.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// Histogram counts values in fixed buckets, like request durations.
+#[derive(Debug)]
+pub struct Histogram {
+    name: &'static str,
+    labels: Labels,
+    bounds: &'static [u64],
+    // One counter per bound, plus one for values larger than every bound.
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    // Event keys, made once so emitting does not allocate.
+    count_key: &'static str,
+    sum_key: &'static str,
+    bucket_keys: Vec<&'static str>,
+}
+
+impl Histogram {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn labels(&self) -> &[(&'static str, String)] {
+        &self.labels
+    }
+
+    /// Bucket upper bounds, in increasing order.
+    pub fn bounds(&self) -> &'static [u64] {
+        self.bounds
+    }
+
+    pub fn record(&self, value: u64) {
+        let index = self.bounds.iter().position(|&bound| value <= bound).unwrap_or(self.bounds.len());
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn record_duration(&self, duration: Duration) {
+        self.record(duration.as_millis() as u64);
+    }
+
+    /// Returns the number of recorded values less than or equal to each bound,
+    /// followed by the total number of recorded values.
+    pub fn cumulative_counts(&self) -> Vec<u64> {
+        let mut total = 0;
+        self.buckets.iter().map(|bucket| {
+            total += bucket.load(Ordering::Relaxed);
+            total
+        }).collect()
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Metric {
+    Counter(Arc<Counter>),
+    Gauge(Arc<Gauge>),
+    Histogram(Arc<Histogram>),
+}
+
+impl Metric {
+    fn name(&self) -> &'static str {
+        match self {
+            Metric::Counter(c) => c.name,
+            Metric::Gauge(g) => g.name,
+            Metric::Histogram(h) => h.name,
+        }
+    }
+
+    fn labels(&self) -> &[(&'static str, String)] {
+        match self {
+            Metric::Counter(c) => &c.labels,
+            Metric::Gauge(g) => &g.labels,
+            Metric::Histogram(h) => &h.labels,
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Metric::Counter(_) => "counter",
+            Metric::Gauge(_) => "gauge",
+            Metric::Histogram(_) => "histogram",
+        }
+    }
+}
+
+fn to_labels(labels: &[(&'static str, &str)]) -> Labels {
+    labels.iter().map(|(name, value)| (*name, String::from(*value))).collect()
+}
+
+/// Registry holds metrics and emits their values as one event.
+///
+/// Counters and histograms count from when the process started.
+/// Values are non-negative integers.
+///
+/// Metrics with the same name and different labels are one Prometheus metric family.
+/// They must all be the same kind.
+///
+/// Names and label names must match `[a-zA-Z_:][a-zA-Z0-9_:]*`.  Label values can be any string.
+///
+/// Get a metric once and keep it.  Each labeled metric and each histogram leaks a few small strings,
+/// once per process.  A registry holds at most `MAX_LABEL_SETS` labeled metrics.  Past that, new
+/// label sets get metrics that work but are not emitted, and the `metrics_dropped_label_sets`
+/// counter counts them.
+#[derive(Debug, Default)]
+pub struct Registry {
+    metrics: Mutex<Vec<Metric>>,
+    help: Mutex<Vec<(&'static str, &'static str)>>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry { metrics: Mutex::new(Vec::new()), help: Mutex::new(Vec::new()) }
+    }
+
+    fn get_or_add(&self, name: &'static str, labels: &[(&'static str, &str)], type_name: &'static str,
+                  make: impl FnOnce(Labels) -> Metric) -> Metric {
+        let mut metrics = self.metrics.lock().unwrap();
+        let same_labels = |metric: &Metric| metric.labels().len() == labels.len()
+            && metric.labels().iter().zip(labels).all(|((n1, v1), (n2, v2))| n1 == n2 && v1 == v2);
+        if let Some(metric) = metrics.iter().find(|m| m.name() == name && same_labels(m)) {
+            return metric.clone();
+        }
+        if let Some(other) = metrics.iter().find(|m| m.name() == name) {
+            assert_eq!(other.type_name(), type_name,
+                       "metric {:?} is a {} and a {}", name, other.type_name(), type_name);
+        }
+        check_name(name);
+        for (label_name, _) in labels {
+            check_name(label_name);
+        }
+        if !labels.is_empty() && metrics.iter().filter(|m| !m.labels().is_empty()).count() >= MAX_LABEL_SETS {
+            drop(metrics);
+            self.counter("metrics_dropped_label_sets").inc();
+            // Without labels, its keys are shared with the unlabeled metric and leak nothing new.
+            return make(Labels::new());
+        }
+        let metric = make(to_labels(labels));
+        metrics.push(metric.clone());
+        metric
+    }
+
+    /// Sets the description shown in the Prometheus `# HELP` line for metrics named `name`.
+    pub fn set_help(&self, name: &'static str, help: &'static str) {
+        let mut entries = self.help.lock().unwrap();
+        match entries.iter_mut().find(|(n, _)| *n == name) {
+            Some(entry) => entry.1 = help,
+            None => entries.push((name, help)),
+        }
+    }
+
+    /// Returns the counter named `name`, making it if needed.
+    ///
+    /// Panics if another kind of metric has the name.
+    pub fn counter(&self, name: &'static str) -> Arc<Counter> {
+        self.counter_with_labels(name, &[])
+    }
+
+    /// Returns the counter with `name` and `labels`, making it if needed.
+    ///
+    /// Panics if another kind of metric has the name.
+    pub fn counter_with_labels(&self, name: &'static str, labels: &[(&'static str, &str)]) -> Arc<Counter> {
+        let make = |labels: Labels| Metric::Counter(Arc::new(Counter {
+            name,
+            key: event_key(name, "", &labels),
+            labels,
+            value: AtomicU64::new(0),
+        }));
+        match self.get_or_add(name, labels, "counter", make) {
+            Metric::Counter(counter) => counter,
+            other => panic!("metric {:?} is not a counter: {:?}", name, other),
+        }
+    }
+
+    /// Returns the gauge named `name`, making it if needed.
+    ///
+    /// Panics if another kind of metric has the name.
+    pub fn gauge(&self, name: &'static str) -> Arc<Gauge> {
+        self.gauge_with_labels(name, &[])
+    }
+
+    /// Returns the gauge with `name` and `labels`, making it if needed.
+    ///
+    /// Panics if another kind of metric has the name.
+    pub fn gauge_with_labels(&self, name: &'static str, labels: &[(&'static str, &str)]) -> Arc<Gauge> {
+        let make = |labels: Labels| Metric::Gauge(Arc::new(Gauge {
+            name,
+            key: event_key(name, "", &labels),
+            labels,
+            value: AtomicU64::new(0),
+        }));
+        match self.get_or_add(name, labels, "gauge", make) {
+            Metric::Gauge(gauge) => gauge,
+            other => panic!("metric {:?} is not a gauge: {:?}", name, other),
+        }
+    }
+
+    /// Returns the histogram named `name`, making it if needed.
+    /// `bounds` are the bucket upper bounds, in increasing order.
+    ///
+    /// Panics if another kind of metric has the name, or the histogram exists with other bounds.
+    pub fn histogram(&self, name: &'static str, bounds: &'static [u64]) -> Arc<Histogram> {
+        self.histogram_with_labels(name, &[], bounds)
+    }
+
+    /// Returns the histogram with `name` and `labels`, making it if needed.
+    ///
+    /// Panics if another kind of metric has the name, or the histogram exists with other bounds.
+    /// Panics if a label is named `le`, which Prometheus uses for bucket bounds.
+    pub fn histogram_with_labels(&self, name: &'static str, labels: &[(&'static str, &str)],
+                                 bounds: &'static [u64]) -> Arc<Histogram> {
+        assert!(bounds.windows(2).all(|w| w[0] < w[1]), "histogram bounds must increase: {:?}", bounds);
+        assert!(labels.iter().all(|(n, _)| *n != "le"), "histogram {:?} has a label named le", name);
+        let make = |labels: Labels| Metric::Histogram(Arc::new(Histogram {
+            name,
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count_key: event_key(name, "_count", &labels),
+            sum_key: event_key(name, "_sum", &labels),
+            bucket_keys: bounds.iter().map(|b| event_key(name, &format!("_le_{}", b), &labels)).collect(),
+            labels,
+        }));
+        match self.get_or_add(name, labels, "histogram", make) {
+            Metric::Histogram(histogram) if histogram.bounds == bounds => histogram,
+            other => panic!("metric {:?} is not a histogram with bounds {:?}: {:?}", name, bounds, other),
+        }
+    }
+
+    /// Returns the current values as event key-value pairs.
+    ///
+    /// A metric with labels has its labels appended to its name in Prometheus syntax,
+    /// so `http_responses` with `[("class", "5xx")]` has key `http_responses{class="5xx"}`.
+    /// Label values are escaped like in `prometheus_text`.
+    ///
+    /// A histogram named `x` has keys `x_count`, `x_sum`, and `x_le_N` for each bucket bound `N`,
+    /// each followed by the labels.
+    /// Each `x_le_N` value is the number of recorded values less than or equal to `N`.
+    pub fn snapshot(&self) -> Vec<(&'static str, u64)> {
+        let mut values = Vec::new();
+        for metric in self.metrics.lock().unwrap().iter() {
+            match metric {
+                Metric::Counter(counter) => values.push((counter.key, counter.get())),
+                Metric::Gauge(gauge) => values.push((gauge.key, gauge.get())),
+                Metric::Histogram(histogram) => {
+                    let counts = histogram.cumulative_counts();
+                    values.push((histogram.count_key, *counts.last().unwrap()));
+                    values.push((histogram.sum_key, histogram.sum()));
+                    for (key, count) in histogram.bucket_keys.iter().zip(counts) {
+                        values.push((key, count));
+                    }
+                }
+            }
+        }
+        values
+    }
+
+    /// Emits one `metrics` event with the value of every metric.
+    ///
+    /// Example:
+    /// `{"time_ns":1585851354242789000,...,"message":"metrics","queue_length":5,"failed_item_count":0}`
+    pub fn emit(&self) {
+        let values = self.snapshot();
+        if !values.is_empty() {
+            slog::info!(slog_scope::logger(), "metrics"; SnapshotKV(values));
+        }
+    }
+
+    /// Returns the current values in the Prometheus text exposition format, version 0.0.4.
+    /// Serve it with content type `text/plain; version=0.0.4`.
+    ///
+    /// Metrics appear in the order they were added, grouped by name.
+    /// See <https://prometheus.io/docs/instrumenting/exposition_formats/>.
+    pub fn prometheus_text(&self) -> String {
+        let metrics = self.metrics.lock().unwrap().clone();
+        let help = self.help.lock().unwrap().clone();
+        let mut names: Vec<&'static str> = Vec::new();
+        for metric in &metrics {
+            if !names.contains(&metric.name()) {
+                names.push(metric.name());
+            }
+        }
+        let mut text = String::new();
+        for name in names {
+            let mut family = metrics.iter().filter(|m| m.name() == name).peekable();
+            if let Some((_, help)) = help.iter().find(|(n, _)| *n == name) {
+                text.push_str(&format!("# HELP {} {}\n", name, escape_help(help)));
+            }
+            text.push_str(&format!("# TYPE {} {}\n", name, family.peek().unwrap().type_name()));
+            for metric in family {
+                match metric {
+                    Metric::Counter(counter) => push_sample(&mut text, name, "", &counter.labels, None, counter.get()),
+                    Metric::Gauge(gauge) => push_sample(&mut text, name, "", &gauge.labels, None, gauge.get()),
+                    Metric::Histogram(histogram) => {
+                        let counts = histogram.cumulative_counts();
+                        for (bound, count) in histogram.bounds.iter().zip(&counts) {
+                            push_sample(&mut text, name, "_bucket", &histogram.labels,
+                                        Some(&bound.to_string()), *count);
+                        }
+                        let total = *counts.last().unwrap();
+                        push_sample(&mut text, name, "_bucket", &histogram.labels, Some("+Inf"), total);
+                        push_sample(&mut text, name, "_sum", &histogram.labels, None, histogram.sum());
+                        push_sample(&mut text, name, "_count", &histogram.labels, None, total);
+                    }
+                }
+            }
+        }
+        text
+    }
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// Appends a line like `name_bucket{method="GET",le="10"} 3`.
+fn push_sample(text: &mut String, name: &str, suffix: &str, labels: &[(&'static str, String)],
+               le: Option<&str>, value: u64) {
+    text.push_str(name);
+    text.push_str(suffix);
+    let mut pairs: Vec<String> = labels.iter()
+        .map(|(n, v)| format!("{}=\"{}\"", n, escape_label_value(v)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if !pairs.is_empty() {
+        text.push('{');
+        text.push_str(&pairs.join(","));
+        text.push('}');
+    }
+    text.push_str(&format!(" {}\n", value));
+}
+
+struct SnapshotKV(Vec<(&'static str, u64)>);
+
+impl slog::KV for SnapshotKV {
+    fn serialize(&self, _record: &slog::Record, serializer: &mut dyn slog::Serializer) -> slog::Result {
+        for (key, value) in &self.0 {
+            serializer.emit_u64(key, *value)?;
+        }
+        Ok(())
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref GLOBAL_REGISTRY: Arc<Registry> = Arc::new(Registry::new());
+}
+
+/// Returns the process's registry.  Libraries record their metrics here.
+pub fn global() -> Arc<Registry> {
+    GLOBAL_REGISTRY.clone()
+}
+
+/// MetricsEmitter emits events from a background thread.
+/// Make one with `emit_periodically` or `ProcessSampler::emit_periodically`.
+///
+/// Dropping it stops the thread, after emitting one last time.
+pub struct MetricsEmitter {
+    stop_tx: Option<std::sync::mpsc::Sender<()>>,
+    join_handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl MetricsEmitter {
+    /// Starts a thread named `thread_name` that calls `emit` every `interval`.
+    pub(crate) fn start(thread_name: &str, interval: Duration, emit: impl Fn() + Send + 'static) -> MetricsEmitter {
+        let (stop_tx, stop_rx) = std::sync::mpsc::channel::<()>();
+        let join_handle = std::thread::Builder::new()
+            .name(String::from(thread_name))
+            .spawn(move || loop {
+                let stopping = match stop_rx.recv_timeout(interval) {
+                    Err(std::sync::mpsc::RecvTimeoutError::Timeout) => false,
+                    Ok(()) | Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => true,
+                };
+                emit();
+                if stopping {
+                    return;
+                }
+            })
+            .unwrap();
+        MetricsEmitter { stop_tx: Some(stop_tx), join_handle: Some(join_handle) }
+    }
+}
+
+impl Drop for MetricsEmitter {
+    fn drop(&mut self) {
+        drop(self.stop_tx.take());
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+    }
+}
+
+/// Starts a thread that calls `registry.emit()` every `interval`.
+pub fn emit_periodically(registry: Arc<Registry>, interval: Duration) -> MetricsEmitter {
+    MetricsEmitter::start("metrics_emitter", interval, move || registry.emit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let registry = Registry::new();
+        let requests = registry.counter("requests");
+        requests.inc();
+        registry.counter("requests").add(2);
+        let queue_length = registry.gauge("queue_length");
+        queue_length.set(2);
+        queue_length.dec();
+        queue_length.dec();
+        queue_length.dec();
+        queue_length.inc();
+        let latency = registry.histogram("latency_ms", &[10, 100]);
+        latency.record(5);
+        latency.record(10);
+        latency.record(50);
+        latency.record(500);
+        assert_eq!(
+            vec![
+                ("requests", 3),
+                ("queue_length", 1),
+                ("latency_ms_count", 4),
+                ("latency_ms_sum", 565),
+                ("latency_ms_le_10", 2),
+                ("latency_ms_le_100", 3),
+            ],
+            registry.snapshot());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_name_conflict() {
+        let registry = Registry::new();
+        registry.counter("x");
+        registry.gauge("x");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_labeled_kind_conflict() {
+        let registry = Registry::new();
+        registry.counter_with_labels("x", &[("method", "GET")]);
+        registry.gauge_with_labels("x", &[("method", "PUT")]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_invalid_name() {
+        Registry::new().counter_with_labels("x", &[("a{b", "1")]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_histogram_le_label() {
+        Registry::new().histogram_with_labels("x", &[("le", "1")], &[10]);
+    }
+
+    #[test]
+    fn test_max_label_sets() {
+        let registry = Registry::new();
+        let values: Vec<String> = (0..=MAX_LABEL_SETS).map(|n| n.to_string()).collect();
+        for value in &values {
+            registry.counter_with_labels("x", &[("n", value)]).inc();
+        }
+        let dropped = registry.counter_with_labels("x", &[("n", "dropped")]);
+        dropped.add(5);
+        assert_eq!(5, dropped.get());
+        // Existing label sets still work.
+        registry.counter_with_labels("x", &[("n", "0")]).inc();
+        let snapshot = registry.snapshot();
+        assert_eq!(MAX_LABEL_SETS + 1, snapshot.len());
+        assert_eq!(("x{n=\"0\"}", 2), snapshot[0]);
+        assert_eq!(("metrics_dropped_label_sets", 2), snapshot[MAX_LABEL_SETS]);
+    }
+
+    #[test]
+    fn test_event_keys() {
+        let registry = Registry::new();
+        registry.counter("x_GET").inc();
+        registry.counter_with_labels("x", &[("method", "GET")]).add(2);
+        registry.counter_with_labels("y", &[("a", "1\",b=\"2")]).add(3);
+        registry.counter_with_labels("y", &[("a", "1"), ("b", "2")]).add(4);
+        registry.histogram_with_labels("z", &[("method", "GET")], &[10]).record(5);
+        assert_eq!(
+            vec![
+                ("x_GET", 1),
+                ("x{method=\"GET\"}", 2),
+                ("y{a=\"1\\\",b=\\\"2\"}", 3),
+                ("y{a=\"1\",b=\"2\"}", 4),
+                ("z_count{method=\"GET\"}", 1),
+                ("z_sum{method=\"GET\"}", 5),
+                ("z_le_10{method=\"GET\"}", 1),
+            ],
+            registry.snapshot());
+        // Registries share keys instead of leaking them again.
+        let key = |registry: &Registry| registry.snapshot()[1].0;
+        assert!(std::ptr::eq(key(&registry), key(&{
+            let other = Registry::new();
+            other.counter("x_GET");
+            other.counter_with_labels("x", &[("method", "GET")]);
+            other
+        })));
+    }
+
+    #[test]
+    fn test_prometheus_text() {
+        let registry = Registry::new();
+        registry.set_help("requests", "Requests handled.");
+        registry.counter_with_labels("requests", &[("method", "GET")]).add(3);
+        registry.counter_with_labels("requests", &[("method", "PUT")]).inc();
+        registry.counter_with_labels("requests", &[("method", "GET")]).inc();
+        registry.gauge_with_labels("queue_length", &[("queue", "a\"b\\c\nd")]).set(2);
+        registry.set_help("latency_ms", "Time to handle\na request.");
+        let latency = registry.histogram("latency_ms", &[10, 100]);
+        latency.record(5);
+        latency.record(50);
+        latency.record(500);
+        assert_eq!(
+            vec![
+                ("requests{method=\"GET\"}", 4),
+                ("requests{method=\"PUT\"}", 1),
+                ("queue_length{queue=\"a\\\"b\\\\c\\nd\"}", 2),
+                ("latency_ms_count", 3),
+                ("latency_ms_sum", 555),
+                ("latency_ms_le_10", 1),
+                ("latency_ms_le_100", 2),
+            ],
+            registry.snapshot());
+        assert_eq!(
+            concat!(
+                "# HELP requests Requests handled.\n",
+                "# TYPE requests counter\n",
+                "requests{method=\"GET\"} 4\n",
+                "requests{method=\"PUT\"} 1\n",
+                "# TYPE queue_length gauge\n",
+                "queue_length{queue=\"a\\\"b\\\\c\\nd\"} 2\n",
+                "# HELP latency_ms Time to handle\\na request.\n",
+                "# TYPE latency_ms histogram\n",
+                "latency_ms_bucket{le=\"10\"} 1\n",
+                "latency_ms_bucket{le=\"100\"} 2\n",
+                "latency_ms_bucket{le=\"+Inf\"} 3\n",
+                "latency_ms_sum 555\n",
+                "latency_ms_count 3\n",
+            ),
+            registry.prometheus_text());
+    }
+}
@@ -0,0 +1,224 @@
+// Samples the process's memory, threads, and file descriptors from `/proc/self` on Linux.
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::metrics::MetricsEmitter;
+
+/// The name tokio 0.2 gives its threads, truncated to 15 bytes like in `/proc/self/task/*/comm`.
+/// Core worker threads and blocking pool threads get the same name.
+pub const TOKIO_THREAD_PREFIX: &str = "tokio-runtime-w";
+
+/// Memory use reported by the allocator, like jemalloc's `stats.allocated` and `stats.active`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AllocatorStats {
+    /// Bytes the program has allocated and not freed.
+    pub allocated_bytes: u64,
+    /// Bytes the allocator holds in pages, including free space in those pages.
+    pub heap_bytes: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProcessStats {
+    /// The allocator's heap size, or the data segment size (`VmData`) when there are no allocator stats.
+    pub heap_size_mb: u64,
+    /// Percent of the allocator's heap that is allocated.  `None` when there are no allocator stats.
+    pub heap_used_p: Option<u64>,
+    /// Resident set size (`VmRSS`).
+    pub rss_mb: u64,
+    pub threads: u64,
+    /// Threads whose names start with the sampler's tokio thread prefix.
+    /// This is not the number of tokio workers.  Tokio names its core workers and its blocking
+    /// pool threads alike, so this counts both.  Blocking pool threads come and go with
+    /// `spawn_blocking` and file I/O.
+    pub tokio_named_threads: u64,
+    /// Open file descriptors of every kind, including pipes and epoll instances.
+    pub fds: u64,
+    pub sockets: u64,
+    /// File descriptors for paths in the filesystem.
+    pub files: u64,
+}
+
+impl slog::KV for ProcessStats {
+    fn serialize(&self, _record: &slog::Record, serializer: &mut dyn slog::Serializer) -> slog::Result {
+        serializer.emit_u64("heap_size_mb", self.heap_size_mb)?;
+        if let Some(heap_used_p) = self.heap_used_p {
+            serializer.emit_u64("heap_used_p", heap_used_p)?;
+        }
+        serializer.emit_u64("rss_mb", self.rss_mb)?;
+        serializer.emit_u64("threads", self.threads)?;
+        serializer.emit_u64("tokio_named_threads", self.tokio_named_threads)?;
+        serializer.emit_u64("fds", self.fds)?;
+        serializer.emit_u64("sockets", self.sockets)?;
+        serializer.emit_u64("files", self.files)
+    }
+}
+
+/// ProcessSampler reads `/proc/self/status`, `/proc/self/stat`, and `/proc/self/fd`
+/// and emits the results as `process_stats` events.
+///
+/// A steadily rising `fds` or `sockets` value shows a file descriptor leak long before
+/// the process runs out and gets `EMFILE` "Too many open files" errors.
+pub struct ProcessSampler {
+    tokio_thread_prefix: &'static str,
+    allocator_stats: Option<Box<dyn Fn() -> AllocatorStats + Send + Sync>>,
+}
+
+impl Default for ProcessSampler {
+    fn default() -> Self {
+        ProcessSampler::new()
+    }
+}
+
+impl ProcessSampler {
+    pub fn new() -> ProcessSampler {
+        ProcessSampler { tokio_thread_prefix: TOKIO_THREAD_PREFIX, allocator_stats: None }
+    }
+
+    /// Counts threads with names starting with `prefix` as tokio threads.
+    /// Use this if you set a thread name with `tokio::runtime::Builder::thread_name`.
+    /// Linux truncates thread names to 15 bytes.
+    pub fn tokio_thread_prefix(mut self, prefix: &'static str) -> ProcessSampler {
+        self.tokio_thread_prefix = prefix;
+        self
+    }
+
+    /// Calls `f` to get heap stats from the allocator.
+    /// Without this, events have no `heap_used_p`.
+    pub fn allocator_stats(mut self, f: impl Fn() -> AllocatorStats + Send + Sync + 'static) -> ProcessSampler {
+        self.allocator_stats = Some(Box::new(f));
+        self
+    }
+
+    pub fn sample(&self) -> io::Result<ProcessStats> {
+        let proc_self = Path::new("/proc/self");
+        let status = std::fs::read_to_string(proc_self.join("status"))?;
+        let stat = std::fs::read_to_string(proc_self.join("stat"))?;
+        let mut stats = ProcessStats {
+            heap_size_mb: status_kb(&status, "VmData")? / 1024,
+            rss_mb: status_kb(&status, "VmRSS")? / 1024,
+            threads: stat_num_threads(&stat)?,
+            ..ProcessStats::default()
+        };
+        if let Some(f) = &self.allocator_stats {
+            let allocator_stats = f();
+            stats.heap_size_mb = allocator_stats.heap_bytes / 1024 / 1024;
+            stats.heap_used_p = Some(
+                (allocator_stats.allocated_bytes * 100).checked_div(allocator_stats.heap_bytes).unwrap_or(0));
+        }
+        for entry in std::fs::read_dir(proc_self.join("task"))? {
+            // Threads can exit while we read.
+            if let Ok(name) = std::fs::read_to_string(entry?.path().join("comm")) {
+                if name.starts_with(self.tokio_thread_prefix) {
+                    stats.tokio_named_threads += 1;
+                }
+            }
+        }
+        // Listing the directory uses a file descriptor, which appears in `fds`.
+        for entry in std::fs::read_dir(proc_self.join("fd"))? {
+            // File descriptors can close while we read.
+            if let Ok(target) = std::fs::read_link(entry?.path()) {
+                stats.fds += 1;
+                if target.to_string_lossy().starts_with("socket:[") {
+                    stats.sockets += 1;
+                } else if target.is_absolute() {
+                    stats.files += 1;
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Emits one `process_stats` event.
+    ///
+    /// Example:
+    /// `{"time_ns":1585851354242789000,...,"message":"process_stats","heap_size_mb":1024,"heap_used_p":38,"rss_mb":610,"threads":594,"tokio_named_threads":8,"fds":750,"sockets":578,"files":163}`
+    pub fn emit(&self) {
+        match self.sample() {
+            Ok(stats) => slog::info!(slog_scope::logger(), "process_stats"; stats),
+            Err(e) => slog::warn!(slog_scope::logger(), "failed reading /proc/self: {}", e),
+        }
+    }
+
+    /// Starts a thread that calls `emit()` every `interval`.
+    pub fn emit_periodically(self, interval: Duration) -> MetricsEmitter {
+        MetricsEmitter::start("process_sampler", interval, move || self.emit())
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Returns the value of a line like `VmData:\t  1234 kB` from `/proc/self/status`.
+fn status_kb(status: &str, key: &str) -> io::Result<u64> {
+    let line = status.lines()
+        .find(|line| line.split(':').next() == Some(key))
+        .ok_or_else(|| invalid_data(format!("/proc/self/status has no {}", key)))?;
+    line[key.len() + 1..].trim().trim_end_matches("kB").trim().parse()
+        .map_err(|e| invalid_data(format!("/proc/self/status has bad {} line {:?}: {}", key, line, e)))
+}
+
+/// Returns the `num_threads` field of `/proc/self/stat`.
+fn stat_num_threads(stat: &str) -> io::Result<u64> {
+    // The second field is the program name in parentheses, which may contain spaces and parentheses.
+    // `num_threads` is the 20th field.
+    let after_name = &stat[stat.rfind(')').ok_or_else(|| invalid_data(format!("bad /proc/self/stat {:?}", stat)))? + 1..];
+    after_name.split_whitespace().nth(17)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid_data(format!("bad /proc/self/stat {:?}", stat)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let status = "Name:\tmy prog\nVmData:\t  204800 kB\nVmRSS:\t    6144 kB\n";
+        assert_eq!(204800, status_kb(status, "VmData").unwrap());
+        assert_eq!(6144, status_kb(status, "VmRSS").unwrap());
+        assert_eq!(io::ErrorKind::InvalidData, status_kb(status, "VmSwap").unwrap_err().kind());
+        let stat = "4242 (my (prog)) S 1 4242 4242 0 -1 4194560 1033 0 0 0 1 2 0 0 20 0 7 0 123 0 0";
+        assert_eq!(7, stat_num_threads(stat).unwrap());
+        assert_eq!(io::ErrorKind::InvalidData, stat_num_threads("4242 (prog) S 1").unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_sample() {
+        let sampler = ProcessSampler::new().tokio_thread_prefix("test_sample_w");
+        let before = sampler.sample().unwrap();
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel::<()>();
+        let (stop_tx, stop_rx) = std::sync::mpsc::channel::<()>();
+        let thread = std::thread::Builder::new()
+            .name(String::from("test_sample_worker"))
+            .spawn(move || {
+                // The new thread sets its own name, so wait until it is running.
+                ready_tx.send(()).unwrap();
+                stop_rx.recv()
+            })
+            .unwrap();
+        ready_rx.recv().unwrap();
+        let _listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _file = std::fs::File::open("/proc/self/status").unwrap();
+        let after = sampler.sample().unwrap();
+        drop(stop_tx);
+        thread.join().unwrap().unwrap_err();
+        assert_eq!(0, before.tokio_named_threads);
+        assert_eq!(1, after.tokio_named_threads);
+        assert!(after.threads >= 2);
+        // Other tests run in parallel threads and open and close files, so compare with the file
+        // descriptors this test holds.
+        assert!(after.sockets >= 1);
+        assert!(after.files >= 1);
+        assert!(after.fds >= after.sockets + after.files);
+        assert!(after.rss_mb > 0);
+        assert_eq!(None, after.heap_used_p);
+
+        let sampler = ProcessSampler::new()
+            .allocator_stats(|| AllocatorStats { allocated_bytes: 30 << 20, heap_bytes: 80 << 20 });
+        let stats = sampler.sample().unwrap();
+        assert_eq!(80, stats.heap_size_mb);
+        assert_eq!(Some(37), stats.heap_used_p);
+    }
+}